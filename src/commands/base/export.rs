@@ -0,0 +1,109 @@
+//! Mappings of the output of commands onto the schemas SIEMs use.
+//!
+//! A command declares an `Export` next to its output schema: the kind of
+//! event its rows are, and the Elastic Common Schema and OCSF attribute each
+//! well-known column corresponds to. The `ecs` and `ocsf` formats write a
+//! document per row from it, and put the columns without a counterpart into
+//! the `rustbelt` extension object.
+
+/// The kind of event the rows of a command are.
+///
+/// # Variants
+/// - `DeviceInventory`: Facts about the computer, such as its operating system.
+/// - `SoftwareInventory`: Software installed or registered on the computer.
+/// - `Finding`: Something an analyst should look at, such as a weak configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    DeviceInventory,
+    SoftwareInventory,
+    Finding,
+}
+
+impl EventClass {
+    /// Returns the `event.kind` and `event.category` of the class in Elastic Common Schema.
+    pub fn ecs_kind(&self) -> (&'static str, &'static str) {
+        match self {
+            EventClass::DeviceInventory => ("state", "host"),
+            EventClass::SoftwareInventory => ("state", "package"),
+            EventClass::Finding => ("alert", "configuration"),
+        }
+    }
+
+    /// Returns the OCSF class of the class, as its `class_uid` and `class_name`.
+    pub fn ocsf_class(&self) -> (u32, &'static str) {
+        match self {
+            EventClass::DeviceInventory => (5001, "Device Inventory Info"),
+            EventClass::SoftwareInventory => (5020, "Software Inventory Info"),
+            EventClass::Finding => (2004, "Detection Finding"),
+        }
+    }
+
+    /// Returns the OCSF category of the class, as its `category_uid` and `category_name`.
+    pub fn ocsf_category(&self) -> (u32, &'static str) {
+        match self {
+            EventClass::DeviceInventory | EventClass::SoftwareInventory => (5, "Discovery"),
+            EventClass::Finding => (2, "Findings"),
+        }
+    }
+
+    /// Returns the OCSF activity of the class, as its `activity_id` and `activity_name`.
+    pub fn ocsf_activity(&self) -> (u32, &'static str) {
+        match self {
+            EventClass::DeviceInventory | EventClass::SoftwareInventory => (2, "Collect"),
+            EventClass::Finding => (1, "Create"),
+        }
+    }
+}
+
+/// The attributes a column corresponds to, as dotted paths such as `host.os.name`.
+///
+/// # Fields
+/// - `column`: The name of the column.
+/// - `ecs`: The Elastic Common Schema field, if there is one.
+/// - `ocsf`: The OCSF attribute, if there is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldMapping {
+    pub column: &'static str,
+    pub ecs: Option<&'static str>,
+    pub ocsf: Option<&'static str>,
+}
+
+impl FieldMapping {
+    /// Creates the mapping of a column without counterparts.
+    pub const fn new(column: &'static str) -> Self {
+        FieldMapping { column, ecs: None, ocsf: None }
+    }
+
+    /// Maps the column onto an Elastic Common Schema field.
+    pub const fn ecs(mut self, field: &'static str) -> Self {
+        self.ecs = Some(field);
+        self
+    }
+
+    /// Maps the column onto an OCSF attribute.
+    pub const fn ocsf(mut self, attribute: &'static str) -> Self {
+        self.ocsf = Some(attribute);
+        self
+    }
+}
+
+/// How the output of a command is exported to a SIEM.
+///
+/// # Fields
+/// - `class`: The kind of event every row is.
+/// - `fields`: The columns with a counterpart. The others go into the extension object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Export {
+    pub class: EventClass,
+    pub fields: &'static [FieldMapping],
+}
+
+impl Export {
+    /// The export of tables without one, such as those of plugins: device inventory with every column in the extension.
+    pub const UNMAPPED: Export = Export { class: EventClass::DeviceInventory, fields: &[] };
+
+    /// Returns the mapping of a column, if it has one.
+    pub fn field(&self, column: &str) -> Option<&FieldMapping> {
+        self.fields.iter().find(|field| field.column == column)
+    }
+}
@@ -1,19 +1,254 @@
 /// This module defines the base structures and traits for commands.
+pub mod export;
 pub mod registry;
+pub mod schema;
 
-use crate::runtime::Runtime;
-use std::collections::HashMap;
-use windows::{core::*, Win32::System::Variant::VARIANT};
+use crate::{
+    error::Result,
+    runtime::Runtime,
+    utils::{
+        registry::{
+            users::{for_each_user, UserProfile},
+            RegistryHive,
+        },
+        time::{TimeDisplay, Timestamp},
+    },
+};
+use export::Export;
+use schema::{Field, FieldTag, FieldType, Schema};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value as Json};
+use indexmap::IndexMap;
+use std::fmt;
+
+/// A single typed value in a command result.
+///
+/// # Variants
+/// - `Null`: An absent value.
+/// - `Bool`: A boolean.
+/// - `Integer`: A signed number.
+/// - `Unsigned`: An unsigned number.
+/// - `Float`: A floating point number.
+/// - `String`: A string.
+/// - `Timestamp`: A point in time, rendered according to the `TimeDisplay` of the output.
+/// - `Bytes`: Raw bytes.
+/// - `List`: A list of values.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+    String(String),
+    Timestamp(Timestamp),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// Returns the value as a string slice if it is a `Value::String`.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Renders the value as text, with timestamps in the given time zone and format.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - How to render timestamps.
+    pub fn render(&self, time: &TimeDisplay) -> String {
+        match self {
+            Value::Timestamp(timestamp) => timestamp.render(time),
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.render(time)).collect();
+                values.join(", ")
+            }
+            value => value.to_string(),
+        }
+    }
+
+    /// Converts the value to JSON. Bytes become an array of numbers and
+    /// timestamps an ISO 8601 string in UTC.
+    pub fn to_json(&self) -> Json {
+        match self {
+            Value::Null => Json::Null,
+            Value::Bool(value) => json!(value),
+            Value::Integer(value) => json!(value),
+            Value::Unsigned(value) => json!(value),
+            Value::Float(value) => json!(value),
+            Value::String(value) => json!(value),
+            Value::Timestamp(value) => json!(value.to_string()),
+            Value::Bytes(value) => json!(value),
+            Value::List(values) => Json::Array(values.iter().map(Value::to_json).collect()),
+        }
+    }
+
+    /// Converts JSON to a value. Objects are kept as their JSON text.
+    pub fn from_json(json: &Json) -> Value {
+        match json {
+            Json::Null => Value::Null,
+            Json::Bool(value) => Value::Bool(*value),
+            Json::Number(number) => {
+                if let Some(value) = number.as_i64() {
+                    Value::Integer(value)
+                } else if let Some(value) = number.as_u64() {
+                    Value::Unsigned(value)
+                } else {
+                    Value::Float(number.as_f64().unwrap_or_default())
+                }
+            }
+            Json::String(value) => Value::String(value.clone()),
+            Json::Array(values) => Value::List(values.iter().map(Value::from_json).collect()),
+            Json::Object(_) => Value::String(json.to_string()),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Integer(value) => write!(f, "{value}"),
+            Value::Unsigned(value) => write!(f, "{value}"),
+            Value::Float(value) => write!(f, "{value}"),
+            Value::String(value) => write!(f, "{value}"),
+            Value::Timestamp(value) => write!(f, "{value}"),
+            Value::Bytes(value) => write!(f, "{:?}", value),
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, "{}", values.join(", "))
+            }
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Unsigned(value as u64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Unsigned(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<Timestamp> for Value {
+    fn from(value: Timestamp) -> Self {
+        Value::Timestamp(value)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Value::Bytes(value)
+    }
+}
+
+impl From<Vec<String>> for Value {
+    fn from(values: Vec<String>) -> Self {
+        Value::List(values.into_iter().map(Value::String).collect())
+    }
+}
+
+/// A single row of a command result, mapping column names to values in column order.
+pub type Row = IndexMap<String, Value>;
+
+/// The column of the SID of the user in rows read with `user_rows`.
+pub const SID_COLUMN: &str = "SID";
+
+/// The column of the name of the user in rows read with `user_rows`.
+pub const USERNAME_COLUMN: &str = "Username";
+
+/// The field of `SID_COLUMN`, first in the schemas of commands that use `user_rows`.
+pub const SID_FIELD: Field =
+    Field::required(SID_COLUMN, FieldType::String, "The SID of the user.").tagged(FieldTag::Sid);
+
+/// The field of `USERNAME_COLUMN`, second in the schemas of commands that use `user_rows`.
+pub const USERNAME_FIELD: Field = Field::required(
+    USERNAME_COLUMN,
+    FieldType::String,
+    "The name of the user, resolved from the SID where the registry allows it.",
+)
+.tagged(FieldTag::Username);
+
+/// Reads rows of every user whose hive the registry of the runtime shows, see `for_each_user`.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime of the command.
+/// * `read` - Reads the rows of a user, typically below `HKEY_USERS\<SID>`, see `UserProfile::key_path`.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Row>)` containing the rows of all users, each with `SID` and `Username` first.
+/// * `Err(e)` if the profiles could not be listed, or `read` failed other than with `Error::NotFound`.
+pub fn user_rows(runtime: &Runtime, read: impl FnMut(&UserProfile) -> Result<Vec<Row>>) -> Result<Vec<Row>> {
+    let mut rows = vec![];
+    for (profile, user_rows) in for_each_user(runtime.registry(), read)? {
+        for row in user_rows {
+            let mut user_row = Row::from([
+                (SID_COLUMN.to_string(), Value::from(profile.sid.as_str())),
+                (USERNAME_COLUMN.to_string(), Value::from(profile.username.as_str())),
+            ]);
+            user_row.extend(row);
+            rows.push(user_row);
+        }
+    }
+    Ok(rows)
+}
 
 /// Data Transfer Object for commands.
 ///
 /// # Fields
 /// - `source`: The source of the command.
-/// - `data`: A vector of hash maps containing command data.
-#[derive(Clone)]
+/// - `data`: A vector of rows containing command data.
+#[derive(Clone, Debug, Serialize)]
 pub struct CommandDTO {
     pub source: String,
-    pub data: Vec<HashMap<String, VARIANT>>,
+    pub data: Vec<Row>,
 }
 
 /// Enum representing the result of a command execution.
@@ -21,17 +256,59 @@ pub struct CommandDTO {
 /// # Variants
 /// - `Simple`: A single command result.
 /// - `Group`: A group of command results.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
 pub enum CommandResult {
     Simple(CommandDTO),
     Group(Vec<CommandDTO>),
 }
 
+impl CommandResult {
+    /// Returns the tables of the result: the table of a simple result, or the tables of a group.
+    pub fn tables(&self) -> &[CommandDTO] {
+        match self {
+            CommandResult::Simple(table) => std::slice::from_ref(table),
+            CommandResult::Group(tables) => tables,
+        }
+    }
+
+    /// Returns the tables of the result for modification.
+    pub fn tables_mut(&mut self) -> &mut [CommandDTO] {
+        match self {
+            CommandResult::Simple(table) => std::slice::from_mut(table),
+            CommandResult::Group(tables) => tables,
+        }
+    }
+}
+
+/// A resource a command accesses, as declared in its `CommandData`.
+///
+/// A `*` in a path stands for every name the command enumerates at that level,
+/// such as every subkey of a key.
+///
+/// # Variants
+/// - `Registry`: A registry key, which the command reads values or subkeys of.
+/// - `Wmi`: A WQL query in a WMI namespace.
+/// - `File`: A file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Registry(RegistryHive, &'static str),
+    Wmi { namespace: &'static str, query: &'static str },
+    File(&'static str),
+}
+
 /// Struct containing data for commands.
 ///
 /// # Fields
 /// - `support_remote`: A boolean indicating if the command supports remote execution.
+/// - `schema`: The schema of the output of the command.
+/// - `accesses`: The registry keys, WMI queries and files the command accesses, for `--dry-run`.
+/// - `export`: How the output is mapped onto the schemas of SIEMs, if it is.
 pub struct CommandData {
     pub support_remote: bool,
+    pub schema: &'static Schema,
+    pub accesses: &'static [Access],
+    pub export: Option<&'static Export>,
 }
 
 /// Trait defining the behavior of a command.
@@ -45,4 +322,20 @@ pub trait Command {
     /// # Returns
     /// A result containing a `CommandResult` or an error.
     fn execute(&self, runtime: &Runtime, args: &[String]) -> Result<CommandResult>;
+
+    /// Returns the data describing the command, if it has any.
+    ///
+    /// # Returns
+    /// The `CommandData` of the command, or `None` for commands without it, such as groups and plugins.
+    fn command_data(&self) -> Option<&CommandData> {
+        None
+    }
+
+    /// Returns the names of the commands a group executes.
+    ///
+    /// # Returns
+    /// The members of a group, or an empty list for other commands.
+    fn members(&self) -> Vec<String> {
+        vec![]
+    }
 }
@@ -1,5 +1,10 @@
-use crate::commands::base::Command;
-use clap::Command as ClapCommand;
+use std::sync::RwLock;
+
+use crate::{
+    commands::base::{export::Export, schema::Schema, Command},
+    error::{Error, Result},
+};
+use clap::{Arg, Command as ClapCommand};
 
 /// Struct representing a command registration.
 ///
@@ -17,6 +22,47 @@ pub struct CommandRegistration {
 // Collect all command registrations.
 inventory::collect!(CommandRegistration);
 
+/// Struct representing a command registered while Rustbelt is running, such as a plugin.
+///
+/// # Fields
+///
+/// * `name` - The name of the command.
+/// * `about` - A short description of the command.
+/// * `factory` - A function that returns a boxed instance of the command.
+pub struct DynamicRegistration {
+    pub name: String,
+    pub about: String,
+    pub factory: Box<dyn Fn() -> Box<dyn Command> + Send + Sync>,
+}
+
+// All commands registered through `register_command`.
+static DYNAMIC_COMMANDS: RwLock<Vec<DynamicRegistration>> = RwLock::new(Vec::new());
+
+/// Registers a command next to the commands collected by `inventory`.
+///
+/// # Arguments
+///
+/// * `registration` - The registration of the command.
+///
+/// # Returns
+///
+/// * `Ok(())` if the command was registered.
+/// * `Err(e)` if a command with the same name already exists.
+pub fn register_command(registration: DynamicRegistration) -> Result<()> {
+    if command_names().contains(&registration.name) {
+        return Err(Error::InvalidData(format!(
+            "a command named '{}' is already registered",
+            registration.name
+        )));
+    }
+
+    DYNAMIC_COMMANDS
+        .write()
+        .expect("command registry poisoned")
+        .push(registration);
+    Ok(())
+}
+
 /// Retrieves a command by its name.
 ///
 /// # Arguments
@@ -27,8 +73,99 @@ inventory::collect!(CommandRegistration);
 ///
 /// An `Option` containing the boxed command if found, or `None` if not found.
 pub fn get_command(name: &str) -> Option<Box<dyn Command>> {
-    inventory::iter::<CommandRegistration>
+    let registered = inventory::iter::<CommandRegistration>
         .into_iter()
         .find(|registration| registration.name == name)
-        .map(|registration| (registration.factory)())
+        .map(|registration| (registration.factory)());
+
+    registered.or_else(|| {
+        DYNAMIC_COMMANDS
+            .read()
+            .expect("command registry poisoned")
+            .iter()
+            .find(|registration| registration.name == name)
+            .map(|registration| (registration.factory)())
+    })
+}
+
+/// Retrieves the names of all registered commands and groups.
+///
+/// # Returns
+///
+/// A vector with the name of every command registration.
+pub fn command_names() -> Vec<String> {
+    let mut names: Vec<String> = inventory::iter::<CommandRegistration>
+        .into_iter()
+        .map(|registration| registration.name.to_string())
+        .collect();
+
+    names.extend(
+        DYNAMIC_COMMANDS
+            .read()
+            .expect("command registry poisoned")
+            .iter()
+            .map(|registration| registration.name.clone()),
+    );
+    names
+}
+
+/// Builds the Clap commands of all registered commands and groups.
+///
+/// # Returns
+///
+/// A vector with a Clap command for every command registration.
+pub fn clap_commands() -> Vec<ClapCommand> {
+    let mut commands: Vec<ClapCommand> = inventory::iter::<CommandRegistration>
+        .into_iter()
+        .map(|registration| (registration.clap_command)())
+        .collect();
+
+    commands.extend(
+        DYNAMIC_COMMANDS
+            .read()
+            .expect("command registry poisoned")
+            .iter()
+            .map(|registration| {
+                // Dynamic commands parse their own arguments, so they take any.
+                ClapCommand::new(registration.name.clone()).about(registration.about.clone()).arg(
+                    Arg::new("ARGS")
+                        .help("The arguments of the command")
+                        .num_args(0..)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true),
+                )
+            }),
+    );
+    commands
+}
+
+/// Retrieves the output schemas of all registered commands that declare one.
+///
+/// # Returns
+///
+/// A vector with the name and the schema of every such command, in registration order.
+pub fn schemas() -> Vec<(String, &'static Schema)> {
+    command_names()
+        .into_iter()
+        .filter_map(|name| {
+            let schema = get_command(&name)?.command_data()?.schema;
+            Some((name, schema))
+        })
+        .collect()
+}
+
+/// Retrieves the SIEM export mappings of all registered commands that declare one.
+///
+/// # Returns
+///
+/// A vector with the output schema and the export mapping of every such command, in registration order.
+pub fn exports() -> Vec<(&'static Schema, &'static Export)> {
+    command_names()
+        .into_iter()
+        .filter_map(|name| {
+            let command = get_command(&name)?;
+            let command_data = command.command_data()?;
+            Some((command_data.schema, command_data.export?))
+        })
+        .collect()
 }
@@ -0,0 +1,348 @@
+//! Output schemas of commands.
+//!
+//! Every command declares the table it returns: its source, its fields with
+//! their types and descriptions, and the identity keys that tell rows apart.
+//! The runtime orders the columns of every row as declared, checks rows
+//! against the schema in debug builds, and the whole catalog can be exported
+//! as a JSON Schema document for consumers of the structured output.
+
+use serde_json::{json, Map, Value as Json};
+
+use super::{CommandDTO, Row, Value};
+use crate::error::{Error, Result};
+
+/// The type of the values of a field.
+///
+/// # Variants
+/// - `Bool`: `Value::Bool`.
+/// - `Integer`: `Value::Integer`.
+/// - `Unsigned`: `Value::Unsigned`.
+/// - `Float`: `Value::Float`.
+/// - `String`: `Value::String`.
+/// - `Timestamp`: `Value::Timestamp`.
+/// - `Bytes`: `Value::Bytes`.
+/// - `List`: `Value::List`, of any values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Bool,
+    Integer,
+    Unsigned,
+    Float,
+    String,
+    Timestamp,
+    Bytes,
+    List,
+}
+
+impl FieldType {
+    /// Returns whether a value has this type. Null is handled by the field.
+    pub fn matches(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (FieldType::Bool, Value::Bool(_))
+                | (FieldType::Integer, Value::Integer(_))
+                | (FieldType::Unsigned, Value::Unsigned(_))
+                | (FieldType::Float, Value::Float(_))
+                | (FieldType::String, Value::String(_))
+                | (FieldType::Timestamp, Value::Timestamp(_))
+                | (FieldType::Bytes, Value::Bytes(_))
+                | (FieldType::List, Value::List(_))
+        )
+    }
+
+    /// Returns the JSON Schema of the JSON form of values of this type, see `Value::to_json`.
+    fn json_schema(&self) -> Json {
+        match self {
+            FieldType::Bool => json!({ "type": "boolean" }),
+            FieldType::Integer => json!({ "type": "integer" }),
+            FieldType::Unsigned => json!({ "type": "integer", "minimum": 0 }),
+            FieldType::Float => json!({ "type": "number" }),
+            FieldType::String => json!({ "type": "string" }),
+            FieldType::Timestamp => json!({ "type": "string", "format": "date-time" }),
+            FieldType::Bytes => json!({ "type": "array", "items": { "type": "integer", "minimum": 0, "maximum": 255 } }),
+            FieldType::List => json!({ "type": "array" }),
+        }
+    }
+}
+
+/// What kind of sensitive data a field holds, for redaction.
+///
+/// # Variants
+/// - `Secret`: A secret, such as a password or key, which is masked.
+/// - `Username`: The name of a user account.
+/// - `Sid`: A security identifier.
+/// - `Hostname`: The name of a computer.
+/// - `IpAddress`: An IPv4 or IPv6 address.
+/// - `Identifier`: Another value that identifies a machine or user, such as a GUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FieldTag {
+    Secret,
+    Username,
+    Sid,
+    Hostname,
+    IpAddress,
+    Identifier,
+}
+
+impl FieldTag {
+    /// Returns the name of the tag, as used in the JSON Schema and the pseudonym mapping.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldTag::Secret => "secret",
+            FieldTag::Username => "username",
+            FieldTag::Sid => "sid",
+            FieldTag::Hostname => "hostname",
+            FieldTag::IpAddress => "ip-address",
+            FieldTag::Identifier => "identifier",
+        }
+    }
+}
+
+/// A column of the output of a command.
+///
+/// # Fields
+/// - `name`: The name of the column.
+/// - `field_type`: The type of its values.
+/// - `description`: What the column contains.
+/// - `optional`: Whether rows may lack the column or have a null value in it.
+/// - `tag`: What kind of sensitive data the column holds, if any.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    pub description: &'static str,
+    pub optional: bool,
+    pub tag: Option<FieldTag>,
+}
+
+impl Field {
+    /// Declares a column every row has a value in.
+    pub const fn required(name: &'static str, field_type: FieldType, description: &'static str) -> Self {
+        Field {
+            name,
+            field_type,
+            description,
+            optional: false,
+            tag: None,
+        }
+    }
+
+    /// Declares a column rows may lack or have a null value in.
+    pub const fn optional(name: &'static str, field_type: FieldType, description: &'static str) -> Self {
+        Field {
+            name,
+            field_type,
+            description,
+            optional: true,
+            tag: None,
+        }
+    }
+
+    /// Tags the column as holding sensitive data.
+    pub const fn tagged(mut self, tag: FieldTag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+}
+
+/// The table a command returns.
+///
+/// # Fields
+/// - `source`: The source of the table, as in `CommandDTO::source`.
+/// - `fields`: The columns, in the order they are output in.
+/// - `identity`: The columns that together identify a row. Empty for tables with a single row.
+#[derive(Debug, Clone, Copy)]
+pub struct Schema {
+    pub source: &'static str,
+    pub fields: &'static [Field],
+    pub identity: &'static [&'static str],
+}
+
+impl Schema {
+    /// Returns the field with the given name.
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+
+    /// Orders the columns of a row as declared. Undeclared columns follow in their current order.
+    pub fn arrange(&self, row: &mut Row) {
+        let position = |name: &str| {
+            self.fields
+                .iter()
+                .position(|field| field.name == name)
+                .unwrap_or(self.fields.len())
+        };
+        // The sort is stable, so undeclared columns keep their order.
+        row.sort_by(|a, _, b, _| position(a).cmp(&position(b)));
+    }
+
+    /// Checks a table against the schema.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the table has the declared source, and every row has
+    ///   only declared columns, all required ones, and values of the declared types.
+    /// * `Err(Error::InvalidData)` describing the first violation otherwise.
+    pub fn validate(&self, table: &CommandDTO) -> Result<()> {
+        let invalid = |what: String| Error::InvalidData(format!("output of '{}' {what}", self.source));
+        if table.source != self.source {
+            return Err(invalid(format!("has the source '{}'", table.source)));
+        }
+
+        for (index, row) in table.data.iter().enumerate() {
+            if let Some(name) = row.keys().find(|name| self.field(name).is_none()) {
+                return Err(invalid(format!("has the undeclared column '{name}' in row {index}")));
+            }
+            for field in self.fields {
+                match row.get(field.name).unwrap_or(&Value::Null) {
+                    Value::Null if field.optional => {}
+                    Value::Null => {
+                        return Err(invalid(format!("lacks the column '{}' in row {index}", field.name)));
+                    }
+                    value if !field.field_type.matches(value) => {
+                        return Err(invalid(format!(
+                            "has {value:?} in the {:?} column '{}' in row {index}",
+                            field.field_type, field.name
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the JSON Schema of the table in the JSON output, see `CommandDTO`.
+    ///
+    /// The identity keys are listed in the `x-identity` keyword of the rows,
+    /// and the tags of fields in their `x-tag` keyword.
+    /// Rows may have more columns, such as the `Target` of multi-target runs.
+    pub fn json_schema(&self) -> Json {
+        let mut properties = Map::new();
+        for field in self.fields {
+            let mut property = field.field_type.json_schema();
+            if field.optional {
+                let types = json!([property["type"].clone(), "null"]);
+                property["type"] = types;
+            }
+            property["description"] = json!(field.description);
+            if let Some(tag) = field.tag {
+                property["x-tag"] = json!(tag.name());
+            }
+            properties.insert(field.name.to_string(), property);
+        }
+        let required: Vec<&str> = self
+            .fields
+            .iter()
+            .filter(|field| !field.optional)
+            .map(|field| field.name)
+            .collect();
+
+        json!({
+            "type": "object",
+            "properties": {
+                "source": { "const": self.source },
+                "data": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": properties,
+                        "required": required,
+                        "x-identity": self.identity,
+                    },
+                },
+            },
+            "required": ["source", "data"],
+        })
+    }
+}
+
+/// Builds the JSON Schema document of the output of a set of commands.
+///
+/// Every command is described in `$defs` under its name. The document
+/// accepts the output of any of them, alone or in a group.
+///
+/// # Arguments
+///
+/// * `schemas` - The commands and their schemas.
+pub fn catalog_json_schema<'a>(schemas: impl IntoIterator<Item = (&'a str, &'a Schema)>) -> Json {
+    let mut definitions = Map::new();
+    let mut references = vec![];
+    for (name, schema) in schemas {
+        definitions.insert(name.to_string(), schema.json_schema());
+        references.push(json!({ "$ref": format!("#/$defs/{name}") }));
+    }
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Rustbelt command output",
+        "$defs": definitions,
+        "anyOf": [
+            { "anyOf": references },
+            { "type": "array", "items": { "anyOf": references } },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: Schema = Schema {
+        source: "Test",
+        fields: &[
+            Field::required("Name", FieldType::String, "The name."),
+            Field::optional("Count", FieldType::Unsigned, "The count."),
+        ],
+        identity: &["Name"],
+    };
+
+    fn table(rows: Vec<Vec<(&str, Value)>>) -> CommandDTO {
+        CommandDTO {
+            source: "Test".to_string(),
+            data: rows
+                .into_iter()
+                .map(|row| row.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = table(vec![
+            vec![("Name", Value::from("a")), ("Count", Value::from(1u64))],
+            vec![("Name", Value::from("b")), ("Count", Value::Null)],
+            vec![("Name", Value::from("c"))],
+        ]);
+        assert!(SCHEMA.validate(&valid).is_ok());
+
+        let invalid = [
+            table(vec![vec![("Count", Value::from(1u64))]]),
+            table(vec![vec![("Name", Value::Null)]]),
+            table(vec![vec![("Name", Value::from("a")), ("Count", Value::from(-1i64))]]),
+            table(vec![vec![("Name", Value::from("a")), ("Other", Value::Null)]]),
+        ];
+        for table in invalid {
+            assert!(matches!(SCHEMA.validate(&table), Err(Error::InvalidData(_))), "{table:?}");
+        }
+    }
+
+    #[test]
+    fn test_arrange() {
+        let mut row: Row = [("Extra", Value::Null), ("Count", Value::from(1u64)), ("Name", Value::from("a"))]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        SCHEMA.arrange(&mut row);
+        assert_eq!(row.keys().collect::<Vec<_>>(), ["Name", "Count", "Extra"]);
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = catalog_json_schema([("test", &SCHEMA)]);
+        let rows = &schema["$defs"]["test"]["properties"]["data"]["items"];
+        assert_eq!(rows["required"], json!(["Name"]));
+        assert_eq!(rows["properties"]["Count"]["type"], json!(["integer", "null"]));
+        assert_eq!(rows["x-identity"], json!(["Name"]));
+    }
+}
@@ -3,14 +3,15 @@
 //! execution logic.
 
 use clap::Command as ClapCommand;
-use windows::core::*;
 
 use crate::{
     commands::base::registry::CommandRegistration,
     commands::base::{
         Command, CommandDTO, CommandData,
         CommandResult::{self, Simple},
+        schema::Schema,
     },
+    error::Result,
     runtime::Runtime,
 };
 
@@ -19,6 +20,13 @@ pub struct ExampleCommand {
     data: CommandData,
 }
 
+/// The schema of the output of `ExampleCommand`, which has no columns.
+const SCHEMA: Schema = Schema {
+    source: "Example",
+    fields: &[],
+    identity: &[],
+};
+
 // Register the `ExampleCommand` with the command registry.
 inventory::submit! {
     CommandRegistration {
@@ -43,12 +51,16 @@ impl Command for ExampleCommand {
     /// # Returns
     ///
     /// A `Result` containing the `CommandResult`.
-    fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
+    fn execute(&self, _: &Runtime, _: &[String]) -> Result<CommandResult> {
         Ok(Simple(CommandDTO {
             source: "Example".to_string(),
             data: vec![],
         }))
     }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
+    }
 }
 
 // Provide a default implementation for `ExampleCommand`.
@@ -62,6 +74,9 @@ impl Default for ExampleCommand {
         ExampleCommand {
             data: CommandData {
                 support_remote: false,
+                schema: &SCHEMA,
+                accesses: &[],
+                export: None,
             },
         }
     }
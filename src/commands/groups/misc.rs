@@ -4,6 +4,7 @@ use crate::commands::base::registry::CommandRegistration;
 
 use super::CommandGroup;
 
+#[derive(Default)]
 pub struct MiscGroup {}
 
 inventory::submit! {
@@ -19,12 +20,7 @@ inventory::submit! {
 
 impl CommandGroup for MiscGroup {
     fn commands(&self) -> Vec<String> {
-        return vec!["antivirus".to_string(), "amsiproviders".to_string()];
+        vec!["antivirus".to_string(), "amsiproviders".to_string()]
     }
 }
 
-impl Default for MiscGroup {
-    fn default() -> Self {
-        MiscGroup {}
-    }
-}
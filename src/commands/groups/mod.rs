@@ -3,6 +3,7 @@ use super::base::{
     Command, CommandDTO,
     CommandResult::{self, Group, Simple},
 };
+use crate::{error::Error, runtime::limits::PartialResult};
 
 pub mod misc;
 
@@ -15,25 +16,48 @@ impl<T: CommandGroup + Default> Command for T {
         &self,
         runtime: &crate::runtime::Runtime,
         _: &[String],
-    ) -> windows::core::Result<CommandResult> {
+    ) -> crate::error::Result<CommandResult> {
         let mut results: Vec<CommandDTO> = vec![];
+        let mut stopped = vec![];
 
-        for command_name in self.commands() {
+        for (index, command_name) in self.commands().into_iter().enumerate() {
+            if index > 0 {
+                std::thread::sleep(runtime.delay());
+            }
             let command: Option<Box<dyn Command>> = get_command(command_name.as_str());
 
-            let command_result = match command {
-                Some(command) => command.execute(runtime, &[])?,
+            let outcome = match command {
+                Some(command) => runtime.run(command.as_ref(), &command_name, &[]),
                 None => {
                     panic!(
                         "Could not find command {command_name}... Who added this in the source code?!"
                     );
                 }
             };
+            // A member that reaches a limit keeps what it returned, and the others still run.
+            let command_result = match outcome {
+                Err(Error::Partial(partial)) => {
+                    stopped.extend(partial.stopped);
+                    partial.result
+                }
+                Err(Error::Limit(limit)) => {
+                    stopped.push((command_name, limit));
+                    break;
+                }
+                outcome => outcome?,
+            };
             match command_result {
                 Simple(simple_result) => results = [results, vec![simple_result]].concat(),
                 Group(group_result) => results = [results, group_result].concat(),
             }
         }
+        if !stopped.is_empty() {
+            return Err(Error::Partial(Box::new(PartialResult { result: Group(results), stopped })));
+        }
         Ok(Group(results))
     }
+
+    fn members(&self) -> Vec<String> {
+        self.commands()
+    }
 }
@@ -1,22 +1,51 @@
-use std::collections::HashMap;
-
 use clap::Command as ClapCommand;
-use windows::{core::*, Win32::System::Variant::VARIANT};
 
 use crate::{
     commands::base::{
         registry::CommandRegistration,
-        Command, CommandDTO, CommandData,
+        export::{EventClass, Export, FieldMapping},
+        Access, Command, CommandDTO, CommandData,
         CommandResult::{self, Simple},
+        schema::{Field, FieldType, Schema},
+        Row, Value,
     },
+    error::Result,
     runtime::Runtime,
-    utils::registry::{get_value, get_sub_key_names, RegistryHive},
+    utils::registry::RegistryHive,
 };
 
 pub struct AmsiProvidersCommand {
     data: CommandData,
 }
 
+const SCHEMA: Schema = Schema {
+    source: "Amsi Providers",
+    fields: &[
+        Field::required(
+            "AMSI Provider",
+            FieldType::String,
+            "The DLL of the provider, as registered for its CLSID.",
+        ),
+        Field::optional(
+            "Last Write Time",
+            FieldType::Timestamp,
+            "When the provider key was last written, usually when the provider was registered.",
+        ),
+    ],
+    identity: &["AMSI Provider"],
+};
+
+const ACCESSES: &[Access] = &[
+    Access::Registry(RegistryHive::LocalMachine, "SOFTWARE\\Microsoft\\AMSI\\Providers"),
+    Access::Registry(RegistryHive::LocalMachine, "SOFTWARE\\Microsoft\\AMSI\\Providers\\*"),
+    Access::Registry(RegistryHive::LocalMachine, "SOFTWARE\\Classes\\CLSID\\*\\InprocServer32"),
+];
+
+const EXPORT: Export = Export {
+    class: EventClass::SoftwareInventory,
+    fields: &[FieldMapping::new("AMSI Provider").ecs("file.path").ocsf("product.path")],
+};
+
 inventory::submit! {
     CommandRegistration {
         name: "amsiproviders",
@@ -30,52 +59,62 @@ inventory::submit! {
 
 // Implement the Command trait for ExampleCommand.
 impl Command for AmsiProvidersCommand {
-    fn execute(&self, _: &Runtime, _: &[String]) -> Result<CommandResult> {
-        // TODO: as long as the runtime does not implement remote access,
-        // there is no sense in using it here...
-        let provider_ids = get_sub_key_names(
+    fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
+        let registry = runtime.registry();
+        let provider_ids = registry.get_sub_key_names(
             RegistryHive::LocalMachine,
             "SOFTWARE\\Microsoft\\AMSI\\Providers",
         )?;
-        let providers: Vec<String> = provider_ids
+        let providers_formatted: Vec<Row> = provider_ids
             .iter()
             .filter_map(|provider| {
-                match get_value(
-                    RegistryHive::LocalMachine,
-                    format!("SOFTWARE\\Classes\\CLSID\\{}\\InprocServer32", provider).as_str(),
-                    "",
-                ) {
-                    Ok(value) => Some(value),
-                    Err(_) => None,
-                }
-            })
-            .collect();
+                let dll = registry
+                    .get_value(
+                        RegistryHive::LocalMachine,
+                        format!("SOFTWARE\\Classes\\CLSID\\{}\\InprocServer32", provider).as_str(),
+                        "",
+                    )
+                    .ok()?;
 
-        let providers_formatted: Vec<HashMap<String, VARIANT>> = providers
-            .iter()
-            .map(|provider| {
-                let mut result = HashMap::new();
+                // The last write time of the provider key tells when the provider was registered.
+                let last_write_time = registry
+                    .get_key_last_write_time(
+                        RegistryHive::LocalMachine,
+                        format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{}", provider).as_str(),
+                    )
+                    .map(Value::from)
+                    .unwrap_or(Value::Null);
+
+                let mut result = Row::new();
                 result.insert(
                     "AMSI Provider".to_string(),
-                    VARIANT::from(provider.as_str()),
+                    Value::from(dll.as_str()),
                 );
-                return result;
+                result.insert("Last Write Time".to_string(), last_write_time);
+                Some(result)
             })
             .collect();
 
-        return Ok(Simple(CommandDTO {
+        Ok(Simple(CommandDTO {
             source: "Amsi Providers".to_string(),
             data: providers_formatted,
-        }));
+        }))
+    }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
     }
 }
 
 impl Default for AmsiProvidersCommand {
     fn default() -> Self {
-        return AmsiProvidersCommand {
+        AmsiProvidersCommand {
             data: CommandData {
-                support_remote: false,
+                support_remote: true,
+                schema: &SCHEMA,
+                accesses: ACCESSES,
+                export: Some(&EXPORT),
             },
-        };
+        }
     }
 }
@@ -1,20 +1,52 @@
 use clap::Command as ClapCommand;
-use windows::{core::*, Win32::System::Wmi::IEnumWbemClassObject};
 
 use crate::{
     commands::base::registry::CommandRegistration,
     commands::base::{
-        Command, CommandDTO, CommandData,
+        export::{EventClass, Export, FieldMapping},
+        Access, Command, CommandDTO, CommandData,
         CommandResult::{self, Simple},
+        schema::{Field, FieldType, Schema},
     },
+    error::Result,
     runtime::Runtime,
-    utils::wmi::WbemIterator,
 };
 
 pub struct AntivirusCommand {
     data: CommandData,
 }
 
+const SCHEMA: Schema = Schema {
+    source: "Antivirus",
+    fields: &[
+        Field::required("displayName", FieldType::String, "The name of the product."),
+        Field::optional(
+            "pathToSignedProductExe",
+            FieldType::String,
+            "The signed executable of the product, or a URI for built-in products.",
+        ),
+        Field::optional(
+            "pathToSignedReportingExe",
+            FieldType::String,
+            "The signed executable that reports the state of the product.",
+        ),
+    ],
+    identity: &["displayName", "pathToSignedProductExe"],
+};
+
+const ACCESSES: &[Access] = &[Access::Wmi {
+    namespace: "root\\SecurityCenter2",
+    query: "SELECT * FROM AntiVirusProduct",
+}];
+
+const EXPORT: Export = Export {
+    class: EventClass::SoftwareInventory,
+    fields: &[
+        FieldMapping::new("displayName").ecs("package.name").ocsf("package.name"),
+        FieldMapping::new("pathToSignedProductExe").ecs("file.path").ocsf("product.path"),
+    ],
+};
+
 inventory::submit! {
     CommandRegistration {
         name: "antivirus",
@@ -29,39 +61,36 @@ inventory::submit! {
 // Implement the Command trait for ExampleCommand.
 impl Command for AntivirusCommand {
     fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
-        let results: IEnumWbemClassObject =
-            runtime.wmi_query("root\\SecurityCenter2", "SELECT * FROM AntiVirusProduct")?;
-
-        let results_iterator: WbemIterator = WbemIterator::from(
-            &results,
-            vec![
+        let results_checked = runtime.wmi_query(
+            "root\\SecurityCenter2",
+            "SELECT * FROM AntiVirusProduct",
+            &[
                 "displayName".to_string(),
                 "pathToSignedProductExe".to_string(),
                 "pathToSignedReportingExe".to_string(),
             ],
-        );
+        )?;
 
-        let mut results_checked = Vec::new();
-
-        for result in results_iterator {
-            if let Some(result_checked) = result.ok() {
-                results_checked.push(result_checked)
-            }
-        }
-        
         Ok(Simple(CommandDTO {
             source: "Antivirus".to_string(),
             data: results_checked,
         }))
     }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
+    }
 }
 
 impl Default for AntivirusCommand {
     fn default() -> Self {
-        return AntivirusCommand {
+        AntivirusCommand {
             data: CommandData {
-                support_remote: false,
+                support_remote: true,
+                schema: &SCHEMA,
+                accesses: ACCESSES,
+                export: Some(&EXPORT),
             },
-        };
+        }
     }
 }
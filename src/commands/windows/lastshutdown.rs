@@ -1,25 +1,41 @@
-use std::collections::HashMap;
-
 use clap::Command as ClapCommand;
-use windows::{core::*, Win32::System::Variant::VARIANT};
-
-use byteorder::{ByteOrder, LittleEndian};
-use chrono::DateTime;
 
 use crate::{
     commands::base::registry::CommandRegistration,
     commands::base::{
-        Command, CommandDTO, CommandData,
+        export::{EventClass, Export},
+        Access, Command, CommandDTO, CommandData,
         CommandResult::{self, Simple},
+        schema::{Field, FieldType, Schema},
+        Row, Value,
     },
+    error::Result,
     runtime::Runtime,
-    utils::registry::{get_binary_value, RegistryHive},
+    utils::{registry::RegistryHive, time::Timestamp},
 };
 
 pub struct LastShutdownCommand {
     data: CommandData,
 }
 
+const SCHEMA: Schema = Schema {
+    source: "Last Shutdown",
+    fields: &[Field::required(
+        "Last Shutdown",
+        FieldType::Timestamp,
+        "When Windows was last shut down.",
+    )],
+    identity: &[],
+};
+
+const ACCESSES: &[Access] = &[Access::Registry(
+    RegistryHive::LocalMachine,
+    "SYSTEM\\ControlSet001\\Control\\Windows",
+)];
+
+// Neither schema has an attribute for the last shutdown, so it goes into the extension object.
+const EXPORT: Export = Export { class: EventClass::DeviceInventory, fields: &[] };
+
 inventory::submit! {
     CommandRegistration {
         name: "lastshutdown",
@@ -32,42 +48,30 @@ inventory::submit! {
 }
 
 impl Command for LastShutdownCommand {
-    fn execute(&self, _: &Runtime, _: &[String]) -> Result<CommandResult> {
+    fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
 
         // Get the shutdown bytes form the registry. These bytes represent the Windows FileTime.
-        let shutdown_bytes = get_binary_value(
+        let shutdown_bytes = runtime.registry().get_binary_value(
             RegistryHive::LocalMachine,
             "SYSTEM\\ControlSet001\\Control\\Windows",
             "ShutdownTime"
         )?;
 
-        // Difference between Windows FileTime Epoch (January 1, 1601 UTC) and Unix Time Epoch (January 1, 1970 UTC)
-        const EPOCH_DIFFERENCE: i64 = 116444736000000000;
-
-        // Convert the bytes to u64 and then to Unix Timestamp (in microseconds)
-        let unix_time: i64 = (LittleEndian::read_i64(
-            &shutdown_bytes
-        ) - EPOCH_DIFFERENCE) / 10;
-        
-        let mut result: Vec<HashMap<String, VARIANT>> = Vec::new();
-
-        match DateTime::from_timestamp_micros(unix_time) {
-            Some(time) => {
-                let mut hashmap: HashMap<String, VARIANT> = HashMap::new();
-                hashmap.insert(
-                    "Last Shutdown".to_string(),
-                    VARIANT::from(time.to_string().as_str())
-                );
-                result.insert(0, hashmap);
-            },
-            None => return Err(HRESULT::from_nt(0).into())
-        };
+        let mut hashmap: Row = Row::new();
+        hashmap.insert(
+            "Last Shutdown".to_string(),
+            Value::from(Timestamp::from_filetime_bytes(&shutdown_bytes)?)
+        );
 
         Ok(Simple(CommandDTO {
             source: "Last Shutdown".to_string(),
-            data: result,
+            data: vec![hashmap],
         }))
     }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
+    }
 }
 
 impl Default for LastShutdownCommand {
@@ -75,6 +79,9 @@ impl Default for LastShutdownCommand {
         LastShutdownCommand {
             data: CommandData {
                 support_remote: true,
+                schema: &SCHEMA,
+                accesses: ACCESSES,
+                export: Some(&EXPORT),
             },
         }
     }
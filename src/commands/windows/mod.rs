@@ -1,4 +1,5 @@
 pub mod amsiproviders;
 pub mod antivirus;
 pub mod lastshutdown;
-pub mod osinfo;
\ No newline at end of file
+pub mod osinfo;
+pub mod runmru;
\ No newline at end of file
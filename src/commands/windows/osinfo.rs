@@ -1,33 +1,85 @@
-use std::{collections::HashMap, env};
+#[cfg(windows)]
+use std::env;
 
 use clap::Command as ClapCommand;
-use windows::{
-    core::*, 
-    Win32::System::{
-        Time::{
-            GetTimeZoneInformation,
-            TIME_ZONE_INFORMATION
-        },
-        SystemInformation::GetTickCount64,
-        Variant::VARIANT,
-    }
+#[cfg(windows)]
+use windows::Win32::System::{
+    Time::{
+        GetTimeZoneInformation,
+        TIME_ZONE_INFORMATION
+    },
+    SystemInformation::GetTickCount64,
 };
+#[cfg(windows)]
 use chrono::prelude::*;
 
 use crate::{
     commands::base::registry::CommandRegistration,
     commands::base::{
-        Command, CommandDTO, CommandData,
+        export::{EventClass, Export, FieldMapping},
+        Access, Command, CommandDTO, CommandData,
         CommandResult::{self, Simple},
+        schema::{Field, FieldTag, FieldType, Schema},
+        Row, Value,
     },
+    error::Result,
     runtime::Runtime,
-    utils::registry::{get_value, RegistryHive},
+    utils::registry::{RegistryBackend, RegistryHive},
 };
+#[cfg(windows)]
+use crate::utils::time::Timestamp;
 
 pub struct OSInfoCommand {
     data: CommandData,
 }
 
+const SCHEMA: Schema = Schema {
+    source: "OSInfo",
+    fields: &[
+        Field::optional("ProductName", FieldType::String, "The name of the Windows edition, such as 'Windows 10 Pro'."),
+        Field::optional("EditionID", FieldType::String, "The identifier of the edition."),
+        Field::optional("ReleaseId", FieldType::String, "The release, such as '2009'."),
+        Field::optional("BuildBranch", FieldType::String, "The branch the build was made from."),
+        Field::optional("CurrentMajorVersionNumber", FieldType::String, "The major version number."),
+        Field::optional("CurrentVersion", FieldType::String, "The version in the NT numbering, such as '6.3'."),
+        Field::optional("CurrentBuildNumber", FieldType::String, "The build number."),
+        Field::optional("UBR", FieldType::String, "The update build revision."),
+        Field::optional("PROCESSOR_ARCHITECTURE", FieldType::String, "The processor architecture, such as 'AMD64'."),
+        Field::optional("NUMBER_OF_PROCESSORS", FieldType::String, "The number of logical processors."),
+        Field::optional("COMPUTERNAME", FieldType::String, "The NetBIOS name of the computer.")
+            .tagged(FieldTag::Hostname),
+        Field::optional("BootTime", FieldType::Timestamp, "When the computer was started. Only available on live systems."),
+        Field::optional("TimeZone", FieldType::String, "The time zone of the computer."),
+        Field::required("MachineGuid", FieldType::String, "The identifier Windows generated at installation.")
+            .tagged(FieldTag::Identifier),
+    ],
+    identity: &["MachineGuid"],
+};
+
+/// The keys the command reads. The `SYSTEM` keys are only read on targets
+/// other than the local machine, which reads the same values from the system.
+const ACCESSES: &[Access] = &[
+    Access::Registry(RegistryHive::LocalMachine, "Software\\Microsoft\\Windows NT\\CurrentVersion"),
+    Access::Registry(RegistryHive::LocalMachine, "SOFTWARE\\Microsoft\\Cryptography"),
+    Access::Registry(RegistryHive::LocalMachine, "SYSTEM\\CurrentControlSet\\Control\\Session Manager\\Environment"),
+    Access::Registry(RegistryHive::LocalMachine, "SYSTEM\\CurrentControlSet\\Control\\ComputerName\\ComputerName"),
+    Access::Registry(RegistryHive::LocalMachine, "SYSTEM\\CurrentControlSet\\Control\\TimeZoneInformation"),
+];
+
+const EXPORT: Export = Export {
+    class: EventClass::DeviceInventory,
+    fields: &[
+        FieldMapping::new("COMPUTERNAME").ecs("host.name").ocsf("device.hostname"),
+        FieldMapping::new("ProductName").ecs("host.os.name").ocsf("device.os.name"),
+        FieldMapping::new("EditionID").ocsf("device.os.edition"),
+        FieldMapping::new("CurrentVersion").ecs("host.os.version").ocsf("device.os.version"),
+        FieldMapping::new("CurrentBuildNumber").ocsf("device.os.build"),
+        FieldMapping::new("PROCESSOR_ARCHITECTURE").ecs("host.architecture").ocsf("device.hw_info.cpu_type"),
+        FieldMapping::new("BootTime").ocsf("device.boot_time"),
+        FieldMapping::new("MachineGuid").ecs("host.id").ocsf("device.uid"),
+    ],
+};
+
 inventory::submit! {
     CommandRegistration {
         name: "osinfo",
@@ -39,13 +91,88 @@ inventory::submit! {
     }
 }
 
-fn is_vm() -> bool {
-    todo!()
+/// Reads the values that are not in the registry from the local machine.
+#[cfg(windows)]
+fn local_values() -> Row {
+    let env_names = [
+        "PROCESSOR_ARCHITECTURE",
+        "NUMBER_OF_PROCESSORS",
+        "COMPUTERNAME",
+    ];
+
+    let mut values: Row = env_names
+        .iter()
+        .filter_map(|env_variable | {
+            env::var_os(env_variable)
+                .map(|value| (env_variable.to_string(), Value::from(value.to_string_lossy().to_string())))
+        })
+        .collect();
+
+    let boot_time = Timestamp::from_unix_millis(
+        Utc::now().timestamp_millis() - (unsafe {GetTickCount64()} as i64)
+    );
+
+    if let Ok(boot_time) = boot_time {
+        values.insert(
+            "BootTime".to_string(), 
+            Value::from(boot_time)
+        );
+    }
+
+    unsafe {
+        let mut tz_info = TIME_ZONE_INFORMATION::default();
+        GetTimeZoneInformation(&mut tz_info);
+        let tz_name = String::from_utf16_lossy(&tz_info.StandardName);
+
+        values.insert(
+            "TimeZone".to_string(),
+            Value::from(tz_name.as_str())
+        );    
+    }
+    values
+}
+
+#[cfg(not(windows))]
+fn local_values() -> Row {
+    Row::new()
+}
+
+/// Reads the values that are not in the registry of a live machine from the
+/// `SYSTEM` hive, for runtimes that do not inspect the local machine, such as
+/// offline images and remote computers.
+fn offline_values(registry: &dyn RegistryBackend) -> Row {
+    let control = "SYSTEM\\CurrentControlSet\\Control";
+    let lookups = [
+        ("PROCESSOR_ARCHITECTURE", "Session Manager\\Environment", "PROCESSOR_ARCHITECTURE"),
+        ("NUMBER_OF_PROCESSORS", "Session Manager\\Environment", "NUMBER_OF_PROCESSORS"),
+        ("COMPUTERNAME", "ComputerName\\ComputerName", "ComputerName"),
+    ];
+
+    let mut values: Row = lookups
+        .iter()
+        .filter_map(|(column, path, name)| {
+            registry
+                .get_value(RegistryHive::LocalMachine, &format!("{control}\\{path}"), name)
+                .ok()
+                .map(|value| (column.to_string(), Value::from(value)))
+        })
+        .collect();
+
+    // StandardName usually is an indirect string (@tzres.dll,-110) that can only
+    // be resolved on a live system, so prefer the name of the time zone key.
+    let time_zone = format!("{control}\\TimeZoneInformation");
+    let time_zone = ["TimeZoneKeyName", "StandardName"]
+        .iter()
+        .find_map(|name| registry.get_value(RegistryHive::LocalMachine, &time_zone, name).ok());
+    if let Some(time_zone) = time_zone {
+        values.insert("TimeZone".to_string(), Value::from(time_zone));
+    }
+    values
 }
 
 impl Command for OSInfoCommand {
     fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
-        let names = vec![
+        let names = [
             "ProductName",
             "EditionID",
             "ReleaseId",
@@ -56,78 +183,48 @@ impl Command for OSInfoCommand {
             "UBR",
         ];
 
-        let mut values: HashMap<String, VARIANT> = names
+        let registry = runtime.registry();
+        let mut values: Row = names
             .iter()
             .filter_map(| name | {
-                match get_value(
+                match registry.get_value(
                         RegistryHive::LocalMachine, 
                         "Software\\Microsoft\\Windows NT\\CurrentVersion", 
                         name
                     ) { 
-                        Ok(value) => Some((name.to_string(), VARIANT::from(value.as_str()))),
+                        Ok(value) => Some((name.to_string(), Value::from(value.as_str()))),
                         Err(_) => None
                     }
             })
             .collect();
 
-        if runtime.is_remote() {
-            todo!()
+        // Remote and offline targets only expose their registry.
+        if runtime.is_live() {
+            values.extend(local_values());
         } else {
-            let env_names = vec![
-                "PROCESSOR_ARCHITECTURE",
-                "NUMBER_OF_PROCESSORS",
-                "COMPUTERNAME",
-            ];
-
-            let env_values: HashMap<String, VARIANT> = env_names
-                .iter()
-                .filter_map(|env_variable | {
-                    match env::var_os(env_variable) {
-                        Some(value) => Some((env_variable.to_string(), VARIANT::from(value.to_str().unwrap()))),
-                        None => None
-                    }
-                })
-                .collect();
-
-            values.extend(env_values);
-            
-            let boot_time_utc = DateTime::from_timestamp_millis(
-                Utc::now().timestamp_millis() - (unsafe {GetTickCount64()} as i64)
-            ).unwrap();
-
-            values.insert(
-                "BootTime".to_string(), 
-                VARIANT::from(boot_time_utc.to_string().as_str())
-            );
-
-            unsafe {
-                let mut tz_info = TIME_ZONE_INFORMATION::default();
-                GetTimeZoneInformation(&mut tz_info);
-                let tz_name = String::from_utf16_lossy(&tz_info.StandardName);
-
-                values.insert(
-                    "TimeZone".to_string(),
-                    VARIANT::from(tz_name.as_str())
-                );    
-            }
-
-            values.insert(
-                "MachineGuid".to_string(), 
-                VARIANT::from(
-                    get_value(
-                        RegistryHive::LocalMachine, 
-                        "SOFTWARE\\Microsoft\\Cryptography", 
-                        "MachineGuid"
-                    )?.as_str()
-                )
-            );
+            values.extend(offline_values(registry));
         }
 
+        values.insert(
+            "MachineGuid".to_string(), 
+            Value::from(
+                registry.get_value(
+                    RegistryHive::LocalMachine, 
+                    "SOFTWARE\\Microsoft\\Cryptography", 
+                    "MachineGuid"
+                )?
+            )
+        );
+
         Ok(Simple(CommandDTO {
             source: "OSInfo".to_string(),
             data: vec![values],
         }))
     }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
+    }
 }
 
 impl Default for OSInfoCommand {
@@ -135,6 +232,9 @@ impl Default for OSInfoCommand {
         OSInfoCommand {
             data: CommandData {
                 support_remote: true,
+                schema: &SCHEMA,
+                accesses: ACCESSES,
+                export: Some(&EXPORT),
             },
         }
     }
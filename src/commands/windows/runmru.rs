@@ -0,0 +1,118 @@
+use clap::Command as ClapCommand;
+
+use crate::{
+    commands::base::{
+        registry::CommandRegistration,
+        export::{EventClass, Export, FieldMapping},
+        user_rows, Access, Command, CommandDTO, CommandData,
+        CommandResult::{self, Simple},
+        schema::{Field, FieldType, Schema},
+        Row, Value, SID_COLUMN, SID_FIELD, USERNAME_COLUMN, USERNAME_FIELD,
+    },
+    error::Result,
+    runtime::Runtime,
+    utils::registry::RegistryHive,
+};
+
+pub struct RunMruCommand {
+    data: CommandData,
+}
+
+// The commands a user typed into the Run dialog, below the hive of the user.
+const RUN_MRU: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\RunMRU";
+
+const SCHEMA: Schema = Schema {
+    source: "Run MRU",
+    fields: &[
+        SID_FIELD,
+        USERNAME_FIELD,
+        Field::required(
+            "Position",
+            FieldType::Integer,
+            "The position of the command in the list, 1 for the most recently run.",
+        ),
+        Field::required("Command", FieldType::String, "The command, as typed into the Run dialog."),
+    ],
+    identity: &[SID_COLUMN, "Command"],
+};
+
+const ACCESSES: &[Access] = &[
+    Access::Registry(
+        RegistryHive::LocalMachine,
+        "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\ProfileList\\*",
+    ),
+    Access::Registry(RegistryHive::LocalMachine, "SAM\\SAM\\Domains\\Account\\Users\\Names\\*"),
+    Access::Registry(RegistryHive::Users, "*\\Volatile Environment"),
+    Access::Registry(
+        RegistryHive::Users,
+        "*\\Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\RunMRU",
+    ),
+];
+
+const EXPORT: Export = Export {
+    class: EventClass::Finding,
+    fields: &[
+        FieldMapping::new(SID_COLUMN).ecs("user.id").ocsf("actor.user.uid"),
+        FieldMapping::new(USERNAME_COLUMN).ecs("user.name").ocsf("actor.user.name"),
+        FieldMapping::new("Command").ecs("process.command_line").ocsf("process.cmd_line"),
+    ],
+};
+
+inventory::submit! {
+    CommandRegistration {
+        name: "runmru",
+        factory: || Box::new(RunMruCommand::default()),
+        clap_command: || ClapCommand
+            ::new("runmru")
+            .version("1.0")
+            .about("Commands each user ran from the Run dialog")
+    }
+}
+
+impl Command for RunMruCommand {
+    fn execute(&self, runtime: &Runtime, _: &[String]) -> Result<CommandResult> {
+        let registry = runtime.registry();
+        let data = user_rows(runtime, |profile| {
+            let key = profile.key_path(RUN_MRU);
+
+            // `MRUList` orders the letters naming the values, most recent first.
+            let order = registry.get_string_value(RegistryHive::Users, &key, "MRUList")?;
+            let mut rows = vec![];
+            for letter in order.chars() {
+                let Ok(command) = registry.get_string_value(RegistryHive::Users, &key, &letter.to_string()) else {
+                    continue;
+                };
+
+                // Explorer ends each command with `\1`.
+                let command = command.strip_suffix("\\1").unwrap_or(&command);
+                let mut row = Row::new();
+                row.insert("Position".to_string(), Value::from(rows.len() as i64 + 1));
+                row.insert("Command".to_string(), Value::from(command));
+                rows.push(row);
+            }
+            Ok(rows)
+        })?;
+
+        Ok(Simple(CommandDTO {
+            source: "Run MRU".to_string(),
+            data,
+        }))
+    }
+
+    fn command_data(&self) -> Option<&CommandData> {
+        Some(&self.data)
+    }
+}
+
+impl Default for RunMruCommand {
+    fn default() -> Self {
+        RunMruCommand {
+            data: CommandData {
+                support_remote: true,
+                schema: &SCHEMA,
+                accesses: ACCESSES,
+                export: Some(&EXPORT),
+            },
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! Configuration files with default options and named profiles.
+//!
+//! A configuration file is TOML. The `defaults` table holds options for every
+//! run, and every table under `profiles` a named set of options that is
+//! applied on top of them with `--profile`. The options have the names of the
+//! long command-line options, and a profile can also name the commands to
+//! run when none is given and extra arguments per command:
+//!
+//! ```toml
+//! [defaults]
+//! timezone = "utc"
+//!
+//! [profiles.stealth]
+//! delay = "5s"
+//! redact = true
+//! commands = ["osinfo", "group:misc"]
+//!
+//! # Plugins receive the arguments of their command in `rustbelt_run`.
+//! [profiles.stealth.args]
+//! persistence = ["--hive", "HKLM"]
+//! ```
+//!
+//! The configuration of the user is read from `rustbelt/config.toml` in the
+//! configuration directory of the platform, and a file given with `--config`
+//! is applied on top of it. Options on the command line override both, see
+//! `Settings::to_args`.
+
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Options of a run, as set by the defaults or a profile of a configuration file.
+///
+/// Every option corresponds to the command-line option with the same name.
+/// Unset options are left to the command line or the built-in defaults.
+///
+/// # Fields
+/// - `commands`: The commands and groups to run when none is given on the command line.
+/// - `args`: Extra arguments for commands, by command name.
+/// - All others: The value of the command-line option of the same name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computername: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub winrm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub winrm_auth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insecure: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_log: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub targets: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypt_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collector: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collector_header: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spool: Option<PathBuf>,
+    #[serde(rename = "where", skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub select: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redact_map: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commands: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, Vec<String>>,
+}
+
+impl Settings {
+    /// Applies other settings on top of these: every option the other settings set replaces this one.
+    /// Extra arguments are replaced per command.
+    pub fn overlay(&mut self, other: &Settings) {
+        macro_rules! overlay {
+            ($($field:ident),*) => {
+                $(
+                    if other.$field.is_some() {
+                        self.$field = other.$field.clone();
+                    }
+                )*
+            };
+        }
+        overlay!(
+            computername, username, winrm, winrm_auth, insecure, offline, view, manifest, dry_run, audit_log,
+            targets, concurrency, merge, delay, timeout, total_timeout, max_rows, max_bytes, watch, watch_count, stats,
+            format, output, encrypt_to, collector, collector_header, spool, filter, select, redact, redact_map,
+            timezone, time_format, commands
+        );
+        self.args.extend(other.args.iter().map(|(command, args)| (command.clone(), args.clone())));
+    }
+
+    /// Returns the command-line options that fill in what a command line leaves to the settings.
+    ///
+    /// The command line takes precedence. Options it sets are left out, and so
+    /// are options that conflict with one it sets, such as `output` when it
+    /// has `--collector`, or `offline` when it has `--computername`. Flags that
+    /// are `false` are left out as well, as they are off unless given; the
+    /// command line turns off a flag of the settings with `--redact=false`.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The definition of the command line.
+    /// * `matches` - The command line, parsed without the settings.
+    ///
+    /// # Returns
+    ///
+    /// The options as `--name=value`, to be parsed together with the command line.
+    pub fn to_args(&self, command: &Command, matches: &ArgMatches) -> Vec<String> {
+        let given: Vec<&Arg> = command
+            .get_arguments()
+            .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+            .collect();
+        let conflict = |arg: &Arg, other: &Arg| {
+            let declared = |arg: &Arg, other: &Arg| {
+                command.get_arg_conflicts_with(arg).iter().any(|conflict| conflict.get_id() == other.get_id())
+            };
+            let grouped = command.get_groups().any(|group| {
+                !group.clone().is_multiple()
+                    && group.get_args().any(|id| id == arg.get_id())
+                    && group.get_args().any(|id| id == other.get_id())
+            });
+            declared(arg, other) || declared(other, arg) || grouped
+        };
+
+        self.options()
+            .into_iter()
+            .filter(|(name, _)| {
+                command
+                    .get_arguments()
+                    .find(|arg| arg.get_id() == name)
+                    .is_some_and(|arg| given.iter().all(|other| arg.get_id() != other.get_id() && !conflict(arg, other)))
+            })
+            .map(|(name, value)| format!("--{name}={value}"))
+            .collect()
+    }
+
+    /// Returns the options that are set, by the names of their command-line options.
+    ///
+    /// `commands` and `args` have no options and are left out.
+    fn options(&self) -> Vec<(&'static str, String)> {
+        let mut options = vec![];
+        let mut option = |name: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                options.push((name, value));
+            }
+        };
+        let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+
+        option("computername", self.computername.clone());
+        option("username", self.username.clone());
+        option("winrm", self.winrm.clone());
+        option("winrm-auth", self.winrm_auth.clone());
+        option("offline", path(&self.offline));
+        option("view", self.view.clone());
+        option("manifest", path(&self.manifest));
+        option("audit-log", path(&self.audit_log));
+        option("targets", path(&self.targets));
+        option("concurrency", self.concurrency.map(|concurrency| concurrency.to_string()));
+        option("delay", self.delay.clone());
+        option("timeout", self.timeout.clone());
+        option("total-timeout", self.total_timeout.clone());
+        option("max-rows", self.max_rows.map(|rows| rows.to_string()));
+        option("max-bytes", self.max_bytes.map(|bytes| bytes.to_string()));
+        option("watch", self.watch.clone());
+        option("watch-count", self.watch_count.map(|count| count.to_string()));
+        option("format", self.format.clone());
+        option("output", path(&self.output));
+        option("encrypt-to", self.encrypt_to.clone());
+        option("collector", self.collector.clone());
+        option("collector-header", self.collector_header.clone());
+        option("spool", path(&self.spool));
+        option("where", self.filter.clone());
+        option("select", self.select.as_ref().map(|columns| columns.join(",")));
+        option("redact-map", path(&self.redact_map));
+        option("timezone", self.timezone.clone());
+        option("time-format", self.time_format.clone());
+
+        let flags = [
+            ("insecure", self.insecure),
+            ("dry-run", self.dry_run),
+            ("merge", self.merge),
+            ("stats", self.stats),
+            ("redact", self.redact),
+        ];
+        for (name, flag) in flags {
+            if flag == Some(true) {
+                options.push((name, true.to_string()));
+            }
+        }
+        options
+    }
+}
+
+/// Makes a flag that can also be given a value, as `--redact=false`.
+///
+/// The command line can so turn off a flag that the configuration turns on.
+/// `ArgMatches::get_flag` reads such flags like any other.
+///
+/// # Arguments
+///
+/// * `arg` - The flag.
+pub fn switch(arg: Arg) -> Arg {
+    arg.action(ArgAction::Set)
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("true")
+        .default_value("false")
+        .value_parser(clap::value_parser!(bool))
+}
+
+/// The contents of one or more configuration files.
+///
+/// # Fields
+/// - `defaults`: The options of every run.
+/// - `profiles`: Named sets of options, applied on top of the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub defaults: Settings,
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+impl Config {
+    /// Parses the text of a configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The TOML text.
+    /// * `origin` - Where the text comes from, for errors.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Config)` if the text is valid.
+    /// * `Err(Error::InvalidData)` if it is not TOML or has unknown options.
+    pub fn parse(text: &str, origin: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| Error::InvalidData(format!("configuration {origin}: {e}")))
+    }
+
+    /// Reads a configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file.
+    pub fn load(path: &Path) -> Result<Self> {
+        Config::parse(&fs::read_to_string(path)?, &path.display().to_string())
+    }
+
+    /// Returns the path of the configuration of the user, if the platform has a configuration directory.
+    ///
+    /// This is `%APPDATA%\rustbelt\config.toml` on Windows, and
+    /// `$XDG_CONFIG_HOME/rustbelt/config.toml` or `~/.config/rustbelt/config.toml` elsewhere.
+    pub fn user_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            std::env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        };
+        dir.map(|dir| dir.join("rustbelt").join("config.toml"))
+    }
+
+    /// Applies another configuration on top of this one, merging profiles with the same name.
+    pub fn overlay(&mut self, other: &Config) {
+        self.defaults.overlay(&other.defaults);
+        for (name, profile) in &other.profiles {
+            self.profiles.entry(name.clone()).or_default().overlay(profile);
+        }
+    }
+
+    /// Returns the settings of a run: the defaults with the profile, if any, on top.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Settings)` with the options in effect.
+    /// * `Err(Error::NotFound)` if there is no profile with that name.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings> {
+        let mut settings = self.defaults.clone();
+        if let Some(name) = profile {
+            let profile = self.profiles.get(name).ok_or_else(|| {
+                let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                Error::NotFound(format!("profile '{name}' (the profiles are: {})", names.join(", ")))
+            })?;
+            settings.overlay(profile);
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ArgGroup;
+
+    const USER: &str = r#"
+        [defaults]
+        timezone = "local"
+        format = "json"
+
+        [profiles.stealth]
+        delay = "5s"
+        redact = true
+        commands = ["osinfo", "group:misc"]
+
+        [profiles.stealth.args]
+        persistence = ["--hive", "HKLM"]
+    "#;
+
+    /// A command line with options like those of `rustbelt`.
+    fn command() -> Command {
+        Command::new("rustbelt")
+            .args([
+                Arg::new("delay").long("delay"),
+                Arg::new("format").long("format"),
+                Arg::new("output").long("output"),
+                Arg::new("collector").long("collector").conflicts_with("output"),
+                Arg::new("where").long("where"),
+                Arg::new("select").long("select").value_delimiter(','),
+                Arg::new("timezone").long("timezone"),
+                Arg::new("offline").long("offline"),
+                Arg::new("computername").long("computername"),
+                switch(Arg::new("redact").long("redact")),
+            ])
+            .group(ArgGroup::new("target").args(["offline", "computername"]))
+    }
+
+    /// Parses a command line with the options of the settings filled in.
+    fn parse(settings: &Settings, argv: &[&str]) -> (Vec<String>, ArgMatches) {
+        let command_line = command().get_matches_from(argv);
+        let args = settings.to_args(&command(), &command_line);
+        let matches = command().get_matches_from(argv[..1].iter().copied().chain(args.iter().map(String::as_str)).chain(argv[1..].iter().copied()));
+        (args, matches)
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config = Config::parse(USER, "user").unwrap();
+        let engagement = Config::parse(
+            r#"
+            defaults = { format = "ndjson", collector = "https://collector/upload" }
+            profiles.stealth = { delay = "10s", where = "Enabled" }
+            profiles.ir-triage = { select = ["Name", "Path"] }
+            "#,
+            "engagement",
+        )
+        .unwrap();
+        config.overlay(&engagement);
+
+        let settings = config.settings(Some("stealth")).unwrap();
+        assert_eq!(settings.timezone.as_deref(), Some("local"));
+        assert_eq!(settings.format.as_deref(), Some("ndjson"));
+        assert_eq!(settings.delay.as_deref(), Some("10s"));
+        assert_eq!(settings.commands, Some(vec!["osinfo".to_string(), "group:misc".to_string()]));
+        assert_eq!(settings.args["persistence"], ["--hive", "HKLM"]);
+        assert_eq!(
+            parse(&settings, &["rustbelt"]).0,
+            [
+                "--delay=10s", "--format=ndjson", "--collector=https://collector/upload", "--where=Enabled",
+                "--timezone=local", "--redact=true"
+            ]
+        );
+
+        let settings = config.settings(Some("ir-triage")).unwrap();
+        assert_eq!(parse(&settings, &["rustbelt"]).0[2..], ["--select=Name,Path", "--timezone=local"]);
+        assert!(matches!(config.settings(Some("full-audit")), Err(Error::NotFound(_))));
+        assert_eq!(config.settings(None).unwrap(), config.defaults);
+    }
+
+    #[test]
+    fn test_command_line_precedence() {
+        let settings = Settings {
+            format: Some("json".to_string()),
+            output: Some(PathBuf::from("out.json")),
+            offline: Some(PathBuf::from("image")),
+            timezone: Some("-05:00".to_string()),
+            redact: Some(true),
+            ..Settings::default()
+        };
+
+        // Without options on the command line, the settings apply.
+        let (_, matches) = parse(&settings, &["rustbelt"]);
+        assert!(matches.get_flag("redact"));
+        assert_eq!(matches.get_one::<String>("timezone").map(String::as_str), Some("-05:00"));
+
+        // Options on the command line replace the settings, and push out the settings they conflict with.
+        let (args, matches) = parse(
+            &settings,
+            &["rustbelt", "--format", "ndjson", "--collector", "https://collector", "--computername", "host", "--redact=false"],
+        );
+        assert_eq!(args, ["--timezone=-05:00"]);
+        assert_eq!(matches.get_one::<String>("format").map(String::as_str), Some("ndjson"));
+        assert!(!matches.contains_id("output") && !matches.contains_id("offline"));
+        assert!(!matches.get_flag("redact"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for invalid in ["[defaults]\nformatt = \"json\"", "[defaults]\nredact = \"yes\"", "[defaults"] {
+            assert!(matches!(Config::parse(invalid, "test"), Err(Error::InvalidData(_))), "{invalid}");
+        }
+    }
+}
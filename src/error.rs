@@ -0,0 +1,75 @@
+//! Error type shared by the runtime, the commands and the backends.
+
+use std::fmt;
+
+use crate::runtime::limits::{Limit, PartialResult};
+
+/// Result type used throughout Rustbelt.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that can occur while running commands.
+///
+/// # Variants
+/// - `Windows`: An error reported by a Windows API.
+/// - `NotFound`: A command, registry key, value or other object that does not exist.
+/// - `InvalidData`: Data that could not be decoded into the expected type.
+/// - `Unsupported`: An operation that the active backend does not support.
+/// - `Io`: An I/O error.
+/// - `Plugin`: A plugin that failed to load or run.
+/// - `Remote`: A remote host that could not be reached or answered with an error.
+/// - `Limit`: A command that reached a limit of the runtime, or was cancelled, before it returned anything.
+/// - `Partial`: A command that reached a limit, with what it returned before, see `runtime::limits`.
+#[derive(Debug)]
+pub enum Error {
+    #[cfg(windows)]
+    Windows(windows::core::Error),
+    NotFound(String),
+    InvalidData(String),
+    Unsupported(String),
+    Io(std::io::Error),
+    Plugin(String),
+    Remote(String),
+    Limit(Limit),
+    Partial(Box<PartialResult>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(windows)]
+            Error::Windows(e) => write!(f, "windows error: {e}"),
+            Error::NotFound(what) => write!(f, "not found: {what}"),
+            Error::InvalidData(what) => write!(f, "invalid data: {what}"),
+            Error::Unsupported(what) => write!(f, "unsupported: {what}"),
+            Error::Io(e) => write!(f, "i/o error: {e}"),
+            Error::Plugin(what) => write!(f, "plugin error: {what}"),
+            Error::Remote(what) => write!(f, "remote error: {what}"),
+            Error::Limit(limit) => write!(f, "stopped: {limit}"),
+            Error::Partial(partial) => write!(f, "partial result: {partial}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(windows)]
+            Error::Windows(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<windows::core::Error> for Error {
+    fn from(e: windows::core::Error) -> Self {
+        Error::Windows(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,32 @@
+//! Rustbelt is a Rust implementation of the Windows enumeration tool Seatbelt.
+//!
+//! Besides the `rustbelt` binary, the crate can be embedded in other tools. A
+//! typical embedding creates a [`Runtime`] for the local machine
+//! (`Runtime::new`, Windows only), an offline image or its own backends, and
+//! executes commands by name:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use rustbelt::{runtime::formatter::{simple_formatter::SimpleFormatter, Formatter}, Runtime};
+//!
+//! let runtime = Runtime::offline(Path::new("/mnt/windows"))?;
+//! let result = runtime.execute("amsiproviders", &[])?;
+//! println!("{}", SimpleFormatter::default().parse_result(&result));
+//! # Ok::<(), rustbelt::Error>(())
+//! ```
+pub mod commands;
+pub mod config;
+pub mod error;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod runtime;
+#[cfg(feature = "shell")]
+pub mod shell;
+pub mod utils;
+
+pub use commands::base::{
+    registry::{command_names, get_command, register_command, schemas},
+    Command, CommandDTO, CommandResult, Row, Value,
+};
+pub use error::{Error, Result};
+pub use runtime::Runtime;
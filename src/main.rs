@@ -1,24 +1,54 @@
-use clap::{arg, Command as ClapCommand};
-use windows::core::*;
-mod commands;
-mod runtime;
-mod utils;
-
-use commands::base::{
-    registry::{get_command, CommandRegistration},
-    Command,
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
-use runtime::{
-    formatter::{simple_formatter::SimpleFormatter, Formatter},
-    writer::{console_writer::ConsoleWriter, Writer},
-    Runtime,
+
+use clap::{arg, parser::ValueSource, ArgGroup, ArgMatches, Command as ClapCommand};
+
+use rustbelt::{
+    commands::base::{
+        registry::{clap_commands, get_command, schemas},
+        schema::{catalog_json_schema, FieldTag},
+    },
+    config::{switch, Config, Settings},
+    error::{Error, Result},
+    runtime::{
+        audit::{plan, AuditLog},
+        formatter::{self, Formatter},
+        limits::Limits,
+        manifest::{Manifest, ManifestRecorder},
+        query::{Filter, Query},
+        redact::Redactor,
+        seatbelt,
+        stats::{self, RunStats},
+        targets::{merge, read_targets, run_targets, RunSummary, Target},
+        watch::Watcher,
+        writer::{
+            bundle_writer::BundleWriter, console_writer::ConsoleWriter, file_writer::FileWriter,
+            http_writer::HttpWriter, Writer,
+        },
+        Runtime,
+    },
+    utils::{
+        auth::{ntlm::Credentials, Anonymous},
+        bundle::{self, IdentityKey, RecipientKey},
+        collector::Collector,
+        registry::RegistryHiveType,
+        time::{parse_duration, DisplayZone, TimeDisplay, TimeFormat},
+        wmi::winrm::{WinRmAuth, WinRmWmi},
+    },
 };
+use zeroize::Zeroizing;
 
 /// The main entry point of the Rustbelt CLI application.
 ///
 /// This function sets up the CLI application using the `clap` crate, registers
 /// available commands, parses the command-line arguments, and executes the
-/// corresponding command.
+/// corresponding command through the `rustbelt` library.
 ///
 /// # Returns
 ///
@@ -31,44 +61,1024 @@ fn main() -> Result<()> {
             Rusty enumeration.. what's not to love!")
         .version("1.0")
         .args([
+            arg!(--config <FILE> "Optional configuration file")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Read default options and profiles from this TOML file, on top of the configuration of the user."),
+            arg!(--profile <NAME> "Optional profile of the configuration")
+                .required(false)
+                .help("Apply the options of this profile of the configuration, such as 'stealth'."),
+            arg!(--delay <DURATION> "Optional delay between commands")
+                .required(false)
+                .value_parser(parse_duration)
+                .help("Wait this long between the commands of a group or run, such as '500ms' or '5s'."),
+            arg!(--timeout <DURATION> "Optional time limit of every command")
+                .required(false)
+                .value_parser(parse_duration)
+                .help("Stop a command that runs longer than this, such as '30s', and output what it returned so far. Every command of a group gets this long."),
+            arg!(--"total-timeout" <DURATION> "Optional time limit of all commands together")
+                .required(false)
+                .value_parser(parse_duration)
+                .help("Stop the commands of a target that run longer than this together, such as '5m', and output what they returned so far."),
+            arg!(--"max-rows" <ROWS> "Optional cap on the rows of every command")
+                .required(false)
+                .value_parser(clap::value_parser!(usize))
+                .help("Keep at most this many rows of every command, and report the commands that returned more."),
+            arg!(--"max-bytes" <BYTES> "Optional cap on the output of every command")
+                .required(false)
+                .value_parser(clap::value_parser!(usize))
+                .help("Keep at most this many bytes of rows of every command, measured as JSON, and report the commands that returned more."),
+            arg!(--watch <INTERVAL> "Optional interval to re-run the commands at")
+                .required(false)
+                .value_parser(parse_duration)
+                .conflicts_with_all(["targets", "manifest", "dry-run"])
+                .help("Keep running the commands at this interval, such as '30s', and only output the rows that were added, removed or changed since the previous pass."),
+            arg!(--"watch-count" <COUNT> "Optional number of passes to watch for")
+                .required(false)
+                .value_parser(clap::value_parser!(usize))
+                .requires("watch")
+                .help("Stop watching after this many passes, the first included. Encrypted output files are only complete once watching stops."),
+            switch(arg!(--stats "Output statistics of the commands after their results")
+                .conflicts_with("dry-run")
+                .help("Output a table of the wall time, rows, bytes and status of every command, group members included, after the results.")),
             arg!(-u --username <USERNAME> "Optional username of the user. Uses the current user by default.")
                 .required(false)
                 .help("Specify the username for the operation."),
             arg!(-p --password <PASSWORD> "Optional password of the user")
                 .required(false)
                 .help("Specify the password for the operation."),
+            arg!(--hash <NTHASH> "Optional NT hash of the user")
+                .required(false)
+                .requires("username")
+                .help("Authenticate to remote computers with this NT hash instead of a password."),
             arg!(-c --computername <COMPUTER_NAME> "Optional computer name")
                 .required(false)
                 .help("Specify the computer in case of remote operations."),
-        ]);
+            arg!(--winrm <URL> "Optional WinRM endpoint of the remote computer")
+                .required(false)
+                .help("Query WMI of the remote computer at this endpoint instead of http://<computer>:5985/wsman."),
+            arg!(--"winrm-auth" <SCHEME> "Optional WinRM authentication scheme")
+                .required(false)
+                .value_parser(["negotiate", "basic"])
+                .help("Authenticate to WinRM with 'negotiate' (NTLM, default) or 'basic'."),
+            switch(arg!(--insecure "Accept invalid TLS certificates")
+                .help("Accept self-signed and other invalid certificates of HTTPS endpoints.")),
+            arg!(--offline <IMAGE_ROOT> "Optional root of an offline Windows image")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Inspect an offline Windows image (the directory containing Windows) instead of this machine."),
+            arg!(--view <VIEW> "Optional registry view")
+                .required(false)
+                .value_parser(["x64", "x86"])
+                .help("Read the registry of the target as 64-bit ('x64', default) or 32-bit programs ('x86') see it, where parts of SOFTWARE are redirected to WOW6432Node."),
+            arg!(--manifest <FILE> "Optional file to write the manifest of the run to")
+                .required(false)
+                .requires("offline")
+                .conflicts_with("targets")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Record the commands of an --offline run and the SHA-256 hashes of every hive and file they read in this file, see the verify subcommand."),
+            switch(arg!(--"dry-run" "List what the commands would access instead of running them")
+                .help("List the registry keys, WMI queries and files the commands declare they access, without connecting to or reading anything.")),
+            arg!(--"audit-log" <FILE> "Optional file to log every access to")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Log every registry, WMI and file access of the run to this file, one JSON object per line."),
+            arg!(--targets <FILE> "Optional list of targets")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Run against every host name or offline image directory listed in this file, one per line."),
+            arg!(--concurrency <N> "Optional number of targets processed at once")
+                .required(false)
+                .value_parser(clap::value_parser!(usize))
+                .help("Process at most this many targets of --targets at the same time (default 4)."),
+            switch(arg!(--merge "Merge the results of all targets")
+                .help("Write the results of --targets as one dataset instead of one section per target.")),
+            arg!(-o --output <FILE> "Optional file to write the output to")
+                .required(false)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the output to this file instead of the console."),
+            arg!(--"encrypt-to" <PUBLIC_KEY> "Optional public key to encrypt the output to")
+                .required(false)
+                .requires("destination")
+                .help("Compress the output and encrypt it to this public key (rustbelt-pub:...), see the keygen and decrypt subcommands."),
+            arg!(--format <FORMAT> "Optional output format")
+                .required(false)
+                .value_parser(formatter::FORMATS)
+                .help("Write the output as 'simple' text (default), one 'json' document per result or 'ndjson' rows (default with --collector), in the text or JSON layout of Seatbelt ('seatbelt', 'seatbelt-json'), or as Elastic Common Schema or OCSF events for SIEMs ('ecs', 'ocsf')."),
+            arg!(--collector <URL> "Optional collector to upload the output to")
+                .required(false)
+                .conflicts_with("output")
+                .help("Post the output to this URL, for example a 'rustbelt serve' collector, instead of writing it to the console."),
+            arg!(--"collector-header" <HEADER> "Optional header for the collector")
+                .required(false)
+                .requires("collector")
+                .help("Send this header, such as 'Authorization: Bearer <token>', with every upload."),
+            arg!(--spool <DIR> "Optional spool directory")
+                .required(false)
+                .requires("collector")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the output to a file in this directory if the collector cannot be reached."),
+            arg!(--where <EXPRESSION> "Optional filter for the rows")
+                .required(false)
+                .value_parser(clap::value_parser!(Filter))
+                .help("Only output rows that satisfy this expression, such as \"UBR >= 3000 and ProductName contains 'server'\"."),
+            arg!(--select <COLUMNS> "Optional columns to output")
+                .required(false)
+                .value_delimiter(',')
+                .help("Only output these columns, separated by commas, in this order."),
+            switch(arg!(--redact "Redact sensitive data in the output")
+                .help("Mask secrets and replace user names, SIDs, host names, addresses and identifiers with consistent pseudonyms.")),
+            arg!(--"redact-map" <FILE> "Optional file for the pseudonym mapping")
+                .required(false)
+                .requires("redact")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write the mapping from pseudonyms back to the real values to this file."),
+            arg!(--timezone <ZONE> "Optional time zone for timestamps")
+                .required(false)
+                .help("Render timestamps in 'utc' (default), 'local' or a fixed offset such as '+02:00'."),
+            arg!(--"time-format" <FORMAT> "Optional format for timestamps")
+                .required(false)
+                .help("Render timestamps as 'iso8601' (default) or 'epoch' seconds."),
+        ])
+        .group(ArgGroup::new("destination").args(["output", "collector"]))
+        .group(ArgGroup::new("target").args(["offline", "computername", "targets"]));
 
-    let mut commands: Vec<Box<dyn Command>> = vec![];
+    // Load the plugins, so they are registered next to the built-in commands.
+    #[cfg(feature = "plugins")]
+    load_plugins();
 
-    // Add each registered command as a subcommand.
-    for reg in inventory::iter::<CommandRegistration> {
-        app = app.subcommand((reg.clap_command)());
-        commands.push((reg.factory)());
+    // Add the built-in subcommands, and each registered command as a subcommand.
+    app = app.subcommands([
+        ClapCommand::new("keygen")
+            .about("Generate a key pair for encrypted output")
+            .arg(
+                arg!(<KEY_FILE> "The file to write the private key to")
+                    .value_parser(clap::value_parser!(PathBuf)),
+            ),
+        ClapCommand::new("decrypt")
+            .about("Decrypt output written with --encrypt-to")
+            .args([
+                arg!(-k --key <KEY_FILE> "The file with the private key")
+                    .value_parser(clap::value_parser!(PathBuf)),
+                arg!(<BUNDLE> "The encrypted output").value_parser(clap::value_parser!(PathBuf)),
+            ]),
+        ClapCommand::new("schema")
+            .about("Print the JSON Schema of the output of the commands")
+            .arg(arg!([COMMAND] "Only describe this command")),
+        ClapCommand::new("serve")
+            .about("Collect output uploaded with --collector")
+            .args([
+                arg!(-l --listen <ADDRESS> "The address to listen on").default_value("127.0.0.1:8080"),
+                arg!(-d --dir <DIR> "The directory to store uploads in")
+                    .value_parser(clap::value_parser!(PathBuf)),
+                arg!(--"require-header" <HEADER> "Only accept uploads with this header, such as 'Authorization: Bearer <token>'")
+                    .required(false),
+            ]),
+        ClapCommand::new("import")
+            .about("Read Seatbelt JSON output and write it as Rustbelt results, to compare old Seatbelt runs with new results")
+            .arg(arg!(<FILE> "The Seatbelt JSON output").value_parser(clap::value_parser!(PathBuf))),
+        ClapCommand::new("verify")
+            .about("Check that the evidence of an offline run is unchanged since its manifest was written")
+            .args([
+                arg!(<MANIFEST> "The manifest written with --manifest").value_parser(clap::value_parser!(PathBuf)),
+                arg!(--root <IMAGE_ROOT> "The root of the image, if it moved since the run")
+                    .required(false)
+                    .value_parser(clap::value_parser!(PathBuf)),
+            ]),
+        ClapCommand::new("config")
+            .about("Inspect the configuration")
+            .subcommand_required(true)
+            .subcommand(ClapCommand::new("show").about("Print the options in effect, from the configuration and the command line")),
+    ]);
+    #[cfg(feature = "shell")]
+    {
+        app = app.subcommand(
+            ClapCommand::new("shell")
+                .about("Run commands interactively, keeping the runtime and its loaded hives between them")
+                .arg(
+                    arg!(--history <FILE> "The file to keep the history in (default ~/.rustbelt_history)")
+                        .required(false)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ),
+        );
+    }
+    for command in clap_commands() {
+        app = app.subcommand(command);
     }
 
-    // Parse the matched arguments and execute the corresponding command.
-    let matches = app.get_matches();
+    // Parse the command line, and again with the options of the configuration it leaves unset in front of it.
+    let argv = Zeroizing::new(std::env::args().collect::<Vec<String>>());
+    let command_line = app.clone().get_matches_from(argv.iter());
+    let (config, sources) = load_config(command_line.get_one::<PathBuf>("config"))?;
+    let profile = command_line.get_one::<String>("profile").cloned();
+    let settings = config.settings(profile.as_deref())?;
+    let mut matches = match settings.to_args(&app, &command_line) {
+        args if args.is_empty() => command_line.clone(),
+        args => app.get_matches_from(argv[..1].iter().chain(&args).chain(&argv[1..])),
+    };
+    // Formats with a fixed encoding of timestamps cannot honour the time options.
+    let format = output_format(&matches);
+    for option in ["timezone", "time-format"] {
+        if formatter::UTC_FORMATS.contains(&format) && command_line.value_source(option) == Some(ValueSource::CommandLine) {
+            return Err(Error::InvalidData(format!("--{option} does not apply to --format {format}, which is always UTC")));
+        }
+    }
+    // The command line is zeroized and dropped, and the secrets are taken out of the matches, so only zeroized
+    // copies of them remain.
+    drop(command_line);
+    drop(argv);
+    let password = matches.remove_one::<String>("password").map(Zeroizing::new);
+    let hash = matches.remove_one::<String>("hash").map(Zeroizing::new);
     let username = matches.get_one::<String>("username");
+    let time = TimeDisplay {
+        zone: match matches.get_one::<String>("timezone") {
+            Some(zone) => zone.parse()?,
+            None => DisplayZone::default(),
+        },
+        format: match matches.get_one::<String>("time-format") {
+            Some(format) => format.parse()?,
+            None => TimeFormat::default(),
+        },
+    };
+
+    let credentials = match (username, &hash) {
+        (Some(username), Some(hash)) => Some(Credentials::with_nt_hash(username, hash)?),
+        (Some(username), None) => Some(Credentials::with_password(
+            username,
+            password.as_ref().map_or("", |password| password.as_str()),
+        )),
+        (None, _) => None,
+    };
+    let basic = match (username, &password) {
+        (Some(username), Some(password))
+            if matches.get_one::<String>("winrm-auth").is_some_and(|scheme| scheme == "basic") =>
+        {
+            Some((username.clone(), password.clone()))
+        }
+        _ => None,
+    };
+    let remote = RemoteOptions {
+        credentials,
+        basic,
+        password,
+        winrm: matches.get_one::<String>("winrm").cloned(),
+        insecure: matches.get_flag("insecure"),
+    };
 
-    // Initialize the runtime with the provided username.
-    let runtime: Runtime = Runtime::new(username.cloned(), None, None)?;
+    // Without a subcommand, run the commands of the configuration, if any.
+    let Some((subcommand_name, sub_matches)) = matches.subcommand() else {
+        let commands: Vec<(String, Vec<String>)> = settings
+            .commands
+            .iter()
+            .flatten()
+            .map(|command| {
+                let args = ["rustbelt".to_string(), command.clone()];
+                (command.clone(), args.into_iter().chain(extra_args(&settings, command)).collect())
+            })
+            .collect();
+        if commands.is_empty() {
+            return Ok(());
+        }
+        return run_commands(&matches, &remote, time, &commands);
+    };
+    match subcommand_name {
+        "keygen" => return keygen(sub_matches.get_one::<PathBuf>("KEY_FILE").expect("required")),
+        "decrypt" => {
+            return decrypt(
+                sub_matches.get_one::<PathBuf>("key").expect("required"),
+                sub_matches.get_one::<PathBuf>("BUNDLE").expect("required"),
+            )
+        }
+        "schema" => return print_schema(sub_matches.get_one::<String>("COMMAND")),
+        "serve" => return serve(sub_matches),
+        "import" => return import_seatbelt(&matches, sub_matches.get_one::<PathBuf>("FILE").expect("required"), time),
+        "verify" => {
+            return verify(
+                sub_matches.get_one::<PathBuf>("MANIFEST").expect("required"),
+                sub_matches.get_one::<PathBuf>("root"),
+            )
+        }
+        "config" => return show_config(&matches, &settings, &sources, profile.as_ref()),
+        _ => {}
+    }
+    #[cfg(feature = "shell")]
+    if subcommand_name == "shell" {
+        return run_shell(&matches, sub_matches, create_runtime(&matches, &remote)?, time);
+    }
+    // Commands only get their own arguments, never the options in front of them, which hold the credentials.
+    let args = ["rustbelt".to_string(), subcommand_name.to_string()]
+        .into_iter()
+        .chain(command_args(sub_matches))
+        .chain(extra_args(&settings, subcommand_name))
+        .collect();
+    run_commands(&matches, &remote, time, &[(subcommand_name.to_string(), args)])
+}
+
+/// Runs commands on the target or targets of the command line and writes their output.
+///
+/// # Arguments
+///
+/// * `matches` - The parsed command line.
+/// * `remote` - How to connect to remote computers.
+/// * `time` - How to render timestamps.
+/// * `commands` - The commands and groups to run, with their arguments.
+fn run_commands(matches: &ArgMatches, remote: &RemoteOptions, time: TimeDisplay, commands: &[(String, Vec<String>)]) -> Result<()> {
+    if let Some((name, _)) = commands.iter().find(|(name, _)| get_command(name).is_none()) {
+        eprintln!("Command '{}' not found.", name);
+        return Ok(());
+    }
+    let format = output_format(matches);
+    let formatter = formatter::by_name(format, time).expect("the format is one of FORMATS");
+    let (mut writer, spool) = create_writer(matches, format)?;
+    let redactor = matches.get_flag("redact").then(|| Arc::new(Redactor::new()));
+    let query = create_query(matches);
+    if matches.get_flag("dry-run") {
+        let names: Vec<String> = commands.iter().map(|(name, _)| name.clone()).collect();
+        writer.write_line(formatter.parse_result(&plan(&names)?))?;
+        return writer.finish();
+    }
+    let audit = matches.get_one::<PathBuf>("audit-log").map(|_| Arc::new(AuditLog::new()));
+    let delay = matches.get_one::<Duration>("delay").copied().unwrap_or_default();
+    let limits = create_limits(matches);
+    let stats = matches.get_flag("stats").then(|| Arc::new(RunStats::new()));
+    let view = registry_view(matches);
+    let configure = |runtime: Runtime| {
+        // The view comes first, so the audit and the manifest see the keys as the commands ask for them.
+        let mut runtime = runtime.with_view(view).with_query(query.clone()).with_delay(delay);
+        if let Some(stats) = &stats {
+            runtime = runtime.with_stats(stats.clone());
+        }
+        if let Some(audit) = &audit {
+            runtime = runtime.with_audit(audit.clone());
+        }
+        // Wrapped after the audit, so accesses a limit prevents are not logged as made.
+        if limits != Limits::new() {
+            runtime = runtime.with_limits(limits);
+        }
+        match &redactor {
+            Some(redactor) => runtime.with_redactor(redactor.clone()),
+            None => runtime,
+        }
+    };
 
-    // Check if a subcommand was provided and execute the corresponding command.
-    if let Some((subcommand_name, _sub_matches)) = matches.subcommand() {
-        if let Some(command) = get_command(subcommand_name) {
-            // Collect the arguments and execute the command.
-            let args = std::env::args().collect::<Vec<_>>();
-            let result = command.execute(&runtime, &args)?;
+    if let Some(list) = matches.get_one::<PathBuf>("targets") {
+        let targets = read_targets(list)?;
+        let concurrency = *matches.get_one::<usize>("concurrency").unwrap_or(&4);
+        let remote = RemoteOptions { winrm: None, ..remote.clone() };
+        let mut reports = run_targets(&targets, commands, concurrency, |target| {
+            let runtime = match target {
+                Target::Host(host) => remote_runtime(host, &remote)?,
+                Target::Image(root) => Runtime::offline(root)?,
+            };
+            Ok(configure(runtime))
+        });
 
-            // Write the result to the console.
-            ConsoleWriter::write_line(SimpleFormatter::parse_result(result));
+        // The targets are host names or paths, so they are pseudonymized in the output and the summary as well,
+        // including where errors name them.
+        let mut pseudonyms = vec![];
+        if let Some(redactor) = &redactor {
+            for report in &mut reports {
+                let target = report.target.to_string();
+                let pseudonym = redactor.pseudonym(FieldTag::Hostname, &target);
+                report.target = Target::Host(pseudonym.clone());
+                pseudonyms.push((target, pseudonym));
+            }
+        }
+        let summary = pseudonyms
+            .iter()
+            .fold(RunSummary::new(&reports).to_string(), |summary, (target, pseudonym)| summary.replace(target, pseudonym));
+
+        // Write the results, merged or per target, and what failed to the error stream.
+        if matches.get_flag("merge") {
+            writer.write_line(formatter.parse_result(&merge(&reports)))?;
         } else {
-            eprintln!("Command '{}' not found.", subcommand_name);
+            for report in &reports {
+                // Structured rows carry their target, only text needs headings.
+                if format == "simple" {
+                    writer.write_line(format!("====[{}]====", report.target))?;
+                }
+                writer.write_line(formatter.parse_result(&report.tagged_result()))?;
+            }
+        }
+        write_stats(stats.as_deref(), redactor.as_deref(), formatter.as_ref(), writer.as_mut())?;
+        writer.finish()?;
+        report_spool(spool.as_deref());
+        write_redaction_map(matches, redactor.as_deref())?;
+        write_audit_log(matches, audit.as_deref())?;
+        eprintln!("{summary}");
+        return Ok(());
+    }
+
+    let mut runtime = configure(create_runtime(matches, remote)?);
+    if let Some(interval) = matches.get_one::<Duration>("watch") {
+        let count = matches.get_one::<usize>("watch-count").copied();
+        let outcome = watch(&runtime, commands, *interval, count, formatter.as_ref(), writer.as_mut());
+        write_audit_log(matches, audit.as_deref())?;
+        write_stats(stats.as_deref(), redactor.as_deref(), formatter.as_ref(), writer.as_mut())?;
+        outcome?;
+        writer.finish()?;
+        report_spool(spool.as_deref());
+        return write_redaction_map(matches, redactor.as_deref());
+    }
+    let recorder = match (matches.get_one::<PathBuf>("manifest"), matches.get_one::<PathBuf>("offline")) {
+        (Some(_), Some(root)) => Some(Arc::new(ManifestRecorder::new(root))),
+        _ => None,
+    };
+    if let Some(recorder) = &recorder {
+        runtime = runtime.with_manifest(recorder.clone());
+    }
+    let outcome = commands.iter().enumerate().try_for_each(|(index, (name, args))| {
+        if index > 0 {
+            std::thread::sleep(runtime.delay());
+        }
+        // A single command fails the run, of several commands the others still run.
+        match runtime.execute(name, args) {
+            Ok(result) => writer.write_line(formatter.parse_result(&result)),
+            // What a command returned before it reached a limit is still output.
+            Err(Error::Partial(partial)) => {
+                eprintln!("Command '{name}' stopped early, the result is partial: {partial}");
+                writer.write_line(formatter.parse_result(&partial.result))
+            }
+            Err(e) if commands.len() > 1 => {
+                eprintln!("Command '{name}' failed: {e}");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    });
+    // The manifest and the audit log record failed runs as well.
+    if let (Some(recorder), Some(path)) = (&recorder, matches.get_one::<PathBuf>("manifest")) {
+        recorder.manifest().save(path)?;
+    }
+    write_audit_log(matches, audit.as_deref())?;
+    // Failed commands are listed as well.
+    write_stats(stats.as_deref(), redactor.as_deref(), formatter.as_ref(), writer.as_mut())?;
+    outcome?;
+    writer.finish()?;
+    report_spool(spool.as_deref());
+    write_redaction_map(matches, redactor.as_deref())
+}
+
+/// Writes the statistics of the commands of the run after their results, if `--stats` was given.
+///
+/// # Arguments
+///
+/// * `stats` - The statistics, or `None` without `--stats`.
+/// * `redactor` - The redactor of the run, which pseudonymizes the hosts as in the results.
+/// * `formatter` - The format of the results.
+/// * `writer` - Where the results were written to.
+fn write_stats(
+    stats: Option<&RunStats>,
+    redactor: Option<&Redactor>,
+    formatter: &dyn Formatter,
+    writer: &mut dyn Writer,
+) -> Result<()> {
+    let Some(stats) = stats else {
+        return Ok(());
+    };
+    let mut result = stats.to_result();
+    if let Some(redactor) = redactor {
+        redactor.redact(&mut result, &[&stats::SCHEMA]);
+    }
+    writer.write_line(formatter.parse_result(&result))
+}
+
+/// Runs commands at an interval on one runtime and writes the rows that changed since the previous pass.
+///
+/// # Arguments
+///
+/// * `runtime` - The runtime to run the commands on, kept for all passes.
+/// * `commands` - The commands and groups to run, with their arguments.
+/// * `interval` - How long to wait between passes.
+/// * `count` - How many passes to run, or `None` to run until interrupted.
+/// * `formatter` - The format of the changes.
+/// * `writer` - Where to write the changes to, flushed after every pass.
+fn watch(
+    runtime: &Runtime,
+    commands: &[(String, Vec<String>)],
+    interval: Duration,
+    count: Option<usize>,
+    formatter: &dyn Formatter,
+    writer: &mut dyn Writer,
+) -> Result<()> {
+    let mut watcher = Watcher::new();
+    for pass in 0..count.unwrap_or(usize::MAX) {
+        if pass > 0 {
+            std::thread::sleep(interval);
+        }
+        for (index, (name, args)) in commands.iter().enumerate() {
+            if index > 0 {
+                std::thread::sleep(runtime.delay());
+            }
+            // A command that fails keeps its previous pass and is run again in the next one.
+            let changes = match runtime.execute(name, args) {
+                Ok(result) => watcher.diff(&result),
+                // What a command returned before it reached a limit cannot tell which rows were removed.
+                Err(Error::Partial(partial)) => {
+                    eprintln!("Command '{name}' stopped early, pass {} is partial: {partial}", pass + 1);
+                    watcher.diff_partial(&partial.result)
+                }
+                Err(e) => {
+                    eprintln!("Command '{name}' failed: {e}");
+                    continue;
+                }
+            };
+            if !changes.tables().is_empty() {
+                writer.write_line(formatter.parse_result(&changes))?;
+            }
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Returns the output format on the command line: the one given, or the default for the destination.
+fn output_format(matches: &ArgMatches) -> &str {
+    match matches.get_one::<String>("format") {
+        Some(format) => format.as_str(),
+        None if matches.contains_id("collector") => "ndjson",
+        None => "simple",
+    }
+}
+
+/// Returns the limits of every command on the command line.
+fn create_limits(matches: &ArgMatches) -> Limits {
+    Limits {
+        timeout: matches.get_one::<Duration>("timeout").copied(),
+        total_timeout: matches.get_one::<Duration>("total-timeout").copied(),
+        max_rows: matches.get_one::<usize>("max-rows").copied(),
+        max_bytes: matches.get_one::<usize>("max-bytes").copied(),
+    }
+}
+
+/// Returns the registry view on the command line.
+fn registry_view(matches: &ArgMatches) -> RegistryHiveType {
+    match matches.get_one::<String>("view").map(String::as_str) {
+        Some("x86") => RegistryHiveType::X86,
+        _ => RegistryHiveType::X64,
+    }
+}
+
+/// Returns the filter and projection on the command line.
+fn create_query(matches: &ArgMatches) -> Query {
+    Query {
+        filter: matches.get_one::<Filter>("where").cloned(),
+        columns: matches.get_many::<String>("select").into_iter().flatten().cloned().collect(),
+    }
+}
+
+/// Reads Seatbelt JSON output and writes it like the results of a run, with the output options of the command line.
+///
+/// # Arguments
+///
+/// * `matches` - The parsed command line.
+/// * `path` - The file of Seatbelt JSON output.
+/// * `time` - How to render timestamps.
+fn import_seatbelt(matches: &ArgMatches, path: &Path, time: TimeDisplay) -> Result<()> {
+    let mut result = seatbelt::import_file(path)?;
+    create_query(matches).apply(&mut result);
+    let redactor = matches.get_flag("redact").then(Redactor::new);
+    if let Some(redactor) = &redactor {
+        let schemas: Vec<_> = schemas().into_iter().map(|(_, schema)| schema).collect();
+        redactor.redact(&mut result, &schemas);
+    }
+
+    let format = output_format(matches);
+    let formatter = formatter::by_name(format, time).expect("the format is one of FORMATS");
+    let (mut writer, spool) = create_writer(matches, format)?;
+    writer.write_line(formatter.parse_result(&result))?;
+    writer.finish()?;
+    report_spool(spool.as_deref());
+    write_redaction_map(matches, redactor.as_ref())
+}
+
+/// Writes the accesses of the run to the file of `--audit-log`, if one was given.
+fn write_audit_log(matches: &ArgMatches, audit: Option<&AuditLog>) -> Result<()> {
+    match (audit, matches.get_one::<PathBuf>("audit-log")) {
+        (Some(audit), Some(path)) => audit.write(path),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the evidence a manifest was recorded on against the hashes of the manifest.
+///
+/// # Arguments
+///
+/// * `path` - The manifest.
+/// * `root` - Optional root of the evidence, if it moved since the run.
+///
+/// # Returns
+///
+/// * `Ok(())` if every artifact is unchanged.
+/// * `Err(Error::InvalidData)` if an artifact is missing or changed.
+fn verify(path: &Path, root: Option<&PathBuf>) -> Result<()> {
+    let manifest = Manifest::load(path)?;
+    let root = root.unwrap_or(&manifest.root);
+    let discrepancies = manifest.verify(root);
+    for discrepancy in &discrepancies {
+        println!("{discrepancy}");
+    }
+    let total = manifest.artifacts.len();
+    if !discrepancies.is_empty() {
+        return Err(rustbelt::Error::InvalidData(format!(
+            "{} of {total} artifacts in {} differ from the manifest",
+            discrepancies.len(),
+            root.display()
+        )));
+    }
+    println!("All {total} artifacts in {} match the manifest.", root.display());
+    Ok(())
+}
+
+/// Reads the configuration of the user, if there is one, and the file given with `--config` on top of it.
+///
+/// # Returns
+///
+/// The configuration, and the files it was read from.
+fn load_config(path: Option<&PathBuf>) -> Result<(Config, Vec<PathBuf>)> {
+    let mut config = Config::default();
+    let mut sources = vec![];
+    let user = Config::user_path().filter(|user| user.is_file());
+    for path in user.iter().chain(path) {
+        config.overlay(&Config::load(path)?);
+        sources.push(path.clone());
+    }
+    Ok((config, sources))
+}
+
+/// Returns the arguments a command was given on the command line, in order.
+fn command_args(sub_matches: &ArgMatches) -> Vec<String> {
+    sub_matches
+        .ids()
+        .flat_map(|id| sub_matches.get_raw(id.as_str()).into_iter().flatten())
+        .map(|value| value.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Returns the extra arguments the configuration has for a command.
+fn extra_args(settings: &Settings, command: &str) -> Vec<String> {
+    settings.args.get(command).cloned().unwrap_or_default()
+}
+
+/// Prints the options in effect: those of the configuration, replaced by those on the command line.
+///
+/// # Arguments
+///
+/// * `matches` - The command line, with the options of the configuration applied.
+/// * `settings` - The settings of the configuration.
+/// * `sources` - The configuration files that were read.
+/// * `profile` - The selected profile, if any.
+fn show_config(matches: &ArgMatches, settings: &Settings, sources: &[PathBuf], profile: Option<&String>) -> Result<()> {
+    let text = |id: &str| {
+        matches
+            .get_raw(id)
+            .and_then(|mut values| values.next())
+            .map(|value| value.to_string_lossy().into_owned())
+    };
+    let flag = |id: &str| matches.get_flag(id).then_some(true);
+    let effective = Settings {
+        computername: text("computername"),
+        username: text("username"),
+        winrm: text("winrm"),
+        winrm_auth: text("winrm-auth"),
+        insecure: flag("insecure"),
+        offline: text("offline").map(PathBuf::from),
+        view: text("view"),
+        manifest: text("manifest").map(PathBuf::from),
+        dry_run: flag("dry-run"),
+        audit_log: text("audit-log").map(PathBuf::from),
+        targets: text("targets").map(PathBuf::from),
+        concurrency: matches.get_one::<usize>("concurrency").copied(),
+        merge: flag("merge"),
+        delay: text("delay"),
+        timeout: text("timeout"),
+        total_timeout: text("total-timeout"),
+        max_rows: matches.get_one::<usize>("max-rows").copied(),
+        max_bytes: matches.get_one::<usize>("max-bytes").copied(),
+        watch: text("watch"),
+        watch_count: matches.get_one::<usize>("watch-count").copied(),
+        stats: flag("stats"),
+        format: text("format"),
+        output: text("output").map(PathBuf::from),
+        encrypt_to: text("encrypt-to"),
+        collector: text("collector"),
+        collector_header: text("collector-header"),
+        spool: text("spool").map(PathBuf::from),
+        filter: text("where"),
+        select: matches.get_many::<String>("select").map(|columns| columns.cloned().collect()),
+        redact: flag("redact"),
+        redact_map: text("redact-map").map(PathBuf::from),
+        timezone: text("timezone"),
+        time_format: text("time-format"),
+        commands: settings.commands.clone(),
+        args: settings.args.clone(),
+    };
+
+    let sources: Vec<String> = sources.iter().map(|source| source.display().to_string()).collect();
+    println!("# Configuration: {}", if sources.is_empty() { "none".to_string() } else { sources.join(", ") });
+    println!("# Profile: {}", profile.map_or("none", String::as_str));
+    let document = toml::to_string(&effective).map_err(|e| rustbelt::Error::InvalidData(e.to_string()))?;
+    print!("{document}");
+    Ok(())
+}
+
+/// Creates the runtime of the single target on the command line: an offline image, a remote computer or this machine.
+fn create_runtime(matches: &ArgMatches, remote: &RemoteOptions) -> Result<Runtime> {
+    match (matches.get_one::<PathBuf>("offline"), matches.get_one::<String>("computername")) {
+        (Some(root), _) => Runtime::offline(root),
+        (None, Some(computer_name)) => remote_runtime(computer_name, remote),
+        (None, None) => live_runtime(
+            matches.get_one::<String>("username").cloned(),
+            remote.password.as_ref().map(|password| password.to_string()),
+        ),
+    }
+}
+
+/// Runs the interactive shell on a runtime, with the output options of the command line as its defaults.
+///
+/// # Arguments
+///
+/// * `matches` - The parsed command line.
+/// * `sub_matches` - The arguments of the shell subcommand.
+/// * `runtime` - The runtime of the session.
+/// * `time` - How to render timestamps.
+#[cfg(feature = "shell")]
+fn run_shell(matches: &ArgMatches, sub_matches: &ArgMatches, runtime: Runtime, time: TimeDisplay) -> Result<()> {
+    use rustbelt::shell::{self, Shell};
+
+    if matches.contains_id("targets") {
+        return Err(Error::Unsupported("the shell runs on a single target, not on --targets".to_string()));
+    }
+    let redactor = matches.get_flag("redact").then(|| Arc::new(Redactor::new()));
+    let runtime = runtime
+        .with_view(registry_view(matches))
+        .with_delay(matches.get_one::<Duration>("delay").copied().unwrap_or_default());
+    // A session has no end to time, so only the limits of single commands apply.
+    let limits = Limits { total_timeout: None, ..create_limits(matches) };
+    let runtime = match limits == Limits::new() {
+        true => runtime,
+        false => runtime.with_limits(limits),
+    };
+    let runtime = match &redactor {
+        Some(redactor) => runtime.with_redactor(redactor.clone()),
+        None => runtime,
+    };
+
+    let mut shell = Shell::new(runtime)
+        .with_time(time)
+        .with_format(matches.get_one::<String>("format").map_or("simple", String::as_str))?
+        .with_columns(matches.get_many::<String>("select").into_iter().flatten().cloned().collect());
+    // The filter was validated while parsing the command line, the shell keeps its text.
+    if let Some(expression) = matches.get_raw("where").and_then(|mut raw| raw.next()) {
+        shell = shell.with_filter(&expression.to_string_lossy())?;
+    }
+
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    let history = match sub_matches.get_one::<PathBuf>("history") {
+        Some(history) => Some(history.clone()),
+        None => home.map(|home| PathBuf::from(home).join(".rustbelt_history")),
+    };
+    shell::run(shell, history.as_deref())?;
+    write_redaction_map(matches, redactor.as_deref())
+}
+
+/// Writes the pseudonym mapping of a redacted run to the file given with `--redact-map`, if any.
+fn write_redaction_map(matches: &ArgMatches, redactor: Option<&Redactor>) -> Result<()> {
+    if let (Some(redactor), Some(path)) = (redactor, matches.get_one::<PathBuf>("redact-map")) {
+        redactor.write_mapping(path)?;
+        eprintln!("Wrote the pseudonym mapping to {}; keep it as safe as the unredacted output.", path.display());
+    }
+    Ok(())
+}
+
+/// Creates the writer for the output destination on the command line.
+///
+/// # Arguments
+///
+/// * `matches` - The parsed command line.
+/// * `format` - The output format.
+///
+/// # Returns
+///
+/// The writer, and for uploads the file the output is spooled to if the collector cannot be reached.
+fn create_writer(matches: &ArgMatches, format: &str) -> Result<(Box<dyn Writer>, Option<PathBuf>)> {
+    let recipient: Option<RecipientKey> = match matches.get_one::<String>("encrypt-to") {
+        Some(recipient) => Some(recipient.parse()?),
+        None => None,
+    };
+
+    if let Some(url) = matches.get_one::<String>("collector") {
+        let mut writer = HttpWriter::new(url.parse()?)?
+            .accept_invalid_certs(matches.get_flag("insecure"));
+        match format {
+            "json" => writer = writer.content_type("application/json"),
+            "simple" | "seatbelt" => writer = writer.content_type("text/plain; charset=utf-8"),
+            _ => {}
+        }
+        if let Some(header) = matches.get_one::<String>("collector-header") {
+            let (name, value) = parse_header(header)?;
+            writer = writer.header(name, value);
         }
+        if let Some(dir) = matches.get_one::<PathBuf>("spool") {
+            writer = writer.spool(dir);
+        }
+        if let Some(recipient) = recipient {
+            writer = writer.encrypt_to(recipient);
+        }
+        let spool = writer.spool_path();
+        return Ok((Box::new(writer), spool));
+    }
+
+    let writer: Box<dyn Writer> = match (matches.get_one::<PathBuf>("output"), recipient) {
+        (Some(path), Some(recipient)) => Box::new(BundleWriter::create(path, &recipient)?),
+        (Some(path), None) => Box::new(FileWriter::create(path)?),
+        (None, _) => Box::new(ConsoleWriter::default()),
+    };
+    Ok((writer, None))
+}
+
+/// Tells the user when output was spooled because the collector could not be reached.
+fn report_spool(spool: Option<&Path>) {
+    if let Some(path) = spool.filter(|path| path.exists()) {
+        eprintln!("The collector could not be reached, the output was spooled to {}", path.display());
     }
+}
+
+/// Parses a header given as `Name: value`.
+fn parse_header(header: &str) -> Result<(&str, &str)> {
+    header
+        .split_once(':')
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| rustbelt::Error::InvalidData(format!("header '{header}' must look like 'Name: value'")))
+}
+
+/// Prints the JSON Schema of the output of all commands, or of one command.
+///
+/// # Arguments
+///
+/// * `command` - Optional name of the only command to describe.
+fn print_schema(command: Option<&String>) -> Result<()> {
+    let schemas: Vec<_> = schemas()
+        .into_iter()
+        .filter(|(name, _)| command.is_none_or(|command| command == name))
+        .collect();
+    if let (Some(command), true) = (command, schemas.is_empty()) {
+        return Err(rustbelt::Error::NotFound(format!("schema of command '{command}'")));
+    }
+
+    let document = catalog_json_schema(schemas.iter().map(|(name, schema)| (name.as_str(), *schema)));
+    println!("{}", serde_json::to_string_pretty(&document).expect("schemas always serialize to JSON"));
+    Ok(())
+}
+
+/// Receives output uploaded with `--collector` until interrupted.
+///
+/// # Arguments
+///
+/// * `matches` - The arguments of the `serve` subcommand.
+fn serve(matches: &ArgMatches) -> Result<()> {
+    let dir = matches.get_one::<PathBuf>("dir").expect("required");
+    let mut collector = Collector::new(dir)?;
+    if let Some(header) = matches.get_one::<String>("require-header") {
+        let (name, value) = parse_header(header)?;
+        collector = collector.require_header(name, value);
+    }
+
+    let listener = TcpListener::bind(matches.get_one::<String>("listen").expect("has a default"))?;
+    eprintln!("Collecting uploads on {} into {}", listener.local_addr()?, dir.display());
+    collector.serve(listener)
+}
+
+/// Generates a key pair for encrypted output.
+///
+/// The private key is written to a new file, which is only readable by the
+/// current user on Unix, and the public key is printed.
+///
+/// # Arguments
+///
+/// * `path` - The file to write the private key to. It must not exist.
+fn keygen(path: &Path) -> Result<()> {
+    let identity = IdentityKey::generate()?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", identity.to_text().as_str())?;
+
+    println!("{}", identity.recipient());
+    Ok(())
+}
+
+/// Decrypts output written with `--encrypt-to` and prints it.
+///
+/// # Arguments
+///
+/// * `key` - The file with the private key.
+/// * `bundle` - The encrypted output.
+fn decrypt(key: &Path, bundle: &Path) -> Result<()> {
+    let identity: IdentityKey = Zeroizing::new(fs::read_to_string(key)?).parse()?;
+    let contents = Zeroizing::new(bundle::open(BufReader::new(File::open(bundle)?), &identity)?);
+    std::io::stdout().write_all(&contents)?;
     Ok(())
 }
+
+/// How to connect to remote computers, and to WMI of this machine.
+///
+/// # Fields
+/// - `credentials`: Optional credentials of the user. Without them, connections are anonymous.
+/// - `basic`: Optional username and password for basic authentication to WinRM.
+/// - `password`: Optional password of the user, for WMI of this machine.
+/// - `winrm`: Optional URL of the WinRM endpoint.
+/// - `insecure`: Whether to accept invalid TLS certificates.
+#[derive(Clone)]
+struct RemoteOptions {
+    credentials: Option<Credentials>,
+    basic: Option<(String, Zeroizing<String>)>,
+    password: Option<Zeroizing<String>>,
+    winrm: Option<String>,
+    insecure: bool,
+}
+
+/// Creates the runtime that inspects a remote computer.
+///
+/// With credentials, the registry is read over an SMB session authenticated
+/// with NTLM and WMI is queried through WinRM with NTLM, or with basic
+/// authentication if requested. Without credentials, both are anonymous.
+///
+/// # Arguments
+///
+/// * `computer_name` - The name or address of the computer.
+/// * `options` - How to connect to the computer.
+fn remote_runtime(computer_name: &str, options: &RemoteOptions) -> Result<Runtime> {
+    let runtime = match &options.credentials {
+        Some(credentials) => Runtime::remote_with_credentials(computer_name, credentials)?,
+        None => Runtime::remote(computer_name, &mut Anonymous)?,
+    };
+
+    let auth = match (&options.basic, &options.credentials) {
+        (Some((username, password)), _) => WinRmAuth::Basic {
+            username: username.clone(),
+            password: password.clone(),
+        },
+        (None, Some(credentials)) => WinRmAuth::Ntlm(credentials.clone()),
+        (None, None) => WinRmAuth::None,
+    };
+    let wmi = match &options.winrm {
+        Some(url) => WinRmWmi::new(url.parse()?, auth),
+        None => WinRmWmi::for_host(computer_name, auth),
+    };
+    Ok(runtime.with_wmi(Box::new(wmi.accept_invalid_certs(options.insecure))))
+}
+
+/// Creates the runtime that inspects the local machine.
+///
+/// # Arguments
+///
+/// * `username` - Optional username used for WMI connections.
+/// * `password` - Optional password used for WMI connections.
+#[cfg(windows)]
+fn live_runtime(username: Option<String>, password: Option<String>) -> Result<Runtime> {
+    Runtime::new(username, password, None)
+}
+
+#[cfg(not(windows))]
+fn live_runtime(_username: Option<String>, _password: Option<String>) -> Result<Runtime> {
+    Err(rustbelt::Error::Unsupported(
+        "the live system can only be inspected on Windows, use --offline".to_string(),
+    ))
+}
+
+/// Loads the WebAssembly plugins from the plugins directory.
+///
+/// The directory is taken from the `RUSTBELT_PLUGIN_DIR` environment variable,
+/// and defaults to the `plugins` directory next to the executable. Plugins that
+/// fail to load are reported and skipped.
+#[cfg(feature = "plugins")]
+fn load_plugins() {
+    use rustbelt::plugins::{self, PluginLimits, PLUGIN_DIR_ENV};
+
+    let dir = match std::env::var_os(PLUGIN_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::current_exe() {
+            Ok(exe) => exe.with_file_name("plugins"),
+            Err(_) => return,
+        },
+    };
+    if !dir.is_dir() {
+        return;
+    }
+
+    match plugins::load_plugins(&dir, PluginLimits::default()) {
+        Ok(loaded) => {
+            for (path, result) in loaded {
+                if let Err(e) = result {
+                    eprintln!("Could not load plugin {path}: {e}");
+                }
+            }
+        }
+        Err(e) => eprintln!("Could not read plugin directory {}: {e}", dir.display()),
+    }
+}
@@ -0,0 +1,181 @@
+//! Host functions available to plugins.
+
+use serde_json::{json, Value as Json};
+use wasmi::{Caller, Extern, Linker};
+
+use super::{plugin_error, read_memory, row_to_json, HostState};
+use crate::{
+    error::{Error, Result},
+    runtime::Runtime,
+    utils::registry::{RegistryHive, RegistryValue},
+};
+
+/// Adds the host functions to a linker.
+pub(super) fn link(linker: &mut Linker<HostState>) -> Result<()> {
+    linker
+        .func_wrap(
+            "rustbelt",
+            "registry_get_value",
+            |mut caller: Caller<HostState>,
+             hive: i32,
+             path_ptr: i32,
+             path_len: i32,
+             name_ptr: i32,
+             name_len: i32| {
+                let answer = (|| {
+                    let hive = hive_from_id(hive)?;
+                    let path = read_string(&caller, path_ptr, path_len)?;
+                    let name = read_string(&caller, name_ptr, name_len)?;
+                    let value = runtime(&caller)?
+                        .registry()
+                        .get_raw_value(hive, &path, &name)?;
+                    Ok(registry_value_to_json(value))
+                })();
+                respond(&mut caller, answer)
+            },
+        )
+        .map_err(plugin_error)?;
+
+    linker
+        .func_wrap(
+            "rustbelt",
+            "registry_sub_keys",
+            |mut caller: Caller<HostState>, hive: i32, path_ptr: i32, path_len: i32| {
+                let answer = (|| {
+                    let hive = hive_from_id(hive)?;
+                    let path = read_string(&caller, path_ptr, path_len)?;
+                    let names = runtime(&caller)?.registry().get_sub_key_names(hive, &path)?;
+                    Ok(json!(names))
+                })();
+                respond(&mut caller, answer)
+            },
+        )
+        .map_err(plugin_error)?;
+
+    linker
+        .func_wrap(
+            "rustbelt",
+            "registry_value_names",
+            |mut caller: Caller<HostState>, hive: i32, path_ptr: i32, path_len: i32| {
+                let answer = (|| {
+                    let hive = hive_from_id(hive)?;
+                    let path = read_string(&caller, path_ptr, path_len)?;
+                    let names = runtime(&caller)?.registry().get_value_names(hive, &path)?;
+                    Ok(json!(names))
+                })();
+                respond(&mut caller, answer)
+            },
+        )
+        .map_err(plugin_error)?;
+
+    linker
+        .func_wrap(
+            "rustbelt",
+            "file_read",
+            |mut caller: Caller<HostState>, path_ptr: i32, path_len: i32| {
+                let answer = (|| {
+                    let path = read_string(&caller, path_ptr, path_len)?;
+                    let contents = runtime(&caller)?.files().read_file(&path)?;
+                    Ok(json!(contents))
+                })();
+                respond(&mut caller, answer)
+            },
+        )
+        .map_err(plugin_error)?;
+
+    linker
+        .func_wrap(
+            "rustbelt",
+            "wmi_query",
+            |mut caller: Caller<HostState>,
+             namespace_ptr: i32,
+             namespace_len: i32,
+             query_ptr: i32,
+             query_len: i32,
+             fields_ptr: i32,
+             fields_len: i32| {
+                let answer = (|| {
+                    let namespace = read_string(&caller, namespace_ptr, namespace_len)?;
+                    let query = read_string(&caller, query_ptr, query_len)?;
+                    let fields: Vec<String> =
+                        serde_json::from_str(&read_string(&caller, fields_ptr, fields_len)?)
+                            .map_err(|e| Error::InvalidData(format!("fields: {e}")))?;
+                    let rows = runtime(&caller)?.wmi_query(&namespace, &query, &fields)?;
+                    Ok(Json::Array(rows.iter().map(row_to_json).collect()))
+                })();
+                respond(&mut caller, answer)
+            },
+        )
+        .map_err(plugin_error)?;
+
+    Ok(())
+}
+
+/// Returns the runtime of the current execution.
+fn runtime<'a>(caller: &Caller<'_, HostState<'a>>) -> Result<&'a Runtime> {
+    caller
+        .data()
+        .runtime
+        .ok_or_else(|| Error::Unsupported("host functions are not available here".to_string()))
+}
+
+/// Maps the hive numbers of the plugin interface to registry hives.
+fn hive_from_id(id: i32) -> Result<RegistryHive> {
+    match id {
+        0 => Ok(RegistryHive::ClassesRoot),
+        1 => Ok(RegistryHive::CurrentConfig),
+        2 => Ok(RegistryHive::CurrentUser),
+        3 => Ok(RegistryHive::LocalMachine),
+        4 => Ok(RegistryHive::Users),
+        _ => Err(Error::InvalidData(format!("unknown hive {id}"))),
+    }
+}
+
+/// Converts a registry value into JSON for a plugin.
+fn registry_value_to_json(value: RegistryValue) -> Json {
+    match value {
+        RegistryValue::None => Json::Null,
+        RegistryValue::String(value) | RegistryValue::ExpandString(value) => json!(value),
+        RegistryValue::Binary(value) | RegistryValue::Other(_, value) => json!(value),
+        RegistryValue::DWord(value) => json!(value),
+        RegistryValue::QWord(value) => json!(value),
+        RegistryValue::MultiString(values) => json!(values),
+    }
+}
+
+/// Returns the memory exported by the calling plugin.
+fn memory(caller: &Caller<HostState>) -> Result<wasmi::Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err(Error::Plugin("plugin does not export its memory".to_string())),
+    }
+}
+
+/// Reads a UTF-8 string from the memory of the calling plugin.
+fn read_string(caller: &Caller<HostState>, ptr: i32, len: i32) -> Result<String> {
+    let buffer = read_memory(memory(caller)?, caller, ptr as u32 as usize, len as u32 as usize)?;
+    String::from_utf8(buffer).map_err(|e| Error::InvalidData(e.to_string()))
+}
+
+/// Writes the answer of a host function into the memory of the calling plugin.
+///
+/// Errors of the host function are reported to the plugin. Only failures to
+/// hand over the answer itself trap the plugin.
+fn respond(caller: &mut Caller<HostState>, answer: Result<Json>) -> std::result::Result<i64, wasmi::Error> {
+    let answer = match answer {
+        Ok(value) => json!({ "ok": value }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    let bytes = answer.to_string().into_bytes();
+
+    let alloc = match caller.get_export("rustbelt_alloc") {
+        Some(Extern::Func(func)) => func.typed::<i32, i32>(&*caller)?,
+        _ => return Err(wasmi::Error::new("plugin does not export rustbelt_alloc")),
+    };
+    let ptr = alloc.call(&mut *caller, bytes.len() as i32)?;
+    memory(caller)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?
+        .write(&mut *caller, ptr as u32 as usize, &bytes)?;
+
+    Ok(((ptr as u32 as i64) << 32) | bytes.len() as i64)
+}
@@ -0,0 +1,351 @@
+//! WebAssembly plugin commands loaded at runtime.
+//!
+//! Every `.wasm` file in the plugins directory becomes a command next to the
+//! commands registered through `inventory`. Plugins run in a sandbox: they
+//! have no access to the host other than the narrow API below, and every
+//! execution is bounded by a fuel budget and a memory limit.
+//!
+//! # Plugin interface
+//!
+//! Data crosses the boundary as UTF-8 JSON. A pointer and length pair is
+//! returned packed in an `i64` as `(ptr << 32) | len`.
+//!
+//! A plugin exports:
+//!
+//! * `memory` - Its linear memory.
+//! * `rustbelt_alloc(len: i32) -> i32` - Allocates `len` bytes for data passed in by the host.
+//! * `rustbelt_describe() -> i64` - Returns `{"name": "...", "about": "..."}`.
+//! * `rustbelt_run(args_ptr: i32, args_len: i32) -> i64` - Receives the arguments as a JSON
+//!   array of strings and returns `{"source": "...", "rows": [{"column": value}]}`, or
+//!   `{"error": "..."}` when it fails.
+//!
+//! The host provides the following functions in the `rustbelt` import module.
+//! All of them answer with `{"ok": ...}` or `{"error": "..."}`, allocated
+//! through `rustbelt_alloc`. Hives are passed as numbers: `0` ClassesRoot,
+//! `1` CurrentConfig, `2` CurrentUser, `3` LocalMachine and `4` Users.
+//!
+//! * `registry_get_value(hive, path_ptr, path_len, name_ptr, name_len) -> i64`
+//! * `registry_sub_keys(hive, path_ptr, path_len) -> i64`
+//! * `registry_value_names(hive, path_ptr, path_len) -> i64`
+//! * `file_read(path_ptr, path_len) -> i64` - Answers with the bytes as an array of numbers.
+//! * `wmi_query(namespace_ptr, namespace_len, query_ptr, query_len, fields_ptr, fields_len) -> i64`
+//!   - `fields` is a JSON array of property names. Answers with an array of rows.
+//!
+//! Registry, file and WMI access goes through the backends of the active
+//! `Runtime`, so plugins work the same against every backend.
+mod host;
+
+use std::{fs, path::Path, sync::Arc};
+
+use serde_json::{json, Map, Value as Json};
+use wasmi::{AsContext, Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{
+    commands::base::{
+        registry::{register_command, DynamicRegistration},
+        Command, CommandDTO,
+        CommandResult::{self, Simple},
+        Row, Value,
+    },
+    error::{Error, Result},
+    runtime::Runtime,
+};
+
+/// Environment variable that overrides the default plugins directory.
+pub const PLUGIN_DIR_ENV: &str = "RUSTBELT_PLUGIN_DIR";
+
+/// Resource limits applied to every plugin execution.
+///
+/// # Fields
+/// - `fuel`: The amount of fuel (roughly, executed instructions) a single execution may consume.
+/// - `memory_bytes`: The maximum size of the linear memory of a plugin.
+#[derive(Debug, Clone, Copy)]
+pub struct PluginLimits {
+    pub fuel: u64,
+    pub memory_bytes: usize,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        PluginLimits {
+            fuel: 1_000_000_000,
+            memory_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// State available to host functions during a plugin execution.
+struct HostState<'a> {
+    runtime: Option<&'a Runtime>,
+    limits: StoreLimits,
+}
+
+/// A compiled WebAssembly plugin.
+pub struct Plugin {
+    name: String,
+    about: String,
+    engine: Engine,
+    module: Module,
+    limits: PluginLimits,
+}
+
+impl Plugin {
+    /// Compiles a plugin and reads its description.
+    ///
+    /// # Arguments
+    ///
+    /// * `wasm` - The WebAssembly module, in binary format.
+    /// * `limits` - The resource limits applied to the plugin.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Plugin)` if the module is a valid plugin.
+    /// * `Err(e)` if the module could not be compiled or described itself incorrectly.
+    pub fn from_bytes(wasm: &[u8], limits: PluginLimits) -> Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(plugin_error)?;
+
+        let mut plugin = Plugin {
+            name: String::new(),
+            about: String::new(),
+            engine,
+            module,
+            limits,
+        };
+
+        let description = plugin.call(None, "rustbelt_describe", None)?;
+        plugin.name = description
+            .get("name")
+            .and_then(Json::as_str)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| Error::Plugin("plugin description has no name".to_string()))?
+            .to_string();
+        plugin.about = description
+            .get("about")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(plugin)
+    }
+
+    /// Reads and compiles a plugin from a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the `.wasm` file.
+    /// * `limits` - The resource limits applied to the plugin.
+    pub fn from_file(path: &Path, limits: PluginLimits) -> Result<Self> {
+        Plugin::from_bytes(&fs::read(path)?, limits)
+    }
+
+    /// The name the plugin registers itself under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The description of the plugin.
+    pub fn about(&self) -> &str {
+        &self.about
+    }
+
+    /// Instantiates the plugin in a fresh sandbox and calls one of its exports.
+    ///
+    /// The export is called with the JSON `input` (if any) and must return a
+    /// packed pointer to a JSON document.
+    fn call(&self, runtime: Option<&Runtime>, export: &str, input: Option<&Json>) -> Result<Json> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.memory_bytes)
+            .instances(1)
+            .memories(1)
+            .build();
+        let mut store = Store::new(&self.engine, HostState { runtime, limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.limits.fuel).map_err(plugin_error)?;
+
+        let mut linker: Linker<HostState> = Linker::new(&self.engine);
+        host::link(&mut linker)?;
+
+        let instance = linker
+            .instantiate_and_start(&mut store, &self.module)
+            .map_err(plugin_error)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| Error::Plugin("plugin does not export its memory".to_string()))?;
+
+        let packed = match input {
+            Some(input) => {
+                let bytes = input.to_string().into_bytes();
+                let alloc = instance
+                    .get_typed_func::<i32, i32>(&store, "rustbelt_alloc")
+                    .map_err(plugin_error)?;
+                let ptr = alloc.call(&mut store, bytes.len() as i32).map_err(plugin_error)?;
+                memory
+                    .write(&mut store, ptr as usize, &bytes)
+                    .map_err(plugin_error)?;
+
+                instance
+                    .get_typed_func::<(i32, i32), i64>(&store, export)
+                    .map_err(plugin_error)?
+                    .call(&mut store, (ptr, bytes.len() as i32))
+                    .map_err(plugin_error)?
+            }
+            None => instance
+                .get_typed_func::<(), i64>(&store, export)
+                .map_err(plugin_error)?
+                .call(&mut store, ())
+                .map_err(plugin_error)?,
+        };
+
+        let (ptr, len) = unpack(packed);
+        let output = read_memory(memory, &store, ptr, len)?;
+
+        serde_json::from_slice(&output)
+            .map_err(|e| Error::Plugin(format!("plugin returned invalid JSON: {e}")))
+    }
+}
+
+/// A command backed by a plugin.
+pub struct PluginCommand {
+    plugin: Arc<Plugin>,
+}
+
+impl Command for PluginCommand {
+    fn execute(&self, runtime: &Runtime, args: &[String]) -> Result<CommandResult> {
+        let output = self
+            .plugin
+            .call(Some(runtime), "rustbelt_run", Some(&json!(args)))?;
+
+        if let Some(error) = output.get("error") {
+            return Err(Error::Plugin(format!("{}: {}", self.plugin.name, error)));
+        }
+
+        let source = output
+            .get("source")
+            .and_then(Json::as_str)
+            .unwrap_or(&self.plugin.name)
+            .to_string();
+        let rows = match output.get("rows") {
+            Some(Json::Array(rows)) => rows,
+            _ => return Err(Error::Plugin(format!("{}: result has no rows", self.plugin.name))),
+        };
+
+        let mut data: Vec<Row> = vec![];
+        for row in rows {
+            let Json::Object(columns) = row else {
+                return Err(Error::Plugin(format!("{}: row is not an object", self.plugin.name)));
+            };
+            data.push(
+                columns
+                    .iter()
+                    .map(|(column, value)| (column.clone(), Value::from_json(value)))
+                    .collect(),
+            );
+        }
+
+        Ok(Simple(CommandDTO { source, data }))
+    }
+}
+
+/// Registers a plugin as a command.
+///
+/// # Arguments
+///
+/// * `plugin` - The plugin to register.
+///
+/// # Returns
+///
+/// * `Ok(())` if the plugin was registered.
+/// * `Err(e)` if a command with the same name already exists.
+pub fn register_plugin(plugin: Plugin) -> Result<()> {
+    let plugin = Arc::new(plugin);
+    let factory_plugin = plugin.clone();
+
+    register_command(DynamicRegistration {
+        name: plugin.name.clone(),
+        about: plugin.about.clone(),
+        factory: Box::new(move || {
+            Box::new(PluginCommand {
+                plugin: factory_plugin.clone(),
+            })
+        }),
+    })
+}
+
+/// Loads and registers every `.wasm` plugin in a directory.
+///
+/// Plugins that fail to load do not prevent the others from loading.
+///
+/// # Arguments
+///
+/// * `dir` - The plugins directory.
+/// * `limits` - The resource limits applied to every plugin.
+///
+/// # Returns
+///
+/// A vector with, for every plugin file, the registered name or the error that prevented loading it.
+pub fn load_plugins(dir: &Path, limits: PluginLimits) -> Result<Vec<(String, Result<String>)>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "wasm"))
+        .collect();
+    paths.sort();
+
+    let loaded = paths
+        .into_iter()
+        .map(|path| {
+            let result = Plugin::from_file(&path, limits).and_then(|plugin| {
+                let name = plugin.name.clone();
+                register_plugin(plugin).map(|_| name)
+            });
+            (path.display().to_string(), result)
+        })
+        .collect();
+    Ok(loaded)
+}
+
+/// Converts a row into a JSON object for a plugin.
+fn row_to_json(row: &Row) -> Json {
+    let columns: Map<String, Json> = row
+        .iter()
+        .map(|(column, value)| (column.clone(), value.to_json()))
+        .collect();
+    Json::Object(columns)
+}
+
+/// Splits a packed `(ptr << 32) | len` value.
+fn unpack(packed: i64) -> (usize, usize) {
+    let packed = packed as u64;
+    ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize)
+}
+
+/// Copies bytes out of the memory of a plugin.
+///
+/// The range is checked against the size of the memory before anything is
+/// allocated, so a plugin cannot make the host allocate more than it has.
+fn read_memory(memory: Memory, store: &impl AsContext, ptr: usize, len: usize) -> Result<Vec<u8>> {
+    let end = ptr
+        .checked_add(len)
+        .filter(|end| *end <= memory.data_size(store))
+        .ok_or_else(|| Error::Plugin(format!("plugin memory range out of bounds: {len} bytes at {ptr}")))?;
+    Ok(memory.data(store)[ptr..end].to_vec())
+}
+
+/// Converts an error raised by the WebAssembly engine.
+fn plugin_error(e: impl std::fmt::Display) -> Error {
+    Error::Plugin(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack() {
+        assert_eq!(unpack((16 << 32) | 42), (16, 42));
+        assert_eq!(unpack(0xffff_fff0_0000_0001u64 as i64), (0xffff_fff0, 1));
+    }
+}
@@ -0,0 +1,273 @@
+//! Audit trails of what a run accesses, and plans of what it would access.
+//!
+//! With an `AuditLog`, the registry, WMI and file backends of a runtime
+//! report every access they make, with the command that made it, see
+//! `Runtime::with_audit`. `plan` lists what commands would access from the
+//! accesses they declare, without running anything, for `--dry-run`.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use serde::Serialize;
+
+use crate::{
+    commands::base::{
+        registry::get_command,
+        Access, CommandDTO,
+        CommandResult::{self, Simple},
+        Row, Value,
+    },
+    error::{Error, Result},
+    utils::{
+        fs::{DirEntry, FileBackend},
+        registry::{RegistryBackend, RegistryHive, RegistryValue},
+        time::Timestamp,
+        wmi::WmiBackend,
+    },
+};
+
+/// The source of the table `plan` returns.
+pub const PLAN_SOURCE: &str = "Planned Accesses";
+
+/// An access a backend made.
+///
+/// # Fields
+/// - `time`: When the access was made.
+/// - `host`: The remote computer of the runtime, if any.
+/// - `command`: The command that made the access, if it was made while a command ran.
+/// - `resource`: `registry`, `wmi` or `file`.
+/// - `operation`: What was done, such as `subkeys`, `value`, `query` or `read`.
+/// - `path`: The registry key, WMI namespace or file.
+/// - `detail`: The name of the registry value or the WQL query, if any.
+/// - `error`: The error the access failed with, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessEvent {
+    pub time: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub resource: &'static str,
+    pub operation: &'static str,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The accesses made during a run, which may be shared by the runtimes of many targets.
+#[derive(Default)]
+pub struct AuditLog {
+    events: Mutex<Vec<AccessEvent>>,
+}
+
+impl AuditLog {
+    /// Creates an empty log.
+    pub fn new() -> Self {
+        AuditLog::default()
+    }
+
+    /// Adds an access to the log.
+    pub fn record(&self, event: AccessEvent) {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(event);
+    }
+
+    /// Returns the accesses made so far, in the order they were made.
+    pub fn events(&self) -> Vec<AccessEvent> {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// Writes the accesses made so far to a file, one JSON object per line.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut file = fs::File::create(path)?;
+        for event in self.events() {
+            let line = serde_json::to_string(&event).map_err(|e| Error::InvalidData(e.to_string()))?;
+            writeln!(file, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the accesses of the backends of one runtime to a log, with the command that is running.
+pub(crate) struct Auditor {
+    log: Arc<AuditLog>,
+    host: Option<String>,
+    command: Mutex<Option<String>>,
+}
+
+impl Auditor {
+    pub fn new(log: Arc<AuditLog>, host: Option<String>) -> Self {
+        Auditor { log, host, command: Mutex::new(None) }
+    }
+
+    fn command(&self) -> MutexGuard<'_, Option<String>> {
+        self.command.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Attributes the following accesses to a command, or to none.
+    pub fn set_command(&self, name: Option<&str>) {
+        *self.command() = name.map(str::to_string);
+    }
+
+    /// Records an access and passes its outcome on.
+    fn record<T>(
+        &self,
+        resource: &'static str,
+        operation: &'static str,
+        path: String,
+        detail: Option<&str>,
+        outcome: Result<T>,
+    ) -> Result<T> {
+        self.log.record(AccessEvent {
+            time: Timestamp::now(),
+            host: self.host.clone(),
+            command: self.command().clone(),
+            resource,
+            operation,
+            path,
+            detail: detail.map(str::to_string),
+            error: outcome.as_ref().err().map(Error::to_string),
+        });
+        outcome
+    }
+}
+
+/// Registry backend that reports the accesses of another backend.
+pub(crate) struct AuditedRegistry {
+    pub inner: Box<dyn RegistryBackend>,
+    pub auditor: Arc<Auditor>,
+}
+
+impl RegistryBackend for AuditedRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let outcome = self.inner.get_sub_key_names(hive, path);
+        self.auditor.record("registry", "subkeys", hive.key_path(path), None, outcome)
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let outcome = self.inner.get_value_names(hive, path);
+        self.auditor.record("registry", "values", hive.key_path(path), None, outcome)
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        let outcome = self.inner.get_raw_value(hive, path, name);
+        self.auditor.record("registry", "value", hive.key_path(path), Some(name), outcome)
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        let outcome = self.inner.get_key_last_write_time(hive, path);
+        self.auditor.record("registry", "last-write-time", hive.key_path(path), None, outcome)
+    }
+
+    fn source_file(&self, hive: RegistryHive, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(hive, path)
+    }
+}
+
+/// WMI backend that reports the queries of another backend.
+pub(crate) struct AuditedWmi {
+    pub inner: Box<dyn WmiBackend>,
+    pub auditor: Arc<Auditor>,
+}
+
+impl WmiBackend for AuditedWmi {
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        let outcome = self.inner.query(namespace, query, fields);
+        self.auditor.record("wmi", "query", namespace.to_string(), Some(query), outcome)
+    }
+}
+
+/// File backend that reports the accesses of another backend.
+pub(crate) struct AuditedFiles {
+    pub inner: Box<dyn FileBackend>,
+    pub auditor: Arc<Auditor>,
+}
+
+impl FileBackend for AuditedFiles {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let outcome = self.inner.read_file(path);
+        self.auditor.record("file", "read", path.to_string(), None, outcome)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let outcome = self.inner.read_dir(path);
+        self.auditor.record("file", "list", path.to_string(), None, outcome)
+    }
+
+    fn source_file(&self, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(path)
+    }
+}
+
+/// Lists what commands would access, from the accesses they declare, without running them.
+///
+/// Groups are expanded into their members. Commands that declare nothing,
+/// such as plugins, get a single row with the resource `undeclared`.
+///
+/// # Arguments
+///
+/// * `names` - The names of the commands and groups.
+///
+/// # Returns
+///
+/// * `Ok(CommandResult)` with a row per access: the command, the resource, the path and the detail.
+/// * `Err(Error::NotFound)` if a command does not exist.
+pub fn plan(names: &[String]) -> Result<CommandResult> {
+    let mut data = vec![];
+    for name in names {
+        plan_command(name, &mut data)?;
+    }
+    Ok(Simple(CommandDTO { source: PLAN_SOURCE.to_string(), data }))
+}
+
+fn plan_command(name: &str, data: &mut Vec<Row>) -> Result<()> {
+    let command = get_command(name).ok_or_else(|| Error::NotFound(format!("command '{name}'")))?;
+    let members = command.members();
+    if !members.is_empty() {
+        return members.iter().try_for_each(|member| plan_command(member, data));
+    }
+
+    let row = |resource: &str, path: Value, detail: Value| {
+        Row::from([
+            ("Command".to_string(), Value::from(name)),
+            ("Resource".to_string(), Value::from(resource)),
+            ("Path".to_string(), path),
+            ("Detail".to_string(), detail),
+        ])
+    };
+    match command.command_data() {
+        Some(command_data) => data.extend(command_data.accesses.iter().map(|access| match access {
+            Access::Registry(hive, path) => row("registry", Value::from(hive.key_path(path)), Value::Null),
+            Access::Wmi { namespace, query } => row("wmi", Value::from(*namespace), Value::from(*query)),
+            Access::File(path) => row("file", Value::from(*path), Value::Null),
+        })),
+        None => data.push(row("undeclared", Value::Null, Value::from("the command does not declare what it accesses"))),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let CommandResult::Simple(table) = plan(&["group:misc".to_string()]).unwrap() else {
+            panic!("plans are simple results");
+        };
+        let accesses: Vec<(String, String)> = table
+            .data
+            .iter()
+            .map(|row| (row["Command"].to_string(), row["Path"].to_string()))
+            .collect();
+        assert_eq!(accesses[0], ("antivirus".to_string(), "root\\SecurityCenter2".to_string()));
+        assert_eq!(accesses[1], ("amsiproviders".to_string(), "HKLM\\SOFTWARE\\Microsoft\\AMSI\\Providers".to_string()));
+        assert_eq!(table.data[0]["Detail"], Value::from("SELECT * FROM AntiVirusProduct"));
+        assert!(matches!(plan(&["nosuchcommand".to_string()]), Err(Error::NotFound(_))));
+    }
+}
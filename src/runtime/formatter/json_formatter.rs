@@ -0,0 +1,52 @@
+use crate::{commands::base::CommandResult, utils::time::TimeDisplay};
+
+use super::{render_times, Formatter};
+
+/// Formats a result as one JSON document on a single line.
+///
+/// Simple results become an object with `source` and `data`, groups an
+/// array of such objects. Timestamps are ISO 8601 strings, in UTC unless
+/// configured otherwise, or numbers of seconds for epoch output.
+#[derive(Default)]
+pub struct JsonFormatter {
+    time: TimeDisplay,
+}
+
+impl JsonFormatter {
+    /// Creates a formatter that renders timestamps as configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - How to render timestamps.
+    pub fn new(time: TimeDisplay) -> Self {
+        JsonFormatter { time }
+    }
+}
+
+impl Formatter for JsonFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        serde_json::to_string(&render_times(result, &self.time)).expect("results always serialize to JSON")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::base::{CommandDTO, Row, Value},
+        utils::time::{TimeFormat, Timestamp},
+    };
+
+    #[test]
+    fn test_time_display() {
+        let row = Row::from([("Changed".to_string(), Value::from(Timestamp::from_unix(1_709_294_400).unwrap()))]);
+        let result = CommandResult::Simple(CommandDTO { source: "Changes".to_string(), data: vec![row] });
+        let format = |zone: &str, format: TimeFormat| {
+            JsonFormatter::new(TimeDisplay { zone: zone.parse().unwrap(), format }).parse_result(&result)
+        };
+
+        assert_eq!(format("utc", TimeFormat::Iso8601), r#"{"source":"Changes","data":[{"Changed":"2024-03-01T12:00:00Z"}]}"#);
+        assert_eq!(format("+02:00", TimeFormat::Iso8601), r#"{"source":"Changes","data":[{"Changed":"2024-03-01T14:00:00+02:00"}]}"#);
+        assert_eq!(format("utc", TimeFormat::Epoch), r#"{"source":"Changes","data":[{"Changed":1709294400}]}"#);
+    }
+}
@@ -1,7 +1,77 @@
+pub mod json_formatter;
+pub mod ndjson_formatter;
+pub mod seatbelt_formatter;
+pub mod siem_formatter;
 pub mod simple_formatter;
 
-use crate::commands::base::CommandResult;
+use crate::{
+    commands::base::{CommandResult, Value},
+    utils::time::{TimeDisplay, TimeFormat},
+};
 
+use json_formatter::JsonFormatter;
+use ndjson_formatter::NdjsonFormatter;
+use seatbelt_formatter::{SeatbeltFormatter, SeatbeltJsonFormatter};
+use siem_formatter::{EcsFormatter, OcsfFormatter};
+use simple_formatter::SimpleFormatter;
+
+/// The names of the output formats, see `by_name`.
+pub const FORMATS: [&str; 7] = ["simple", "json", "ndjson", "seatbelt", "seatbelt-json", "ecs", "ocsf"];
+
+/// The formats that always write timestamps in UTC, in the encoding their consumers expect.
+///
+/// `TimeDisplay` does not apply to them, so the command line rejects
+/// `--timezone` and `--time-format` together with them.
+pub const UTC_FORMATS: [&str; 3] = ["seatbelt-json", "ecs", "ocsf"];
+
+/// Trait implemented by every output format.
 pub trait Formatter {
-    fn parse_result(result: CommandResult) -> String;
+    /// Formats the result of a command or group into text.
+    fn parse_result(&self, result: &CommandResult) -> String;
+}
+
+/// Creates the formatter of an output format by its name.
+///
+/// # Arguments
+///
+/// * `name` - One of `FORMATS`.
+/// * `time` - How to render timestamps, for the formats not in `UTC_FORMATS`.
+///
+/// # Returns
+///
+/// The formatter, or `None` if there is no format with that name.
+pub fn by_name(name: &str, time: TimeDisplay) -> Option<Box<dyn Formatter>> {
+    match name {
+        "simple" => Some(Box::new(SimpleFormatter::new(time))),
+        "json" => Some(Box::new(JsonFormatter::new(time))),
+        "ndjson" => Some(Box::new(NdjsonFormatter::new(time))),
+        "seatbelt" => Some(Box::new(SeatbeltFormatter::new(time))),
+        "seatbelt-json" => Some(Box::new(SeatbeltJsonFormatter::default())),
+        "ecs" => Some(Box::new(EcsFormatter::default())),
+        "ocsf" => Some(Box::new(OcsfFormatter::default())),
+        _ => None,
+    }
+}
+
+/// Renders the timestamps of a result as configured, for the formats that write values as JSON.
+///
+/// Timestamps become ISO 8601 strings in the configured time zone, or numbers
+/// of seconds for epoch output. Other values are kept.
+fn render_times(result: &CommandResult, time: &TimeDisplay) -> CommandResult {
+    let mut result = result.clone();
+    for row in result.tables_mut().iter_mut().flat_map(|table| &mut table.data) {
+        for value in row.values_mut() {
+            *value = render_time(value, time);
+        }
+    }
+    result
+}
+
+fn render_time(value: &Value, time: &TimeDisplay) -> Value {
+    match value {
+        Value::Timestamp(timestamp) if time.format == TimeFormat::Epoch => Value::Integer(timestamp.to_utc().timestamp()),
+        Value::Timestamp(timestamp) => Value::String(timestamp.render(time)),
+        Value::List(values) => Value::List(values.iter().map(|value| render_time(value, time)).collect()),
+        value => value.clone(),
+    }
 }
@@ -0,0 +1,44 @@
+use serde_json::json;
+
+use crate::{
+    commands::base::{CommandDTO, CommandResult},
+    utils::time::TimeDisplay,
+};
+
+use super::{render_times, Formatter};
+
+/// Formats a result as newline delimited JSON: one object per row.
+///
+/// Every object has the `source` of its table and the row as `data`, so
+/// rows of a group can be split up and streamed independently. Timestamps
+/// are rendered like the `json` format does.
+#[derive(Default)]
+pub struct NdjsonFormatter {
+    time: TimeDisplay,
+}
+
+impl NdjsonFormatter {
+    /// Creates a formatter that renders timestamps as configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - How to render timestamps.
+    pub fn new(time: TimeDisplay) -> Self {
+        NdjsonFormatter { time }
+    }
+}
+
+impl Formatter for NdjsonFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        render_times(result, &self.time)
+            .tables()
+            .iter().flat_map(format_rows).collect::<Vec<_>>().join("\n")
+    }
+}
+
+fn format_rows(table: &CommandDTO) -> impl Iterator<Item = String> + '_ {
+    table
+        .data
+        .iter()
+        .map(|row| json!({ "source": table.source, "data": row }).to_string())
+}
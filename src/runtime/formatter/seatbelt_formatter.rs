@@ -0,0 +1,75 @@
+use serde_json::Value as Json;
+
+use crate::{
+    commands::base::{CommandDTO, CommandResult},
+    runtime::seatbelt::{command_name, dto_type, to_seatbelt, to_seatbelt_json},
+    utils::time::TimeDisplay,
+};
+
+use super::Formatter;
+
+/// Formats a result in the text layout of Seatbelt.
+///
+/// Every table gets Seatbelt's section header, `====== OSInfo ======`, and
+/// every row its properties as `  Name                           : value`,
+/// followed by an empty line. Tables of commands that exist in Seatbelt use
+/// its command and property names, see `seatbelt::MAPPINGS`.
+#[derive(Default)]
+pub struct SeatbeltFormatter {
+    time: TimeDisplay,
+}
+
+impl SeatbeltFormatter {
+    /// Creates a formatter that renders timestamps as configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - How to render timestamps.
+    pub fn new(time: TimeDisplay) -> Self {
+        SeatbeltFormatter { time }
+    }
+
+    fn format_table(&self, table: &CommandDTO) -> String {
+        let mut output = format!("====== {} ======\n\n", command_name(&table.source));
+        for row in &table.data {
+            for (property, value) in to_seatbelt(table, row) {
+                output += &format!("  {property:<30} : {}\n", value.render(&self.time));
+            }
+            output += "\n";
+        }
+        output
+    }
+}
+
+impl Formatter for SeatbeltFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        let sections: Vec<String> = result.tables().iter().map(|table| self.format_table(table)).collect();
+        sections.concat().trim_end().to_string()
+    }
+}
+
+/// Formats a result as Seatbelt JSON output: one object per row, with the
+/// type of the Seatbelt DTO as `Type` and its properties as `Data`.
+///
+/// Properties keep Seatbelt's order and timestamps are always written as
+/// `/Date(milliseconds)/` since the epoch, in UTC, like Seatbelt does.
+#[derive(Default)]
+pub struct SeatbeltJsonFormatter {}
+
+impl Formatter for SeatbeltJsonFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        let mut lines = vec![];
+        for table in result.tables() {
+            let dto = Json::from(dto_type(&table.source));
+            for row in &table.data {
+                // Written by hand, as JSON maps of serde_json do not keep the order of their keys.
+                let properties: Vec<String> = to_seatbelt(table, row)
+                    .into_iter()
+                    .map(|(property, value)| format!("{}:{}", Json::from(property), to_seatbelt_json(value)))
+                    .collect();
+                lines.push(format!("{{\"Type\":{dto},\"Data\":{{{}}}}}", properties.join(",")));
+            }
+        }
+        lines.join("\n")
+    }
+}
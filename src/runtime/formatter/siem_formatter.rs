@@ -0,0 +1,255 @@
+use serde_json::{json, Map, Value as Json};
+
+use crate::{
+    commands::base::{
+        export::{EventClass, Export},
+        registry::exports,
+        schema::Schema,
+        CommandResult, Row, Value,
+    },
+    utils::time::Timestamp,
+};
+
+use super::Formatter;
+
+/// The namespace of the extension object that holds the columns without a counterpart.
+pub const EXTENSION: &str = "rustbelt";
+
+/// The version of Elastic Common Schema the `ecs` format writes.
+pub const ECS_VERSION: &str = "8.11.0";
+
+/// The version of OCSF the `ocsf` format writes.
+pub const OCSF_VERSION: &str = "1.1.0";
+
+/// Formats a result as Elastic Common Schema documents, one per row.
+///
+/// Columns are mapped as their command declares in its `Export`, and the
+/// others go into `rustbelt.<dataset>`, where the dataset is the source of
+/// the table in snake case, such as `rustbelt.osinfo.UBR`. Timestamps are
+/// always ISO 8601 strings in UTC.
+#[derive(Default)]
+pub struct EcsFormatter {}
+
+impl Formatter for EcsFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        format_events(result, ecs_document)
+    }
+}
+
+/// Formats a result as OCSF events, one per row.
+///
+/// Columns are mapped as their command declares in its `Export`, and the
+/// others go into `unmapped.rustbelt.<dataset>`. Timestamps are always
+/// milliseconds since the epoch, as OCSF requires.
+#[derive(Default)]
+pub struct OcsfFormatter {}
+
+impl Formatter for OcsfFormatter {
+    fn parse_result(&self, result: &CommandResult) -> String {
+        format_events(result, ocsf_document)
+    }
+}
+
+/// A row to export, with what is known about its table.
+struct Event<'a> {
+    source: &'a str,
+    schema: Option<&'a Schema>,
+    export: &'a Export,
+    index: usize,
+    row: &'a Row,
+    time: &'a Timestamp,
+}
+
+impl Event<'_> {
+    /// The source of the table in snake case, such as `amsi_providers`.
+    fn dataset(&self) -> String {
+        let name: String = self
+            .source
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect();
+        name.trim_matches('_').to_string()
+    }
+
+    /// Splits the values of the row into those with a counterpart, by their path, and the others, by their column.
+    fn split(
+        &self,
+        path: impl Fn(&str) -> Option<&'static str>,
+        convert: fn(&Value) -> Json,
+    ) -> (Vec<(&'static str, Json)>, Map<String, Json>) {
+        let mut mapped = vec![];
+        let mut unmapped = Map::new();
+        for (column, value) in self.row.iter().filter(|(_, value)| **value != Value::Null) {
+            match path(column) {
+                Some(path) => mapped.push((path, convert(value))),
+                None => {
+                    unmapped.insert(column.clone(), convert(value));
+                }
+            }
+        }
+        (mapped, unmapped)
+    }
+}
+
+fn format_events(result: &CommandResult, document: fn(&Event) -> Json) -> String {
+    let exports = exports();
+    let time = Timestamp::now();
+    let mut lines = vec![];
+    for table in result.tables() {
+        let declared = exports.iter().find(|(schema, _)| schema.source == table.source);
+        for (index, row) in table.data.iter().enumerate() {
+            let event = Event {
+                source: &table.source,
+                schema: declared.map(|(schema, _)| *schema),
+                export: declared.map_or(&Export::UNMAPPED, |(_, export)| *export),
+                index,
+                row,
+                time: &time,
+            };
+            lines.push(document(&event).to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+fn ecs_document(event: &Event) -> Json {
+    let dataset = event.dataset();
+    let (kind, category) = event.export.class.ecs_kind();
+    let mut document = Map::new();
+    document.insert("@timestamp".to_string(), json!(event.time.to_string()));
+    document.insert("ecs".to_string(), json!({ "version": ECS_VERSION }));
+    document.insert(
+        "event".to_string(),
+        json!({
+            "kind": kind,
+            "category": [category],
+            "type": ["info"],
+            "module": EXTENSION,
+            "dataset": format!("{EXTENSION}.{dataset}"),
+        }),
+    );
+
+    let (mapped, unmapped) = event.split(|column| event.export.field(column)?.ecs, Value::to_json);
+    for (path, value) in mapped {
+        insert_path(&mut document, path, value);
+    }
+    if !unmapped.is_empty() {
+        insert_path(&mut document, &format!("{EXTENSION}.{dataset}"), Json::Object(unmapped));
+    }
+    Json::Object(document)
+}
+
+fn ocsf_document(event: &Event) -> Json {
+    let dataset = event.dataset();
+    let class = event.export.class;
+    let (class_uid, class_name) = class.ocsf_class();
+    let (category_uid, category_name) = class.ocsf_category();
+    let (activity_id, activity_name) = class.ocsf_activity();
+    let mut document = Map::new();
+    for (name, value) in [
+        ("class_uid", json!(class_uid)),
+        ("class_name", json!(class_name)),
+        ("category_uid", json!(category_uid)),
+        ("category_name", json!(category_name)),
+        ("activity_id", json!(activity_id)),
+        ("activity_name", json!(activity_name)),
+        ("type_uid", json!(class_uid * 100 + activity_id)),
+        ("severity_id", json!(1)),
+        ("severity", json!("Informational")),
+        ("time", json!(event.time.to_utc().timestamp_millis())),
+        (
+            "metadata",
+            json!({
+                "version": OCSF_VERSION,
+                "log_name": event.source,
+                "product": { "name": "Rustbelt", "vendor_name": "Rustbelt", "version": env!("CARGO_PKG_VERSION") },
+            }),
+        ),
+    ] {
+        document.insert(name.to_string(), value);
+    }
+    if class == EventClass::Finding {
+        document.insert("finding_info".to_string(), json!({ "title": event.source, "uid": finding_uid(event, &dataset) }));
+    }
+
+    let (mapped, unmapped) = event.split(|column| event.export.field(column)?.ocsf, ocsf_value);
+    for (path, value) in mapped {
+        insert_path(&mut document, path, value);
+    }
+    if !unmapped.is_empty() {
+        insert_path(&mut document, &format!("unmapped.{EXTENSION}.{dataset}"), Json::Object(unmapped));
+    }
+    Json::Object(document)
+}
+
+/// Identifies a finding by the identity columns of its schema, or by its position in the table without them.
+fn finding_uid(event: &Event, dataset: &str) -> String {
+    let identity = event.schema.map_or(&[][..], |schema| schema.identity);
+    if identity.is_empty() {
+        return format!("{dataset}:{}", event.index);
+    }
+    let values: Vec<String> = identity
+        .iter()
+        .map(|column| event.row.get(*column).map(Value::to_string).unwrap_or_default())
+        .collect();
+    format!("{dataset}:{}", values.join("|"))
+}
+
+/// Converts a value to OCSF, where timestamps are milliseconds since the epoch.
+fn ocsf_value(value: &Value) -> Json {
+    match value {
+        Value::Timestamp(timestamp) => json!(timestamp.to_utc().timestamp_millis()),
+        Value::List(values) => Json::Array(values.iter().map(ocsf_value).collect()),
+        value => value.to_json(),
+    }
+}
+
+/// Inserts a value at a dotted path such as `host.os.name`, creating the objects on the way.
+fn insert_path(document: &mut Map<String, Json>, path: &str, value: Json) {
+    let (parents, name) = path.rsplit_once('.').map_or((None, path), |(parents, name)| (Some(parents), name));
+    let mut object = document;
+    for parent in parents.into_iter().flat_map(|parents| parents.split('.')) {
+        let child = object.entry(parent).or_insert_with(|| Json::Object(Map::new()));
+        if !child.is_object() {
+            *child = Json::Object(Map::new());
+        }
+        object = child.as_object_mut().expect("made an object above");
+    }
+    object.insert(name.to_string(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::base::export::FieldMapping;
+
+    #[test]
+    fn test_finding() {
+        const SCHEMA: Schema = Schema { source: "Weak Services", fields: &[], identity: &["Name"] };
+        const EXPORT: Export = Export {
+            class: EventClass::Finding,
+            fields: &[FieldMapping::new("Path").ecs("file.path").ocsf("resources.path")],
+        };
+        let row = Row::from([
+            ("Name".to_string(), Value::from("Spooler")),
+            ("Path".to_string(), Value::from("C:\\spoolsv.exe")),
+            ("Changed".to_string(), Value::from(Timestamp::from_unix_millis(1_000).unwrap())),
+            ("Owner".to_string(), Value::Null),
+        ]);
+        let time = Timestamp::from_unix_millis(5_000).unwrap();
+        let event = Event { source: "Weak Services", schema: Some(&SCHEMA), export: &EXPORT, index: 3, row: &row, time: &time };
+
+        let ecs = ecs_document(&event);
+        assert_eq!(ecs["event"]["kind"], "alert");
+        assert_eq!(ecs["event"]["dataset"], "rustbelt.weak_services");
+        assert_eq!(ecs["file"]["path"], "C:\\spoolsv.exe");
+        assert_eq!(ecs["rustbelt"]["weak_services"], json!({ "Name": "Spooler", "Changed": "1970-01-01T00:00:01Z" }));
+
+        let ocsf = ocsf_document(&event);
+        assert_eq!(ocsf["type_uid"], 200401);
+        assert_eq!(ocsf["time"], 5_000);
+        assert_eq!(ocsf["finding_info"], json!({ "title": "Weak Services", "uid": "weak_services:Spooler" }));
+        assert_eq!(ocsf["resources"]["path"], "C:\\spoolsv.exe");
+        assert_eq!(ocsf["unmapped"]["rustbelt"]["weak_services"], json!({ "Name": "Spooler", "Changed": 1_000 }));
+    }
+}
@@ -1,50 +1,56 @@
-use crate::commands::base::{
-    CommandDTO,
-    CommandResult::{self, Group, Simple},
+use crate::{
+    commands::base::{
+        CommandDTO,
+        CommandResult::{self, Group, Simple},
+    },
+    utils::time::TimeDisplay,
 };
-use windows::Win32::System::Variant::{VariantToString, VARIANT};
 
 use super::Formatter;
 
-pub struct SimpleFormatter {}
+#[derive(Default)]
+pub struct SimpleFormatter {
+    time: TimeDisplay,
+}
+
+impl SimpleFormatter {
+    /// Creates a formatter that renders timestamps as configured.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - How to render timestamps.
+    pub fn new(time: TimeDisplay) -> Self {
+        SimpleFormatter { time }
+    }
+}
 
 impl Formatter for SimpleFormatter {
-    fn parse_result(result: CommandResult) -> String {
+    fn parse_result(&self, result: &CommandResult) -> String {
         match result {
-            Group(group_result) => format_group_result(group_result),
-            Simple(simple_result) => format_command_dto(simple_result),
+            Group(group_result) => format_group_result(group_result, &self.time),
+            Simple(simple_result) => format_command_dto(simple_result, &self.time),
         }
     }
 }
 
-fn format_group_result(tables: Vec<CommandDTO>) -> String {
+fn format_group_result(tables: &[CommandDTO], time: &TimeDisplay) -> String {
     let mut output = "".to_string();
 
     for table in tables {
-        output = format!("{output}\n\n{}", format_command_dto(table));
+        output = format!("{output}\n\n{}", format_command_dto(table, time));
     }
     output
 }
 
-fn format_command_dto(table: CommandDTO) -> String {
+fn format_command_dto(table: &CommandDTO, time: &TimeDisplay) -> String {
     let mut output = format!("==[{}]==", table.source);
-    let mut count = 0;
 
-    for row in table.data {
+    for (count, row) in table.data.iter().enumerate() {
         output = format!("{output}\n [{:?}]", count);
 
         for (col, value) in row.iter() {
-            let mut pszbuf = vec![0u16; 256];
-
-            unsafe {
-                let _ = VariantToString(value as *const VARIANT, pszbuf.as_mut_slice());
-            }
-            let null_pos = pszbuf.iter().position(|&c| c == 0).unwrap_or(pszbuf.len());
-            let content = String::from_utf16_lossy(&pszbuf[..null_pos]);
-
-            output = format!("{output}\n\t{col} : {content}")
+            output = format!("{output}\n\t{col} : {}", value.render(time))
         }
-        count += 1;
     }
     output
 }
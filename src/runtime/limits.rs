@@ -0,0 +1,447 @@
+//! Timeouts, cancellation and caps on the commands of a runtime.
+//!
+//! With `Limits`, every command gets its own deadline, the whole runtime a
+//! total one, and the rows and bytes a command may return are capped. The
+//! deadlines are cooperative: while a command runs, its budget is known to
+//! the thread it runs on, and the backends check it with `checkpoint` before
+//! every access and while they wait, such as for WMI results. A command that
+//! reaches a limit stops with `Error::Partial`, which holds what it returned
+//! before, instead of blocking the run.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    commands::base::{CommandResult, Row},
+    error::{Error, Result},
+    utils::{
+        fs::{DirEntry, FileBackend},
+        registry::{RegistryBackend, RegistryHive, RegistryValue},
+        time::Timestamp,
+        wmi::WmiBackend,
+    },
+};
+
+/// The limits of the commands of a runtime. Limits that are `None` do not apply.
+///
+/// # Fields
+/// - `timeout`: How long a single command may run. The members of a group get this long each.
+/// - `total_timeout`: How long all commands of the runtime may run together, from the first one.
+/// - `max_rows`: How many rows a single command may return.
+/// - `max_bytes`: How many bytes the rows of a single command may take, measured as JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub total_timeout: Option<Duration>,
+    pub max_rows: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl Limits {
+    /// Creates limits that do not limit anything.
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    /// Limits how long a single command may run.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how long all commands of the runtime may run together.
+    pub fn total_timeout(mut self, timeout: Duration) -> Self {
+        self.total_timeout = Some(timeout);
+        self
+    }
+
+    /// Caps the rows of a single command.
+    pub fn max_rows(mut self, rows: usize) -> Self {
+        self.max_rows = Some(rows);
+        self
+    }
+
+    /// Caps the bytes of the rows of a single command.
+    pub fn max_bytes(mut self, bytes: usize) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+}
+
+/// A limit a command reached.
+///
+/// # Variants
+/// - `Timeout`: The command ran longer than its timeout.
+/// - `TotalTimeout`: The commands of the runtime ran longer than the total timeout.
+/// - `Rows`: The command returned more rows than the cap; the rest were dropped.
+/// - `Bytes`: The rows of the command took more bytes than the cap; the rest were dropped.
+/// - `Cancelled`: The commands were cancelled through a `CancelHandle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Timeout(Duration),
+    TotalTimeout(Duration),
+    Rows(usize),
+    Bytes(usize),
+    Cancelled,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Timeout(timeout) => write!(f, "timed out after {timeout:?}"),
+            Limit::TotalTimeout(timeout) => write!(f, "the run timed out after {timeout:?}"),
+            Limit::Rows(rows) => write!(f, "returned more than {rows} rows"),
+            Limit::Bytes(bytes) => write!(f, "returned more than {bytes} bytes"),
+            Limit::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// The result of a command that reached a limit, see `Error::Partial`.
+///
+/// # Fields
+/// - `result`: What the command returned before it stopped, which may be no tables at all.
+/// - `stopped`: The commands that reached a limit, with the limit. Groups may have several.
+#[derive(Debug)]
+pub struct PartialResult {
+    pub result: CommandResult,
+    pub stopped: Vec<(String, Limit)>,
+}
+
+impl fmt::Display for PartialResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stopped: Vec<String> = self.stopped.iter().map(|(command, limit)| format!("'{command}' {limit}")).collect();
+        write!(f, "{}", stopped.join(", "))
+    }
+}
+
+/// Cancels the commands of a runtime from another thread.
+///
+/// The command that is running stops at its next checkpoint, and the
+/// commands after it stop right away, until the handle is reset.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Cancels the commands.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Lets commands run again after a cancellation.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether the commands are cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The limits of a runtime with the state to enforce them.
+#[derive(Default)]
+pub(crate) struct Budget {
+    pub limits: Limits,
+    pub cancel: CancelHandle,
+    started: Mutex<Option<Instant>>,
+}
+
+impl Budget {
+    pub fn new(limits: Limits, cancel: CancelHandle) -> Self {
+        Budget { limits, cancel, started: Mutex::new(None) }
+    }
+
+    /// Starts the total timeout with the first command.
+    fn start(&self) -> Instant {
+        *self.started.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get_or_insert_with(Instant::now)
+    }
+
+    /// Runs a command within the budget: with its own deadline unless it is a group, and the caps applied to its result.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the command.
+    /// * `group` - Whether the command is a group, whose members are limited one by one.
+    /// * `run` - Runs the command.
+    pub fn run(&self, name: &str, group: bool, run: impl FnOnce() -> Result<CommandResult>) -> Result<CommandResult> {
+        let started = Instant::now();
+        let deadline = Deadline {
+            cancel: self.cancel.clone(),
+            total: self.limits.total_timeout.map(|timeout| (self.start() + timeout, timeout)),
+            command: self.limits.timeout.filter(|_| !group).map(|timeout| (started + timeout, timeout)),
+        };
+        deadline.check().map_err(Error::Limit)?;
+
+        let previous = CURRENT.with(|current| current.borrow_mut().replace(deadline.clone()));
+        let outcome = run();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+
+        // Backends report a limit as an error, which most commands pass on without what they had so far.
+        let (mut result, mut stopped) = match outcome {
+            Err(Error::Limit(limit)) => (CommandResult::Group(vec![]), vec![(name.to_string(), limit)]),
+            Err(Error::Partial(partial)) => (partial.result, partial.stopped),
+            Err(e) => return Err(e),
+            Ok(result) => (result, vec![]),
+        };
+        // Commands that skip what they could not read return normally, so their deadline is checked afterwards.
+        if let (Err(limit), false) = (deadline.check(), group) {
+            if stopped.is_empty() {
+                stopped.push((name.to_string(), limit));
+            }
+        }
+        if !group {
+            if let Some(limit) = self.cap(&mut result) {
+                stopped.push((name.to_string(), limit));
+            }
+        }
+
+        match stopped.is_empty() {
+            true => Ok(result),
+            false => Err(Error::Partial(Box::new(PartialResult { result, stopped }))),
+        }
+    }
+
+    /// Drops the rows past the row and byte caps, and returns the cap that was reached, if any.
+    fn cap(&self, result: &mut CommandResult) -> Option<Limit> {
+        let mut rows = 0;
+        let mut bytes = 0;
+        let mut reached = None;
+        for table in result.tables_mut() {
+            let kept = table.data.iter().take_while(|row| {
+                rows += 1;
+                bytes += row_bytes(row);
+                let limit = match (self.limits.max_rows, self.limits.max_bytes) {
+                    (Some(max), _) if rows > max => Some(Limit::Rows(max)),
+                    (_, Some(max)) if bytes > max => Some(Limit::Bytes(max)),
+                    _ => None,
+                };
+                reached = reached.or(limit);
+                limit.is_none()
+            });
+            let kept = kept.count();
+            table.data.truncate(kept);
+        }
+        reached
+    }
+}
+
+/// The size of a row in the JSON output.
+pub(crate) fn row_bytes(row: &Row) -> usize {
+    serde_json::to_string(row).map_or(0, |json| json.len())
+}
+
+/// The deadlines of the command running on a thread.
+#[derive(Clone)]
+struct Deadline {
+    cancel: CancelHandle,
+    total: Option<(Instant, Duration)>,
+    command: Option<(Instant, Duration)>,
+}
+
+impl Deadline {
+    fn check(&self) -> std::result::Result<(), Limit> {
+        let now = Instant::now();
+        if self.cancel.is_cancelled() {
+            return Err(Limit::Cancelled);
+        }
+        match (self.total, self.command) {
+            (Some((deadline, timeout)), _) if now >= deadline => Err(Limit::TotalTimeout(timeout)),
+            (_, Some((deadline, timeout))) if now >= deadline => Err(Limit::Timeout(timeout)),
+            _ => Ok(()),
+        }
+    }
+}
+
+thread_local! {
+    // The deadlines of the command running on this thread, if it runs with limits.
+    static CURRENT: RefCell<Option<Deadline>> = const { RefCell::new(None) };
+}
+
+/// Checks whether the command running on this thread may go on.
+///
+/// Backends and long-running commands call this between steps, so commands
+/// stop when they reach their deadline or are cancelled. Outside of a
+/// command with limits, it always succeeds.
+///
+/// # Returns
+///
+/// * `Ok(())` if the command may go on.
+/// * `Err(Error::Limit)` with the limit it reached otherwise.
+pub fn checkpoint() -> Result<()> {
+    CURRENT.with(|current| match current.borrow().as_ref() {
+        Some(deadline) => deadline.check().map_err(Error::Limit),
+        None => Ok(()),
+    })
+}
+
+/// Returns how long the command running on this thread may still run.
+///
+/// Backends that wait on the network bound every wait by it, as `checkpoint`
+/// only runs between their calls.
+///
+/// # Returns
+///
+/// * `Some(Duration)` until the nearest deadline, zero if it has passed.
+/// * `None` outside of a command with a deadline.
+pub fn remaining() -> Option<Duration> {
+    CURRENT.with(|current| {
+        let deadline = current.borrow().clone()?;
+        let nearest = deadline.total.into_iter().chain(deadline.command).map(|(deadline, _)| deadline).min()?;
+        Some(nearest.saturating_duration_since(Instant::now()))
+    })
+}
+
+/// Returns the timeout of a connection, read or write of the command running on this thread.
+///
+/// # Arguments
+///
+/// * `max` - The timeout of the transport, which applies outside of a command with a deadline.
+///
+/// # Returns
+///
+/// The time the command has left, up to `max`, and at least a millisecond, as sockets reject a zero
+/// timeout and a command past its deadline stops at the first wait anyway.
+pub fn wait_timeout(max: Duration) -> Duration {
+    remaining().map_or(max, |remaining| remaining.min(max)).max(Duration::from_millis(1))
+}
+
+/// Reports a connection, read or write that timed out as a limit.
+///
+/// # Arguments
+///
+/// * `error` - The error of the wait.
+/// * `timeout` - The timeout of the wait, see `wait_timeout`.
+///
+/// # Returns
+///
+/// The limit the running command reached, or `Limit::Timeout` of the wait outside of one, if the
+/// wait timed out, and `error` otherwise.
+pub fn timed_out(error: Error, timeout: Duration) -> Error {
+    match error {
+        Error::Io(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+            checkpoint().err().unwrap_or(Error::Limit(Limit::Timeout(timeout)))
+        }
+        error => error,
+    }
+}
+
+/// Registry backend that checks the budget of the running command before every access.
+pub(crate) struct LimitedRegistry {
+    pub inner: Box<dyn RegistryBackend>,
+}
+
+impl RegistryBackend for LimitedRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        checkpoint()?;
+        self.inner.get_sub_key_names(hive, path)
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        checkpoint()?;
+        self.inner.get_value_names(hive, path)
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        checkpoint()?;
+        self.inner.get_raw_value(hive, path, name)
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        checkpoint()?;
+        self.inner.get_key_last_write_time(hive, path)
+    }
+
+    fn source_file(&self, hive: RegistryHive, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(hive, path)
+    }
+}
+
+/// WMI backend that checks the budget of the running command before every query.
+pub(crate) struct LimitedWmi {
+    pub inner: Box<dyn WmiBackend>,
+}
+
+impl WmiBackend for LimitedWmi {
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        checkpoint()?;
+        self.inner.query(namespace, query, fields)
+    }
+}
+
+/// File backend that checks the budget of the running command before every access.
+pub(crate) struct LimitedFiles {
+    pub inner: Box<dyn FileBackend>,
+}
+
+impl FileBackend for LimitedFiles {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        checkpoint()?;
+        self.inner.read_file(path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        checkpoint()?;
+        self.inner.read_dir(path)
+    }
+
+    fn source_file(&self, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::base::{CommandDTO, Value};
+
+    fn rows(count: usize) -> CommandResult {
+        let data = (0..count).map(|index| Row::from([("Index".to_string(), Value::from(index as u64))])).collect();
+        CommandResult::Simple(CommandDTO { source: "Numbers".to_string(), data })
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = Budget::new(Limits::new().max_rows(3).timeout(Duration::from_millis(20)), CancelHandle::default());
+        assert_eq!(budget.run("numbers", false, || Ok(rows(3))).unwrap().tables()[0].data.len(), 3);
+
+        // Rows past the cap are dropped and reported.
+        let Err(Error::Partial(partial)) = budget.run("numbers", false, || Ok(rows(5))) else {
+            panic!("the cap was reached");
+        };
+        assert_eq!(partial.result.tables()[0].data.len(), 3);
+        assert_eq!(partial.stopped, [("numbers".to_string(), Limit::Rows(3))]);
+
+        // A command past its deadline stops at the next checkpoint.
+        let outcome = budget.run("slow", false, || {
+            std::thread::sleep(Duration::from_millis(30));
+            checkpoint()?;
+            Ok(rows(1))
+        });
+        let Err(Error::Partial(partial)) = outcome else {
+            panic!("the command timed out");
+        };
+        assert!(partial.result.tables().is_empty());
+        assert_eq!(partial.stopped, [("slow".to_string(), Limit::Timeout(Duration::from_millis(20)))]);
+        assert!(checkpoint().is_ok());
+
+        // Cancelled commands do not start.
+        budget.cancel.cancel();
+        assert!(matches!(budget.run("numbers", false, || Ok(rows(1))), Err(Error::Limit(Limit::Cancelled))));
+
+        let budget = Budget::new(Limits::new().max_bytes(30), CancelHandle::default());
+        let Err(Error::Partial(partial)) = budget.run("numbers", false, || Ok(rows(5))) else {
+            panic!("the cap was reached");
+        };
+        assert_eq!(partial.result.tables()[0].data.len(), 2);
+    }
+}
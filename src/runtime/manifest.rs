@@ -0,0 +1,360 @@
+//! Manifests of runs, for the chain of custody of the evidence they read.
+//!
+//! While a runtime records a manifest, every hive and file its backends read
+//! from disk is hashed with SHA-256 the first time it is accessed, and every
+//! command is recorded with its arguments, its times and the artifacts it
+//! used. `Manifest::verify` later checks that the evidence is unchanged.
+//!
+//! Only backends that read local files, such as those of an offline image,
+//! report their artifacts, see `RegistryBackend::source_file` and
+//! `FileBackend::source_file`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{Error, Result},
+    utils::{
+        fs::{DirEntry, FileBackend},
+        registry::{RegistryBackend, RegistryHive, RegistryValue},
+        time::Timestamp,
+    },
+};
+
+/// A file read during a run.
+///
+/// # Fields
+/// - `path`: The path of the file, relative to the root of the run if it is inside it.
+/// - `size`: The size of the file in bytes.
+/// - `sha256`: The SHA-256 hash of the contents, in lowercase hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Artifact {
+    /// Reads and hashes a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The file to hash.
+    /// * `path` - The path to record for the file.
+    pub fn hash(file: &Path, path: PathBuf) -> Result<Self> {
+        let contents = fs::read(file)?;
+        let sha256 = Sha256::digest(&contents).iter().map(|byte| format!("{byte:02x}")).collect();
+        Ok(Artifact { path, size: contents.len() as u64, sha256 })
+    }
+}
+
+/// A command executed during a run.
+///
+/// # Fields
+/// - `name`: The name of the command or group.
+/// - `args`: The arguments of the command, without the global options.
+/// - `started`: When the command started.
+/// - `finished`: When the command finished.
+/// - `error`: The error the command failed with, if any.
+/// - `artifacts`: The paths of the artifacts the command read, see `Manifest::artifacts`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub name: String,
+    pub args: Vec<String>,
+    pub started: Timestamp,
+    pub finished: Timestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub artifacts: Vec<PathBuf>,
+}
+
+/// The record of a run: what was executed, when, and on which evidence.
+///
+/// # Fields
+/// - `tool`: The name of the tool, `rustbelt`.
+/// - `version`: The version of the tool.
+/// - `root`: The root of the evidence, such as the offline image.
+/// - `started`: When the run started.
+/// - `finished`: When the run finished.
+/// - `commands`: The commands of the run, in the order they were executed.
+/// - `artifacts`: Every file read during the run, ordered by path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub tool: String,
+    pub version: String,
+    pub root: PathBuf,
+    pub started: Timestamp,
+    pub finished: Timestamp,
+    pub commands: Vec<CommandRecord>,
+    pub artifacts: Vec<Artifact>,
+}
+
+/// A difference between the artifacts of a manifest and the evidence.
+///
+/// # Variants
+/// - `Missing`: The artifact cannot be read anymore.
+/// - `Changed`: The artifact has another size or hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    Missing(PathBuf),
+    Changed { path: PathBuf, expected: String, actual: String },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing(path) => write!(f, "MISSING  {}", path.display()),
+            Discrepancy::Changed { path, expected, actual } => {
+                write!(f, "CHANGED  {} (expected sha256 {expected}, found {actual})", path.display())
+            }
+        }
+    }
+}
+
+impl Manifest {
+    /// Reads a manifest written by `Manifest::save`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Manifest)` if the file is a manifest.
+    /// * `Err(Error::InvalidData)` if it is not.
+    pub fn load(path: &Path) -> Result<Self> {
+        serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| Error::InvalidData(format!("manifest {}: {e}", path.display())))
+    }
+
+    /// Writes the manifest as JSON.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| Error::InvalidData(e.to_string()))?;
+        Ok(fs::write(path, json + "\n")?)
+    }
+
+    /// Checks the artifacts of the manifest against the evidence.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The root of the evidence, which may have moved since the run. Relative artifact paths are resolved against it.
+    ///
+    /// # Returns
+    ///
+    /// The artifacts that are missing or changed, empty if the evidence is unchanged.
+    pub fn verify(&self, root: &Path) -> Vec<Discrepancy> {
+        self.artifacts
+            .iter()
+            .filter_map(|artifact| match Artifact::hash(&root.join(&artifact.path), artifact.path.clone()) {
+                Ok(actual) if actual == *artifact => None,
+                Ok(actual) => Some(Discrepancy::Changed {
+                    path: artifact.path.clone(),
+                    expected: artifact.sha256.clone(),
+                    actual: actual.sha256,
+                }),
+                Err(_) => Some(Discrepancy::Missing(artifact.path.clone())),
+            })
+            .collect()
+    }
+}
+
+/// The state of a recorder.
+struct Record {
+    started: Timestamp,
+    artifacts: BTreeMap<PathBuf, Artifact>,
+    // Artifacts used by the command that is running.
+    used: BTreeSet<PathBuf>,
+    commands: Vec<CommandRecord>,
+}
+
+/// Collects the manifest of a run, see `Runtime::with_manifest`.
+///
+/// # Fields
+/// - `root`: The root of the evidence. Artifacts inside it are recorded relative to it.
+pub struct ManifestRecorder {
+    root: PathBuf,
+    record: Mutex<Record>,
+}
+
+impl ManifestRecorder {
+    /// Starts recording a run on the evidence at `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ManifestRecorder {
+            root: root.into(),
+            record: Mutex::new(Record {
+                started: Timestamp::now(),
+                artifacts: BTreeMap::new(),
+                used: BTreeSet::new(),
+                commands: vec![],
+            }),
+        }
+    }
+
+    fn record(&self) -> std::sync::MutexGuard<'_, Record> {
+        self.record.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Records that a file was read, hashing it the first time.
+    ///
+    /// Files that cannot be read are not recorded: the backend that reads
+    /// them fails as well.
+    pub fn record_file(&self, file: &Path) {
+        let path = file.strip_prefix(&self.root).unwrap_or(file).to_path_buf();
+        let mut record = self.record();
+        if !record.artifacts.contains_key(&path) {
+            let Ok(artifact) = Artifact::hash(file, path.clone()) else {
+                return;
+            };
+            record.artifacts.insert(path.clone(), artifact);
+        }
+        record.used.insert(path);
+    }
+
+    /// Starts recording a command and returns its start time.
+    pub(crate) fn begin(&self) -> Timestamp {
+        self.record().used.clear();
+        Timestamp::now()
+    }
+
+    /// Records a command that finished, with the artifacts it used since `begin`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the command.
+    /// * `args` - The arguments the command was executed with, which start with the program and may include global options.
+    /// * `started` - The time `begin` returned.
+    /// * `error` - The error the command failed with, if any.
+    pub(crate) fn end(&self, name: &str, args: &[String], started: Timestamp, error: Option<&Error>) {
+        // Only the arguments after the command name are its own.
+        let args = match args.iter().position(|arg| arg == name) {
+            Some(index) => args[index + 1..].to_vec(),
+            None => vec![],
+        };
+        let mut record = self.record();
+        let artifacts = std::mem::take(&mut record.used).into_iter().collect();
+        record.commands.push(CommandRecord {
+            name: name.to_string(),
+            args,
+            started,
+            finished: Timestamp::now(),
+            error: error.map(Error::to_string),
+            artifacts,
+        });
+    }
+
+    /// Returns the manifest of the run so far, finished now.
+    pub fn manifest(&self) -> Manifest {
+        let record = self.record();
+        Manifest {
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            // An absolute root, so the manifest can be verified from anywhere.
+            root: fs::canonicalize(&self.root).unwrap_or_else(|_| self.root.clone()),
+            started: record.started,
+            finished: Timestamp::now(),
+            commands: record.commands.clone(),
+            artifacts: record.artifacts.values().cloned().collect(),
+        }
+    }
+}
+
+/// Registry backend that records the hive files another backend reads.
+pub(crate) struct RecordingRegistry {
+    pub inner: Box<dyn RegistryBackend>,
+    pub recorder: Arc<ManifestRecorder>,
+}
+
+impl RecordingRegistry {
+    fn note(&self, hive: RegistryHive, path: &str) {
+        if let Some(file) = self.inner.source_file(hive, path) {
+            self.recorder.record_file(&file);
+        }
+    }
+}
+
+impl RegistryBackend for RecordingRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.note(hive, path);
+        self.inner.get_sub_key_names(hive, path)
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.note(hive, path);
+        self.inner.get_value_names(hive, path)
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        self.note(hive, path);
+        self.inner.get_raw_value(hive, path, name)
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        self.note(hive, path);
+        self.inner.get_key_last_write_time(hive, path)
+    }
+
+    fn source_file(&self, hive: RegistryHive, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(hive, path)
+    }
+}
+
+/// File backend that records the files another backend reads.
+pub(crate) struct RecordingFiles {
+    pub inner: Box<dyn FileBackend>,
+    pub recorder: Arc<ManifestRecorder>,
+}
+
+impl FileBackend for RecordingFiles {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        if let Some(file) = self.inner.source_file(path) {
+            self.recorder.record_file(&file);
+        }
+        self.inner.read_file(path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        self.inner.read_dir(path)
+    }
+
+    fn source_file(&self, path: &str) -> Option<PathBuf> {
+        self.inner.source_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let root = std::env::temp_dir().join(format!("rustbelt-manifest-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("SOFTWARE"), b"regf").unwrap();
+        fs::write(root.join("SYSTEM"), b"regf").unwrap();
+
+        let recorder = ManifestRecorder::new(&root);
+        let started = recorder.begin();
+        recorder.record_file(&root.join("SOFTWARE"));
+        recorder.record_file(&root.join("SYSTEM"));
+        recorder.record_file(&root.join("missing"));
+        let args = ["rustbelt", "--offline", "image", "osinfo", "--brief"].map(String::from);
+        recorder.end("osinfo", &args, started, None);
+
+        let manifest = recorder.manifest();
+        assert_eq!(manifest.commands[0].args, ["--brief"]);
+        assert_eq!(manifest.commands[0].artifacts, [PathBuf::from("SOFTWARE"), PathBuf::from("SYSTEM")]);
+        assert_eq!(manifest.artifacts[0].size, 4);
+        assert_eq!(manifest.artifacts[0].sha256, "7323e77d1f51ee69b36ef874d2ee6f85f322e12a46f6281f9bd29c8dbb829460");
+        assert!(manifest.verify(&root).is_empty());
+
+        fs::write(root.join("SYSTEM"), b"regf!").unwrap();
+        fs::remove_file(root.join("SOFTWARE")).unwrap();
+        let discrepancies = manifest.verify(&root);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(discrepancies[0], Discrepancy::Missing(PathBuf::from("SOFTWARE")));
+        assert!(matches!(&discrepancies[1], Discrepancy::Changed { path, .. } if path == Path::new("SYSTEM")));
+    }
+}
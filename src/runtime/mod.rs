@@ -1,35 +1,120 @@
+pub mod audit;
 pub mod formatter;
+pub mod limits;
+pub mod manifest;
+pub mod query;
+pub mod redact;
+pub mod seatbelt;
+pub mod stats;
+pub mod targets;
+pub mod watch;
 pub mod writer;
 
-use inventory::iter;
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use windows::{core::*, Win32::System::Com::*, Win32::System::Wmi::*};
+#[cfg(windows)]
+use windows::{Win32::Foundation::RPC_E_TOO_LATE, Win32::System::Com::*};
 
+#[cfg(windows)]
+use crate::utils::{
+    fs::LocalFileSystem,
+    registry::live::LiveRegistry,
+    wmi::live::LiveWmi,
+};
 use crate::{
-    commands::base::registry::CommandRegistration,
-    utils::{self, registry::RegistryHive},
+    commands::base::{
+        registry::{get_command, schemas},
+        Command, CommandResult, Row,
+    },
+    error::{Error, Result},
+    utils::{
+        auth::{
+            ntlm::{Credentials, NtlmAuthenticator},
+            Authenticator,
+        },
+        fs::{FileBackend, ImageFileSystem, UnavailableFileSystem},
+        registry::{
+            offline::OfflineRegistry,
+            remote::RemoteRegistry,
+            view::RegistryView,
+            RegistryBackend, RegistryHiveType,
+        },
+        time::Timestamp,
+        wmi::{
+            winrm::{WinRmAuth, WinRmWmi},
+            UnavailableWmi, WmiBackend,
+        },
+    },
 };
+use audit::{AuditLog, AuditedFiles, AuditedRegistry, AuditedWmi, Auditor};
+use limits::{Budget, CancelHandle, LimitedFiles, LimitedRegistry, LimitedWmi, Limits};
+use manifest::{ManifestRecorder, RecordingFiles, RecordingRegistry};
+use query::Query;
+use redact::Redactor;
+use stats::{CommandStats, RunStats};
 
+/// The environment commands are executed in.
+///
+/// The runtime holds the target of the run and the backends commands use to
+/// access the system: the registry, WMI and the file system.
 pub struct Runtime {
     computer_name: Option<String>,
-    username: Option<String>,
-    password: Option<String>,
+    live: bool,
+    registry: Box<dyn RegistryBackend>,
+    wmi: Box<dyn WmiBackend>,
+    files: Box<dyn FileBackend>,
+    query: Query,
+    redactor: Option<Arc<Redactor>>,
+    delay: Duration,
+    manifest: Option<Arc<ManifestRecorder>>,
+    audit: Option<Arc<Auditor>>,
+    budget: Budget,
+    stats: Option<Arc<RunStats>>,
     // TODO: add the following features that\
     // filter_results: bool,
-    // delay_commands: String,
     // randomize_order: bool,
 }
 
 impl Runtime {
+    /// Creates a new runtime that uses the live registry, WMI and file system of the local machine.
+    ///
+    /// When a computer name is given, the runtime targets that computer
+    /// instead, see `Runtime::remote`.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - Optional username used for WMI connections.
+    /// * `password` - Optional password used for WMI connections.
+    /// * `computer_name` - Optional name of the computer to target.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Runtime)` if COM could be initialized, or the remote computer could be reached.
+    /// * `Err(e)` if COM initialization or the remote connection failed.
+    #[cfg(windows)]
     pub fn new(
         username: Option<String>,
         password: Option<String>,
         computer_name: Option<String>,
     ) -> Result<Self> {
+        if let Some(computer_name) = computer_name {
+            return match username {
+                Some(username) => Runtime::remote_with_credentials(
+                    &computer_name,
+                    &Credentials::with_password(&username, password.as_deref().unwrap_or_default()),
+                ),
+                None => Runtime::remote(&computer_name, &mut crate::utils::auth::Anonymous),
+            };
+        }
+
         unsafe {
             // Initialize COM and set up security
             CoInitializeEx(None, COINIT_MULTITHREADED).ok()?;
-            CoInitializeSecurity(
+            let security = CoInitializeSecurity(
                 None,
                 -1,
                 None,
@@ -39,56 +124,432 @@ impl Runtime {
                 None,
                 EOAC_NONE,
                 None,
-            )?;
+            );
+
+            // Security can only be set up once per process, which is fine when
+            // an embedder creates more than one runtime.
+            if let Err(e) = security {
+                if e.code() != RPC_E_TOO_LATE {
+                    return Err(e.into());
+                }
+            }
         };
 
-        return Ok(Self {
-            username: username,
-            password: password,
-            computer_name: computer_name,
-        });
-    }
-
-    pub fn wmi_query(&self, namespace: &str, query: &str) -> Result<IEnumWbemClassObject> {
-        let username_bstr = self
-            .username
-            .as_ref()
-            .map(BSTR::from)
-            .unwrap_or_else(BSTR::new);
-        let password_bstr = self
-            .password
-            .as_ref()
-            .map(BSTR::from)
-            .unwrap_or_else(BSTR::new);
+        Ok(Self {
+            computer_name: None,
+            live: true,
+            registry: Box::new(LiveRegistry::default()),
+            wmi: Box::new(LiveWmi { username, password }),
+            files: Box::new(LocalFileSystem::default()),
+            query: Query::default(),
+            redactor: None,
+            delay: Duration::ZERO,
+            manifest: None,
+            audit: None,
+            budget: Budget::default(),
+            stats: None,
+        })
+    }
 
-        unsafe {
-            let locator: IWbemLocator = CoCreateInstance(&WbemLocator, None, CLSCTX_INPROC_SERVER)?;
-            let server = locator.ConnectServer(
-                &BSTR::from(namespace),
-                &username_bstr,
-                &password_bstr,
-                &BSTR::new(),
-                0,
-                &BSTR::new(),
-                None,
-            )?;
+    /// Creates a new runtime that reads an offline Windows image instead of the local machine.
+    ///
+    /// The registry is read from the hive files under `Windows\System32\config`
+    /// and files are read from the image. WMI is not available offline.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The root of the image, the directory that contains `Windows`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Runtime)` if the hives of the image could be read.
+    /// * `Err(e)` if a hive is corrupt or unreadable.
+    pub fn offline(root: &Path) -> Result<Self> {
+        Ok(Runtime::from_backends(
+            Box::new(OfflineRegistry::from_image(root)?),
+            Box::new(UnavailableWmi::default()),
+            Box::new(ImageFileSystem::new(root)),
+        ))
+    }
 
-            // Execute the query to get antivirus products
-            let enumerator = server.ExecQuery(
-                &BSTR::from("WQL"),
-                &BSTR::from(query),
-                WBEM_FLAG_FORWARD_ONLY | WBEM_FLAG_RETURN_IMMEDIATELY,
-                None,
-            )?;
+    /// Creates a new runtime that inspects a remote computer.
+    ///
+    /// The registry is read over the Remote Registry Protocol on the `winreg`
+    /// named pipe, and WMI is queried through WinRM at the default HTTP
+    /// endpoint of the computer without authentication. Use `with_wmi` with a
+    /// `WinRmWmi` to use another endpoint or credentials. Files of remote
+    /// computers are not available.
+    ///
+    /// # Arguments
+    ///
+    /// * `computer_name` - The name or address of the computer, optionally with a port (`host:445`).
+    /// * `auth` - The authenticator for the SMB session.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Runtime)` if the remote registry could be opened.
+    /// * `Err(e)` if the computer could not be reached or refused the connection.
+    pub fn remote(computer_name: &str, auth: &mut dyn Authenticator) -> Result<Self> {
+        let mut runtime = Runtime::from_backends(
+            Box::new(RemoteRegistry::connect(computer_name, auth)?),
+            Box::new(WinRmWmi::for_host(computer_name, WinRmAuth::None)),
+            Box::new(UnavailableFileSystem::default()),
+        );
+        runtime.computer_name = Some(computer_name.to_string());
+        Ok(runtime)
+    }
 
-            return Ok(enumerator);
-        };
+    /// Creates a new runtime that inspects a remote computer as a user.
+    ///
+    /// Like `Runtime::remote`, but the SMB session is authenticated with NTLM
+    /// and signed, and WinRM requests are authenticated with NTLM as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `computer_name` - The name or address of the computer, optionally with a port (`host:445`).
+    /// * `credentials` - The credentials of the user.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Runtime)` if the remote registry could be opened.
+    /// * `Err(e)` if the computer could not be reached or refused the credentials.
+    pub fn remote_with_credentials(computer_name: &str, credentials: &Credentials) -> Result<Self> {
+        let mut auth = NtlmAuthenticator::new(credentials.clone()).with_signing(true);
+        let runtime = Runtime::remote(computer_name, &mut auth)?;
+        Ok(runtime.with_wmi(Box::new(WinRmWmi::for_host(
+            computer_name,
+            WinRmAuth::Ntlm(credentials.clone()),
+        ))))
     }
 
-    pub fn is_remote(&self) -> bool {
-        match self.computer_name {
-            Some(_) => true,
-            None => false
+    /// Creates a new runtime from a set of backends.
+    ///
+    /// Such a runtime is never considered live: commands only see the system
+    /// through the given backends, which makes it suitable for tests and for
+    /// embedders that bring their own data sources.
+    ///
+    /// # Arguments
+    ///
+    /// * `registry` - The registry backend to use.
+    /// * `wmi` - The WMI backend to use.
+    /// * `files` - The file backend to use.
+    pub fn from_backends(
+        registry: Box<dyn RegistryBackend>,
+        wmi: Box<dyn WmiBackend>,
+        files: Box<dyn FileBackend>,
+    ) -> Self {
+        Self {
+            computer_name: None,
+            live: false,
+            registry,
+            wmi,
+            files,
+            query: Query::default(),
+            redactor: None,
+            delay: Duration::ZERO,
+            manifest: None,
+            audit: None,
+            budget: Budget::default(),
+            stats: None,
         }
     }
+
+    /// Replaces the registry backend used by the commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `registry` - The registry backend to use.
+    pub fn with_registry(mut self, registry: Box<dyn RegistryBackend>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Shows the registry to the commands in a registry view, see `RegistryView`.
+    ///
+    /// The current registry backend is wrapped, so the view works the same on
+    /// live, remote and offline registries. The native 64-bit view leaves the
+    /// backend as it is.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The view to show.
+    pub fn with_view(mut self, view: RegistryHiveType) -> Self {
+        if view != RegistryHiveType::X64 {
+            self.registry = Box::new(RegistryView::new(self.registry, view));
+        }
+        self
+    }
+
+    /// Replaces the WMI backend used by the commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `wmi` - The WMI backend to use.
+    pub fn with_wmi(mut self, wmi: Box<dyn WmiBackend>) -> Self {
+        self.wmi = wmi;
+        self
+    }
+
+    /// Replaces the file backend used by the commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - The file backend to use.
+    pub fn with_files(mut self, files: Box<dyn FileBackend>) -> Self {
+        self.files = files;
+        self
+    }
+
+    /// Filters and projects the results of every command, see `query`.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query to apply.
+    pub fn with_query(mut self, query: Query) -> Self {
+        self.query = query;
+        self
+    }
+
+    /// Redacts the results of every command before they are returned, see `redact`.
+    ///
+    /// # Arguments
+    ///
+    /// * `redactor` - The redactor, which may be shared with other runtimes of the run.
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    /// Waits between the commands of a group, or of a run with several commands,
+    /// to spread out the activity on the target.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - How long to wait between two commands.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Records the commands of the runtime and the hives and files they read, see `manifest`.
+    ///
+    /// The current registry and file backends are wrapped to record what
+    /// they read, so backends should be replaced before, not after.
+    ///
+    /// # Arguments
+    ///
+    /// * `recorder` - The recorder of the run.
+    pub fn with_manifest(mut self, recorder: Arc<ManifestRecorder>) -> Self {
+        self.registry = Box::new(RecordingRegistry { inner: self.registry, recorder: recorder.clone() });
+        self.files = Box::new(RecordingFiles { inner: self.files, recorder: recorder.clone() });
+        self.manifest = Some(recorder);
+        self
+    }
+
+    /// Reports every access of the registry, WMI and file backends to an audit log, see `audit`.
+    ///
+    /// The current backends are wrapped to report what they access, so
+    /// backends should be replaced before, not after.
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - The log, which may be shared with other runtimes of the run.
+    pub fn with_audit(mut self, log: Arc<AuditLog>) -> Self {
+        let auditor = Arc::new(Auditor::new(log, self.computer_name.clone()));
+        self.registry = Box::new(AuditedRegistry { inner: self.registry, auditor: auditor.clone() });
+        self.wmi = Box::new(AuditedWmi { inner: self.wmi, auditor: auditor.clone() });
+        self.files = Box::new(AuditedFiles { inner: self.files, auditor: auditor.clone() });
+        self.audit = Some(auditor);
+        self
+    }
+
+    /// Limits how long commands may run and how much they may return, see `limits`.
+    ///
+    /// The current backends are wrapped to check the deadline of the running
+    /// command before every access, so backends should be replaced before,
+    /// not after. Commands that reach a limit fail with `Error::Partial`,
+    /// which holds what they returned before they stopped.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The limits of every command, and of all of them together.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.registry = Box::new(LimitedRegistry { inner: self.registry });
+        self.wmi = Box::new(LimitedWmi { inner: self.wmi });
+        self.files = Box::new(LimitedFiles { inner: self.files });
+        self.budget = Budget::new(limits, self.budget.cancel.clone());
+        self
+    }
+
+    /// Records the duration, rows, bytes and status of every command, including the members of groups, see `stats`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The statistics, which may be shared with other runtimes of the run.
+    pub fn with_stats(mut self, stats: Arc<RunStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Returns the handle that cancels the commands of this runtime from another thread.
+    ///
+    /// Commands check it when they start and, once the backends are wrapped
+    /// by `with_limits`, before every access.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.budget.cancel.clone()
+    }
+
+    /// Returns the limits of the commands of this runtime.
+    pub fn limits(&self) -> Limits {
+        self.budget.limits
+    }
+
+    /// Returns how long to wait between two commands.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Returns the registry backend of this runtime.
+    pub fn registry(&self) -> &dyn RegistryBackend {
+        self.registry.as_ref()
+    }
+
+    /// Returns the WMI backend of this runtime.
+    pub fn wmi(&self) -> &dyn WmiBackend {
+        self.wmi.as_ref()
+    }
+
+    /// Returns the file backend of this runtime.
+    pub fn files(&self) -> &dyn FileBackend {
+        self.files.as_ref()
+    }
+
+    /// Executes a registered command or group by name.
+    ///
+    /// The columns of every table with a declared schema are put in the
+    /// declared order. Debug builds also check the tables against their
+    /// schema, so commands cannot drift from what they declare. The query of
+    /// the runtime is applied afterwards and, with a redactor, tagged columns
+    /// are redacted last, so filters see the real values. With a manifest,
+    /// the command is recorded with the artifacts it read, and with an audit
+    /// log, its accesses are attributed to it. With limits, a command that
+    /// reaches one fails with `Error::Partial`, whose result is processed the
+    /// same way.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the command, for example `amsiproviders` or `group:misc`.
+    /// * `args` - The arguments for the command.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(CommandResult)` containing the typed results of the command.
+    /// * `Err(Error::NotFound)` if no command with that name is registered.
+    /// * `Err(Error::Unsupported)` if the runtime is remote and the command does not support remote execution.
+    /// * `Err(Error::InvalidData)` in debug builds if a table does not match its schema.
+    /// * `Err(Error::Partial)` if the command reached a limit, with what it returned before.
+    /// * `Err(Error::Limit)` if a limit was reached before the command started.
+    /// * `Err(e)` if the command failed.
+    pub fn execute(&self, name: &str, args: &[String]) -> Result<CommandResult> {
+        let command = get_command(name).ok_or_else(|| Error::NotFound(format!("command '{name}'")))?;
+
+        let local_only = command.command_data().is_some_and(|data| !data.support_remote);
+        if self.is_remote() && local_only {
+            return Err(Error::Unsupported(format!("command '{name}' cannot run remotely")));
+        }
+
+        let started = self.manifest.as_ref().map(|recorder| recorder.begin());
+        if let Some(auditor) = &self.audit {
+            auditor.set_command(Some(name));
+        }
+        let outcome = self.run(command.as_ref(), name, args);
+        if let Some(auditor) = &self.audit {
+            auditor.set_command(None);
+        }
+        if let (Some(recorder), Some(started)) = (&self.manifest, started) {
+            recorder.end(name, args, started, outcome.as_ref().err());
+        }
+        match outcome {
+            Ok(mut result) => {
+                self.process(&mut result)?;
+                Ok(result)
+            }
+            Err(Error::Partial(mut partial)) => {
+                self.process(&mut partial.result)?;
+                Err(Error::Partial(partial))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs a command within the limits of the runtime, without processing its result.
+    ///
+    /// Groups run their members with this, so every member gets its own
+    /// timeout and caps, and the group's result is processed once.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command.
+    /// * `name` - The name of the command, for the limits it reaches.
+    /// * `args` - The arguments for the command.
+    pub(crate) fn run(&self, command: &dyn Command, name: &str, args: &[String]) -> Result<CommandResult> {
+        let group = !command.members().is_empty();
+        let (started, clock) = (Timestamp::now(), Instant::now());
+        let outcome = self.budget.run(name, group, || command.execute(self, args));
+        // Groups are the sum of their members, which are recorded one by one.
+        if let (Some(stats), false) = (&self.stats, group) {
+            stats.record(CommandStats::new(name, self.computer_name(), started, clock.elapsed(), &outcome));
+        }
+        outcome
+    }
+
+    /// Arranges, checks, filters and redacts the tables of a result.
+    fn process(&self, result: &mut CommandResult) -> Result<()> {
+        let schemas = schemas();
+        for table in result.tables_mut() {
+            let Some((_, schema)) = schemas.iter().find(|(_, schema)| schema.source == table.source) else {
+                continue;
+            };
+            for row in &mut table.data {
+                schema.arrange(row);
+            }
+            #[cfg(debug_assertions)]
+            schema.validate(table)?;
+        }
+        self.query.apply(result);
+        if let Some(redactor) = &self.redactor {
+            let schemas: Vec<_> = schemas.iter().map(|(_, schema)| *schema).collect();
+            redactor.redact(result, &schemas);
+        }
+        Ok(())
+    }
+
+    /// Runs a WQL query through the WMI backend of this runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The WMI namespace to query.
+    /// * `query` - The WQL query to run.
+    /// * `fields` - The properties to retrieve from each returned instance.
+    pub fn wmi_query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        self.wmi.query(namespace, query, fields)
+    }
+
+    /// Returns whether the runtime inspects a remote computer.
+    pub fn is_remote(&self) -> bool {
+        self.computer_name.is_some()
+    }
+
+    /// Returns the name of the remote computer the runtime inspects, if any.
+    pub fn computer_name(&self) -> Option<&str> {
+        self.computer_name.as_deref()
+    }
+
+    /// Returns whether the runtime inspects the machine it is running on.
+    ///
+    /// Commands use this to decide whether they may use APIs of the local
+    /// machine, such as environment variables, that have no backend.
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
 }
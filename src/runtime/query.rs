@@ -0,0 +1,558 @@
+//! Filtering and projection of results.
+//!
+//! A `Filter` is a boolean expression over the columns of a row, written as
+//! text, for example:
+//!
+//! ```text
+//! ProductName contains 'server' and not (UBR < 3000 or `AMSI Provider` matches '^c:\\temp')
+//! ```
+//!
+//! Columns are bare words or quoted with backticks if they contain other
+//! characters, and are matched regardless of case. Literals are quoted
+//! strings, numbers, `true`, `false` and `null`. The operators are `=`, `!=`,
+//! `<`, `<=`, `>`, `>=`, `contains` and `matches` (a regular expression),
+//! combined with `and`, `or`, `not` and parentheses. A column on its own
+//! holds when it has a value that is not `false`, `0` or empty.
+//!
+//! Text is compared regardless of case. Numbers are compared as numbers,
+//! also against numeric text such as registry strings, and timestamps
+//! against RFC 3339 times or dates (`2024-03-01`). A comparison with a list
+//! holds if it holds for any element, and comparisons with missing or null
+//! values only hold for `= null` and `!= <value>`.
+//!
+//! A `Query` combines a filter with a projection onto a list of columns, and
+//! is applied by the runtime to every result before it is formatted.
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    commands::base::{CommandResult, Row, Value},
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// A comparison operator.
+///
+/// # Variants
+/// - `Eq`, `Ne`, `Lt`, `Le`, `Gt`, `Ge`: The comparisons `=`, `!=`, `<`, `<=`, `>` and `>=`.
+/// - `Contains`: Whether text contains the literal.
+/// - `Matches`: Whether text matches the regular expression, regardless of case.
+#[derive(Debug, Clone)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    Matches(Regex),
+}
+
+/// A literal in a filter.
+///
+/// # Variants
+/// - `Null`: `null`.
+/// - `Bool`: `true` or `false`.
+/// - `Number`: A number.
+/// - `Text`: A quoted string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+}
+
+/// A boolean expression over the columns of a row.
+///
+/// # Variants
+/// - `And`, `Or`, `Not`: Boolean logic.
+/// - `Compare`: A comparison of a column with a literal.
+/// - `Truthy`: Whether a column has a value that is not `false`, `0` or empty.
+#[derive(Debug, Clone)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare {
+        column: String,
+        operator: Operator,
+        literal: Literal,
+    },
+    Truthy(String),
+}
+
+impl Filter {
+    /// Parses a filter expression, see the module documentation for the syntax.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Filter)` if the expression is valid.
+    /// * `Err(Error::InvalidData)` describing the first problem otherwise.
+    pub fn parse(expression: &str) -> Result<Self> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(syntax(format!("unexpected {token}"))),
+        }
+    }
+
+    /// Returns whether a row satisfies the filter.
+    pub fn matches(&self, row: &Row) -> bool {
+        match self {
+            Filter::And(left, right) => left.matches(row) && right.matches(row),
+            Filter::Or(left, right) => left.matches(row) || right.matches(row),
+            Filter::Not(filter) => !filter.matches(row),
+            Filter::Compare {
+                column,
+                operator,
+                literal,
+            } => compare(column_value(row, column), operator, literal),
+            Filter::Truthy(column) => truthy(column_value(row, column)),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        Filter::parse(expression)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    /// Deserializes a filter from its text, so declarative definitions can embed filters.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        Filter::parse(&expression).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A filter and a projection applied to results.
+///
+/// # Fields
+/// - `filter`: Only rows that satisfy it are kept, if set.
+/// - `columns`: Only these columns are kept, in this order, if not empty.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub filter: Option<Filter>,
+    pub columns: Vec<String>,
+}
+
+impl Query {
+    /// Creates a query that keeps everything.
+    pub fn new() -> Self {
+        Query::default()
+    }
+
+    /// Keeps only the rows that satisfy a filter.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Keeps only the given columns, in the given order. Columns a table lacks are left out.
+    pub fn select<S: Into<String>>(mut self, columns: impl IntoIterator<Item = S>) -> Self {
+        self.columns = columns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Filters and projects every table of a result.
+    pub fn apply(&self, result: &mut CommandResult) {
+        for table in result.tables_mut() {
+            if let Some(filter) = &self.filter {
+                table.data.retain(|row| filter.matches(row));
+            }
+            if !self.columns.is_empty() {
+                for row in &mut table.data {
+                    *row = self.project(row);
+                }
+            }
+        }
+    }
+
+    /// Returns the selected columns of a row.
+    fn project(&self, row: &Row) -> Row {
+        self.columns
+            .iter()
+            .filter_map(|column| {
+                row.iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(column))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Returns the value of a column, regardless of the case of its name.
+fn column_value<'a>(row: &'a Row, column: &str) -> &'a Value {
+    row.get(column)
+        .or_else(|| row.iter().find(|(name, _)| name.eq_ignore_ascii_case(column)).map(|(_, value)| value))
+        .unwrap_or(&Value::Null)
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Integer(value) => *value != 0,
+        Value::Unsigned(value) => *value != 0,
+        Value::Float(value) => *value != 0.0,
+        Value::String(value) => !value.is_empty(),
+        Value::Timestamp(_) => true,
+        Value::Bytes(value) => !value.is_empty(),
+        Value::List(values) => !values.is_empty(),
+    }
+}
+
+fn compare(value: &Value, operator: &Operator, literal: &Literal) -> bool {
+    match (value, operator) {
+        (Value::List(values), Operator::Ne) => !values.iter().any(|value| compare(value, &Operator::Eq, literal)),
+        (Value::List(values), _) => values.iter().any(|value| compare(value, operator, literal)),
+        (Value::Null, Operator::Eq) => *literal == Literal::Null,
+        (Value::Null, Operator::Ne) => *literal != Literal::Null,
+        (Value::Null, _) => false,
+        (_, Operator::Contains) => text(value).contains(&literal_text(literal).to_lowercase()),
+        (_, Operator::Matches(regex)) => regex.is_match(&value.to_string()),
+        _ => match order(value, literal) {
+            Some(ordering) => match operator {
+                Operator::Eq => ordering == Ordering::Equal,
+                Operator::Ne => ordering != Ordering::Equal,
+                Operator::Lt => ordering == Ordering::Less,
+                Operator::Le => ordering != Ordering::Greater,
+                Operator::Gt => ordering == Ordering::Greater,
+                Operator::Ge => ordering != Ordering::Less,
+                Operator::Contains | Operator::Matches(_) => unreachable!("handled above"),
+            },
+            None => matches!(operator, Operator::Ne),
+        },
+    }
+}
+
+/// Orders a value relative to a literal, or `None` if they cannot be compared.
+fn order(value: &Value, literal: &Literal) -> Option<Ordering> {
+    match (value, literal) {
+        (_, Literal::Null) => None,
+        (Value::Bool(value), Literal::Bool(literal)) => Some(value.cmp(literal)),
+        (Value::Timestamp(value), Literal::Text(literal)) => {
+            let literal = match literal.len() {
+                10 => format!("{literal}T00:00:00Z"),
+                _ => literal.clone(),
+            };
+            literal.parse::<Timestamp>().ok().map(|literal| value.cmp(&literal))
+        }
+        (value, Literal::Number(literal)) => number(value)?.partial_cmp(literal),
+        (value, literal) => Some(text(value).cmp(&literal_text(literal).to_lowercase())),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(value) => Some(*value as f64),
+        Value::Unsigned(value) => Some(*value as f64),
+        Value::Float(value) => Some(*value),
+        Value::String(value) => value.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns the text of a value for case-insensitive comparisons.
+fn text(value: &Value) -> String {
+    value.to_string().to_lowercase()
+}
+
+fn literal_text(literal: &Literal) -> String {
+    match literal {
+        Literal::Null => String::new(),
+        Literal::Bool(value) => value.to_string(),
+        Literal::Number(value) => value.to_string(),
+        Literal::Text(value) => value.clone(),
+    }
+}
+
+fn syntax(what: String) -> Error {
+    Error::InvalidData(format!("filter: {what}"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Column(String),
+    Text(String),
+    Number(f64),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Column(column) => write!(f, "`{column}`"),
+            Token::Text(text) => write!(f, "{text:?}"),
+            Token::Number(number) => write!(f, "{number}"),
+            Token::Symbol(symbol) => write!(f, "'{symbol}'"),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    const SYMBOLS: [&str; 11] = ["==", "!=", "<>", "<=", ">=", "=", "<", ">", "~", "(", ")"];
+
+    let mut tokens = vec![];
+    let mut rest = expression;
+    loop {
+        rest = rest.trim_start();
+        let Some(first) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if matches!(first, '\'' | '"' | '`') {
+            let (quoted, remainder) = quoted(rest, first)?;
+            tokens.push(match first {
+                '`' => Token::Column(quoted),
+                _ => Token::Text(quoted),
+            });
+            rest = remainder;
+        } else if first.is_ascii_digit() || (first == '-' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            let end = rest[1..]
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.'))
+                .map_or(rest.len(), |end| end + 1);
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| syntax(format!("invalid number {}", &rest[..end])))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if first.is_alphanumeric() || first == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '.' | '-')))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(syntax(format!("unexpected character '{first}'")));
+        }
+    }
+}
+
+/// Reads a quoted string, in which a backslash only escapes the quote, so
+/// Windows paths and regular expressions can mostly be written as they are.
+///
+/// # Returns
+///
+/// The unquoted string and the rest of the expression.
+fn quoted(text: &str, quote: char) -> Result<(String, &str)> {
+    let mut unquoted = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some((_, next)) if *next == quote => {
+                    unquoted.push(quote);
+                    chars.next();
+                }
+                // A pair of backslashes stays a pair, so regular expressions can end in an escaped backslash.
+                Some((_, '\\')) => {
+                    unquoted.push_str("\\\\");
+                    chars.next();
+                }
+                _ => unquoted.push('\\'),
+            },
+            c if c == quote => return Ok((unquoted, &text[index + 1..])),
+            c => unquoted.push(c),
+        }
+    }
+    Err(syntax(format!("unterminated {quote}")))
+}
+
+/// A recursive descent parser, from the lowest precedence (`or`) to the highest (comparisons).
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Consumes the next token if it is the given keyword.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Filter> {
+        let mut filter = self.and()?;
+        while self.keyword("or") {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter> {
+        let mut filter = self.not()?;
+        while self.keyword("and") {
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter> {
+        if self.keyword("not") {
+            return Ok(Filter::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Filter> {
+        let column = match self.next() {
+            Some(Token::Symbol("(")) => {
+                let filter = self.or()?;
+                return match self.next() {
+                    Some(Token::Symbol(")")) => Ok(filter),
+                    _ => Err(syntax("missing ')'".to_string())),
+                };
+            }
+            Some(Token::Word(column)) | Some(Token::Column(column)) => column,
+            Some(token) => return Err(syntax(format!("expected a column, found {token}"))),
+            None => return Err(syntax("expected a column".to_string())),
+        };
+
+        let operator = match self.peek() {
+            Some(Token::Symbol(symbol)) if *symbol != "(" && *symbol != ")" => *symbol,
+            Some(Token::Word(word)) if ["contains", "matches"].contains(&word.to_lowercase().as_str()) => {
+                if word.eq_ignore_ascii_case("contains") { "contains" } else { "matches" }
+            }
+            _ => return Ok(Filter::Truthy(column)),
+        };
+        self.position += 1;
+
+        let literal = match self.next() {
+            Some(Token::Text(text)) => Literal::Text(text),
+            Some(Token::Number(number)) => Literal::Number(number),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("null") => Literal::Null,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("true") => Literal::Bool(true),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("false") => Literal::Bool(false),
+            Some(token) => return Err(syntax(format!("expected a value after '{operator}', found {token}"))),
+            None => return Err(syntax(format!("expected a value after '{operator}'"))),
+        };
+
+        let operator = match operator {
+            "=" | "==" => Operator::Eq,
+            "!=" | "<>" => Operator::Ne,
+            "<" => Operator::Lt,
+            "<=" => Operator::Le,
+            ">" => Operator::Gt,
+            ">=" => Operator::Ge,
+            "contains" => Operator::Contains,
+            _ => {
+                let pattern = literal_text(&literal);
+                let regex = RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| syntax(format!("invalid regular expression {pattern:?}: {e}")))?;
+                Operator::Matches(regex)
+            }
+        };
+        Ok(Filter::Compare {
+            column,
+            operator,
+            literal,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::base::CommandDTO;
+
+    fn row(values: Vec<(&str, Value)>) -> Row {
+        values.into_iter().map(|(name, value)| (name.to_string(), value)).collect()
+    }
+
+    #[test]
+    fn test_filter() {
+        let row = row(vec![
+            ("ProductName", Value::from("Windows Server 2022 Datacenter")),
+            ("UBR", Value::from("3342")),
+            ("Enabled", Value::from(true)),
+            ("AMSI Provider", Value::from("C:\\ProgramData\\mpoav.dll")),
+            ("Paths", Value::from(vec!["C:\\a".to_string(), "C:\\b".to_string()])),
+            ("Missing", Value::Null),
+            ("Started", Value::from("2024-03-01T12:00:00Z".parse::<Timestamp>().unwrap())),
+        ]);
+
+        let holds = [
+            "productname contains 'server'",
+            "UBR > 3000 and UBR <= 3342",
+            "Enabled and not Missing",
+            "Enabled = true",
+            "`AMSI Provider` matches '\\\\mpoav\\.dll$'",
+            "`AMSI Provider` ~ '^c:\\\\programdata'",
+            "Paths = 'c:\\b'",
+            "Missing = null or UBR = 1",
+            "Missing != 'x'",
+            "Started > '2024-03-01' and Started < \"2024-03-02T00:00:00Z\"",
+            "(UBR < 3000 or ProductName = 'windows server 2022 datacenter') and UBR != 1",
+        ];
+        for expression in holds {
+            assert!(Filter::parse(expression).unwrap().matches(&row), "{expression}");
+        }
+
+        let fails = [
+            "ProductName contains 'workstation'",
+            "UBR < 3000",
+            "Missing > 1",
+            "Missing contains ''",
+            "Paths = 'c:\\c'",
+            "Paths != 'c:\\a'",
+            "not Enabled",
+            "NoSuchColumn",
+        ];
+        for expression in fails {
+            assert!(!Filter::parse(expression).unwrap().matches(&row), "{expression}");
+        }
+
+        for invalid in ["", "UBR >", "UBR > and", "(UBR > 1", "UBR > 1 1", "A matches '('", "'text'", "A = 'open"] {
+            assert!(matches!(Filter::parse(invalid), Err(Error::InvalidData(_))), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let mut result = CommandResult::Simple(CommandDTO {
+            source: "Test".to_string(),
+            data: (0..5u64)
+                .map(|index| row(vec![("Name", Value::from(format!("n{index}"))), ("Count", Value::from(index))]))
+                .collect(),
+        });
+        Query::new()
+            .filter("Count >= 3".parse().unwrap())
+            .select(["count", "Other"])
+            .apply(&mut result);
+
+        let rows = &result.tables()[0].data;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], row(vec![("Count", Value::from(3u64))]));
+    }
+}
@@ -0,0 +1,293 @@
+//! Redaction of secrets and identifying data in results.
+//!
+//! Columns are redacted according to the tag their schema declares: secrets
+//! are masked, and usernames, SIDs, host names, IP addresses and other
+//! identifiers are replaced by pseudonyms. A value gets the same pseudonym
+//! everywhere in a run, so results can still be correlated, and the mapping
+//! from pseudonyms back to the real values can be saved to a separate file.
+//! Tables without a schema, such as those of plugins, declare no tags, so
+//! their columns are tagged by the words of their names instead, see
+//! `column_tag`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use serde_json::{json, Map};
+
+use crate::{
+    commands::base::{
+        schema::{FieldTag, Schema},
+        CommandResult, Value,
+    },
+    error::Result,
+};
+
+/// What masked secrets are replaced with.
+pub const MASK: &str = "[REDACTED]";
+
+/// SIDs of accounts in a domain or on a computer: `S-1-5-21-<3 sub-authorities>-<RID>`.
+const ACCOUNT_SID_PREFIX: &str = "S-1-5-21-";
+
+/// The words of column names that tag the columns of tables without a schema, see `column_tag`.
+const COLUMN_WORDS: [(FieldTag, &[&str]); 5] = [
+    (FieldTag::Secret, &["password", "passwd", "secret", "token", "hash", "credential", "credentials", "key"]),
+    (FieldTag::Sid, &["sid"]),
+    (FieldTag::Username, &["user", "username", "account", "owner", "login"]),
+    (FieldTag::Hostname, &["host", "hostname", "computer", "machine", "server", "domain", "fqdn"]),
+    (FieldTag::IpAddress, &["ip", "address", "addr"]),
+];
+
+/// The pseudonyms of the values of a tag by normalized value, with the value as first seen.
+type Pseudonyms = BTreeMap<String, (String, String)>;
+
+/// Replaces sensitive values in results, with the same pseudonyms across a run.
+///
+/// A redactor can be shared by the runtimes of a multi-target run, so a
+/// value that appears on several targets gets the same pseudonym on all.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    pseudonyms: Mutex<BTreeMap<FieldTag, Pseudonyms>>,
+}
+
+impl Redactor {
+    /// Creates a redactor without pseudonyms.
+    pub fn new() -> Self {
+        Redactor::default()
+    }
+
+    /// Redacts the tables of a result.
+    ///
+    /// Tables with a schema are redacted by the tags of its fields, and
+    /// tables without one by the names of their columns, see `column_tag`.
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - The result to redact.
+    /// * `schemas` - The schemas of the tables, matched by their source.
+    pub fn redact(&self, result: &mut CommandResult, schemas: &[&Schema]) {
+        for table in result.tables_mut() {
+            let tags: Vec<(String, FieldTag)> = match schemas.iter().find(|schema| schema.source == table.source) {
+                Some(schema) => schema
+                    .fields
+                    .iter()
+                    .filter_map(|field| Some((field.name.to_string(), field.tag?)))
+                    .collect(),
+                None => {
+                    let columns: BTreeSet<&String> = table.data.iter().flat_map(|row| row.keys()).collect();
+                    columns.into_iter().filter_map(|column| Some((column.clone(), column_tag(column)?))).collect()
+                }
+            };
+            for (column, tag) in tags {
+                for row in &mut table.data {
+                    if let Some(value) = row.get_mut(&column) {
+                        *value = self.redact_value(tag, value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Redacts a single value.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - What kind of data the value is.
+    /// * `value` - The value. Strings and lists of strings are pseudonymized, other values are masked.
+    pub fn redact_value(&self, tag: FieldTag, value: &Value) -> Value {
+        match (tag, value) {
+            (_, Value::Null) => Value::Null,
+            (FieldTag::Secret, _) => Value::from(MASK),
+            (_, Value::String(text)) => Value::String(self.pseudonym(tag, text)),
+            (_, Value::List(values)) => Value::List(values.iter().map(|value| self.redact_value(tag, value)).collect()),
+            _ => Value::from(MASK),
+        }
+    }
+
+    /// Returns the pseudonym of a value, creating one if it has none yet.
+    ///
+    /// Usernames, host names and identifiers are compared case insensitively.
+    /// Well-known SIDs, and loopback and unspecified addresses, are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - What kind of data the value is.
+    /// * `value` - The value.
+    pub fn pseudonym(&self, tag: FieldTag, value: &str) -> String {
+        match tag {
+            FieldTag::Secret => MASK.to_string(),
+            FieldTag::Sid => self.sid_pseudonym(value),
+            FieldTag::IpAddress => match value.trim().parse::<IpAddr>() {
+                Ok(address) if address.is_loopback() || address.is_unspecified() => value.to_string(),
+                Ok(address) => {
+                    let key = address.to_string();
+                    match address {
+                        IpAddr::V4(_) => self.lookup(tag, &key, value, |index| {
+                            format!("198.18.{}.{}", (index >> 8) & 0xff, index & 0xff)
+                        }),
+                        IpAddr::V6(_) => self.lookup(tag, &key, value, |index| format!("2001:db8::{index:x}")),
+                    }
+                }
+                Err(_) => self.lookup(tag, value, value, |index| format!("address-{index}")),
+            },
+            FieldTag::Username => self.lookup(tag, &value.to_lowercase(), value, |index| format!("user-{index}")),
+            FieldTag::Hostname => self.lookup(tag, &value.to_lowercase(), value, |index| format!("host-{index}")),
+            FieldTag::Identifier => self.lookup(tag, &value.to_lowercase(), value, |index| format!("id-{index}")),
+        }
+    }
+
+    /// Pseudonymizes the domain or computer of an account SID and keeps its RID,
+    /// so well-known accounts such as the built-in administrator (500) stay recognizable.
+    fn sid_pseudonym(&self, sid: &str) -> String {
+        let upper = sid.trim().to_uppercase();
+        let Some(rest) = upper.strip_prefix(ACCOUNT_SID_PREFIX) else {
+            return sid.to_string();
+        };
+        let parts: Vec<&str> = rest.split('-').collect();
+        if parts.len() != 4 || parts.iter().any(|part| part.parse::<u32>().is_err()) {
+            return self.lookup(FieldTag::Sid, &upper, sid, |index| format!("sid-{index}"));
+        }
+
+        let domain = format!("{ACCOUNT_SID_PREFIX}{}", parts[..3].join("-"));
+        let pseudonym = self.lookup(FieldTag::Sid, &domain, &domain, |index| {
+            format!("{ACCOUNT_SID_PREFIX}0-0-{index}")
+        });
+        format!("{pseudonym}-{}", parts[3])
+    }
+
+    /// Returns the pseudonym of a value, creating it with `make` from the number of the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - What kind of data the value is.
+    /// * `key` - The normalized value, which equal values share.
+    /// * `value` - The value as found, for the mapping.
+    /// * `make` - Creates the pseudonym of the nth value of the tag.
+    fn lookup(&self, tag: FieldTag, key: &str, value: &str, make: impl Fn(usize) -> String) -> String {
+        let mut pseudonyms = self.pseudonyms.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let known = pseudonyms.entry(tag).or_default();
+        if let Some((pseudonym, _)) = known.get(key) {
+            return pseudonym.clone();
+        }
+        let pseudonym = make(known.len() + 1);
+        known.insert(key.to_string(), (pseudonym.clone(), value.to_string()));
+        pseudonym
+    }
+
+    /// Returns the pseudonyms handed out so far, by tag, from pseudonym to the value as first seen.
+    pub fn mapping(&self) -> BTreeMap<FieldTag, BTreeMap<String, String>> {
+        self.pseudonyms
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|(tag, known)| (*tag, known.values().cloned().collect()))
+            .collect()
+    }
+
+    /// Writes the mapping from pseudonyms back to the real values as JSON.
+    ///
+    /// The file maps the name of every tag to an object from pseudonym to
+    /// value. It allows undoing the redaction, so it must be kept as safe as
+    /// the unredacted results.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to write.
+    pub fn write_mapping(&self, path: &Path) -> Result<()> {
+        let mut document = Map::new();
+        for (tag, pseudonyms) in self.mapping() {
+            let pseudonyms: Map<String, serde_json::Value> = pseudonyms
+                .into_iter()
+                .map(|(pseudonym, value)| (pseudonym, json!(value)))
+                .collect();
+            document.insert(tag.name().to_string(), pseudonyms.into());
+        }
+        let text = serde_json::to_string_pretty(&document).expect("mappings always serialize to JSON");
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+/// Returns the tag of a column of a table without a schema, from the words of its name.
+///
+/// Names are split into words at separators and at lower to upper case
+/// changes, so `User Name`, `user_name` and `UserName` are all usernames.
+/// The first tag in `COLUMN_WORDS` with a matching word wins, so a
+/// `Host Key` is a secret. Columns without such a word are not redacted.
+///
+/// # Arguments
+///
+/// * `column` - The name of the column.
+pub fn column_tag(column: &str) -> Option<FieldTag> {
+    let mut words = vec![String::new()];
+    let mut previous = ' ';
+    for c in column.chars() {
+        if !c.is_alphanumeric() || (c.is_uppercase() && previous.is_lowercase()) {
+            words.push(String::new());
+        }
+        if c.is_alphanumeric() {
+            words.last_mut().expect("words is never empty").extend(c.to_lowercase());
+        }
+        previous = c;
+    }
+    COLUMN_WORDS
+        .iter()
+        .find(|(_, names)| words.iter().any(|word| names.contains(&word.as_str())))
+        .map(|(tag, _)| *tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::base::{CommandDTO, Row};
+
+    #[test]
+    fn test_pseudonyms() {
+        let redactor = Redactor::new();
+        assert_eq!(redactor.pseudonym(FieldTag::Hostname, "WS01"), "host-1");
+        assert_eq!(redactor.pseudonym(FieldTag::Hostname, "DC01"), "host-2");
+        assert_eq!(redactor.pseudonym(FieldTag::Hostname, "ws01"), "host-1");
+        assert_eq!(redactor.pseudonym(FieldTag::Username, "CORP\\alice"), "user-1");
+        assert_eq!(redactor.pseudonym(FieldTag::Secret, "hunter2"), MASK);
+
+        let domain = "S-1-5-21-3623811015-3361044348-30300820";
+        assert_eq!(redactor.pseudonym(FieldTag::Sid, &format!("{domain}-500")), "S-1-5-21-0-0-1-500");
+        assert_eq!(redactor.pseudonym(FieldTag::Sid, &format!("{domain}-1104")), "S-1-5-21-0-0-1-1104");
+        assert_eq!(redactor.pseudonym(FieldTag::Sid, "S-1-5-21-1-2-3-1001"), "S-1-5-21-0-0-2-1001");
+        assert_eq!(redactor.pseudonym(FieldTag::Sid, "S-1-5-18"), "S-1-5-18");
+
+        assert_eq!(redactor.pseudonym(FieldTag::IpAddress, "10.0.0.5"), "198.18.0.1");
+        assert_eq!(redactor.pseudonym(FieldTag::IpAddress, "fe80::1"), "2001:db8::2");
+        assert_eq!(redactor.pseudonym(FieldTag::IpAddress, "127.0.0.1"), "127.0.0.1");
+
+        let mapping = redactor.mapping();
+        assert_eq!(mapping[&FieldTag::Hostname]["host-1"], "WS01");
+        assert_eq!(mapping[&FieldTag::Sid]["S-1-5-21-0-0-1"], domain);
+        assert!(!mapping.contains_key(&FieldTag::Secret));
+    }
+
+    #[test]
+    fn test_tables_without_schema() {
+        assert_eq!(column_tag("UserName"), Some(FieldTag::Username));
+        assert_eq!(column_tag("Owner SID"), Some(FieldTag::Sid));
+        assert_eq!(column_tag("ip_address"), Some(FieldTag::IpAddress));
+        assert_eq!(column_tag("Host Key"), Some(FieldTag::Secret));
+        assert_eq!(column_tag("Description"), None);
+
+        let row = Row::from([
+            ("Computer".to_string(), Value::from("WS01")),
+            ("ApiToken".to_string(), Value::from("hunter2")),
+            ("Description".to_string(), Value::from("WS01 is a workstation")),
+        ]);
+        let mut result = CommandResult::Simple(CommandDTO { source: "Plugin".to_string(), data: vec![row] });
+        Redactor::new().redact(&mut result, &[]);
+        let row = &result.tables()[0].data[0];
+        assert_eq!(row["Computer"], Value::from("host-1"));
+        assert_eq!(row["ApiToken"], Value::from(MASK));
+        assert_eq!(row["Description"], Value::from("WS01 is a workstation"));
+    }
+}
@@ -0,0 +1,265 @@
+//! Compatibility with the output of Seatbelt.
+//!
+//! Commands that exist in both tools have a `Mapping` between the columns of
+//! the Rustbelt table and the properties of the Seatbelt DTO. The `seatbelt`
+//! and `seatbelt-json` formats use them to write Seatbelt's layouts, and
+//! `import` uses them to read Seatbelt JSON output back into Rustbelt tables,
+//! so old Seatbelt runs can be compared with new Rustbelt results.
+//!
+//! Seatbelt JSON output has one object per line, with the type of the DTO and
+//! its properties: `{"Type":"Seatbelt.Commands.Windows.OSInfoDTO","Data":{...}}`.
+
+use std::{fs, path::Path};
+
+use serde_json::{json, Map, Value as Json};
+
+use crate::{
+    commands::base::{
+        registry::schemas,
+        schema::{FieldType, Schema},
+        CommandDTO, CommandResult, Row, Value,
+    },
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// How a Rustbelt table corresponds to a Seatbelt command.
+///
+/// # Fields
+/// - `source`: The source of the Rustbelt table.
+/// - `command`: The name of the Seatbelt command, as in its section header.
+/// - `dto`: The type of the Seatbelt DTO, as in its JSON output.
+/// - `fields`: The Seatbelt properties in Seatbelt's order, with the Rustbelt column of each.
+///   Properties Rustbelt does not collect are left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub source: &'static str,
+    pub command: &'static str,
+    pub dto: &'static str,
+    pub fields: &'static [(&'static str, &'static str)],
+}
+
+/// The commands that exist in both tools.
+pub const MAPPINGS: &[Mapping] = &[
+    Mapping {
+        source: "Amsi Providers",
+        command: "AMSIProviders",
+        dto: "Seatbelt.Commands.Windows.AMSIProviderDTO",
+        fields: &[("ProviderPath", "AMSI Provider")],
+    },
+    Mapping {
+        source: "Antivirus",
+        command: "AntiVirus",
+        dto: "Seatbelt.Commands.Windows.AntiVirusDTO",
+        fields: &[
+            ("Engine", "displayName"),
+            ("ProductEXE", "pathToSignedProductExe"),
+            ("ReportingEXE", "pathToSignedReportingExe"),
+        ],
+    },
+    Mapping {
+        source: "Last Shutdown",
+        command: "LastShutdown",
+        dto: "Seatbelt.Commands.Windows.LastShutdownDTO",
+        fields: &[("LastShutdown", "Last Shutdown")],
+    },
+    Mapping {
+        source: "OSInfo",
+        command: "OSInfo",
+        dto: "Seatbelt.Commands.Windows.OSInfoDTO",
+        fields: &[
+            ("Hostname", "COMPUTERNAME"),
+            ("ProductName", "ProductName"),
+            ("EditionId", "EditionID"),
+            ("ReleaseId", "ReleaseId"),
+            ("Build", "CurrentBuildNumber"),
+            ("BuildBranch", "BuildBranch"),
+            ("CurrentMajorVersionNumber", "CurrentMajorVersionNumber"),
+            ("CurrentVersion", "CurrentVersion"),
+            ("Architecture", "PROCESSOR_ARCHITECTURE"),
+            ("ProcessorCount", "NUMBER_OF_PROCESSORS"),
+            ("BootTimeUtc", "BootTime"),
+            ("TimeZone", "TimeZone"),
+            ("MachineGuid", "MachineGuid"),
+        ],
+    },
+];
+
+/// Returns the mapping of a Rustbelt table, if the command exists in Seatbelt.
+pub fn mapping(source: &str) -> Option<&'static Mapping> {
+    MAPPINGS.iter().find(|mapping| mapping.source == source)
+}
+
+/// Returns the name of the Seatbelt command of a table, or its source if there is none.
+pub fn command_name(source: &str) -> &str {
+    mapping(source).map_or(source, |mapping| mapping.command)
+}
+
+/// Returns the properties of a row in Seatbelt's order, with Seatbelt's names.
+///
+/// Tables without a mapping keep their columns. Mapped columns the row
+/// does not have are `Value::Null`, so the layout stays the same.
+pub fn to_seatbelt<'a>(table: &CommandDTO, row: &'a Row) -> Vec<(&'a str, &'a Value)> {
+    match mapping(&table.source) {
+        Some(mapping) => mapping
+            .fields
+            .iter()
+            .map(|(property, column)| (*property, row.get(*column).unwrap_or(&Value::Null)))
+            .collect(),
+        None => row.iter().map(|(column, value)| (column.as_str(), value)).collect(),
+    }
+}
+
+/// Returns the type Seatbelt gives the DTO of a table, for its JSON output.
+pub fn dto_type(source: &str) -> String {
+    match mapping(source) {
+        Some(mapping) => mapping.dto.to_string(),
+        None => format!("Seatbelt.Commands.{}DTO", source.replace(' ', "")),
+    }
+}
+
+/// Converts a value to JSON the way Seatbelt serializes it: timestamps as `/Date(milliseconds)/`.
+pub fn to_seatbelt_json(value: &Value) -> Json {
+    match value {
+        Value::Timestamp(timestamp) => json!(format!("/Date({})/", timestamp.to_utc().timestamp_millis())),
+        Value::List(values) => Json::Array(values.iter().map(to_seatbelt_json).collect()),
+        value => value.to_json(),
+    }
+}
+
+/// Reads Seatbelt JSON output into Rustbelt tables.
+///
+/// Objects of a DTO with a mapping become rows of the Rustbelt table, with
+/// Rustbelt's column names and value types. Properties without a Rustbelt
+/// column, and DTOs of commands Rustbelt does not have, keep their Seatbelt
+/// names, with the command name derived from the DTO type. Consecutive rows
+/// of the same table are one table. Besides one object per line, a JSON
+/// array of such objects is accepted.
+///
+/// # Arguments
+///
+/// * `text` - The Seatbelt JSON output.
+///
+/// # Returns
+///
+/// * `Ok(CommandResult::Group)` with a table per command.
+/// * `Err(Error::InvalidData)` if the text is not Seatbelt JSON output.
+pub fn import(text: &str) -> Result<CommandResult> {
+    let invalid = |e: serde_json::Error| Error::InvalidData(format!("Seatbelt JSON: {e}"));
+    let objects: Vec<Json> = match text.trim_start().starts_with('[') {
+        true => serde_json::from_str(text).map_err(invalid)?,
+        false => text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(invalid))
+            .collect::<Result<_>>()?,
+    };
+
+    let schemas = schemas();
+    let mut tables: Vec<CommandDTO> = vec![];
+    for object in &objects {
+        let (Some(dto), Some(Json::Object(data))) = (object["Type"].as_str(), object.get("Data")) else {
+            return Err(Error::InvalidData(format!("Seatbelt JSON: not a DTO: {object}")));
+        };
+        let mapping = MAPPINGS.iter().find(|mapping| mapping.dto == dto);
+        let source = match mapping {
+            Some(mapping) => mapping.source.to_string(),
+            None => command_of(dto),
+        };
+        let schema = schemas.iter().map(|(_, schema)| *schema).find(|schema| schema.source == source);
+        let row = import_row(data, mapping, schema);
+
+        match tables.last_mut() {
+            Some(table) if table.source == source => table.data.push(row),
+            _ => tables.push(CommandDTO { source, data: vec![row] }),
+        }
+    }
+    Ok(CommandResult::Group(tables))
+}
+
+/// Reads a file of Seatbelt JSON output, see `import`.
+pub fn import_file(path: &Path) -> Result<CommandResult> {
+    import(&fs::read_to_string(path)?)
+}
+
+/// Derives the command name from a DTO type, such as `TokenPrivileges` from `Seatbelt.Commands.Windows.TokenPrivilegesDTO`.
+fn command_of(dto: &str) -> String {
+    let name = dto.rsplit('.').next().unwrap_or(dto);
+    name.strip_suffix("DTO").unwrap_or(name).to_string()
+}
+
+fn import_row(data: &Map<String, Json>, mapping: Option<&Mapping>, schema: Option<&Schema>) -> Row {
+    let mut row = Row::new();
+    for (property, json) in data {
+        let column = mapping
+            .and_then(|mapping| mapping.fields.iter().find(|(name, _)| name == property))
+            .map_or(property.as_str(), |(_, column)| *column);
+        let field_type = schema.and_then(|schema| schema.field(column)).map(|field| field.field_type);
+        row.insert(column.to_string(), import_value(json, field_type));
+    }
+    if let Some(schema) = schema {
+        schema.arrange(&mut row);
+    }
+    row
+}
+
+/// Converts a Seatbelt JSON value to the type of the Rustbelt column, where it is known.
+fn import_value(json: &Json, field_type: Option<FieldType>) -> Value {
+    match (field_type, json) {
+        (Some(FieldType::Timestamp), Json::String(text)) => parse_date(text).map_or_else(|| Value::from_json(json), Value::from),
+        (Some(FieldType::String), Json::Number(number)) => Value::from(number.to_string()),
+        (Some(FieldType::String), Json::Bool(value)) => Value::from(value.to_string()),
+        _ => Value::from_json(json),
+    }
+}
+
+/// Parses the dates of Seatbelt JSON output: `/Date(milliseconds)/` or ISO 8601, in UTC if no zone is given.
+fn parse_date(text: &str) -> Option<Timestamp> {
+    if let Some(millis) = text.strip_prefix("/Date(").and_then(|rest| rest.strip_suffix(")/")) {
+        // A zone offset may follow the milliseconds, which are in UTC regardless.
+        let end = millis[1..].find(['+', '-']).map_or(millis.len(), |index| index + 1);
+        return Timestamp::from_unix_millis(millis[..end].parse().ok()?).ok();
+    }
+    text.parse()
+        .ok()
+        .or_else(|| format!("{text}Z").parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import() {
+        let output = concat!(
+            r#"{"Type":"Seatbelt.Commands.Windows.OSInfoDTO","Data":{"Hostname":"WS01","ProcessorCount":8,"BootTimeUtc":"\/Date(1611234567000)\/","IsVirtualMachine":true,"MachineGuid":"3e1f"}}"#,
+            "\n\n",
+            r#"{"Type":"Seatbelt.Commands.Windows.AMSIProviderDTO","Data":{"GUID":"{2781761E}","ProviderPath":"C:\\mpoav.dll"}}"#,
+            "\n",
+            r#"{"Type":"Seatbelt.Commands.Windows.AMSIProviderDTO","Data":{"GUID":"{1}","ProviderPath":"C:\\other.dll"}}"#,
+            "\n",
+            r#"{"Type":"Seatbelt.Commands.Windows.TokenPrivilegesDTO","Data":{"Privilege":"SeDebugPrivilege"}}"#,
+        );
+        let CommandResult::Group(tables) = import(output).unwrap() else {
+            panic!("imports are groups");
+        };
+        let sources: Vec<&str> = tables.iter().map(|table| table.source.as_str()).collect();
+        assert_eq!(sources, ["OSInfo", "Amsi Providers", "TokenPrivileges"]);
+
+        // Mapped properties get Rustbelt's names, order and types, the others are kept.
+        let os = &tables[0].data[0];
+        let columns: Vec<&str> = os.keys().map(String::as_str).collect();
+        assert_eq!(columns, ["NUMBER_OF_PROCESSORS", "COMPUTERNAME", "BootTime", "MachineGuid", "IsVirtualMachine"]);
+        assert_eq!(os["NUMBER_OF_PROCESSORS"], Value::from("8"));
+        assert_eq!(os["BootTime"], Value::from(Timestamp::from_unix_millis(1_611_234_567_000).unwrap()));
+        assert_eq!(os["IsVirtualMachine"], Value::Bool(true));
+        assert_eq!(tables[1].data.len(), 2);
+        assert_eq!(tables[1].data[1]["AMSI Provider"], Value::from("C:\\other.dll"));
+        assert_eq!(tables[1].data[1]["GUID"], Value::from("{1}"));
+
+        assert_eq!(parse_date("/Date(1611234567000+0100)/"), Timestamp::from_unix_millis(1_611_234_567_000).ok());
+        assert_eq!(parse_date("2021-01-21T13:09:27"), "2021-01-21T13:09:27Z".parse().ok());
+        assert!(matches!(import("{\"Data\":{}}"), Err(Error::InvalidData(_))));
+        assert!(matches!(import("====== OSInfo ======"), Err(Error::InvalidData(_))));
+    }
+}
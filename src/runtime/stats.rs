@@ -0,0 +1,200 @@
+//! Statistics of the commands of a run, for tuning groups and spotting empty results.
+//!
+//! With `RunStats`, a runtime records for every command it runs, including
+//! the members of groups, when it started, how long it took, how many rows
+//! and bytes it returned and whether it failed, see `Runtime::with_stats`.
+
+use std::{
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    commands::base::{
+        schema::{Field, FieldTag, FieldType, Schema},
+        CommandDTO,
+        CommandResult::{self, Simple},
+        Row, Value,
+    },
+    error::{Error, Result},
+    runtime::limits::row_bytes,
+    utils::time::Timestamp,
+};
+
+/// The source of the table `RunStats::to_result` returns.
+pub const STATS_SOURCE: &str = "Command Statistics";
+
+/// The schema of the table `RunStats::to_result` returns, which redacts its hosts.
+pub const SCHEMA: Schema = Schema {
+    source: STATS_SOURCE,
+    fields: &[
+        Field::required("Command", FieldType::String, "The name of the command"),
+        Field::optional("Host", FieldType::String, "The remote computer the command ran on").tagged(FieldTag::Hostname),
+        Field::required("Started", FieldType::Timestamp, "When the command started"),
+        Field::required("Duration (ms)", FieldType::Unsigned, "How long the command ran, in milliseconds"),
+        Field::required("Rows", FieldType::Unsigned, "How many rows the command returned, before filters"),
+        Field::required("Bytes", FieldType::Unsigned, "How many bytes the rows took, measured as JSON"),
+        Field::required("Status", FieldType::String, "'ok', 'partial' or 'failed'"),
+        Field::optional("Error", FieldType::String, "Why the command failed or stopped early"),
+    ],
+    identity: &["Command", "Host", "Started"],
+};
+
+/// How a command ended.
+///
+/// # Variants
+/// - `Ok`: The command returned its result.
+/// - `Partial`: The command reached a limit and returned part of its result.
+/// - `Failed`: The command failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Partial,
+    Failed,
+}
+
+impl Status {
+    /// Returns the name of the status, as in the statistics table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Partial => "partial",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// What a command did.
+///
+/// # Fields
+/// - `command`: The name of the command.
+/// - `host`: The remote computer of the runtime, if any.
+/// - `started`: When the command started.
+/// - `duration`: How long it ran.
+/// - `rows`: How many rows it returned, before filters.
+/// - `bytes`: How many bytes the rows took, measured as JSON.
+/// - `status`: How it ended.
+/// - `error`: Why it failed or is partial, if it did or is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandStats {
+    pub command: String,
+    pub host: Option<String>,
+    pub started: Timestamp,
+    pub duration: Duration,
+    pub rows: usize,
+    pub bytes: usize,
+    pub status: Status,
+    pub error: Option<String>,
+}
+
+impl CommandStats {
+    /// Describes a command from its outcome.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The name of the command.
+    /// * `host` - The remote computer of the runtime, if any.
+    /// * `started` - When the command started.
+    /// * `duration` - How long it ran.
+    /// * `outcome` - What it returned.
+    pub fn new(
+        command: &str,
+        host: Option<&str>,
+        started: Timestamp,
+        duration: Duration,
+        outcome: &Result<CommandResult>,
+    ) -> Self {
+        let (result, status) = match outcome {
+            Ok(result) => (Some(result), Status::Ok),
+            Err(Error::Partial(partial)) => (Some(&partial.result), Status::Partial),
+            Err(_) => (None, Status::Failed),
+        };
+        let rows = result.into_iter().flat_map(CommandResult::tables).flat_map(|table| &table.data);
+        let (rows, bytes) = rows.fold((0, 0), |(rows, bytes), row| (rows + 1, bytes + row_bytes(row)));
+        CommandStats {
+            command: command.to_string(),
+            host: host.map(str::to_string),
+            started,
+            duration,
+            rows,
+            bytes,
+            status,
+            error: outcome.as_ref().err().map(Error::to_string),
+        }
+    }
+}
+
+/// The statistics of the commands of a run, which may be shared by the runtimes of many targets.
+#[derive(Default)]
+pub struct RunStats {
+    commands: Mutex<Vec<CommandStats>>,
+}
+
+impl RunStats {
+    /// Creates empty statistics.
+    pub fn new() -> Self {
+        RunStats::default()
+    }
+
+    /// Adds the statistics of a command.
+    pub fn record(&self, stats: CommandStats) {
+        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(stats);
+    }
+
+    /// Returns the statistics of the commands so far, in the order they ended.
+    pub fn commands(&self) -> Vec<CommandStats> {
+        self.commands.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// Returns the statistics as a table, to be output with the results.
+    ///
+    /// # Returns
+    ///
+    /// A simple result with a row per command: the command, the host, when it
+    /// started, its duration in milliseconds, its rows and bytes, its status
+    /// and its error.
+    pub fn to_result(&self) -> CommandResult {
+        let data = self
+            .commands()
+            .into_iter()
+            .map(|stats| {
+                Row::from([
+                    ("Command".to_string(), Value::from(stats.command)),
+                    ("Host".to_string(), stats.host.map_or(Value::Null, Value::from)),
+                    ("Started".to_string(), Value::from(stats.started)),
+                    ("Duration (ms)".to_string(), Value::from(stats.duration.as_millis() as u64)),
+                    ("Rows".to_string(), Value::from(stats.rows as u64)),
+                    ("Bytes".to_string(), Value::from(stats.bytes as u64)),
+                    ("Status".to_string(), Value::from(stats.status.name())),
+                    ("Error".to_string(), stats.error.map_or(Value::Null, Value::from)),
+                ])
+            })
+            .collect();
+        Simple(CommandDTO { source: STATS_SOURCE.to_string(), data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::limits::{Limit, PartialResult};
+
+    #[test]
+    fn test_partial_stats() {
+        let row = Row::from([("AMSI Provider".to_string(), Value::from("a.dll"))]);
+        let partial = PartialResult {
+            result: Simple(CommandDTO { source: "Amsi Providers".to_string(), data: vec![row.clone(), row] }),
+            stopped: vec![("amsiproviders".to_string(), Limit::Rows(2))],
+        };
+        let outcome = Err(Error::Partial(Box::new(partial)));
+        let stats = CommandStats::new("amsiproviders", Some("host"), Timestamp::now(), Duration::from_millis(12), &outcome);
+        assert_eq!((stats.rows, stats.bytes, stats.status), (2, 2 * r#"{"AMSI Provider":"a.dll"}"#.len(), Status::Partial));
+        assert!(stats.error.is_some());
+
+        let run = RunStats::new();
+        run.record(stats);
+        let result = run.to_result();
+        SCHEMA.validate(&result.tables()[0]).unwrap();
+        assert_eq!(result.tables()[0].data[0]["Duration (ms)"], Value::from(12u64));
+    }
+}
@@ -0,0 +1,305 @@
+//! Runs commands against many targets, such as a fleet of hosts or a directory of images.
+//!
+//! Every target gets its own `Runtime`, created by a caller-provided
+//! function, and is processed by one of a bounded number of worker threads.
+//! The results of each target are kept apart in a `TargetReport`, and can be
+//! tagged with their target and merged into one dataset with `merge`.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use super::Runtime;
+use crate::{
+    commands::base::{CommandDTO, CommandResult, Value},
+    error::{Error, Result},
+};
+
+/// The column that tagged results carry their target in.
+pub const TARGET_COLUMN: &str = "Target";
+
+/// A machine to run commands against.
+///
+/// # Variants
+/// - `Host`: A remote computer, by name or address, optionally with a port.
+/// - `Image`: The root of an offline Windows image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Host(String),
+    Image(PathBuf),
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    /// Parses a target. Existing directories are images, anything else is a host name.
+    fn from_str(text: &str) -> Result<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(Error::InvalidData("empty target".to_string()));
+        }
+        let path = Path::new(text);
+        if path.is_dir() {
+            Ok(Target::Image(path.to_path_buf()))
+        } else {
+            Ok(Target::Host(text.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Host(host) => write!(f, "{host}"),
+            Target::Image(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Reads a list of targets, one per line. Empty lines and lines starting with `#` are skipped.
+///
+/// # Arguments
+///
+/// * `path` - The path of the list.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Target>)` containing the targets in the order of the list.
+/// * `Err(e)` if the list could not be read.
+pub fn read_targets(path: &Path) -> Result<Vec<Target>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Target::from_str)
+        .collect()
+}
+
+/// The outcome of one command on one target.
+///
+/// # Fields
+/// - `command`: The name of the command.
+/// - `result`: The result of the command, or the error it failed with.
+#[derive(Debug)]
+pub struct CommandRun {
+    pub command: String,
+    pub result: Result<CommandResult>,
+}
+
+/// The outcome of all commands on one target.
+///
+/// # Fields
+/// - `target`: The target.
+/// - `outcome`: The runs of the commands, or the error if the target could not be reached.
+#[derive(Debug)]
+pub struct TargetReport {
+    pub target: Target,
+    pub outcome: Result<Vec<CommandRun>>,
+}
+
+impl TargetReport {
+    /// Returns the successful and partial results of this target as one group, with every row tagged with the target.
+    pub fn tagged_result(&self) -> CommandResult {
+        let mut tables = vec![];
+        if let Ok(runs) = &self.outcome {
+            for run in runs {
+                let result = match &run.result {
+                    Ok(result) => result,
+                    Err(Error::Partial(partial)) => &partial.result,
+                    Err(_) => continue,
+                };
+                tables.extend(result.tables().iter().map(|table| tag(table.clone(), &self.target)));
+            }
+        }
+        CommandResult::Group(tables)
+    }
+}
+
+fn tag(mut table: CommandDTO, target: &Target) -> CommandDTO {
+    for row in &mut table.data {
+        row.insert(TARGET_COLUMN.to_string(), Value::from(target.to_string()));
+    }
+    table
+}
+
+/// Runs commands against targets.
+///
+/// # Arguments
+///
+/// * `targets` - The targets, processed in order.
+/// * `commands` - The commands or groups to run on every target, with their arguments.
+/// * `concurrency` - The most targets processed at the same time, at least one.
+/// * `connect` - Creates the runtime of a target. Runtimes are created and used on the worker threads,
+///   and the commands of a target are spaced out by the delay of its runtime.
+///
+/// # Returns
+///
+/// The reports of the targets, in the order of `targets`.
+pub fn run_targets<F>(targets: &[Target], commands: &[(String, Vec<String>)], concurrency: usize, connect: F) -> Vec<TargetReport>
+where
+    F: Fn(&Target) -> Result<Runtime> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<TargetReport>>> = Mutex::new(targets.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, targets.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(target) = targets.get(index) else {
+                    break;
+                };
+
+                let outcome = connect(target).map(|runtime| {
+                    commands
+                        .iter()
+                        .enumerate()
+                        .map(|(index, (command, args))| {
+                            if index > 0 {
+                                thread::sleep(runtime.delay());
+                            }
+                            CommandRun {
+                                command: command.clone(),
+                                result: runtime.execute(command, args),
+                            }
+                        })
+                        .collect()
+                });
+                let report = TargetReport {
+                    target: target.clone(),
+                    outcome,
+                };
+                reports.lock().expect("a worker panicked")[index] = Some(report);
+            });
+        }
+    });
+
+    reports
+        .into_inner()
+        .expect("a worker panicked")
+        .into_iter()
+        .map(|report| report.expect("every target is processed"))
+        .collect()
+}
+
+/// Merges the results of all targets into one dataset.
+///
+/// Tables with the same source are combined into one, and every row is
+/// tagged with its target in the `Target` column. Tables keep the order in
+/// which their source first appears.
+pub fn merge(reports: &[TargetReport]) -> CommandResult {
+    let mut tables: Vec<CommandDTO> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for report in reports {
+        let CommandResult::Group(tagged) = report.tagged_result() else {
+            unreachable!("tagged results are groups");
+        };
+        for table in tagged {
+            match positions.get(&table.source) {
+                Some(&position) => tables[position].data.extend(table.data),
+                None => {
+                    positions.insert(table.source.clone(), tables.len());
+                    tables.push(table);
+                }
+            }
+        }
+    }
+    CommandResult::Group(tables)
+}
+
+/// What went wrong in a multi-target run.
+///
+/// # Fields
+/// - `targets`: The number of targets.
+/// - `unreachable`: The targets that could not be reached, with the reason.
+/// - `failed`: The commands that failed, with their target and the reason.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub targets: usize,
+    pub unreachable: Vec<(String, String)>,
+    pub failed: Vec<(String, String, String)>,
+}
+
+impl RunSummary {
+    /// Summarizes the reports of a run.
+    pub fn new(reports: &[TargetReport]) -> Self {
+        let mut summary = RunSummary {
+            targets: reports.len(),
+            ..Default::default()
+        };
+        for report in reports {
+            let target = report.target.to_string();
+            match &report.outcome {
+                Err(e) => summary.unreachable.push((target, e.to_string())),
+                Ok(runs) => {
+                    for run in runs {
+                        if let Err(e) = &run.result {
+                            summary.failed.push((target.clone(), run.command.clone(), e.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "==[Run summary]==\n{} targets, {} reachable, {} failed commands",
+            self.targets,
+            self.targets - self.unreachable.len(),
+            self.failed.len()
+        )?;
+        if !self.unreachable.is_empty() {
+            write!(f, "\nUnreachable targets:")?;
+            for (target, error) in &self.unreachable {
+                write!(f, "\n\t{target} : {error}")?;
+            }
+        }
+        if !self.failed.is_empty() {
+            write!(f, "\nFailed commands:")?;
+            for (target, command, error) in &self.failed {
+                write!(f, "\n\t{target} : {command} : {error}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_targets() {
+        let dir = std::env::temp_dir().join(format!("rustbelt-targets-{}", std::process::id()));
+        fs::create_dir_all(dir.join("image")).unwrap();
+        let list = dir.join("targets.txt");
+        let image = dir.join("image");
+        fs::write(&list, format!("# fleet\nhost1\n\n  10.0.0.2:445 \n{}\n", image.display())).unwrap();
+
+        let targets = read_targets(&list).unwrap();
+        assert_eq!(
+            targets,
+            vec![
+                Target::Host("host1".to_string()),
+                Target::Host("10.0.0.2:445".to_string()),
+                Target::Image(image),
+            ]
+        );
+        assert!("  ".parse::<Target>().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Differences between passes of the same commands, for `--watch`.
+//!
+//! A `Watcher` keeps the rows of the previous pass of every table, by their
+//! identity keys, see `Schema::identity`. Diffing a new result against them
+//! returns only the rows that were added, removed or changed, with the kind
+//! of change in the `Change` column. The result of a command that stopped at
+//! a limit is diffed with `Watcher::diff_partial`, which cannot tell removed
+//! rows from rows the command did not reach.
+
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+use serde_json::Value as Json;
+
+use crate::commands::base::{
+    registry::schemas,
+    CommandDTO,
+    CommandResult::{self, Group},
+    Row, Value,
+};
+
+/// The column that tells whether a row was `added`, `removed` or `changed`.
+pub const CHANGE_COLUMN: &str = "Change";
+
+/// The column that lists the columns of a changed row whose values changed.
+pub const CHANGED_COLUMNS: &str = "Changed Columns";
+
+/// The column that marks the changes of a partial pass, see `Watcher::diff_partial`.
+pub const PARTIAL_COLUMN: &str = "Partial";
+
+/// The rows of the previous pass of every table, to diff the next pass against.
+#[derive(Default)]
+pub struct Watcher {
+    tables: HashMap<String, IndexMap<String, Row>>,
+}
+
+impl Watcher {
+    /// Creates a watcher that has not seen any pass, so every row of the first one is added.
+    pub fn new() -> Self {
+        Watcher::default()
+    }
+
+    /// Diffs a result against the previous pass of its tables and keeps it for the next one.
+    ///
+    /// Rows are matched by the identity columns of the schema of their table.
+    /// Tables without a schema, and tables of several rows without identity
+    /// columns, match rows by all their values and how many equal rows came
+    /// before, so a change shows as a removed and an added row, and so do
+    /// duplicates. Tables that are not in the result, such as those of a
+    /// command that failed, keep their previous pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - The result of the new pass.
+    ///
+    /// # Returns
+    ///
+    /// A group with a table per table of the result that changed. Its rows are
+    /// the added and changed rows as they are now and the removed rows as they
+    /// were, each with `Change` first and, for changed rows, `Changed Columns`.
+    pub fn diff(&mut self, result: &CommandResult) -> CommandResult {
+        self.compare(result, false)
+    }
+
+    /// Diffs what a command returned before it reached a limit against the previous pass.
+    ///
+    /// Rows the command did not reach are missing from such a result, so no
+    /// row is reported as removed. The rows of the result are merged into the
+    /// previous pass instead of replacing it, so the next full pass does not
+    /// report the rows that are missing now as added.
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - The partial result of the new pass.
+    ///
+    /// # Returns
+    ///
+    /// Like `diff`, without removed rows, and with `Partial` set after `Change` in every row.
+    pub fn diff_partial(&mut self, result: &CommandResult) -> CommandResult {
+        self.compare(result, true)
+    }
+
+    fn compare(&mut self, result: &CommandResult, partial: bool) -> CommandResult {
+        let schemas = schemas();
+        let mut changes = vec![];
+        for table in result.tables() {
+            let identity = schemas
+                .iter()
+                .find(|(_, schema)| schema.source == table.source)
+                .map(|(_, schema)| schema.identity)
+                .filter(|identity| !identity.is_empty() || table.data.len() <= 1);
+            // Rows matched by all their values are told apart by how many equal rows came before.
+            let mut occurrences: HashMap<String, usize> = HashMap::new();
+            let current: IndexMap<String, Row> = table
+                .data
+                .iter()
+                .map(|row| {
+                    let key = key(row, identity);
+                    if identity.is_some() {
+                        return (key, row.clone());
+                    }
+                    let occurrence = occurrences.entry(key.clone()).or_default();
+                    *occurrence += 1;
+                    (format!("{key}#{occurrence}"), row.clone())
+                })
+                .collect();
+            let previous = self.tables.remove(&table.source).unwrap_or_default();
+
+            let mut data = vec![];
+            for (key, row) in &current {
+                match previous.get(key) {
+                    None => data.push(change("added", row, None)),
+                    Some(old) if old != row => data.push(change("changed", row, Some(old))),
+                    Some(_) => {}
+                }
+            }
+            if partial {
+                for row in &mut data {
+                    row.shift_insert(1, PARTIAL_COLUMN.to_string(), Value::from(true));
+                }
+            } else {
+                for (key, old) in &previous {
+                    if !current.contains_key(key) {
+                        data.push(change("removed", old, None));
+                    }
+                }
+            }
+            let kept = match partial {
+                true => previous.into_iter().chain(current).collect(),
+                false => current,
+            };
+            self.tables.insert(table.source.clone(), kept);
+            if !data.is_empty() {
+                changes.push(CommandDTO { source: table.source.clone(), data });
+            }
+        }
+        Group(changes)
+    }
+}
+
+/// Returns the key of a row: the values of the identity columns, or all its values without them.
+fn key(row: &Row, identity: Option<&[&str]>) -> String {
+    let values: Vec<Json> = match identity {
+        Some(columns) => columns.iter().map(|column| row.get(*column).map_or(Json::Null, Value::to_json)).collect(),
+        None => row.iter().flat_map(|(column, value)| [Json::from(column.as_str()), value.to_json()]).collect(),
+    };
+    Json::Array(values).to_string()
+}
+
+/// Returns a row with the kind of change first, and the columns that changed since the old row.
+fn change(kind: &str, row: &Row, old: Option<&Row>) -> Row {
+    let mut changed = Row::from([(CHANGE_COLUMN.to_string(), Value::from(kind))]);
+    if let Some(old) = old {
+        let mut columns: Vec<String> = row
+            .iter()
+            .filter(|(column, value)| old.get(*column) != Some(value))
+            .map(|(column, _)| column.clone())
+            .collect();
+        columns.extend(old.keys().filter(|column| !row.contains_key(*column)).cloned());
+        changed.insert(CHANGED_COLUMNS.to_string(), Value::from(columns));
+    }
+    changed.extend(row.iter().map(|(column, value)| (column.clone(), value.clone())));
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(source: &str, rows: &[(&str, &str)]) -> CommandResult {
+        let data = rows
+            .iter()
+            .map(|(provider, time)| {
+                Row::from([
+                    ("AMSI Provider".to_string(), Value::from(*provider)),
+                    ("Last Write Time".to_string(), Value::from(*time)),
+                ])
+            })
+            .collect();
+        CommandResult::Simple(CommandDTO { source: source.to_string(), data })
+    }
+
+    fn changes(result: &CommandResult) -> Vec<(String, String)> {
+        result
+            .tables()
+            .iter()
+            .flat_map(|table| &table.data)
+            .map(|row| (row[CHANGE_COLUMN].to_string(), row["AMSI Provider"].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut watcher = Watcher::new();
+        let first = watcher.diff(&table("Amsi Providers", &[("a.dll", "1"), ("b.dll", "1")]));
+        assert_eq!(changes(&first), [("added".into(), "a.dll".into()), ("added".into(), "b.dll".into())]);
+        assert!(watcher.diff(&table("Amsi Providers", &[("a.dll", "1"), ("b.dll", "1")])).tables().is_empty());
+
+        // Rows are matched by the identity of the schema, other columns may change.
+        let next = watcher.diff(&table("Amsi Providers", &[("a.dll", "2"), ("c.dll", "1")]));
+        assert_eq!(
+            changes(&next),
+            [("changed".into(), "a.dll".into()), ("added".into(), "c.dll".into()), ("removed".into(), "b.dll".into())]
+        );
+        assert_eq!(next.tables()[0].data[0][CHANGED_COLUMNS], Value::from(vec!["Last Write Time".to_string()]));
+
+        // Without a schema, rows are matched by all their values.
+        watcher.diff(&table("Plugin", &[("a.dll", "1")]));
+        let next = watcher.diff(&table("Plugin", &[("a.dll", "2")]));
+        assert_eq!(changes(&next), [("added".into(), "a.dll".into()), ("removed".into(), "a.dll".into())]);
+    }
+
+    #[test]
+    fn test_diff_partial() {
+        let mut watcher = Watcher::new();
+        watcher.diff(&table("Amsi Providers", &[("a.dll", "1"), ("b.dll", "1"), ("c.dll", "1")]));
+
+        // The rows the command did not reach are not removed, and changes are marked as partial.
+        let next = watcher.diff_partial(&table("Amsi Providers", &[("a.dll", "2")]));
+        assert_eq!(changes(&next), [("changed".into(), "a.dll".into())]);
+        let columns: Vec<&str> = next.tables()[0].data[0].keys().map(String::as_str).collect();
+        assert_eq!(columns[..3], [CHANGE_COLUMN, PARTIAL_COLUMN, CHANGED_COLUMNS]);
+        assert_eq!(next.tables()[0].data[0][PARTIAL_COLUMN], Value::from(true));
+
+        // They are kept for the next full pass, which compares against the merged rows.
+        let next = watcher.diff(&table("Amsi Providers", &[("a.dll", "2"), ("b.dll", "1")]));
+        assert_eq!(changes(&next), [("removed".into(), "c.dll".into())]);
+        assert!(!next.tables()[0].data[0].contains_key(PARTIAL_COLUMN));
+    }
+
+    #[test]
+    fn test_diff_duplicates() {
+        // Rows matched by all their values may repeat, and every copy counts.
+        let mut watcher = Watcher::new();
+        watcher.diff(&table("Plugin", &[("a.dll", "1")]));
+        let next = watcher.diff(&table("Plugin", &[("a.dll", "1"), ("a.dll", "1")]));
+        assert_eq!(changes(&next), [("added".into(), "a.dll".into())]);
+        let next = watcher.diff(&table("Plugin", &[("a.dll", "1"), ("b.dll", "1")]));
+        assert_eq!(changes(&next), [("added".into(), "b.dll".into()), ("removed".into(), "a.dll".into())]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::Writer;
+use crate::{
+    error::{Error, Result},
+    utils::bundle::{BundleEncoder, RecipientKey},
+};
+
+/// Writes the output compressed and encrypted to a recipient, see `utils::bundle`.
+///
+/// The bundle is only complete once the writer is finished. Open it with
+/// `utils::bundle::open` or the `decrypt` subcommand.
+pub struct BundleWriter<W: Write> {
+    encoder: Option<BundleEncoder<W>>,
+    output: Option<W>,
+}
+
+impl BundleWriter<BufWriter<File>> {
+    /// Creates a bundle file, or truncates it if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the bundle.
+    /// * `recipient` - The public key to encrypt the bundle to.
+    pub fn create(path: &Path, recipient: &RecipientKey) -> Result<Self> {
+        BundleWriter::new(BufWriter::new(File::create(path)?), recipient)
+    }
+}
+
+impl<W: Write> BundleWriter<W> {
+    /// Starts a bundle on any destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Where the bundle is written to.
+    /// * `recipient` - The public key to encrypt the bundle to.
+    pub fn new(inner: W, recipient: &RecipientKey) -> Result<Self> {
+        Ok(BundleWriter {
+            encoder: Some(BundleEncoder::new(inner, recipient)?),
+            output: None,
+        })
+    }
+
+    /// Returns the destination of a finished bundle.
+    pub fn into_inner(self) -> Option<W> {
+        self.output
+    }
+}
+
+impl<W: Write> Writer for BundleWriter<W> {
+    fn write_line(&mut self, line: String) -> Result<()> {
+        let encoder = self
+            .encoder
+            .as_mut()
+            .ok_or_else(|| Error::Unsupported("writing to a finished bundle".to_string()))?;
+        encoder.write_all(line.as_bytes())?;
+        encoder.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            let mut output = encoder.finish()?;
+            output.flush()?;
+            self.output = Some(output);
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
 use super::Writer;
+use crate::error::Result;
 
+#[derive(Default)]
 pub struct ConsoleWriter {}
 
 impl Writer for ConsoleWriter {
-    fn write_line(line: String) -> () {
-        println!("{}", line)
+    fn write_line(&mut self, line: String) -> Result<()> {
+        println!("{}", line);
+        Ok(())
     }
 }
@@ -0,0 +1,43 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use super::Writer;
+use crate::error::Result;
+
+/// Writes the output to a file in plain text.
+pub struct FileWriter {
+    file: BufWriter<File>,
+}
+
+impl FileWriter {
+    /// Creates the file, or truncates it if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file.
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(FileWriter {
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl Writer for FileWriter {
+    fn write_line(&mut self, line: String) -> Result<()> {
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
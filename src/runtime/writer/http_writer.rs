@@ -0,0 +1,276 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use super::{bundle_writer::BundleWriter, file_writer::FileWriter, Writer};
+use crate::{
+    error::{Error, Result},
+    utils::{
+        bundle::{BundleEncoder, RecipientKey},
+        collector::{BUNDLE_CONTENT_TYPE, RUN_HEADER, SEQUENCE_HEADER},
+        http::{HttpConnection, Request, Url},
+    },
+};
+
+/// The content type of plain uploads.
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Posts the output to a collector, see `utils::collector`.
+///
+/// Lines are collected into chunks of at most `chunk_size` bytes, unless a
+/// single line is larger, and every chunk is posted as soon as it is full.
+/// Chunks carry the identifier of the run and their sequence number, and are
+/// encrypted to a recipient if one is set, each chunk as its own bundle.
+///
+/// Failed uploads are retried with an exponential backoff when the
+/// collector could not be reached or answered with a temporary error. If a
+/// chunk still cannot be delivered, it and all later chunks are written to a
+/// spool file instead, if a spool directory is set.
+pub struct HttpWriter {
+    url: Url,
+    headers: Vec<(String, String)>,
+    content_type: String,
+    chunk_size: usize,
+    retries: u32,
+    retry_delay: Duration,
+    timeout: Duration,
+    accept_invalid_certs: bool,
+    recipient: Option<RecipientKey>,
+    spool_dir: Option<PathBuf>,
+    run: String,
+    sequence: u64,
+    buffer: String,
+    connection: Option<HttpConnection>,
+    failure: Option<Error>,
+    spool: Option<Box<dyn Writer>>,
+}
+
+impl HttpWriter {
+    /// Creates a writer that posts to a collector, with a new random run identifier.
+    ///
+    /// By default, chunks are up to 1 MiB of NDJSON, failed uploads are
+    /// retried 3 times starting after one second, and nothing is spooled.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL to post to.
+    pub fn new(url: Url) -> Result<Self> {
+        let mut run = [0; 8];
+        getrandom::fill(&mut run).map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok(HttpWriter {
+            url,
+            headers: vec![],
+            content_type: NDJSON_CONTENT_TYPE.to_string(),
+            chunk_size: 1024 * 1024,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(30),
+            accept_invalid_certs: false,
+            recipient: None,
+            spool_dir: None,
+            run: run.iter().map(|byte| format!("{byte:02x}")).collect(),
+            sequence: 0,
+            buffer: String::new(),
+            connection: None,
+            failure: None,
+            spool: None,
+        })
+    }
+
+    /// Adds a header to every upload, for example an auth token.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the content type of plain uploads, NDJSON by default.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    /// Sets the most bytes of output in one upload.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets how often a failed upload is retried, and the delay before the first retry.
+    pub fn retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Sets the timeout for connecting and for every read and write.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets whether invalid certificates of HTTPS collectors are accepted.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Encrypts every upload, and the spool file, to a recipient.
+    pub fn encrypt_to(mut self, recipient: RecipientKey) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    /// Writes the output that could not be delivered to a file in this directory.
+    pub fn spool(mut self, dir: &Path) -> Self {
+        self.spool_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Returns the identifier of the run, sent with every upload.
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Returns the file output is spooled to if the collector cannot be reached.
+    pub fn spool_path(&self) -> Option<PathBuf> {
+        let extension = if self.recipient.is_some() { "bundle" } else { "ndjson" };
+        self.spool_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("rustbelt-{}.{extension}", self.run)))
+    }
+
+    /// Uploads or spools the collected lines.
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+
+        if self.failure.is_none() {
+            match self.upload(chunk.as_bytes()) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.failure = Some(e),
+            }
+        }
+
+        if self.spool.is_none() {
+            let Some(path) = self.spool_path() else {
+                return Err(self.failure.take().expect("the upload failed"));
+            };
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            self.spool = Some(match &self.recipient {
+                Some(recipient) => Box::new(BundleWriter::create(&path, recipient)?),
+                None => Box::new(FileWriter::create(&path)?),
+            });
+        }
+        let spool = self.spool.as_mut().expect("the spool is open");
+        spool.write_line(chunk.trim_end_matches('\n').to_string())
+    }
+
+    /// Posts a chunk, retrying temporary failures.
+    fn upload(&mut self, chunk: &[u8]) -> Result<()> {
+        let (content_type, body) = match &self.recipient {
+            Some(recipient) => {
+                let mut encoder = BundleEncoder::new(vec![], recipient)?;
+                encoder.write_all(chunk)?;
+                (BUNDLE_CONTENT_TYPE.to_string(), encoder.finish()?)
+            }
+            None => (self.content_type.clone(), chunk.to_vec()),
+        };
+
+        let mut request = Request::new("POST", &self.url.path)
+            .header("Content-Type", &content_type)
+            .header(RUN_HEADER, &self.run)
+            .header(SEQUENCE_HEADER, &self.sequence.to_string())
+            .body(body);
+        request.headers.extend(self.headers.iter().cloned());
+
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.send(&request) {
+                Ok(()) => break,
+                Err((e, temporary)) => {
+                    if !temporary || attempt == self.retries {
+                        return Err(e);
+                    }
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Sends a request once.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the collector accepted the request.
+    /// * `Err((e, temporary))` otherwise, with whether the failure is temporary and worth retrying.
+    fn send(&mut self, request: &Request) -> std::result::Result<(), (Error, bool)> {
+        if self.connection.is_none() {
+            let connection = HttpConnection::connect(&self.url, self.timeout, self.accept_invalid_certs)
+                .map_err(|e| (e, true))?;
+            self.connection = Some(connection);
+        }
+        let connection = self.connection.as_mut().expect("the connection is open");
+
+        let response = match connection.send(request) {
+            Ok(response) => response,
+            Err(e) => {
+                self.connection = None;
+                return Err((e, true));
+            }
+        };
+        if response
+            .get_header("Connection")
+            .is_some_and(|connection| connection.eq_ignore_ascii_case("close"))
+        {
+            self.connection = None;
+        }
+
+        match response.status {
+            200..=299 => Ok(()),
+            status => Err((
+                Error::Remote(format!("collector {} answered {status} {}", self.url, response.reason)),
+                matches!(status, 408 | 429 | 500..=599),
+            )),
+        }
+    }
+}
+
+impl Writer for HttpWriter {
+    fn write_line(&mut self, line: String) -> Result<()> {
+        for line in line.lines().filter(|line| !line.is_empty()) {
+            if !self.buffer.is_empty() && self.buffer.len() + line.len() + 1 > self.chunk_size {
+                self.flush_chunk()?;
+            }
+            self.buffer.push_str(line);
+            self.buffer.push('\n');
+        }
+        if self.buffer.len() >= self.chunk_size {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_chunk()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush_chunk()?;
+        if let Some(spool) = self.spool.as_mut() {
+            spool.finish()?;
+        }
+        Ok(())
+    }
+}
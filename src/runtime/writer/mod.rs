@@ -1,5 +1,28 @@
+pub mod bundle_writer;
 pub mod console_writer;
+pub mod file_writer;
+pub mod http_writer;
 
+use crate::error::Result;
+
+/// Trait implemented by every output destination.
 pub trait Writer {
-    fn write_line(line: String) -> ();
+    /// Writes a line of formatted output.
+    fn write_line(&mut self, line: String) -> Result<()>;
+
+    /// Passes the lines written so far on, for output that is followed while it is produced, such as `--watch`.
+    ///
+    /// Writers that can only complete their output at the end, such as
+    /// encrypted files, keep it until they are finished.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Completes the output after the last line.
+    ///
+    /// Writers that buffer or encode their output must be finished, or the
+    /// output may be incomplete. Nothing may be written after this.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
 }
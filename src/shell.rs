@@ -0,0 +1,493 @@
+//! The interactive shell.
+//!
+//! The shell keeps one `Runtime` for the whole session, so a loaded offline
+//! image or a remote connection is reused by every command. Besides running
+//! commands and groups, it switches the output format, filters and projects
+//! results with the expressions of `runtime::query`, and browses the
+//! registry of the runtime directly. Lines are read with completion of
+//! command names, verbs and registry keys, and with a history.
+
+use std::{io::Write, path::Path};
+
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+
+use crate::{
+    commands::base::registry::{command_names, get_command},
+    error::{Error, Result},
+    runtime::{
+        formatter::{self, FORMATS},
+        query::{Filter, Query},
+        Runtime,
+    },
+    utils::{
+        registry::{reg_file::root_hive, RegistryHive, RegistryValue},
+        time::TimeDisplay,
+    },
+};
+
+/// The verbs of the shell, next to the names of the commands.
+const VERBS: [&str; 11] = ["cd", "commands", "exit", "format", "get", "help", "ls", "pwd", "select", "show", "where"];
+
+/// The roots of registry paths, as completed.
+const HIVES: [&str; 5] = ["HKCR", "HKCC", "HKCU", "HKLM", "HKU"];
+
+/// Returns the help of the shell.
+fn help() -> String {
+    format!(
+        "\
+<command> [args]     Run a command or group, such as 'osinfo' or 'group:misc'
+commands             List the commands and groups
+format [FORMAT]      Show or switch the output format: {formats}
+where [EXPRESSION]   Only show rows that satisfy the expression, or show all rows again
+select [COLUMNS]     Only show these comma-separated columns, or all columns again
+show                 Show the format, filter, columns and current key
+cd [KEY]             Go to a registry key, such as 'HKLM\\SOFTWARE' or '..', or back to the top
+ls [KEY]             List the subkeys and values of a registry key
+get [KEY] VALUE      Print a registry value, of the current key if no key is given
+pwd                  Print the current registry key
+help                 Print this help
+exit                 Leave the shell",
+        formats = FORMATS.join(", ")
+    )
+}
+
+/// What the shell does after a line.
+///
+/// # Variants
+/// - `Continue`: Read the next line.
+/// - `Exit`: End the session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Exit,
+}
+
+/// The state of an interactive session.
+pub struct Shell {
+    runtime: Runtime,
+    format: String,
+    time: TimeDisplay,
+    query: Query,
+    filter: Option<String>,
+    key: Option<(RegistryHive, String)>,
+}
+
+impl Shell {
+    /// Creates a session on a runtime, with simple output and without filters.
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - The runtime every command of the session runs in.
+    pub fn new(runtime: Runtime) -> Self {
+        Shell {
+            runtime,
+            format: "simple".to_string(),
+            time: TimeDisplay::default(),
+            query: Query::default(),
+            filter: None,
+            key: None,
+        }
+    }
+
+    /// Sets the output format, one of `formatter::FORMATS`.
+    pub fn with_format(mut self, format: &str) -> Result<Self> {
+        self.set_format(format)?;
+        Ok(self)
+    }
+
+    /// Sets how timestamps are rendered in simple output.
+    pub fn with_time(mut self, time: TimeDisplay) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Sets the filter expression results are filtered with, see `runtime::query`.
+    pub fn with_filter(mut self, expression: &str) -> Result<Self> {
+        self.set_filter(expression)?;
+        Ok(self)
+    }
+
+    /// Sets the columns results are projected onto.
+    pub fn with_columns(mut self, columns: Vec<String>) -> Self {
+        self.query.columns = columns;
+        self
+    }
+
+    /// Returns the prompt, with the current registry key if there is one.
+    pub fn prompt(&self) -> String {
+        match &self.key {
+            Some(key) => format!("rustbelt {}> ", key_name(key)),
+            None => "rustbelt> ".to_string(),
+        }
+    }
+
+    /// Handles a line of input.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line.
+    /// * `out` - Where to write the output to.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Flow)` telling whether to continue.
+    /// * `Err(e)` if the line is invalid or the command failed. The session can continue.
+    pub fn handle(&mut self, line: &str, out: &mut dyn Write) -> Result<Flow> {
+        let line = line.trim();
+        let (verb, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        // Filter expressions have their own quoting, so only split the arguments of the verbs that take words.
+        let words = || split_words(rest);
+
+        match verb {
+            "" => {}
+            "exit" | "quit" => return Ok(Flow::Exit),
+            "help" => writeln!(out, "{}", help())?,
+            "commands" => {
+                for name in command_names() {
+                    writeln!(out, "{name}")?;
+                }
+            }
+            "format" if rest.is_empty() => writeln!(out, "{}", self.format)?,
+            "format" => self.set_format(rest)?,
+            "where" if rest.is_empty() => {
+                self.query.filter = None;
+                self.filter = None;
+            }
+            "where" => self.set_filter(rest)?,
+            "select" => {
+                self.query.columns = rest
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|column| !column.is_empty())
+                    .map(str::to_string)
+                    .collect();
+            }
+            "show" => {
+                writeln!(out, "format: {}", self.format)?;
+                writeln!(out, "where:  {}", self.filter.as_deref().unwrap_or("-"))?;
+                writeln!(out, "select: {}", match self.query.columns.is_empty() {
+                    true => "-".to_string(),
+                    false => self.query.columns.join(", "),
+                })?;
+                writeln!(out, "key:    {}", self.key.as_ref().map_or("-".to_string(), key_name))?;
+            }
+            "pwd" => writeln!(out, "{}", self.key.as_ref().map_or(String::new(), key_name))?,
+            "cd" => match words()?.as_slice() {
+                [] => self.key = None,
+                [path] => {
+                    let key = self.resolve(path)?;
+                    // Only go to keys that exist.
+                    self.runtime.registry().get_sub_key_names(key.0, &key.1)?;
+                    self.key = Some(key);
+                }
+                _ => return Err(usage("cd [KEY]")),
+            },
+            "ls" => {
+                let key = match words()?.as_slice() {
+                    [] => self.key.clone().ok_or_else(|| usage("ls KEY, or cd to a key first"))?,
+                    [path] => self.resolve(path)?,
+                    _ => return Err(usage("ls [KEY]")),
+                };
+                self.list_key(&key, out)?;
+            }
+            "get" => {
+                let (key, name) = match words()?.as_slice() {
+                    [name] => (
+                        self.key.clone().ok_or_else(|| usage("get KEY VALUE, or cd to a key first"))?,
+                        name.clone(),
+                    ),
+                    [path, name] => (self.resolve(path)?, name.clone()),
+                    _ => return Err(usage("get [KEY] VALUE")),
+                };
+                let value = self.runtime.registry().get_raw_value(key.0, &key.1, &name)?;
+                let (_, data) = render_registry_value(&value);
+                writeln!(out, "{data}")?;
+            }
+            name if get_command(name).is_some() => {
+                let args: Vec<String> = ["rustbelt".to_string(), name.to_string()].into_iter().chain(words()?).collect();
+                // What a command returned before it reached a limit is still shown.
+                let (mut result, partial) = match self.runtime.execute(name, &args) {
+                    Err(Error::Partial(partial)) => {
+                        let stopped = partial.to_string();
+                        (partial.result, Some(stopped))
+                    }
+                    outcome => (outcome?, None),
+                };
+                self.query.apply(&mut result);
+                let formatter = formatter::by_name(&self.format, self.time).expect("the format is one of FORMATS");
+                writeln!(out, "{}", formatter.parse_result(&result))?;
+                if let Some(stopped) = partial {
+                    writeln!(out, "The result is partial: {stopped}")?;
+                }
+            }
+            name => {
+                return Err(Error::NotFound(format!("command or verb '{name}', see 'help'")));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Returns the completions of the word before the cursor.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line being edited.
+    /// * `pos` - The position of the cursor in the line.
+    ///
+    /// # Returns
+    ///
+    /// Where the completed word starts, and the candidates for it.
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let line = line.get(..pos).unwrap_or(line);
+        let start = line.rfind(char::is_whitespace).map_or(0, |index| index + 1);
+        let word = &line[start..];
+        let verb = line.split_whitespace().next().unwrap_or("");
+
+        let mut candidates: Vec<String> = if start == 0 {
+            VERBS
+                .iter()
+                .map(|verb| verb.to_string())
+                .chain(command_names())
+                .filter(|candidate| candidate.starts_with(word))
+                .collect()
+        } else {
+            match verb {
+                "format" => FORMATS
+                    .iter()
+                    .filter(|format| format.starts_with(word))
+                    .map(|format| format.to_string())
+                    .collect(),
+                "cd" | "ls" | "get" => self.complete_key(word),
+                _ => vec![],
+            }
+        };
+        candidates.sort();
+        candidates.dedup();
+        (start, candidates)
+    }
+
+    /// Returns the registry keys a partial path can be completed to.
+    fn complete_key(&self, word: &str) -> Vec<String> {
+        let starts_with = |name: &str, prefix: &str| name.to_lowercase().starts_with(&prefix.to_lowercase());
+        let (parent, prefix) = match word.rfind('\\') {
+            Some(index) => (&word[..=index], &word[index + 1..]),
+            None => ("", word),
+        };
+
+        let mut candidates = vec![];
+        if parent.is_empty() {
+            candidates.extend(
+                HIVES
+                    .iter()
+                    .filter(|hive| starts_with(hive, prefix))
+                    .map(|hive| format!("{hive}\\")),
+            );
+            if self.key.is_none() {
+                return candidates;
+            }
+        }
+        let Ok((hive, path)) = self.resolve(parent) else {
+            return candidates;
+        };
+        if let Ok(names) = self.runtime.registry().get_sub_key_names(hive, &path) {
+            candidates.extend(
+                names
+                    .into_iter()
+                    .filter(|name| starts_with(name, prefix))
+                    .map(|name| format!("{parent}{name}\\")),
+            );
+        }
+        candidates
+    }
+
+    fn set_format(&mut self, format: &str) -> Result<()> {
+        if !FORMATS.contains(&format) {
+            return Err(usage(&format!("format {}", FORMATS.join("|"))));
+        }
+        self.format = format.to_string();
+        Ok(())
+    }
+
+    fn set_filter(&mut self, expression: &str) -> Result<()> {
+        self.query.filter = Some(Filter::parse(expression)?);
+        self.filter = Some(expression.to_string());
+        Ok(())
+    }
+
+    /// Resolves a registry path, absolute from a hive (`HKLM\SOFTWARE`) or relative to the current key.
+    fn resolve(&self, path: &str) -> Result<(RegistryHive, String)> {
+        let mut components = path.split('\\').filter(|component| !component.is_empty()).peekable();
+        let (hive, mut parts): (RegistryHive, Vec<&str>) = match components.peek().and_then(|root| root_hive(root)) {
+            Some(hive) => {
+                components.next();
+                (hive, vec![])
+            }
+            None => match &self.key {
+                Some((hive, current)) => (*hive, current.split('\\').filter(|part| !part.is_empty()).collect()),
+                None => {
+                    return Err(Error::InvalidData(format!(
+                        "{path} is relative, start it with a hive such as HKLM"
+                    )))
+                }
+            },
+        };
+        for component in components {
+            match component {
+                "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        Ok((hive, parts.join("\\")))
+    }
+
+    /// Writes the subkeys and values of a key.
+    fn list_key(&self, (hive, path): &(RegistryHive, String), out: &mut dyn Write) -> Result<()> {
+        let registry = self.runtime.registry();
+        let mut subkeys = registry.get_sub_key_names(*hive, path)?;
+        subkeys.sort_by_key(|name| name.to_lowercase());
+        for name in subkeys {
+            writeln!(out, "{name}\\")?;
+        }
+        // Keys without values are fine, such as the roots of hives.
+        for name in registry.get_value_names(*hive, path).unwrap_or_default() {
+            let (kind, data) = match registry.get_raw_value(*hive, path, &name) {
+                Ok(value) => render_registry_value(&value),
+                Err(e) => ("?".to_string(), e.to_string()),
+            };
+            let name = if name.is_empty() { "(Default)" } else { name.as_str() };
+            writeln!(out, "{name}  {kind}  {data}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads lines from the terminal and handles them until the user exits.
+///
+/// # Arguments
+///
+/// * `shell` - The session.
+/// * `history` - The file the history is loaded from and saved to, if any.
+pub fn run(shell: Shell, history: Option<&Path>) -> Result<()> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> =
+        Editor::new().map_err(|e| Error::Unsupported(format!("terminal: {e}")))?;
+    editor.set_helper(Some(ShellHelper { shell }));
+    if let Some(history) = history {
+        // There is no history yet on the first run.
+        let _ = editor.load_history(history);
+    }
+
+    let mut stdout = std::io::stdout();
+    loop {
+        let prompt = editor.helper().expect("the helper is set").shell.prompt();
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(Error::Io(std::io::Error::other(e.to_string()))),
+        };
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        let shell = &mut editor.helper_mut().expect("the helper is set").shell;
+        match shell.handle(&line, &mut stdout) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Exit) => break,
+            Err(e) => eprintln!("error: {e}"),
+        }
+    }
+
+    if let Some(history) = history {
+        if let Err(e) = editor.save_history(history) {
+            eprintln!("Could not save the history to {}: {e}", history.display());
+        }
+    }
+    Ok(())
+}
+
+/// Connects the completion of the shell to the line editor.
+struct ShellHelper {
+    shell: Shell,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.shell.complete(line, pos))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn usage(usage: &str) -> Error {
+    Error::InvalidData(format!("usage: {usage}"))
+}
+
+/// Splits arguments at whitespace, keeping double-quoted parts such as key names with spaces together.
+fn split_words(text: &str) -> Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidData("unterminated \"".to_string()));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn key_name((hive, path): &(RegistryHive, String)) -> String {
+    hive.key_path(path)
+}
+
+/// Returns the type name and the text of a registry value, as `reg query` shows them.
+fn render_registry_value(value: &RegistryValue) -> (String, String) {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(" ");
+    let (kind, data) = match value {
+        RegistryValue::None => ("REG_NONE", String::new()),
+        RegistryValue::String(value) => ("REG_SZ", value.clone()),
+        RegistryValue::ExpandString(value) => ("REG_EXPAND_SZ", value.clone()),
+        RegistryValue::Binary(value) => ("REG_BINARY", hex(value)),
+        RegistryValue::DWord(value) => ("REG_DWORD", format!("0x{value:08x} ({value})")),
+        RegistryValue::QWord(value) => ("REG_QWORD", format!("0x{value:016x} ({value})")),
+        RegistryValue::MultiString(values) => ("REG_MULTI_SZ", values.join("\\0")),
+        RegistryValue::Other(kind, data) => return (format!("REG_TYPE_{kind}"), hex(data)),
+    };
+    (kind.to_string(), data)
+}
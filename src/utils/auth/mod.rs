@@ -0,0 +1,43 @@
+//! Authentication of remote connections.
+//!
+//! Remote backends do not know how the user authenticates. They pass the
+//! tokens of the server to an `Authenticator` and send back whatever it
+//! produces, until the server accepts the session.
+
+pub mod ntlm;
+
+use crate::error::Result;
+
+/// Produces the security tokens of a challenge-response authentication.
+pub trait Authenticator: Send {
+    /// Returns the next token to send to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge` - The token the server sent last, or `None` for the first token.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` containing the token to send.
+    /// * `Err(e)` if the challenge is invalid or the authentication cannot continue.
+    fn next_token(&mut self, challenge: Option<&[u8]>) -> Result<Vec<u8>>;
+
+    /// Returns the session key of a completed authentication, which protocols use to sign messages.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the authentication is not complete or does not establish a key.
+    fn session_key(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Anonymous authentication, which servers accept as a null session.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Anonymous;
+
+impl Authenticator for Anonymous {
+    fn next_token(&mut self, _challenge: Option<&[u8]>) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+}
@@ -0,0 +1,762 @@
+//! NTLMv2 authentication and session security, as specified in MS-NLMP.
+//!
+//! `NtlmAuthenticator` produces the NEGOTIATE and AUTHENTICATE messages of
+//! a connection-oriented NTLMv2 exchange with extended session security.
+//! Once the exchange is complete, its `NtlmSession` signs and seals
+//! messages for protocols that protect their payload with NTLM, such as
+//! WinRM over HTTP. Secrets are only ever held as NT hashes and session
+//! keys, which are zeroized when they are dropped.
+
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+use hmac::{Hmac, KeyInit as _, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use rc4::{Rc4, StreamCipher};
+use zeroize::Zeroizing;
+
+use super::Authenticator;
+use crate::{
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// The signature at the start of every NTLM message.
+pub const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+pub const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+pub const REQUEST_TARGET: u32 = 0x0000_0004;
+pub const NEGOTIATE_SIGN: u32 = 0x0000_0010;
+pub const NEGOTIATE_SEAL: u32 = 0x0000_0020;
+pub const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+pub const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+pub const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+pub const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+pub const NEGOTIATE_VERSION: u32 = 0x0200_0000;
+pub const NEGOTIATE_128: u32 = 0x2000_0000;
+pub const NEGOTIATE_KEY_EXCH: u32 = 0x4000_0000;
+pub const NEGOTIATE_56: u32 = 0x8000_0000;
+
+pub const AV_EOL: u16 = 0;
+pub const AV_NB_COMPUTER_NAME: u16 = 1;
+pub const AV_NB_DOMAIN_NAME: u16 = 2;
+pub const AV_FLAGS: u16 = 6;
+pub const AV_TIMESTAMP: u16 = 7;
+/// The `AV_FLAGS` bit that announces a MIC in the AUTHENTICATE message.
+const AV_FLAG_MIC: u32 = 0x0000_0002;
+
+/// The version the client reports: Windows 10, build 19041, NTLM revision 15.
+const VERSION: [u8; 8] = [10, 0, 0x61, 0x4a, 0, 0, 0, 15];
+/// The size of the fixed part of an AUTHENTICATE message, with its version and MIC.
+const AUTHENTICATE_HEADER_SIZE: usize = 88;
+/// The offset of the MIC in an AUTHENTICATE message.
+const MIC_OFFSET: usize = 72;
+
+/// The user an `NtlmAuthenticator` authenticates as.
+///
+/// The NT hash is derived from the password as soon as the credentials are
+/// created, and is zeroized when the credentials are dropped.
+#[derive(Clone)]
+pub struct Credentials {
+    domain: String,
+    username: String,
+    nt_hash: Zeroizing<[u8; 16]>,
+}
+
+impl Credentials {
+    /// Creates credentials from a password.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user name, optionally qualified as `DOMAIN\user` or `user@domain`.
+    /// * `password` - The password of the user.
+    pub fn with_password(username: &str, password: &str) -> Self {
+        Credentials::with_hash(username, nt_hash(password))
+    }
+
+    /// Creates credentials from a pre-computed NT hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The user name, optionally qualified as `DOMAIN\user` or `user@domain`.
+    /// * `hash` - The NT hash in hexadecimal, optionally preceded by an LM hash as in `LM:NT`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Credentials)` if the hash is valid.
+    /// * `Err(Error::InvalidData)` if it is not 32 hexadecimal digits.
+    pub fn with_nt_hash(username: &str, hash: &str) -> Result<Self> {
+        let hex = hash.rsplit(':').next().unwrap_or_default().trim();
+        let invalid = || Error::InvalidData("an NT hash must be 32 hexadecimal digits".to_string());
+        if hex.len() != 32 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut bytes = Zeroizing::new([0u8; 16]);
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Credentials::with_hash(username, bytes))
+    }
+
+    fn with_hash(username: &str, nt_hash: Zeroizing<[u8; 16]>) -> Self {
+        // A user principal name is sent as is, with an empty domain, like Windows does.
+        let (domain, username) = match username.split_once('\\') {
+            Some((domain, username)) => (domain, username),
+            None => ("", username),
+        };
+        Credentials {
+            domain: domain.to_string(),
+            username: username.to_string(),
+            nt_hash,
+        }
+    }
+
+    /// Returns the domain of the user, which is empty for unqualified names and user principal names.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Returns the name of the user, without the domain.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("domain", &self.domain)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encodes text as UTF-16LE, the encoding of NTLM strings.
+fn utf16(text: &str) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(text.encode_utf16().flat_map(u16::to_le_bytes).collect())
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5>>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn md5(parts: &[&[u8]]) -> [u8; 16] {
+    let mut digest = Md5::new();
+    for part in parts {
+        digest.update(part);
+    }
+    digest.finalize().into()
+}
+
+fn rc4(key: &[u8]) -> Rc4 {
+    Rc4::new_from_slice(key).expect("NTLM keys are 5 to 16 bytes")
+}
+
+/// Computes the NT hash of a password, the MD4 digest of its UTF-16LE encoding.
+pub fn nt_hash(password: &str) -> Zeroizing<[u8; 16]> {
+    Zeroizing::new(Md4::digest(utf16(password).as_slice()).into())
+}
+
+/// Computes the NTLMv2 response key of a user, `NTOWFv2` in MS-NLMP.
+///
+/// # Arguments
+///
+/// * `nt_hash` - The NT hash of the password of the user.
+/// * `username` - The name of the user.
+/// * `domain` - The domain of the user.
+pub fn ntowf_v2(nt_hash: &[u8; 16], username: &str, domain: &str) -> Zeroizing<[u8; 16]> {
+    Zeroizing::new(hmac_md5(nt_hash, &[&utf16(&username.to_uppercase()), &utf16(domain)]))
+}
+
+/// Reads the fields of a `len, max_len, offset` payload descriptor and returns the data it points to.
+pub fn payload(message: &[u8], descriptor: usize) -> Result<&[u8]> {
+    let invalid = || Error::Remote("truncated NTLM message".to_string());
+    let fields = message.get(descriptor..descriptor + 8).ok_or_else(invalid)?;
+    let length = LittleEndian::read_u16(&fields[0..2]) as usize;
+    let offset = LittleEndian::read_u32(&fields[4..8]) as usize;
+    message.get(offset..offset + length).ok_or_else(invalid)
+}
+
+/// Returns the AV pairs of a target information block, without the terminating `AV_EOL`.
+pub fn av_pairs(target_info: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+    let mut pairs = vec![];
+    if target_info.is_empty() {
+        return Ok(pairs);
+    }
+    let mut rest = target_info;
+    while rest.len() >= 4 {
+        let id = LittleEndian::read_u16(&rest[0..2]);
+        let length = LittleEndian::read_u16(&rest[2..4]) as usize;
+        if id == AV_EOL {
+            return Ok(pairs);
+        }
+        let value = rest
+            .get(4..4 + length)
+            .ok_or_else(|| Error::Remote("truncated NTLM target information".to_string()))?;
+        pairs.push((id, value));
+        rest = &rest[4 + length..];
+    }
+    Err(Error::Remote("NTLM target information without an end".to_string()))
+}
+
+/// Encodes AV pairs into a target information block terminated by `AV_EOL`.
+pub fn encode_av_pairs(pairs: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut block = vec![];
+    for (id, value) in pairs {
+        block.extend_from_slice(&id.to_le_bytes());
+        block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        block.extend_from_slice(value);
+    }
+    block.extend_from_slice(&[0; 4]);
+    block
+}
+
+/// The parts of a CHALLENGE message the client uses.
+struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    fn parse(message: &[u8]) -> Result<Self> {
+        if message.len() < 48 || &message[..8] != SIGNATURE || LittleEndian::read_u32(&message[8..12]) != 2 {
+            return Err(Error::Remote("invalid NTLM challenge".to_string()));
+        }
+        let flags = LittleEndian::read_u32(&message[20..24]);
+        let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 {
+            payload(message, 40)?.to_vec()
+        } else {
+            vec![]
+        };
+        Ok(Challenge {
+            flags,
+            server_challenge: message[24..32].try_into().expect("slice of 8 bytes"),
+            target_info,
+        })
+    }
+}
+
+/// The NTLMv2 responses to a challenge.
+///
+/// # Fields
+/// - `lm`: The LMv2 response.
+/// - `nt`: The NTLMv2 response, the proof followed by the client blob.
+/// - `session_base_key`: The key the session keys are derived from.
+struct Responses {
+    lm: Vec<u8>,
+    nt: Vec<u8>,
+    session_base_key: Zeroizing<[u8; 16]>,
+}
+
+/// Computes the NTLMv2 responses, `ComputeResponse` in MS-NLMP.
+///
+/// # Arguments
+///
+/// * `response_key` - The result of `ntowf_v2`.
+/// * `server_challenge` - The challenge of the server.
+/// * `client_challenge` - A random challenge of the client.
+/// * `time` - The current time as a FILETIME.
+/// * `target_info` - The target information to include in the blob.
+/// * `omit_lm` - Whether to send an empty LMv2 response, as required when the server sent a timestamp.
+fn compute_responses(
+    response_key: &[u8; 16],
+    server_challenge: &[u8; 8],
+    client_challenge: &[u8; 8],
+    time: u64,
+    target_info: &[u8],
+    omit_lm: bool,
+) -> Responses {
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&time.to_le_bytes());
+    blob.extend_from_slice(client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let proof = hmac_md5(response_key, &[server_challenge, &blob]);
+    let mut nt = proof.to_vec();
+    nt.extend_from_slice(&blob);
+
+    let lm = if omit_lm {
+        vec![0; 24]
+    } else {
+        let mut lm = hmac_md5(response_key, &[server_challenge, client_challenge]).to_vec();
+        lm.extend_from_slice(client_challenge);
+        lm
+    };
+
+    Responses {
+        lm,
+        nt,
+        session_base_key: Zeroizing::new(hmac_md5(response_key, &[&proof])),
+    }
+}
+
+/// The progress of an `NtlmAuthenticator`.
+enum State {
+    Initial,
+    Negotiated(Vec<u8>),
+    Authenticated,
+}
+
+/// Authenticates with NTLMv2 and extended session security.
+///
+/// The first token is a NEGOTIATE message, the token for the server's
+/// CHALLENGE message is the AUTHENTICATE message. The server's challenge
+/// may be wrapped in SPNEGO, as SMB servers do.
+pub struct NtlmAuthenticator {
+    credentials: Credentials,
+    flags: u32,
+    state: State,
+    session_key: Option<Zeroizing<[u8; 16]>>,
+    session: Option<NtlmSession>,
+}
+
+impl NtlmAuthenticator {
+    /// Creates an authenticator for a user, without signing or sealing.
+    pub fn new(credentials: Credentials) -> Self {
+        NtlmAuthenticator {
+            credentials,
+            flags: NEGOTIATE_UNICODE
+                | REQUEST_TARGET
+                | NEGOTIATE_NTLM
+                | NEGOTIATE_ALWAYS_SIGN
+                | NEGOTIATE_EXTENDED_SESSIONSECURITY
+                | NEGOTIATE_TARGET_INFO
+                | NEGOTIATE_VERSION
+                | NEGOTIATE_128
+                | NEGOTIATE_KEY_EXCH
+                | NEGOTIATE_56,
+            state: State::Initial,
+            session_key: None,
+            session: None,
+        }
+    }
+
+    /// Sets whether to negotiate message signing.
+    pub fn with_signing(mut self, signing: bool) -> Self {
+        self.set_flag(NEGOTIATE_SIGN, signing);
+        self
+    }
+
+    /// Sets whether to negotiate message sealing, which implies signing.
+    pub fn with_sealing(mut self, sealing: bool) -> Self {
+        self.set_flag(NEGOTIATE_SEAL, sealing);
+        if sealing {
+            self.set_flag(NEGOTIATE_SIGN, true);
+        }
+        self
+    }
+
+    fn set_flag(&mut self, flag: u32, enabled: bool) {
+        if enabled {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    /// Returns the session security of the completed exchange, once.
+    ///
+    /// # Returns
+    ///
+    /// `None` before the AUTHENTICATE message was produced, or if the session was already taken.
+    pub fn take_session(&mut self) -> Option<NtlmSession> {
+        self.session.take()
+    }
+
+    fn negotiate(&self) -> Vec<u8> {
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&self.flags.to_le_bytes());
+        message.extend_from_slice(&[0; 16]); // no domain and workstation
+        message.extend_from_slice(&VERSION);
+        message
+    }
+
+    /// Builds the AUTHENTICATE message for a CHALLENGE message.
+    ///
+    /// # Arguments
+    ///
+    /// * `negotiate` - The NEGOTIATE message that was sent.
+    /// * `challenge` - The CHALLENGE message of the server.
+    /// * `client_challenge` - The random challenge of the client.
+    /// * `exported_session_key` - The random session key, used if the server agrees to exchange keys.
+    fn authenticate(
+        &mut self,
+        negotiate: &[u8],
+        challenge: &[u8],
+        client_challenge: &[u8; 8],
+        exported_session_key: &[u8; 16],
+    ) -> Result<Vec<u8>> {
+        let parsed = Challenge::parse(challenge)?;
+        let flags = self.flags & parsed.flags;
+        if flags & NEGOTIATE_EXTENDED_SESSIONSECURITY == 0 && self.flags & NEGOTIATE_SIGN != 0 {
+            return Err(Error::Remote("the server does not support NTLMv2 session security".to_string()));
+        }
+
+        // A server that sends a timestamp expects it in the response, and a MIC over all messages.
+        let mut pairs = av_pairs(&parsed.target_info)?;
+        let timestamp = pairs
+            .iter()
+            .find(|(id, value)| *id == AV_TIMESTAMP && value.len() == 8)
+            .map(|(_, value)| LittleEndian::read_u64(value));
+        let time = match timestamp {
+            Some(time) => time,
+            None => Timestamp::now().to_filetime()?,
+        };
+        let av_flags = pairs
+            .iter()
+            .find(|(id, value)| *id == AV_FLAGS && value.len() == 4)
+            .map_or(0, |(_, value)| LittleEndian::read_u32(value))
+            | AV_FLAG_MIC;
+        let av_flags = av_flags.to_le_bytes();
+        if timestamp.is_some() {
+            pairs.retain(|(id, _)| *id != AV_FLAGS);
+            pairs.push((AV_FLAGS, &av_flags));
+        }
+        let target_info = encode_av_pairs(&pairs);
+
+        let response_key = ntowf_v2(
+            &self.credentials.nt_hash,
+            &self.credentials.username,
+            &self.credentials.domain,
+        );
+        let responses = compute_responses(
+            &response_key,
+            &parsed.server_challenge,
+            client_challenge,
+            time,
+            &target_info,
+            timestamp.is_some(),
+        );
+
+        // With NTLMv2, the key exchange key is the session base key.
+        let (session_key, encrypted_key) = if flags & NEGOTIATE_KEY_EXCH != 0 {
+            let mut encrypted = *exported_session_key;
+            rc4(responses.session_base_key.as_slice()).apply_keystream(&mut encrypted);
+            (Zeroizing::new(*exported_session_key), encrypted.to_vec())
+        } else {
+            (responses.session_base_key.clone(), vec![])
+        };
+
+        let domain = utf16(&self.credentials.domain);
+        let username = utf16(&self.credentials.username);
+        let fields: [&[u8]; 6] = [&responses.lm, &responses.nt, &domain, &username, &[], &encrypted_key];
+
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&3u32.to_le_bytes());
+        let mut offset = AUTHENTICATE_HEADER_SIZE;
+        for field in fields {
+            message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += field.len();
+        }
+        message.extend_from_slice(&flags.to_le_bytes());
+        message.extend_from_slice(&VERSION);
+        message.extend_from_slice(&[0; 16]); // MIC
+        for field in fields {
+            message.extend_from_slice(field);
+        }
+
+        if timestamp.is_some() {
+            let mic = hmac_md5(session_key.as_slice(), &[negotiate, challenge, &message]);
+            message[MIC_OFFSET..MIC_OFFSET + 16].copy_from_slice(&mic);
+        }
+
+        self.session = Some(NtlmSession::client(&session_key, flags));
+        self.session_key = Some(session_key);
+        Ok(message)
+    }
+}
+
+/// Returns the NTLM message in a token that may wrap it in SPNEGO.
+fn unwrap_token(token: &[u8]) -> Result<&[u8]> {
+    // SPNEGO only adds a DER prefix around the NTLM message, so the message runs to the end.
+    token
+        .windows(SIGNATURE.len())
+        .position(|window| window == SIGNATURE)
+        .map(|start| &token[start..])
+        .ok_or_else(|| Error::Remote("the server did not answer with an NTLM challenge".to_string()))
+}
+
+impl Authenticator for NtlmAuthenticator {
+    fn next_token(&mut self, challenge: Option<&[u8]>) -> Result<Vec<u8>> {
+        match (std::mem::replace(&mut self.state, State::Authenticated), challenge) {
+            (State::Initial, _) => {
+                let negotiate = self.negotiate();
+                self.state = State::Negotiated(negotiate.clone());
+                Ok(negotiate)
+            }
+            (State::Negotiated(negotiate), Some(challenge)) => {
+                let mut client_challenge = [0u8; 8];
+                let mut exported_session_key = Zeroizing::new([0u8; 16]);
+                getrandom::fill(&mut client_challenge)
+                    .and_then(|_| getrandom::fill(exported_session_key.as_mut_slice()))
+                    .map_err(|e| Error::Io(std::io::Error::other(e.to_string())))?;
+                self.authenticate(&negotiate, unwrap_token(challenge)?, &client_challenge, &exported_session_key)
+            }
+            (State::Negotiated(_), None) => Err(Error::Remote("the server sent no NTLM challenge".to_string())),
+            (State::Authenticated, _) => Err(Error::Remote("NTLM authentication is already complete".to_string())),
+        }
+    }
+
+    fn session_key(&self) -> Option<&[u8]> {
+        self.session_key.as_ref().map(|key| key.as_slice())
+    }
+}
+
+/// The sealing key and sequence number of one direction of a session.
+struct Direction {
+    signing_key: Zeroizing<[u8; 16]>,
+    cipher: Rc4,
+    sequence: u32,
+}
+
+impl Direction {
+    fn new(session_key: &[u8; 16], flags: u32, sender: &str) -> Self {
+        let signing_key = md5(&[
+            session_key,
+            format!("session key to {sender} signing key magic constant\0").as_bytes(),
+        ]);
+        let key_length = if flags & NEGOTIATE_128 != 0 {
+            16
+        } else if flags & NEGOTIATE_56 != 0 {
+            7
+        } else {
+            5
+        };
+        let sealing_key = Zeroizing::new(md5(&[
+            &session_key[..key_length],
+            format!("session key to {sender} sealing key magic constant\0").as_bytes(),
+        ]));
+        Direction {
+            signing_key: Zeroizing::new(signing_key),
+            cipher: rc4(sealing_key.as_slice()),
+            sequence: 0,
+        }
+    }
+
+    /// Computes the signature of the next message, after sealing if `message` is the plain text.
+    fn signature(&mut self, message: &[u8], key_exchange: bool) -> [u8; 16] {
+        let sequence = self.sequence.to_le_bytes();
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut checksum: [u8; 8] = hmac_md5(self.signing_key.as_slice(), &[&sequence, message])[..8]
+            .try_into()
+            .expect("slice of 8 bytes");
+        if key_exchange {
+            self.cipher.apply_keystream(&mut checksum);
+        }
+
+        let mut signature = [0u8; 16];
+        signature[0] = 1;
+        signature[4..12].copy_from_slice(&checksum);
+        signature[12..].copy_from_slice(&sequence);
+        signature
+    }
+}
+
+/// Signs and seals messages of an authenticated NTLM session.
+///
+/// Each side has its own keys and sequence numbers, so messages have to be
+/// processed in the order they are sent.
+pub struct NtlmSession {
+    flags: u32,
+    outgoing: Direction,
+    incoming: Direction,
+}
+
+impl NtlmSession {
+    /// Creates the session security of the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_key` - The exported session key.
+    /// * `flags` - The negotiated flags.
+    pub fn client(session_key: &[u8; 16], flags: u32) -> Self {
+        NtlmSession {
+            flags,
+            outgoing: Direction::new(session_key, flags, "client-to-server"),
+            incoming: Direction::new(session_key, flags, "server-to-client"),
+        }
+    }
+
+    /// Creates the session security of the server, see `NtlmSession::client`.
+    pub fn server(session_key: &[u8; 16], flags: u32) -> Self {
+        NtlmSession {
+            flags,
+            outgoing: Direction::new(session_key, flags, "server-to-client"),
+            incoming: Direction::new(session_key, flags, "client-to-server"),
+        }
+    }
+
+    fn key_exchange(&self) -> bool {
+        self.flags & NEGOTIATE_KEY_EXCH != 0
+    }
+
+    /// Returns the signature of an outgoing message.
+    pub fn sign(&mut self, message: &[u8]) -> [u8; 16] {
+        let key_exchange = self.key_exchange();
+        self.outgoing.signature(message, key_exchange)
+    }
+
+    /// Checks the signature of an incoming message.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the signature is valid.
+    /// * `Err(Error::Remote)` if the message was tampered with or is out of order.
+    pub fn verify(&mut self, message: &[u8], signature: &[u8]) -> Result<()> {
+        let key_exchange = self.key_exchange();
+        if self.incoming.signature(message, key_exchange).as_slice() != signature {
+            return Err(Error::Remote("invalid NTLM message signature".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Encrypts an outgoing message in place and returns its signature.
+    pub fn seal(&mut self, message: &mut [u8]) -> [u8; 16] {
+        let key_exchange = self.key_exchange();
+        let signature_input = message.to_vec();
+        self.outgoing.cipher.apply_keystream(message);
+        self.outgoing.signature(&signature_input, key_exchange)
+    }
+
+    /// Decrypts an incoming message in place and checks its signature.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the message was decrypted and its signature is valid.
+    /// * `Err(Error::Remote)` if the message was tampered with or is out of order.
+    pub fn unseal(&mut self, message: &mut [u8], signature: &[u8]) -> Result<()> {
+        self.incoming.cipher.apply_keystream(message);
+        self.verify(message, signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The NTLMv2 test vectors of MS-NLMP, section 4.2.4.
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+    const CLIENT_CHALLENGE: [u8; 8] = [0xaa; 8];
+    const RANDOM_SESSION_KEY: [u8; 16] = [0x55; 16];
+    const FLAGS: u32 = 0xe28a_8233;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&text[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn target_info() -> Vec<u8> {
+        encode_av_pairs(&[
+            (AV_NB_DOMAIN_NAME, &utf16("Domain")),
+            (AV_NB_COMPUTER_NAME, &utf16("Server")),
+        ])
+    }
+
+    #[test]
+    fn test_ntlmv2_vectors() {
+        let credentials = Credentials::with_password("Domain\\User", "Password");
+        assert_eq!(credentials.domain(), "Domain");
+        assert_eq!(credentials.username(), "User");
+        assert_eq!(credentials.nt_hash.to_vec(), hex("a4f49c406510bdcab6824ee7c30fd852"));
+
+        let response_key = ntowf_v2(&credentials.nt_hash, "User", "Domain");
+        assert_eq!(response_key.to_vec(), hex("0c868a403bfd7a93a3001ef22ef02e3f"));
+
+        let responses =
+            compute_responses(&response_key, &SERVER_CHALLENGE, &CLIENT_CHALLENGE, 0, &target_info(), false);
+        assert_eq!(responses.lm, hex("86c35097ac9cec102554764a57cccc19 aaaaaaaaaaaaaaaa"));
+        assert_eq!(responses.nt[..16], hex("68cd0ab851e51c96aabc927bebef6a1c"));
+        assert_eq!(
+            responses.session_base_key.to_vec(),
+            hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+        );
+
+        let mut encrypted = RANDOM_SESSION_KEY;
+        rc4(responses.session_base_key.as_slice()).apply_keystream(&mut encrypted);
+        assert_eq!(encrypted.to_vec(), hex("c5dad2544fc9799094ce1ce90bc9d03e"));
+    }
+
+    #[test]
+    fn test_seal_vector() {
+        let mut client = NtlmSession::client(&RANDOM_SESSION_KEY, FLAGS);
+        let mut message = utf16("Plaintext").to_vec();
+        let signature = client.seal(&mut message);
+        assert_eq!(message, hex("54e50165bf1936dc996020c1811b0f06fb5f"));
+        assert_eq!(signature.to_vec(), hex("01000000 7fb38ec5c55d4976 00000000"));
+
+        // The server reads what the client sealed, in order, and rejects replays.
+        let mut server = NtlmSession::server(&RANDOM_SESSION_KEY, FLAGS);
+        let mut received = message.clone();
+        server.unseal(&mut received, &signature).unwrap();
+        assert_eq!(received, utf16("Plaintext").to_vec());
+        assert!(server.unseal(&mut message, &signature).is_err());
+
+        let signature = server.sign(b"reply");
+        client.verify(b"reply", &signature).unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_message() {
+        let challenge = {
+            let info = encode_av_pairs(&[
+                (AV_NB_DOMAIN_NAME, &utf16("Domain")),
+                (AV_TIMESTAMP, &[1, 0, 0, 0, 0, 0, 0, 0]),
+            ]);
+            let mut message = SIGNATURE.to_vec();
+            message.extend_from_slice(&2u32.to_le_bytes());
+            message.extend_from_slice(&[0; 8]);
+            message.extend_from_slice(&FLAGS.to_le_bytes());
+            message.extend_from_slice(&SERVER_CHALLENGE);
+            message.extend_from_slice(&[0; 8]);
+            message.extend_from_slice(&(info.len() as u16).to_le_bytes());
+            message.extend_from_slice(&(info.len() as u16).to_le_bytes());
+            message.extend_from_slice(&56u32.to_le_bytes());
+            message.extend_from_slice(&VERSION);
+            message.extend_from_slice(&info);
+            message
+        };
+
+        let credentials = Credentials::with_nt_hash("user@example.com", ":a4f49c406510bdcab6824ee7c30fd852").unwrap();
+        assert_eq!(credentials.domain(), "");
+        let mut auth = NtlmAuthenticator::new(credentials).with_sealing(true);
+        let negotiate = auth.next_token(None).unwrap();
+        assert_eq!(&negotiate[..8], SIGNATURE);
+
+        let message = auth
+            .authenticate(&negotiate, &challenge, &CLIENT_CHALLENGE, &RANDOM_SESSION_KEY)
+            .unwrap();
+        assert_eq!(payload(&message, 12).unwrap(), [0; 24]);
+        assert_eq!(payload(&message, 36).unwrap(), utf16("user@example.com").as_slice());
+        assert_eq!(auth.session_key(), Some(RANDOM_SESSION_KEY.as_slice()));
+
+        // The blob repeats the timestamp of the server and announces the MIC.
+        let blob = &payload(&message, 20).unwrap()[16..];
+        assert_eq!(blob[8..16], [1, 0, 0, 0, 0, 0, 0, 0]);
+        let pairs = av_pairs(&blob[28..]).unwrap();
+        assert!(pairs.contains(&(AV_FLAGS, AV_FLAG_MIC.to_le_bytes().as_slice())));
+
+        let mut zeroed = message.clone();
+        zeroed[MIC_OFFSET..MIC_OFFSET + 16].fill(0);
+        let mic = hmac_md5(&RANDOM_SESSION_KEY, &[&negotiate, &challenge, &zeroed]);
+        assert_eq!(message[MIC_OFFSET..MIC_OFFSET + 16], mic);
+
+        assert!(auth.take_session().is_some());
+        assert!(Credentials::with_nt_hash("user", "abc").is_err());
+    }
+}
@@ -0,0 +1,438 @@
+//! Encrypted and compressed output bundles.
+//!
+//! A bundle is readable only by the holder of the private key it was
+//! encrypted to. The writer generates an ephemeral X25519 key pair, agrees on
+//! a secret with the public key of the recipient and derives the bundle key
+//! from it with HKDF-SHA256. The output is compressed with deflate and split
+//! into chunks that are sealed with ChaCha20-Poly1305, so it is never held in
+//! memory as a whole and never written to disk in plain text.
+//!
+//! Layout of a bundle:
+//!
+//! ```text
+//! "RBBUNDLE" | version (1 byte) | ephemeral public key (32 bytes) | chunk...
+//! chunk = length (u32, little endian, top bit set on the last chunk) | sealed data
+//! ```
+//!
+//! The nonce of a chunk is its index and whether it is the last one, so
+//! chunks cannot be reordered, dropped or appended without the bundle
+//! failing to open.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+
+/// The first bytes of every bundle.
+pub const MAGIC: &[u8; 8] = b"RBBUNDLE";
+/// The version of the bundle format.
+pub const VERSION: u8 = 1;
+/// The prefix of public keys in text form.
+pub const PUBLIC_KEY_PREFIX: &str = "rustbelt-pub:";
+/// The prefix of private keys in text form.
+pub const PRIVATE_KEY_PREFIX: &str = "rustbelt-key:";
+
+/// The most plaintext bytes in one chunk.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The size of the authentication tag of a sealed chunk.
+const TAG_SIZE: usize = 16;
+/// The bit of the chunk length that marks the last chunk.
+const LAST_CHUNK: u32 = 1 << 31;
+const KEY_INFO: &[u8] = b"rustbelt bundle v1";
+
+/// The public key bundles are encrypted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecipientKey(PublicKey);
+
+impl FromStr for RecipientKey {
+    type Err = Error;
+
+    /// Parses a public key in the form `rustbelt-pub:<base64>`.
+    fn from_str(text: &str) -> Result<Self> {
+        let key = decode_key(text.trim(), PUBLIC_KEY_PREFIX, "public key")?;
+        Ok(RecipientKey(PublicKey::from(*key)))
+    }
+}
+
+impl fmt::Display for RecipientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PUBLIC_KEY_PREFIX}{}", STANDARD.encode(self.0.as_bytes()))
+    }
+}
+
+/// The private key that opens bundles.
+pub struct IdentityKey(StaticSecret);
+
+impl IdentityKey {
+    /// Generates a new random private key.
+    pub fn generate() -> Result<Self> {
+        Ok(IdentityKey(StaticSecret::from(*random_bytes()?)))
+    }
+
+    /// Returns the public key that bundles for this private key are encrypted to.
+    pub fn recipient(&self) -> RecipientKey {
+        RecipientKey(PublicKey::from(&self.0))
+    }
+
+    /// Returns the private key in the form `rustbelt-key:<base64>`.
+    pub fn to_text(&self) -> Zeroizing<String> {
+        Zeroizing::new(format!("{PRIVATE_KEY_PREFIX}{}", STANDARD.encode(self.0.as_bytes())))
+    }
+}
+
+impl FromStr for IdentityKey {
+    type Err = Error;
+
+    /// Parses a private key in the form `rustbelt-key:<base64>`.
+    fn from_str(text: &str) -> Result<Self> {
+        let key = decode_key(text.trim(), PRIVATE_KEY_PREFIX, "private key")?;
+        Ok(IdentityKey(StaticSecret::from(*key)))
+    }
+}
+
+impl fmt::Debug for IdentityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdentityKey").field(&self.recipient()).finish()
+    }
+}
+
+fn decode_key(text: &str, prefix: &str, what: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let invalid = || Error::InvalidData(format!("{what} must look like '{prefix}<base64 of 32 bytes>'"));
+    let encoded = text.strip_prefix(prefix).ok_or_else(invalid)?;
+    let bytes = Zeroizing::new(STANDARD.decode(encoded).map_err(|_| invalid())?);
+    let mut key = Zeroizing::new([0; 32]);
+    if bytes.len() != key.len() {
+        return Err(invalid());
+    }
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn random_bytes() -> Result<Zeroizing<[u8; 32]>> {
+    let mut bytes = Zeroizing::new([0; 32]);
+    getrandom::fill(bytes.as_mut()).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Derives the key of a bundle from the key agreement of its ephemeral key and the recipient key.
+fn bundle_key(secret: &StaticSecret, public: &PublicKey, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<ChaCha20Poly1305> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(Error::InvalidData("bundle key is a low order point".to_string()));
+    }
+
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut key = Zeroizing::new([0; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(KEY_INFO, key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(ChaCha20Poly1305::new(&Key::from(*key)))
+}
+
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+/// Encrypts a stream of data in chunks, see the module documentation.
+struct Sealer<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    buffer: Zeroizing<Vec<u8>>,
+    index: u64,
+}
+
+impl<W: Write> Sealer<W> {
+    fn new(mut inner: W, recipient: &RecipientKey) -> Result<Self> {
+        let secret = StaticSecret::from(*random_bytes()?);
+        let ephemeral = PublicKey::from(&secret);
+        let cipher = bundle_key(&secret, &recipient.0, &ephemeral, &recipient.0)?;
+
+        inner.write_all(MAGIC)?;
+        inner.write_all(&[VERSION])?;
+        inner.write_all(ephemeral.as_bytes())?;
+        Ok(Sealer {
+            inner,
+            cipher,
+            buffer: Zeroizing::new(Vec::with_capacity(CHUNK_SIZE)),
+            index: 0,
+        })
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(&chunk_nonce(self.index, last), self.buffer.as_slice())
+            .map_err(|_| io::Error::other("could not seal bundle chunk"))?;
+        let length = sealed.len() as u32 | if last { LAST_CHUNK } else { 0 };
+        self.inner.write_all(&length.to_le_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Seals the last chunk and returns the underlying writer.
+    fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full buffer is only sealed once more data arrives, so that the
+        // last chunk is never empty unless the whole bundle is.
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        let count = data.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a bundle: compresses data and encrypts it to a recipient.
+pub struct BundleEncoder<W: Write> {
+    encoder: DeflateEncoder<Sealer<W>>,
+}
+
+impl<W: Write> BundleEncoder<W> {
+    /// Starts a bundle and writes its header.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Where the bundle is written to.
+    /// * `recipient` - The public key to encrypt the bundle to.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BundleEncoder)` if the header could be written.
+    /// * `Err(e)` if no random key could be generated or the header could not be written.
+    pub fn new(inner: W, recipient: &RecipientKey) -> Result<Self> {
+        Ok(BundleEncoder {
+            encoder: DeflateEncoder::new(Sealer::new(inner, recipient)?, Compression::default()),
+        })
+    }
+
+    /// Completes the bundle and returns the underlying writer.
+    ///
+    /// A bundle that is not finished cannot be opened.
+    pub fn finish(self) -> Result<W> {
+        Ok(self.encoder.finish()?.finish()?)
+    }
+}
+
+impl<W: Write> Write for BundleEncoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.encoder.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Decrypts a stream of chunks, see the module documentation.
+struct Opener<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    chunk: Zeroizing<Vec<u8>>,
+    position: usize,
+    index: u64,
+    done: bool,
+}
+
+impl<R: Read> Opener<R> {
+    fn new(mut inner: R, identity: &IdentityKey) -> Result<Self> {
+        let mut header = [0; 41];
+        inner
+            .read_exact(&mut header)
+            .map_err(|_| Error::InvalidData("not a rustbelt bundle".to_string()))?;
+        if &header[..8] != MAGIC {
+            return Err(Error::InvalidData("not a rustbelt bundle".to_string()));
+        }
+        if header[8] != VERSION {
+            return Err(Error::Unsupported(format!("bundle version {}", header[8])));
+        }
+
+        let ephemeral = PublicKey::from(<[u8; 32]>::try_from(&header[9..]).expect("the header has 32 key bytes"));
+        let recipient = PublicKey::from(&identity.0);
+        Ok(Opener {
+            inner,
+            cipher: bundle_key(&identity.0, &ephemeral, &ephemeral, &recipient)?,
+            chunk: Zeroizing::new(vec![]),
+            position: 0,
+            index: 0,
+            done: false,
+        })
+    }
+
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bundle {what}"));
+
+        let mut length = [0; 4];
+        self.inner
+            .read_exact(&mut length)
+            .map_err(|_| invalid("is truncated"))?;
+        let length = u32::from_le_bytes(length);
+        let last = length & LAST_CHUNK != 0;
+        let size = (length & !LAST_CHUNK) as usize;
+        if !(TAG_SIZE..=CHUNK_SIZE + TAG_SIZE).contains(&size) {
+            return Err(invalid("has an invalid chunk"));
+        }
+
+        let mut sealed = vec![0; size];
+        self.inner
+            .read_exact(&mut sealed)
+            .map_err(|_| invalid("is truncated"))?;
+        let chunk = self
+            .cipher
+            .decrypt(&chunk_nonce(self.index, last), sealed.as_slice())
+            .map_err(|_| invalid("was modified or encrypted to another key"))?;
+
+        if last && self.inner.read(&mut [0])? != 0 {
+            return Err(invalid("has data after its last chunk"));
+        }
+        self.chunk = Zeroizing::new(chunk);
+        self.position = 0;
+        self.index += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let count = buffer.len().min(self.chunk.len() - self.position);
+        buffer[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Reads a bundle: decrypts data with a private key and decompresses it.
+pub struct BundleDecoder<R: Read> {
+    decoder: DeflateDecoder<Opener<R>>,
+}
+
+impl<R: Read> BundleDecoder<R> {
+    /// Opens a bundle and reads its header.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Where the bundle is read from.
+    /// * `identity` - The private key the bundle was encrypted to.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BundleDecoder)` if the header is valid.
+    /// * `Err(Error::InvalidData)` if the data is not a bundle.
+    /// * `Err(Error::Unsupported)` if the bundle has an unknown version.
+    pub fn new(inner: R, identity: &IdentityKey) -> Result<Self> {
+        Ok(BundleDecoder {
+            decoder: DeflateDecoder::new(Opener::new(inner, identity)?),
+        })
+    }
+}
+
+impl<R: Read> Read for BundleDecoder<R> {
+    /// Reads decrypted data. Fails with `ErrorKind::InvalidData` if the bundle was modified or truncated.
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buffer)
+    }
+}
+
+/// Decrypts and decompresses a whole bundle.
+///
+/// # Arguments
+///
+/// * `bundle` - The bundle.
+/// * `identity` - The private key the bundle was encrypted to.
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` containing the contents of the bundle.
+/// * `Err(Error::InvalidData)` if the bundle is invalid, was modified or was encrypted to another key.
+pub fn open(bundle: impl Read, identity: &IdentityKey) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    BundleDecoder::new(bundle, identity)?
+        .read_to_end(&mut contents)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => Error::InvalidData(e.to_string()),
+            _ => Error::Io(e),
+        })?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(identity: &IdentityKey, data: &[u8]) -> Vec<u8> {
+        let mut encoder = BundleEncoder::new(vec![], &identity.recipient()).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let identity = IdentityKey::generate().unwrap();
+        let parsed: IdentityKey = identity.to_text().parse().unwrap();
+        assert_eq!(parsed.recipient(), identity.recipient());
+        assert_eq!(identity.recipient().to_string().parse::<RecipientKey>().unwrap(), identity.recipient());
+        assert!(identity.to_text().parse::<RecipientKey>().is_err());
+
+        // Large enough for several chunks even after compression.
+        let mut data = vec![0; 3 * CHUNK_SIZE];
+        getrandom::fill(&mut data).unwrap();
+        data.extend_from_slice(&b"AMSI Provider : C:\\Windows\\amsi.dll\n".repeat(1000));
+
+        let bundle = seal(&identity, &data);
+        assert_eq!(&bundle[..8], MAGIC);
+        assert!(!bundle.windows(20).any(|window| window == &data[..20]));
+        assert_eq!(open(bundle.as_slice(), &identity).unwrap(), data);
+        assert_eq!(open(seal(&identity, b"").as_slice(), &identity).unwrap(), b"");
+
+        // Other keys, modified, truncated and extended bundles are rejected.
+        let other = IdentityKey::generate().unwrap();
+        assert!(matches!(open(bundle.as_slice(), &other), Err(Error::InvalidData(_))));
+        let mut modified = bundle.clone();
+        modified[100] ^= 1;
+        assert!(matches!(open(modified.as_slice(), &identity), Err(Error::InvalidData(_))));
+        assert!(matches!(open(&bundle[..bundle.len() - 1], &identity), Err(Error::InvalidData(_))));
+        let mut extended = bundle.clone();
+        extended.push(0);
+        assert!(matches!(open(extended.as_slice(), &identity), Err(Error::InvalidData(_))));
+        let first_chunk = 41 + 4 + (u32::from_le_bytes(bundle[41..45].try_into().unwrap()) & !LAST_CHUNK) as usize;
+        assert!(matches!(open(&bundle[..first_chunk], &identity), Err(Error::InvalidData(_))));
+    }
+}
@@ -0,0 +1,143 @@
+//! The receiving end of uploaded results.
+//!
+//! The HTTP writer posts the output of a run in chunks, each tagged with the
+//! identifier of the run and its sequence number. The collector appends plain
+//! chunks to one NDJSON file per run, and stores encrypted chunks, which it
+//! cannot read, as one bundle file per chunk for the `decrypt` subcommand.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    error::Result,
+    utils::http::{read_request, write_response, Request, Response},
+};
+
+/// The header with the identifier of the run a chunk belongs to.
+pub const RUN_HEADER: &str = "X-Rustbelt-Run";
+/// The header with the position of a chunk in its run, starting at zero.
+pub const SEQUENCE_HEADER: &str = "X-Rustbelt-Sequence";
+/// The content type of encrypted chunks.
+pub const BUNDLE_CONTENT_TYPE: &str = "application/x-rustbelt-bundle";
+
+/// How long a connection may stay idle before the collector closes it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Stores the chunks uploaded by HTTP writers in a directory.
+pub struct Collector {
+    dir: PathBuf,
+    required_header: Option<(String, String)>,
+    append: Mutex<()>,
+}
+
+impl Collector {
+    /// Creates a collector that stores chunks in a directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory, which is created if it does not exist.
+    pub fn new(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Collector {
+            dir: dir.to_path_buf(),
+            required_header: None,
+            append: Mutex::new(()),
+        })
+    }
+
+    /// Only accepts uploads that carry a header with the given value, such as an auth token.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the header.
+    /// * `value` - The value the header must have.
+    pub fn require_header(mut self, name: &str, value: &str) -> Self {
+        self.required_header = Some((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Serves uploads on a listener until it fails, one thread per connection.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        thread::scope(|scope| loop {
+            let (stream, _) = listener.accept()?;
+            scope.spawn(move || {
+                // A broken connection only ends that connection.
+                let _ = self.serve_connection(stream);
+            });
+        })
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        while let Some(request) = read_request(&mut reader)? {
+            write_response(&mut writer, &self.handle(&request))?;
+        }
+        Ok(())
+    }
+
+    /// Handles one upload and returns the response for it.
+    ///
+    /// # Returns
+    ///
+    /// * `204 No Content` if the chunk was stored.
+    /// * `401 Unauthorized` if the required header is missing or wrong.
+    /// * `400 Bad Request` if the run or sequence headers are missing or invalid.
+    /// * `405 Method Not Allowed` for other methods than `POST`.
+    /// * `500 Internal Server Error` if the chunk could not be stored.
+    pub fn handle(&self, request: &Request) -> Response {
+        if request.method != "POST" {
+            return Response::new(405, "Method Not Allowed").header("Allow", "POST");
+        }
+        if let Some((name, value)) = &self.required_header {
+            if request.get_header(name) != Some(value.as_str()) {
+                return Response::new(401, "Unauthorized");
+            }
+        }
+
+        let run = request.get_header(RUN_HEADER).filter(|run| is_valid_run(run));
+        let sequence = request
+            .get_header(SEQUENCE_HEADER)
+            .and_then(|sequence| sequence.parse::<u64>().ok());
+        let (Some(run), Some(sequence)) = (run, sequence) else {
+            return Response::new(400, "Bad Request")
+                .body(format!("{RUN_HEADER} and {SEQUENCE_HEADER} are required").into_bytes());
+        };
+
+        let encrypted = request
+            .get_header("Content-Type")
+            .is_some_and(|content_type| content_type.eq_ignore_ascii_case(BUNDLE_CONTENT_TYPE));
+        let stored = if encrypted {
+            fs::write(self.dir.join(format!("{run}-{sequence:06}.bundle")), &request.body)
+        } else {
+            self.append(&self.dir.join(format!("{run}.ndjson")), &request.body)
+        };
+        match stored {
+            Ok(()) => Response::new(204, "No Content"),
+            Err(e) => Response::new(500, "Internal Server Error").body(e.to_string().into_bytes()),
+        }
+    }
+
+    fn append(&self, path: &Path, body: &[u8]) -> std::io::Result<()> {
+        let _guard = self.append.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(body)?;
+        if !body.ends_with(b"\n") {
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether a run identifier is safe to use in a file name.
+fn is_valid_run(run: &str) -> bool {
+    (1..=64).contains(&run.len()) && run.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
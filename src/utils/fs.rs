@@ -0,0 +1,174 @@
+//! Access to files through interchangeable backends.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Error, Result};
+
+/// An entry of a directory listing.
+///
+/// # Fields
+/// - `name`: The file name of the entry.
+/// - `is_dir`: Whether the entry is a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Trait implemented by every file system backend.
+pub trait FileBackend {
+    /// Reads the complete contents of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file, for example `C:\Windows\win.ini`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` containing the contents of the file.
+    /// * `Err(e)` if the file could not be read.
+    fn read_file(&self, path: &str) -> Result<Vec<u8>>;
+
+    /// Lists the entries of a directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<DirEntry>)` containing the entries of the directory.
+    /// * `Err(e)` if the directory could not be read.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>>;
+
+    /// Returns the file on the machine Rustbelt runs on that holds a file of the target.
+    ///
+    /// Backends that read local files return the file, so what a run read
+    /// can be accounted for. The default implementation returns `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file on the target.
+    fn source_file(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// File backend that reads the file system of the machine Rustbelt is running on.
+#[derive(Default)]
+pub struct LocalFileSystem {}
+
+impl FileBackend for LocalFileSystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(Path::new(path))?)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        list_dir(Path::new(path))
+    }
+}
+
+/// File backend for targets whose files cannot be read, such as remote machines.
+///
+/// Every operation fails with `Error::Unsupported`.
+#[derive(Default)]
+pub struct UnavailableFileSystem {}
+
+impl FileBackend for UnavailableFileSystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        Err(Error::Unsupported(format!("file {path} is not available for this target")))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        Err(Error::Unsupported(format!("directory {path} is not available for this target")))
+    }
+}
+
+/// File backend that reads the file system of a mounted or extracted Windows image.
+///
+/// Windows paths such as `C:\Windows\win.ini` are mapped into the image
+/// root. The drive letter is ignored and path components are matched case
+/// insensitively, like Windows does, even when the image is on a case
+/// sensitive file system.
+///
+/// # Fields
+/// - `root`: The root of the image, the directory that contains `Windows`.
+pub struct ImageFileSystem {
+    pub root: PathBuf,
+}
+
+impl ImageFileSystem {
+    /// Creates a backend for the image at `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ImageFileSystem { root: root.into() }
+    }
+
+    /// Maps a Windows path to a path on the local file system.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The Windows path, for example `C:\Windows\win.ini`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PathBuf)` containing the local path.
+    /// * `Err(Error::NotFound)` if a component of the path does not exist in the image.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let path = match path.as_bytes() {
+            [drive, b':', ..] if drive.is_ascii_alphabetic() => &path[2..],
+            _ => path,
+        };
+
+        let mut resolved = self.root.clone();
+        for component in path.split(['\\', '/']).filter(|component| !component.is_empty()) {
+            if component == "." || component == ".." {
+                return Err(Error::InvalidData(format!("relative path component in {path}")));
+            }
+
+            let exact = resolved.join(component);
+            if exact.exists() {
+                resolved = exact;
+                continue;
+            }
+
+            let name = fs::read_dir(&resolved)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name())
+                .find(|name| name.to_string_lossy().eq_ignore_ascii_case(component))
+                .ok_or_else(|| Error::NotFound(path.to_string()))?;
+            resolved.push(name);
+        }
+        Ok(resolved)
+    }
+}
+
+impl FileBackend for ImageFileSystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.resolve(path)?)?)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>> {
+        list_dir(&self.resolve(path)?)
+    }
+
+    fn source_file(&self, path: &str) -> Option<PathBuf> {
+        self.resolve(path).ok().filter(|path| path.is_file())
+    }
+}
+
+fn list_dir(path: &Path) -> Result<Vec<DirEntry>> {
+    let mut entries = vec![];
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            is_dir: entry.file_type()?.is_dir(),
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
@@ -0,0 +1,553 @@
+//! A minimal HTTP/1.1 client and message codec for the network backends.
+//!
+//! Protocols such as WS-Management authenticate a connection rather than a
+//! request, so the client exposes persistent connections instead of hiding
+//! them in a pool. HTTPS is available with the `tls` feature.
+
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::error::{Error, Result};
+
+/// The most header bytes accepted in one message.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// An `http` or `https` URL.
+///
+/// # Fields
+/// - `https`: Whether the URL uses TLS.
+/// - `host`: The host name or address, without brackets for IPv6 addresses.
+/// - `port`: The port, the default of the scheme if the URL has none.
+/// - `path`: The path and query, starting with `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    /// Returns the value of the `Host` header for this URL.
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let default_port = if self.https { 443 } else { 80 };
+        if self.port == default_port {
+            host
+        } else {
+            format!("{host}:{}", self.port)
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let invalid = || Error::InvalidData(format!("invalid URL {url}"));
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Url {
+            https,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+/// An HTTP request.
+///
+/// # Fields
+/// - `method`: The method, for example `POST`.
+/// - `path`: The path and query of the target.
+/// - `headers`: The headers, in order.
+/// - `body`: The body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response.
+///
+/// # Fields
+/// - `status`: The status code.
+/// - `reason`: The reason phrase.
+/// - `headers`: The headers, in order.
+/// - `body`: The body, with any transfer encoding removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Returns the first header with the given name, compared case insensitively.
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+impl Request {
+    /// Creates a request without headers or body.
+    pub fn new(method: &str, path: &str) -> Self {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a header.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Returns the first header with the given name.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+impl Response {
+    /// Creates a response without headers or body.
+    pub fn new(status: u16, reason: &str) -> Self {
+        Response {
+            status,
+            reason: reason.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a header.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the body.
+    pub fn body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// Returns the first header with the given name.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Returns every header with the given name.
+    pub fn get_headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Header names and values, in the order they were sent.
+type Headers = Vec<(String, String)>;
+
+fn protocol_error(what: &str) -> Error {
+    Error::Remote(format!("HTTP: {what}"))
+}
+
+/// Reads the start line and the headers of a message.
+///
+/// # Returns
+///
+/// `None` if the stream ended before the message started.
+fn read_head(reader: &mut impl BufRead) -> Result<Option<(String, Headers)>> {
+    let mut size = 0;
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let start = line.trim_end().to_string();
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        size += reader.read_line(&mut line)?;
+        if size > MAX_HEADER_SIZE {
+            return Err(protocol_error("headers too large"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(Some((start, headers)));
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| protocol_error(&format!("invalid header {header}")))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Reads the body of a message according to its headers.
+///
+/// # Arguments
+///
+/// * `until_eof` - Whether a message without a length runs until the end of the stream, as responses do.
+fn read_body(reader: &mut impl BufRead, headers: &[(String, String)], until_eof: bool) -> Result<Vec<u8>> {
+    let chunked = find_header(headers, "Transfer-Encoding")
+        .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+    let mut body = vec![];
+
+    if chunked {
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).map_err(|_| protocol_error("invalid chunk size"))?;
+            if size == 0 {
+                // Skip the trailers.
+                while {
+                    line.clear();
+                    reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty()
+                } {}
+                return Ok(body);
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    }
+
+    match find_header(headers, "Content-Length") {
+        Some(length) => {
+            let length: usize = length.parse().map_err(|_| protocol_error("invalid content length"))?;
+            body.resize(length, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None if until_eof => {
+            reader.read_to_end(&mut body)?;
+        }
+        None => {}
+    }
+    Ok(body)
+}
+
+/// Writes the headers and the body of a message, adding its length.
+fn write_message(writer: &mut impl Write, start: &str, headers: &[(String, String)], body: &[u8]) -> Result<()> {
+    let mut message = format!("{start}\r\n");
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        message.push_str(&format!("{name}: {value}\r\n"));
+    }
+    message.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+    let mut bytes = message.into_bytes();
+    bytes.extend_from_slice(body);
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a request, as a server does.
+///
+/// # Returns
+///
+/// * `Ok(Some(Request))` containing the request.
+/// * `Ok(None)` if the client closed the connection.
+/// * `Err(e)` if the request is malformed.
+pub fn read_request(reader: &mut impl BufRead) -> Result<Option<Request>> {
+    let Some((start, headers)) = read_head(reader)? else {
+        return Ok(None);
+    };
+    let mut parts = start.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(protocol_error(&format!("invalid request line {start}")));
+    };
+
+    let body = read_body(reader, &headers, false)?;
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body,
+    }))
+}
+
+/// Writes a response, as a server does.
+pub fn write_response(writer: &mut impl Write, response: &Response) -> Result<()> {
+    write_message(
+        writer,
+        &format!("HTTP/1.1 {} {}", response.status, response.reason),
+        &response.headers,
+        &response.body,
+    )
+}
+
+/// A byte stream an HTTP connection runs over.
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A persistent connection to an HTTP server.
+pub struct HttpConnection {
+    stream: BufReader<Box<dyn Stream>>,
+    // The socket under the stream, to change its timeouts.
+    socket: TcpStream,
+    authority: String,
+}
+
+impl HttpConnection {
+    /// Opens a connection to the server of a URL.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The URL to connect to. Only its scheme, host and port are used.
+    /// * `timeout` - The timeout for connecting and for every read and write.
+    /// * `accept_invalid_certs` - Whether to skip the verification of the server certificate of HTTPS URLs.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HttpConnection)` if the server could be reached.
+    /// * `Err(Error::Unsupported)` for HTTPS URLs when built without the `tls` feature.
+    /// * `Err(e)` if the connection or the TLS handshake failed.
+    pub fn connect(url: &Url, timeout: Duration, accept_invalid_certs: bool) -> Result<Self> {
+        let address = std::net::ToSocketAddrs::to_socket_addrs(&(url.host.as_str(), url.port))?
+            .next()
+            .ok_or_else(|| Error::NotFound(format!("address of {}", url.host)))?;
+        let tcp = TcpStream::connect_timeout(&address, timeout)?;
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        tcp.set_nodelay(true)?;
+        let socket = tcp.try_clone()?;
+
+        let stream: Box<dyn Stream> = if url.https {
+            tls::wrap(tcp, &url.host, accept_invalid_certs)?
+        } else {
+            Box::new(tcp)
+        };
+
+        Ok(HttpConnection {
+            stream: BufReader::new(stream),
+            socket,
+            authority: url.authority(),
+        })
+    }
+
+    /// Changes the timeout for every read and write from now on.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The new timeout.
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.socket.set_write_timeout(Some(timeout))?;
+        Ok(())
+    }
+
+    /// Sends a request and reads the response.
+    ///
+    /// A `Host` header is added. The connection stays open for the next
+    /// request unless the server closes it.
+    pub fn send(&mut self, request: &Request) -> Result<Response> {
+        let mut headers = vec![("Host".to_string(), self.authority.clone())];
+        headers.extend(request.headers.iter().cloned());
+        write_message(
+            self.stream.get_mut(),
+            &format!("{} {} HTTP/1.1", request.method, request.path),
+            &headers,
+            &request.body,
+        )?;
+
+        let (start, headers) =
+            read_head(&mut self.stream)?.ok_or_else(|| protocol_error("connection closed by the server"))?;
+        let mut parts = start.splitn(3, ' ');
+        let status = parts
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| protocol_error(&format!("invalid status line {start}")))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+
+        let body = if request.method == "HEAD" || status == 204 || status == 304 {
+            vec![]
+        } else {
+            read_body(&mut self.stream, &headers, true)?
+        };
+        Ok(Response {
+            status,
+            reason,
+            headers,
+            body,
+        })
+    }
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use std::{net::TcpStream, sync::Arc};
+
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+        StreamOwned,
+    };
+
+    use super::Stream;
+    use crate::error::{Error, Result};
+
+    /// Accepts any server certificate, for servers with self-signed certificates.
+    #[derive(Debug)]
+    struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    /// Wraps a connection in TLS.
+    pub(super) fn wrap(tcp: TcpStream, host: &str, accept_invalid_certs: bool) -> Result<Box<dyn Stream>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Remote(format!("TLS: {e}")))?;
+
+        let config = if accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+                .with_no_client_auth()
+        } else {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|_| Error::InvalidData(format!("invalid server name {host}")))?;
+        let connection = ClientConnection::new(Arc::new(config), name)
+            .map_err(|e| Error::Remote(format!("TLS: {e}")))?;
+        Ok(Box::new(StreamOwned::new(connection, tcp)))
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod tls {
+    use std::net::TcpStream;
+
+    use super::Stream;
+    use crate::error::{Error, Result};
+
+    pub(super) fn wrap(_tcp: TcpStream, _host: &str, _accept_invalid_certs: bool) -> Result<Box<dyn Stream>> {
+        Err(Error::Unsupported("HTTPS, Rustbelt was built without the tls feature".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let url: Url = "http://dc01:5985/wsman".parse().unwrap();
+        assert_eq!((url.https, url.host.as_str(), url.port, url.path.as_str()), (false, "dc01", 5985, "/wsman"));
+        let url: Url = "https://[::1]/wsman".parse().unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 443));
+        assert_eq!(url.to_string(), "https://[::1]/wsman");
+        assert!("ftp://host".parse::<Url>().is_err());
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        let headers = vec![("Transfer-Encoding".to_string(), "chunked".to_string())];
+        let mut data = &b"4\r\nWiki\r\n5;x=y\r\npedia\r\n0\r\nTrailer: 1\r\n\r\n"[..];
+        assert_eq!(read_body(&mut data, &headers, true).unwrap(), b"Wikipedia");
+    }
+}
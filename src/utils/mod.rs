@@ -1,2 +1,10 @@
+pub mod auth;
+pub mod bundle;
+pub mod collector;
+pub mod fs;
+pub mod http;
 pub mod registry;
+pub mod rpc;
+pub mod time;
 pub mod wmi;
+pub mod xml;
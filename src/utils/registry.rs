@@ -1,308 +0,0 @@
-use windows::core::HRESULT;
-use windows_registry::*;
-
-/// Represents the different registry hives available on a Windows system.
-#[derive(Debug, Copy, Clone)]
-pub enum RegistryHive {
-    ClassesRoot,
-    CurrentConfig,
-    CurrentUser,
-    DynData,
-    LocalMachine,
-    PerformanceData,
-    Users,
-}
-
-/// Represents the different registry view types (64-bit or 32-bit).
-#[derive(Debug, Copy, Clone)]
-pub enum RegistryHiveType {
-    X64,
-    X86,
-}
-
-/// Opens the base registry key for the given hive and registry view (x64 or x86).
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to open.
-/// * `hive_type` - The registry view type (x64 or x86).
-///
-/// # Returns
-///
-/// * `Ok(Some(Key))` if the key was successfully opened.
-/// * `Ok(None)` if the key was not found.
-/// * `Err(e)` if there was an error opening the key.
-pub fn open_base_key(hive: RegistryHive, hive_type: RegistryHiveType) -> Result<Option<Key>> {
-    let base = match hive {
-        RegistryHive::ClassesRoot => CLASSES_ROOT,
-        RegistryHive::CurrentConfig => CURRENT_CONFIG,
-        RegistryHive::CurrentUser => CURRENT_USER,
-        // RegistryHive::DynData => DYN_DATA,
-        RegistryHive::LocalMachine => LOCAL_MACHINE,
-        // RegistryHive::PerformanceData => PERFORMANCE_DATA,
-        RegistryHive::Users => USERS,
-        _ => panic!("todo registry kind {:?}", hive),
-    };
-
-    // Attempt to open the base key (empty string means the root key)
-    match base.options().read().open("") {
-        Ok(key) => Ok(Some(key)),
-        Err(e) => {
-            // If the underlying error’s raw OS error is 2 (ERROR_FILE_NOT_FOUND),
-            // we return None rather than propagating the error.
-            if e.code() == HRESULT(2) {
-                Ok(None)
-            } else {
-                Err(e)
-            }
-        }
-    }
-}
-
-/// Opens a subkey for a given registry hive and path.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-///
-/// # Returns
-///
-/// * `Ok(Key)` if the subkey was successfully opened.
-/// * `Err(e)` if there was an error opening the subkey.
-pub fn open_sub_key(hive: RegistryHive, path: &str) -> Result<Key> {
-    let base_maybe = open_base_key(hive, RegistryHiveType::X64)?;
-
-    match base_maybe {
-        Some(base) => base.open(path),
-        None => Err(HRESULT::from_nt(0).into()),
-    }
-}
-
-/// Retrieves the value from a given registry hive, path, and value name.
-///
-/// This function can be used when you don't know the type of the registry key or don't care about its type.
-/// It will always return the value in the registry as a string value. If you need the value in the actual type,
-/// use the underlying functions below.
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_value(hive: RegistryHive, path: &str, name: &str) -> Result<String> {
-    let key = open_sub_key(hive, path)?;
-    match key.get_type(name)? {
-        Type::U32 => {
-            let value = get_dword_value(hive, path, name)?;
-            return Ok(value.to_string());
-        },
-        Type::U64 => {
-            let value = get_qword_value(hive, path, name)?;
-            return Ok(value.to_string());
-        },
-        Type::String => get_string_value(hive, path, name),
-        Type::ExpandString => todo!(),
-        Type::MultiString => todo!(),
-        Type::Bytes => {
-            let value = get_binary_value(hive, path, name)?;
-            return Ok(format!("{:?}", value));
-        },
-        _ => Err(HRESULT::from_nt(0).into())
-    }
-}
-
-/// Retrieves a string value from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_string_value(hive: RegistryHive, path: &str, name: &str) -> Result<String> {
-    let key = open_sub_key(hive, path)?;
-    return key.get_value(name)?.try_into();
-}
-
-
-/// Retrieves a multi string value from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_multi_string_value(hive: RegistryHive, path: &str, name: &str) {
-    todo!();
-}
-
-/// Retrieves an expanded string value from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(Vec<String>)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_expanded_string_value(hive: RegistryHive, path: &str, name: &str) -> Result<Vec<String>> {
-    todo!();
-}
-
-/// Retrieves a dword value (32-bit number) from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_dword_value(hive: RegistryHive, path: &str, name: &str) -> Result<u32> {
-    let key = open_sub_key(hive, path)?;
-    return key.get_value(name)?.try_into();
-}
-
-/// Retrieves a qword value (64-bit number) from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_qword_value(hive: RegistryHive, path: &str, name: &str) -> Result<u64> {
-    todo!();
-}
-
-/// Retrieves a binary value from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(Vec<u8>)` containing the binary values.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_binary_value(hive: RegistryHive, path: &str, name: &str) -> Result<Vec<u8>> {
-    let value = open_sub_key(hive, path)?.get_value(name)?;
-
-    match value.get(0..value.len()) {
-        Some(value) => return Ok(value.to_vec()),
-        None => Err(HRESULT::from_nt(0).into()),
-    }
-
-}
-
-/// Retrieves a value from a given registry hive, path, and value name.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-/// * `name` - The name of the value to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(String)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_values(hive: RegistryHive, path: &str, name: &str) {
-    todo!();
-}
-
-/// Retrieves the names of the subkeys for a given registry hive and path.
-///
-/// # Arguments
-///
-/// * `hive` - The registry hive to query.
-/// * `path` - The path within the hive to query.
-///
-/// # Returns
-///
-/// * `Ok(Vec<String>)` containing the names of the subkeys.
-/// * `Err(e)` if there was an error querying the subkeys.
-pub fn get_sub_key_names(hive: RegistryHive, path: &str) -> Result<Vec<String>> {
-    let base_maybe = open_base_key(hive, RegistryHiveType::X64)?;
-
-    match base_maybe {
-        Some(base) => Ok(base.open(path)?.keys()?.map(|key| key).collect()),
-        None => Ok(vec![]),
-    }
-}
-
-/// Retrieves the user SIDs.
-///
-/// # Arguments
-///
-/// # Returns
-///
-/// * `Ok(Vec<&str>)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_user_sids(hive: RegistryHive, path: &str, name: &str) {
-    todo!();
-}
-
-/// Retrieves a registry hive from a  given name.
-///
-/// # Arguments
-///
-/// * `name` - The name of the hive to retrieve.
-///
-/// # Returns
-///
-/// * `Ok(Hive)` containing the value.
-/// * `Err(e)` if there was an error retrieving the value.
-pub fn get_hive(name: &str) -> Result<RegistryHive> {
-    todo!();
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    /// Tests opening the CURRENT_USER hive using the 64-bit view.
-    #[test]
-    fn test_open_current_user_x64() {
-        // Attempt to open the CURRENT_USER hive using the 64-bit view.
-        let key = open_base_key(RegistryHive::CurrentUser, RegistryHiveType::X64)
-            .expect("Failed to open key");
-        // When run on a normal Windows system, CURRENT_USER should always exist.
-        assert!(key.is_some());
-    }
-
-    /// Tests retrieving the names of subkeys in the SOFTWARE key of the LOCAL_MACHINE hive.
-    #[test]
-    fn test_get_sub_key_names_basic() {
-        let strings =
-            get_sub_key_names(RegistryHive::LocalMachine, "SOFTWARE").expect("Failed to open key");
-        println!("{:?}", strings);
-        assert!(strings.len() > 0)
-    }
-}
@@ -0,0 +1,380 @@
+//! Reader for registry hive files (the `regf` format).
+//!
+//! A hive is read into memory as a whole. Every offset taken from the file is
+//! bounds checked, so a corrupt hive results in an error instead of a panic.
+//! Transaction logs are not replayed, so a dirty hive is read as it is on disk.
+
+use std::{fs, path::Path};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::RegistryValue;
+use crate::error::{Error, Result};
+
+/// Size of the base block at the start of every hive. Cell offsets are relative to its end.
+pub(super) const BASE_BLOCK_SIZE: usize = 4096;
+/// Key flag: the key name is stored in (extended) ASCII instead of UTF-16.
+pub(super) const KEY_COMP_NAME: u16 = 0x0020;
+/// Value flag: the value name is stored in (extended) ASCII instead of UTF-16.
+pub(super) const VALUE_COMP_NAME: u16 = 0x0001;
+/// Data size flag: the data is stored in the data offset field itself.
+pub(super) const DATA_INLINE: u32 = 0x8000_0000;
+/// Offset used for lists and cells that do not exist.
+pub(super) const NO_CELL: u32 = 0xffff_ffff;
+/// Largest value data stored in a single cell by hives of version 1.4 and later.
+const BIG_DATA_THRESHOLD: usize = 16344;
+/// Maximum nesting of `ri` subkey lists, to protect against cycles.
+const MAX_LIST_DEPTH: usize = 8;
+
+/// Reads a little endian `u16` at `offset`.
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(LittleEndian::read_u16)
+        .ok_or_else(|| truncated(offset))
+}
+
+/// Reads a little endian `u32` at `offset`.
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(LittleEndian::read_u32)
+        .ok_or_else(|| truncated(offset))
+}
+
+/// Reads a little endian `u64` at `offset`.
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    data.get(offset..offset + 8)
+        .map(LittleEndian::read_u64)
+        .ok_or_else(|| truncated(offset))
+}
+
+/// Reads `len` bytes at `offset`.
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    data.get(offset..offset + len).ok_or_else(|| truncated(offset))
+}
+
+fn truncated(offset: usize) -> Error {
+    Error::InvalidData(format!("hive record truncated at offset {offset}"))
+}
+
+/// Decodes a key or value name.
+fn decode_name(bytes: &[u8], compressed: bool) -> String {
+    if compressed {
+        bytes.iter().map(|&b| b as char).collect()
+    } else {
+        decode_utf16(bytes)
+    }
+}
+
+/// Decodes UTF-16LE bytes, ignoring a trailing odd byte.
+pub(super) fn decode_utf16(bytes: &[u8]) -> String {
+    let wide: Vec<u16> = bytes.chunks_exact(2).map(LittleEndian::read_u16).collect();
+    String::from_utf16_lossy(&wide)
+}
+
+/// Decodes a `REG_SZ` or `REG_EXPAND_SZ` string, which ends at the first NUL.
+fn decode_string(bytes: &[u8]) -> String {
+    let string = decode_utf16(bytes);
+    match string.find('\0') {
+        Some(end) => string[..end].to_string(),
+        None => string,
+    }
+}
+
+/// Decodes the raw data of a value into a `RegistryValue`.
+pub fn decode_value(data_type: u32, data: Vec<u8>) -> Result<RegistryValue> {
+    let value = match data_type {
+        0 => RegistryValue::None,
+        1 => RegistryValue::String(decode_string(&data)),
+        2 => RegistryValue::ExpandString(decode_string(&data)),
+        3 => RegistryValue::Binary(data),
+        4 => RegistryValue::DWord(read_u32(&data, 0)?),
+        7 => RegistryValue::MultiString(
+            decode_utf16(&data)
+                .split('\0')
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ),
+        11 => RegistryValue::QWord(read_u64(&data, 0)?),
+        other => RegistryValue::Other(other, data),
+    };
+    Ok(value)
+}
+
+/// Compares two key or value names the way Windows does (case insensitive).
+pub(super) fn names_equal(a: &str, b: &str) -> bool {
+    a == b || a.to_uppercase() == b.to_uppercase()
+}
+
+/// A registry hive file loaded into memory.
+pub struct Hive {
+    data: Vec<u8>,
+    root: u32,
+    minor_version: u32,
+}
+
+impl Hive {
+    /// Parses a hive from its bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The complete contents of the hive file.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Hive)` if the data starts with a valid base block.
+    /// * `Err(e)` if the data is not a hive.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        if data.len() < BASE_BLOCK_SIZE || &data[0..4] != b"regf" {
+            return Err(Error::InvalidData("not a registry hive".to_string()));
+        }
+
+        let hive = Hive {
+            minor_version: read_u32(&data, 24)?,
+            root: read_u32(&data, 36)?,
+            data,
+        };
+        // Make sure the root key can be read before handing out the hive.
+        hive.root()?;
+        Ok(hive)
+    }
+
+    /// Reads and parses a hive file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the hive file, for example `Windows\System32\config\SOFTWARE`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Hive::from_bytes(fs::read(path)?)
+    }
+
+    /// Returns the root key of the hive.
+    pub fn root(&self) -> Result<KeyNode<'_>> {
+        KeyNode::read(self, self.root)
+    }
+
+    /// Opens a key by its path relative to the root key.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The backslash separated path of the key. An empty path is the root key.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(KeyNode)` if the key exists.
+    /// * `Err(Error::NotFound)` if the key does not exist.
+    /// * `Err(e)` if the hive is corrupt.
+    pub fn open_key(&self, path: &str) -> Result<KeyNode<'_>> {
+        let mut key = self.root()?;
+
+        for component in path.split('\\').filter(|component| !component.is_empty()) {
+            key = key
+                .sub_key(component)?
+                .ok_or_else(|| Error::NotFound(path.to_string()))?;
+        }
+        Ok(key)
+    }
+
+    /// Returns the data of the cell at `offset`, without its size field.
+    fn cell(&self, offset: u32) -> Result<&[u8]> {
+        let start = BASE_BLOCK_SIZE
+            .checked_add(offset as usize)
+            .ok_or_else(|| truncated(offset as usize))?;
+        let size = read_u32(&self.data, start)? as i32;
+        let size = size.unsigned_abs() as usize;
+
+        if size < 4 {
+            return Err(Error::InvalidData(format!("invalid cell size at offset {offset}")));
+        }
+        read_bytes(&self.data, start + 4, size - 4)
+    }
+}
+
+/// A key (`nk` record) in a hive.
+#[derive(Clone, Copy)]
+pub struct KeyNode<'a> {
+    hive: &'a Hive,
+    data: &'a [u8],
+}
+
+impl<'a> KeyNode<'a> {
+    fn read(hive: &'a Hive, offset: u32) -> Result<Self> {
+        let data = hive.cell(offset)?;
+
+        if data.get(0..2) != Some(b"nk") {
+            return Err(Error::InvalidData(format!("expected a key at offset {offset}")));
+        }
+        Ok(KeyNode { hive, data })
+    }
+
+    /// The name of the key.
+    pub fn name(&self) -> Result<String> {
+        let flags = read_u16(self.data, 2)?;
+        let len = read_u16(self.data, 72)? as usize;
+        Ok(decode_name(read_bytes(self.data, 76, len)?, flags & KEY_COMP_NAME != 0))
+    }
+
+    /// The last write time of the key, as a Windows `FILETIME`.
+    pub fn last_written(&self) -> Result<u64> {
+        read_u64(self.data, 4)
+    }
+
+    /// Returns the subkeys of the key.
+    pub fn sub_keys(&self) -> Result<Vec<KeyNode<'a>>> {
+        let count = read_u32(self.data, 20)?;
+        let list = read_u32(self.data, 28)?;
+
+        let mut offsets = vec![];
+        if count > 0 && list != NO_CELL {
+            self.collect_sub_key_offsets(list, 0, &mut offsets)?;
+        }
+        offsets
+            .into_iter()
+            .map(|offset| KeyNode::read(self.hive, offset))
+            .collect()
+    }
+
+    /// Collects the key offsets of a subkey list, following `ri` lists.
+    fn collect_sub_key_offsets(&self, list: u32, depth: usize, offsets: &mut Vec<u32>) -> Result<()> {
+        if depth > MAX_LIST_DEPTH {
+            return Err(Error::InvalidData("subkey lists nested too deeply".to_string()));
+        }
+
+        let data = self.hive.cell(list)?;
+        let count = read_u16(data, 2)? as usize;
+
+        match data.get(0..2) {
+            Some(b"lf") | Some(b"lh") => {
+                for index in 0..count {
+                    offsets.push(read_u32(data, 4 + index * 8)?);
+                }
+            }
+            Some(b"li") => {
+                for index in 0..count {
+                    offsets.push(read_u32(data, 4 + index * 4)?);
+                }
+            }
+            Some(b"ri") => {
+                for index in 0..count {
+                    self.collect_sub_key_offsets(read_u32(data, 4 + index * 4)?, depth + 1, offsets)?;
+                }
+            }
+            _ => {
+                return Err(Error::InvalidData(format!(
+                    "expected a subkey list at offset {list}"
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the subkey with the given name, if it exists.
+    pub fn sub_key(&self, name: &str) -> Result<Option<KeyNode<'a>>> {
+        for key in self.sub_keys()? {
+            if names_equal(&key.name()?, name) {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the values of the key.
+    pub fn values(&self) -> Result<Vec<ValueNode<'a>>> {
+        let count = read_u32(self.data, 36)? as usize;
+        let list = read_u32(self.data, 40)?;
+
+        if count == 0 || list == NO_CELL {
+            return Ok(vec![]);
+        }
+
+        let offsets = self.hive.cell(list)?;
+        (0..count)
+            .map(|index| ValueNode::read(self.hive, read_u32(offsets, index * 4)?))
+            .collect()
+    }
+
+    /// Returns the value with the given name, if it exists. An empty name is the default value.
+    pub fn value(&self, name: &str) -> Result<Option<ValueNode<'a>>> {
+        for value in self.values()? {
+            if names_equal(&value.name()?, name) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A value (`vk` record) in a hive.
+#[derive(Clone, Copy)]
+pub struct ValueNode<'a> {
+    hive: &'a Hive,
+    data: &'a [u8],
+}
+
+impl<'a> ValueNode<'a> {
+    fn read(hive: &'a Hive, offset: u32) -> Result<Self> {
+        let data = hive.cell(offset)?;
+
+        if data.get(0..2) != Some(b"vk") {
+            return Err(Error::InvalidData(format!("expected a value at offset {offset}")));
+        }
+        Ok(ValueNode { hive, data })
+    }
+
+    /// The name of the value. The default value has an empty name.
+    pub fn name(&self) -> Result<String> {
+        let len = read_u16(self.data, 2)? as usize;
+        let flags = read_u16(self.data, 16)?;
+        Ok(decode_name(read_bytes(self.data, 20, len)?, flags & VALUE_COMP_NAME != 0))
+    }
+
+    /// The type of the value, for example `1` for `REG_SZ`.
+    pub fn data_type(&self) -> Result<u32> {
+        read_u32(self.data, 12)
+    }
+
+    /// The raw data of the value.
+    pub fn raw_data(&self) -> Result<Vec<u8>> {
+        let size = read_u32(self.data, 4)?;
+
+        if size & DATA_INLINE != 0 {
+            let len = ((size & !DATA_INLINE) as usize).min(4);
+            return Ok(read_bytes(self.data, 8, len)?.to_vec());
+        }
+
+        let size = size as usize;
+        let cell = self.hive.cell(read_u32(self.data, 8)?)?;
+
+        if size > BIG_DATA_THRESHOLD && self.hive.minor_version >= 4 && cell.get(0..2) == Some(b"db") {
+            return self.big_data(cell, size);
+        }
+        Ok(read_bytes(cell, 0, size)?.to_vec())
+    }
+
+    /// Reads data that is split over the segments of a `db` record.
+    fn big_data(&self, record: &[u8], size: usize) -> Result<Vec<u8>> {
+        let count = read_u16(record, 2)? as usize;
+        let segments = self.hive.cell(read_u32(record, 4)?)?;
+
+        // The size is untrusted, so it is checked against what the segments can hold before allocating it.
+        if size > count * BIG_DATA_THRESHOLD || size > self.hive.data.len() {
+            return Err(Error::InvalidData("big data value is larger than its segments".to_string()));
+        }
+        let mut data = Vec::with_capacity(size);
+        for index in 0..count {
+            let segment = self.hive.cell(read_u32(segments, index * 4)?)?;
+            let len = segment.len().min(BIG_DATA_THRESHOLD).min(size - data.len());
+            data.extend_from_slice(&segment[..len]);
+        }
+
+        if data.len() < size {
+            return Err(Error::InvalidData("big data value is truncated".to_string()));
+        }
+        Ok(data)
+    }
+
+    /// The value, decoded according to its type.
+    pub fn value(&self) -> Result<RegistryValue> {
+        decode_value(self.data_type()?, self.raw_data()?)
+    }
+}
@@ -0,0 +1,417 @@
+//! Writer for registry hive files (the `regf` format).
+//!
+//! The writer produces small, valid hives from scratch, for example to build
+//! synthetic hives for tests. It writes version 1.3 hives: all cells live in a
+//! single hive bin and subkeys are indexed by `lf` lists.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{
+    hive::{names_equal, BASE_BLOCK_SIZE, DATA_INLINE, KEY_COMP_NAME, NO_CELL, VALUE_COMP_NAME},
+    RegistryValue,
+};
+
+/// Key flags of the root key: `KEY_HIVE_ENTRY | KEY_NO_DELETE`.
+const ROOT_KEY_FLAGS: u16 = 0x0004 | 0x0008;
+/// Size of the header of a hive bin.
+const BIN_HEADER_SIZE: usize = 32;
+/// Hive bins are allocated in multiples of this size.
+const BIN_ALIGNMENT: usize = 4096;
+
+/// A key that is being built.
+#[derive(Default)]
+struct KeyBuilder {
+    name: String,
+    last_written: u64,
+    values: Vec<(String, RegistryValue)>,
+    sub_keys: Vec<KeyBuilder>,
+}
+
+impl KeyBuilder {
+    /// Returns the subkey with the given name, creating it if needed.
+    fn sub_key(&mut self, name: &str) -> &mut KeyBuilder {
+        let index = match self.sub_keys.iter().position(|key| names_equal(&key.name, name)) {
+            Some(index) => index,
+            None => {
+                self.sub_keys.push(KeyBuilder {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.sub_keys.len() - 1
+            }
+        };
+        &mut self.sub_keys[index]
+    }
+}
+
+/// Builds a registry hive in memory.
+///
+/// ```
+/// use rustbelt::utils::registry::{hive::Hive, hive_writer::HiveBuilder, RegistryValue};
+///
+/// let mut builder = HiveBuilder::new();
+/// builder.set_value("Microsoft\\Cryptography", "MachineGuid", RegistryValue::String("x".into()));
+///
+/// let hive = Hive::from_bytes(builder.build()).unwrap();
+/// let key = hive.open_key("Microsoft\\Cryptography").unwrap();
+/// assert_eq!(key.value("MachineGuid").unwrap().unwrap().value().unwrap(), RegistryValue::String("x".into()));
+/// ```
+#[derive(Default)]
+pub struct HiveBuilder {
+    root: KeyBuilder,
+}
+
+impl HiveBuilder {
+    /// Creates a builder for an empty hive.
+    pub fn new() -> Self {
+        HiveBuilder {
+            root: KeyBuilder {
+                name: "ROOT".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns the key at `path`, creating it and its parents if needed.
+    fn key(&mut self, path: &str) -> &mut KeyBuilder {
+        let mut key = &mut self.root;
+        for component in path.split('\\').filter(|component| !component.is_empty()) {
+            key = key.sub_key(component);
+        }
+        key
+    }
+
+    /// Creates a key and its parents.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key relative to the root key.
+    pub fn add_key(&mut self, path: &str) -> &mut Self {
+        self.key(path);
+        self
+    }
+
+    /// Sets a value, creating the key if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key relative to the root key.
+    /// * `name` - The name of the value. An empty name is the default value.
+    /// * `value` - The value to store.
+    pub fn set_value(&mut self, path: &str, name: &str, value: RegistryValue) -> &mut Self {
+        let key = self.key(path);
+        match key.values.iter_mut().find(|(existing, _)| names_equal(existing, name)) {
+            Some((_, existing)) => *existing = value,
+            None => key.values.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    /// Sets the last write time of a key, creating the key if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key relative to the root key.
+    /// * `filetime` - The last write time as a Windows `FILETIME`.
+    pub fn set_last_written(&mut self, path: &str, filetime: u64) -> &mut Self {
+        self.key(path).last_written = filetime;
+        self
+    }
+
+    /// Serializes the hive.
+    ///
+    /// # Returns
+    ///
+    /// The bytes of a complete hive file.
+    pub fn build(&self) -> Vec<u8> {
+        let mut cells = CellWriter {
+            bin: vec![0; BIN_HEADER_SIZE],
+        };
+        let root = cells.write_key(&self.root, NO_CELL, true);
+
+        // Pad the bin and mark the remaining space as a free cell.
+        let bin_size = cells.bin.len().div_ceil(BIN_ALIGNMENT) * BIN_ALIGNMENT;
+        let free = bin_size - cells.bin.len();
+        if free > 0 {
+            let mut free_cell = vec![0; free];
+            LittleEndian::write_i32(&mut free_cell, free as i32);
+            cells.bin.extend_from_slice(&free_cell);
+        }
+
+        let mut bin = cells.bin;
+        bin[0..4].copy_from_slice(b"hbin");
+        LittleEndian::write_u32(&mut bin[4..8], 0);
+        LittleEndian::write_u32(&mut bin[8..12], bin_size as u32);
+
+        let mut hive = vec![0; BASE_BLOCK_SIZE];
+        hive[0..4].copy_from_slice(b"regf");
+        LittleEndian::write_u32(&mut hive[4..8], 1); // primary sequence number
+        LittleEndian::write_u32(&mut hive[8..12], 1); // secondary sequence number
+        LittleEndian::write_u32(&mut hive[20..24], 1); // major version
+        LittleEndian::write_u32(&mut hive[24..28], 3); // minor version
+        LittleEndian::write_u32(&mut hive[32..36], 1); // file format: direct memory load
+        LittleEndian::write_u32(&mut hive[36..40], root);
+        LittleEndian::write_u32(&mut hive[40..44], bin_size as u32);
+        LittleEndian::write_u32(&mut hive[44..48], 1); // clustering factor
+
+        let checksum = (0..127).fold(0u32, |checksum, index| {
+            checksum ^ LittleEndian::read_u32(&hive[index * 4..index * 4 + 4])
+        });
+        let checksum = match checksum {
+            0 => 1,
+            0xffff_ffff => 0xffff_fffe,
+            checksum => checksum,
+        };
+        LittleEndian::write_u32(&mut hive[508..512], checksum);
+
+        hive.extend_from_slice(&bin);
+        hive
+    }
+}
+
+/// Encodes a name, compressed to one byte per character when possible.
+fn encode_name(name: &str) -> (Vec<u8>, bool) {
+    if name.chars().all(|c| (c as u32) < 0x100) {
+        (name.chars().map(|c| c as u8).collect(), true)
+    } else {
+        (encode_utf16(name), false)
+    }
+}
+
+fn encode_utf16(string: &str) -> Vec<u8> {
+    string.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Encodes a value into its type number and raw data.
+pub fn encode_value(value: &RegistryValue) -> (u32, Vec<u8>) {
+    let terminated = |string: &str| {
+        let mut data = encode_utf16(string);
+        data.extend_from_slice(&[0, 0]);
+        data
+    };
+
+    match value {
+        RegistryValue::None => (0, vec![]),
+        RegistryValue::String(string) => (1, terminated(string)),
+        RegistryValue::ExpandString(string) => (2, terminated(string)),
+        RegistryValue::Binary(data) => (3, data.clone()),
+        RegistryValue::DWord(number) => (4, number.to_le_bytes().to_vec()),
+        RegistryValue::MultiString(strings) => {
+            let mut data: Vec<u8> = strings.iter().flat_map(|string| terminated(string)).collect();
+            data.extend_from_slice(&[0, 0]);
+            (7, data)
+        }
+        RegistryValue::QWord(number) => (11, number.to_le_bytes().to_vec()),
+        RegistryValue::Other(data_type, data) => (*data_type, data.clone()),
+    }
+}
+
+/// Allocates cells in a single hive bin.
+struct CellWriter {
+    bin: Vec<u8>,
+}
+
+impl CellWriter {
+    /// Allocates a cell for `data` and returns its offset.
+    fn alloc(&mut self, data: &[u8]) -> u32 {
+        let offset = self.bin.len();
+        let size = (data.len() + 4).div_ceil(8) * 8;
+
+        self.bin.extend_from_slice(&(-(size as i32)).to_le_bytes());
+        self.bin.extend_from_slice(data);
+        self.bin.resize(offset + size, 0);
+        offset as u32
+    }
+
+    /// Returns the data of the cell at `offset`, to fill it in after allocation.
+    fn cell_mut(&mut self, offset: u32) -> &mut [u8] {
+        &mut self.bin[offset as usize + 4..]
+    }
+
+    /// Writes a key, its values and its subkeys, and returns the offset of the key.
+    fn write_key(&mut self, key: &KeyBuilder, parent: u32, is_root: bool) -> u32 {
+        let (name, compressed) = encode_name(&key.name);
+        let offset = self.alloc(&vec![0; 76 + name.len()]);
+
+        // Subkeys are sorted by their uppercase name, as Windows expects.
+        let mut sub_keys: Vec<&KeyBuilder> = key.sub_keys.iter().collect();
+        sub_keys.sort_by_key(|sub_key| sub_key.name.to_uppercase());
+
+        let mut entries = vec![];
+        for sub_key in &sub_keys {
+            let sub_key_offset = self.write_key(sub_key, offset, false);
+            let mut hint = [0u8; 4];
+            for (index, c) in sub_key.name.chars().take(4).enumerate() {
+                hint[index] = if (c as u32) < 0x100 { c as u8 } else { 0 };
+            }
+            entries.push((sub_key_offset, hint));
+        }
+
+        let sub_key_list = if entries.is_empty() {
+            NO_CELL
+        } else {
+            let mut list = b"lf".to_vec();
+            list.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+            for (entry_offset, hint) in &entries {
+                list.extend_from_slice(&entry_offset.to_le_bytes());
+                list.extend_from_slice(hint);
+            }
+            self.alloc(&list)
+        };
+
+        let mut value_offsets = vec![];
+        for (value_name, value) in &key.values {
+            value_offsets.push(self.write_value(value_name, value));
+        }
+        let value_list = if value_offsets.is_empty() {
+            NO_CELL
+        } else {
+            let list: Vec<u8> = value_offsets.iter().flat_map(|offset| offset.to_le_bytes()).collect();
+            self.alloc(&list)
+        };
+
+        let max_sub_key_name = sub_keys.iter().map(|sub_key| sub_key.name.len() * 2).max().unwrap_or(0);
+        let max_value_name = key.values.iter().map(|(name, _)| name.len() * 2).max().unwrap_or(0);
+        let max_value_data = key
+            .values
+            .iter()
+            .map(|(_, value)| encode_value(value).1.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut flags = if compressed { KEY_COMP_NAME } else { 0 };
+        if is_root {
+            flags |= ROOT_KEY_FLAGS;
+        }
+
+        let nk = self.cell_mut(offset);
+        nk[0..2].copy_from_slice(b"nk");
+        LittleEndian::write_u16(&mut nk[2..4], flags);
+        LittleEndian::write_u64(&mut nk[4..12], key.last_written);
+        LittleEndian::write_u32(&mut nk[16..20], parent);
+        LittleEndian::write_u32(&mut nk[20..24], entries.len() as u32);
+        LittleEndian::write_u32(&mut nk[28..32], sub_key_list);
+        LittleEndian::write_u32(&mut nk[32..36], NO_CELL); // volatile subkeys
+        LittleEndian::write_u32(&mut nk[36..40], value_offsets.len() as u32);
+        LittleEndian::write_u32(&mut nk[40..44], value_list);
+        LittleEndian::write_u32(&mut nk[44..48], NO_CELL); // security descriptor
+        LittleEndian::write_u32(&mut nk[48..52], NO_CELL); // class name
+        LittleEndian::write_u32(&mut nk[52..56], max_sub_key_name as u32);
+        LittleEndian::write_u32(&mut nk[60..64], max_value_name as u32);
+        LittleEndian::write_u32(&mut nk[64..68], max_value_data as u32);
+        LittleEndian::write_u16(&mut nk[72..74], name.len() as u16);
+        nk[76..76 + name.len()].copy_from_slice(&name);
+
+        offset
+    }
+
+    /// Writes a value and its data, and returns the offset of the value.
+    fn write_value(&mut self, name: &str, value: &RegistryValue) -> u32 {
+        let (name, compressed) = encode_name(name);
+        let (data_type, data) = encode_value(value);
+
+        let (size, data_field) = if data.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..data.len()].copy_from_slice(&data);
+            (data.len() as u32 | DATA_INLINE, inline)
+        } else {
+            (data.len() as u32, self.alloc(&data).to_le_bytes())
+        };
+
+        let mut vk = vec![0; 20 + name.len()];
+        vk[0..2].copy_from_slice(b"vk");
+        LittleEndian::write_u16(&mut vk[2..4], name.len() as u16);
+        LittleEndian::write_u32(&mut vk[4..8], size);
+        vk[8..12].copy_from_slice(&data_field);
+        LittleEndian::write_u32(&mut vk[12..16], data_type);
+        LittleEndian::write_u16(&mut vk[16..18], if compressed { VALUE_COMP_NAME } else { 0 });
+        vk[20..].copy_from_slice(&name);
+
+        self.alloc(&vk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hive::Hive;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let values = vec![
+            ("", RegistryValue::String("default".to_string())),
+            ("Small", RegistryValue::DWord(42)),
+            ("Large", RegistryValue::QWord(u64::MAX)),
+            ("Path", RegistryValue::ExpandString("%SystemRoot%\\x".to_string())),
+            ("List", RegistryValue::MultiString(vec!["a".to_string(), "b".to_string()])),
+            ("Blob", RegistryValue::Binary((0..=255).collect())),
+            ("Empty", RegistryValue::None),
+            ("Ünïcødé ☃", RegistryValue::Other(5, vec![0, 0, 0, 1])),
+        ];
+
+        let mut builder = HiveBuilder::new();
+        for (name, value) in &values {
+            builder.set_value("Software\\Test", name, value.clone());
+        }
+        builder.add_key("Software\\Empty");
+        builder.add_key("System");
+        builder.set_last_written("Software\\Test", 133_000_000_000_000_000);
+
+        let hive = Hive::from_bytes(builder.build()).expect("Failed to parse hive");
+
+        let root_names: Vec<String> = hive
+            .root()
+            .unwrap()
+            .sub_keys()
+            .unwrap()
+            .iter()
+            .map(|key| key.name().unwrap())
+            .collect();
+        assert_eq!(root_names, vec!["Software", "System"]);
+
+        let key = hive.open_key("SOFTWARE\\test").expect("Failed to open key");
+        assert_eq!(key.name().unwrap(), "Test");
+        assert_eq!(key.last_written().unwrap(), 133_000_000_000_000_000);
+        for (name, value) in &values {
+            let node = key.value(name).unwrap().expect("Value is missing");
+            assert_eq!(&node.name().unwrap(), name);
+            assert_eq!(&node.value().unwrap(), value);
+        }
+        assert!(key.value("Missing").unwrap().is_none());
+        assert!(hive.open_key("Software\\Missing").is_err());
+    }
+
+    #[test]
+    fn test_corrupt_hive_is_an_error() {
+        let mut data = HiveBuilder::new().build();
+        // Point the root key past the end of the file.
+        LittleEndian::write_u32(&mut data[36..40], 0x7fff_0000);
+
+        assert!(Hive::from_bytes(data).is_err());
+        assert!(Hive::from_bytes(b"regf".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_oversized_big_data_is_an_error() {
+        let mut builder = HiveBuilder::new();
+        builder.set_value("Software", "Blob", RegistryValue::Binary(vec![0xaa; 8]));
+        let mut data = builder.build();
+        // Big data needs format 1.4 or later.
+        LittleEndian::write_u32(&mut data[24..28], 5);
+
+        // Turn the data cell into a `db` record with one segment, and claim far more data than it holds.
+        let find = |data: &[u8], needle: &[u8]| data.windows(needle.len()).position(|window| window == needle).unwrap();
+        let cell = find(&data, &[0xaa; 8]);
+        let offset = (cell - 4 - BASE_BLOCK_SIZE) as u32;
+        data[cell..cell + 2].copy_from_slice(b"db");
+        LittleEndian::write_u16(&mut data[cell + 2..cell + 4], 1);
+        LittleEndian::write_u32(&mut data[cell + 4..cell + 8], offset);
+        let vk = find(&data, b"Blob") - 20;
+        LittleEndian::write_u32(&mut data[vk + 4..vk + 8], 0x7fff_0000);
+
+        let hive = Hive::from_bytes(data).expect("Failed to parse hive");
+        let value = hive.open_key("Software").unwrap().value("Blob").unwrap().expect("Value is missing");
+        let error = value.raw_data().unwrap_err().to_string();
+        assert!(error.contains("larger than its segments"), "{error}");
+    }
+}
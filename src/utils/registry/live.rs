@@ -0,0 +1,269 @@
+//! Registry backend for the registry of the local machine.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use windows::{
+    core::{HRESULT, HSTRING},
+    Win32::{
+        Foundation::FILETIME,
+        System::Registry::{
+            RegLoadAppKeyW, RegQueryInfoKeyW, HKEY, KEY_READ, KEY_WOW64_32KEY, KEY_WOW64_64KEY, REG_PROCESS_APPKEY,
+        },
+    },
+};
+use windows_registry::*;
+
+use super::{
+    expand_environment_strings,
+    users::{CLASSES_HIVE_FILE, CLASSES_SUFFIX, PROFILE_LIST, USER_HIVE_FILE},
+    RegistryBackend, RegistryHive, RegistryHiveType, RegistryValue,
+};
+use crate::{
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// `HRESULT` of `ERROR_FILE_NOT_FOUND`, returned when a key or value does not exist.
+const HRESULT_FILE_NOT_FOUND: HRESULT = HRESULT::from_win32(2);
+
+/// Registry backend that reads the registry of the machine Rustbelt is running on.
+///
+/// The backend shows the 64-bit view by default, even when Rustbelt is a
+/// 32-bit program, see `LiveRegistry::with_view`. The hives of users that are not logged on are not in `HKEY_USERS`. They
+/// are loaded from the profile of the user the first time one of their keys
+/// is accessed, as hives private to this process, which needs administrative
+/// rights.
+#[derive(Default)]
+pub struct LiveRegistry {
+    view: RegistryHiveType,
+    // The hives loaded from profiles by their name in HKEY_USERS, in upper case, or None if they could not be.
+    user_hives: RefCell<HashMap<String, Option<Key>>>,
+}
+
+/// Converts a registry error, turning missing keys and values into `Error::NotFound`.
+fn map_error(e: windows::core::Error, what: &str) -> Error {
+    if e.code() == HRESULT_FILE_NOT_FOUND {
+        Error::NotFound(what.to_string())
+    } else {
+        Error::Windows(e)
+    }
+}
+
+/// Returns the access flag that selects a registry view.
+fn view_access(hive_type: RegistryHiveType) -> u32 {
+    match hive_type {
+        RegistryHiveType::X64 => KEY_WOW64_64KEY.0,
+        RegistryHiveType::X86 => KEY_WOW64_32KEY.0,
+    }
+}
+
+/// Opens the base registry key for the given hive and registry view (x64 or x86).
+///
+/// # Arguments
+///
+/// * `hive` - The registry hive to open.
+/// * `hive_type` - The registry view type (x64 or x86).
+///
+/// # Returns
+///
+/// * `Ok(Some(Key))` if the key was successfully opened.
+/// * `Ok(None)` if the key was not found.
+/// * `Err(Error::Unsupported)` for `DynData`, which only Windows 9x had, and `PerformanceData`, which is not a tree of keys.
+/// * `Err(e)` if there was an error opening the key.
+pub fn open_base_key(hive: RegistryHive, hive_type: RegistryHiveType) -> Result<Option<Key>> {
+    let base = match hive {
+        RegistryHive::ClassesRoot => CLASSES_ROOT,
+        RegistryHive::CurrentConfig => CURRENT_CONFIG,
+        RegistryHive::CurrentUser => CURRENT_USER,
+        RegistryHive::LocalMachine => LOCAL_MACHINE,
+        RegistryHive::Users => USERS,
+        RegistryHive::DynData | RegistryHive::PerformanceData => {
+            return Err(Error::Unsupported(format!("{hive:?} hive")))
+        }
+    };
+
+    // Attempt to open the base key (empty string means the root key)
+    match base.options().read().access(view_access(hive_type)).open("") {
+        Ok(key) => Ok(Some(key)),
+        Err(e) => {
+            // If the key does not exist we return None rather than propagating the error.
+            if e.code() == HRESULT_FILE_NOT_FOUND {
+                Ok(None)
+            } else {
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Opens a subkey for a given registry hive, registry view and path.
+///
+/// # Arguments
+///
+/// * `hive` - The registry hive to query.
+/// * `hive_type` - The registry view type (x64 or x86).
+/// * `path` - The path within the hive to query.
+///
+/// # Returns
+///
+/// * `Ok(Key)` if the subkey was successfully opened.
+/// * `Err(e)` if there was an error opening the subkey.
+pub fn open_sub_key(hive: RegistryHive, hive_type: RegistryHiveType, path: &str) -> Result<Key> {
+    let base_maybe = open_base_key(hive, hive_type)?;
+
+    match base_maybe {
+        // The view has to be selected again for every key that is opened.
+        Some(base) => base
+            .options()
+            .read()
+            .access(view_access(hive_type))
+            .open(path)
+            .map_err(|e| map_error(e, path)),
+        None => Err(Error::NotFound(format!("{:?}", hive))),
+    }
+}
+
+impl LiveRegistry {
+    /// Creates a backend that shows a registry view.
+    ///
+    /// # Arguments
+    ///
+    /// * `view` - The view, `X86` for what 32-bit programs see.
+    pub fn with_view(view: RegistryHiveType) -> Self {
+        LiveRegistry { view, ..LiveRegistry::default() }
+    }
+
+    /// Opens a key, loading the hive of a user that is not logged on when the key is in it.
+    fn open(&self, hive: RegistryHive, path: &str) -> Result<Key> {
+        match open_sub_key(hive, self.view, path) {
+            Err(Error::NotFound(_)) if hive == RegistryHive::Users => self.open_user_key(path),
+            result => result,
+        }
+    }
+
+    fn open_user_key(&self, path: &str) -> Result<Key> {
+        let path = path.trim_matches('\\');
+        let (name, rest) = path.split_once('\\').unwrap_or((path, ""));
+        let mut hives = self.user_hives.borrow_mut();
+        let hive = hives.entry(name.to_uppercase()).or_insert_with(|| load_user_hive(name));
+        match hive {
+            Some(hive) => hive.open(rest).map_err(|e| map_error(e, path)),
+            None => Err(Error::NotFound(format!("Users\\{path}"))),
+        }
+    }
+}
+
+/// Loads the hive of a user, or the classes of a user, from the profile of the user.
+///
+/// # Arguments
+///
+/// * `name` - The name of the hive in `HKEY_USERS`, the SID of the user with `_Classes` for their classes.
+///
+/// # Returns
+///
+/// The root key of the hive, or `None` if the user has no profile or the hive could not be loaded.
+fn load_user_hive(name: &str) -> Option<Key> {
+    let (sid, file) = match name.strip_suffix(CLASSES_SUFFIX) {
+        Some(sid) => (sid, CLASSES_HIVE_FILE),
+        None => (name, USER_HIVE_FILE),
+    };
+    let profile = open_sub_key(RegistryHive::LocalMachine, RegistryHiveType::X64, &format!("{PROFILE_LIST}\\{sid}")).ok()?;
+    let environment = std::env::vars().collect();
+    let directory = expand_environment_strings(&profile.get_string("ProfileImagePath").ok()?, &environment);
+    let file = HSTRING::from(format!("{directory}\\{file}"));
+
+    let mut handle = HKEY::default();
+    // SAFETY: `handle` is only used if the call succeeded, and is then owned by the key.
+    unsafe {
+        RegLoadAppKeyW(&file, &mut handle, KEY_READ.0, REG_PROCESS_APPKEY, None)
+            .ok()
+            .ok()?;
+        Some(Key::from_raw(handle.0))
+    }
+}
+
+impl RegistryBackend for LiveRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        Ok(self.open(hive, path)?.keys()?.collect())
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let key = self.open(hive, path)?;
+        let names = key.values()?.map(|(name, _)| name).collect();
+        Ok(names)
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        let key = self.open(hive, path)?;
+        let value = key
+            .get_value(name)
+            .map_err(|e| map_error(e, &format!("{path}\\{name}")))?;
+
+        let value = match value.ty() {
+            Type::U32 => RegistryValue::DWord(value.try_into()?),
+            Type::U64 => RegistryValue::QWord(value.try_into()?),
+            Type::String => RegistryValue::String(value.try_into()?),
+            Type::ExpandString => RegistryValue::ExpandString(value.try_into()?),
+            Type::MultiString => {
+                let strings: Vec<String> = value.try_into()?;
+                RegistryValue::MultiString(strings.into_iter().filter(|s| !s.is_empty()).collect())
+            }
+            Type::Bytes => RegistryValue::Binary(value.to_vec()),
+            Type::Other(0) => RegistryValue::None,
+            Type::Other(ty) => RegistryValue::Other(ty, value.to_vec()),
+        };
+        Ok(value)
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        let key = self.open(hive, path)?;
+        let mut last_write_time = FILETIME::default();
+
+        unsafe {
+            RegQueryInfoKeyW(
+                HKEY(key.as_raw()),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(&mut last_write_time),
+            )
+            .ok()
+            .map_err(|e| map_error(e, path))?;
+        }
+
+        let filetime = ((last_write_time.dwHighDateTime as u64) << 32) | last_write_time.dwLowDateTime as u64;
+        Timestamp::from_filetime(filetime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests opening the CURRENT_USER hive using the 64-bit view.
+    #[test]
+    fn test_open_current_user_x64() {
+        // Attempt to open the CURRENT_USER hive using the 64-bit view.
+        let key = open_base_key(RegistryHive::CurrentUser, RegistryHiveType::X64)
+            .expect("Failed to open key");
+        // When run on a normal Windows system, CURRENT_USER should always exist.
+        assert!(key.is_some());
+    }
+
+    /// Tests retrieving the names of subkeys in the SOFTWARE key of the LOCAL_MACHINE hive.
+    #[test]
+    fn test_get_sub_key_names_basic() {
+        let strings = LiveRegistry::default()
+            .get_sub_key_names(RegistryHive::LocalMachine, "SOFTWARE")
+            .expect("Failed to open key");
+        println!("{:?}", strings);
+        assert!(!strings.is_empty())
+    }
+}
@@ -0,0 +1,475 @@
+//! Access to the Windows registry through interchangeable backends.
+//!
+//! Commands never talk to the registry directly. Instead they go through the
+//! `RegistryBackend` of the `Runtime`, so the same command can run against the
+//! live registry, offline hive files or any other backend an embedder plugs in.
+pub mod hive;
+pub mod hive_writer;
+#[cfg(windows)]
+pub mod live;
+pub mod offline;
+pub mod reg_file;
+pub mod remote;
+pub mod users;
+pub mod view;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// Represents the different registry hives available on a Windows system.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RegistryHive {
+    ClassesRoot,
+    CurrentConfig,
+    CurrentUser,
+    DynData,
+    LocalMachine,
+    PerformanceData,
+    Users,
+}
+
+impl RegistryHive {
+    /// Returns the abbreviated name of the root key of the hive, such as `HKLM`.
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            RegistryHive::ClassesRoot => "HKCR",
+            RegistryHive::CurrentConfig => "HKCC",
+            RegistryHive::CurrentUser => "HKCU",
+            RegistryHive::DynData => "HKDD",
+            RegistryHive::LocalMachine => "HKLM",
+            RegistryHive::PerformanceData => "HKPD",
+            RegistryHive::Users => "HKU",
+        }
+    }
+
+    /// Returns the full path of a key in the hive, such as `HKLM\SOFTWARE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key within the hive, empty for the root key.
+    pub fn key_path(&self, path: &str) -> String {
+        match path.is_empty() {
+            true => self.short_name().to_string(),
+            false => format!("{}\\{path}", self.short_name()),
+        }
+    }
+}
+
+/// Represents the different registry view types (64-bit or 32-bit).
+///
+/// # Variants
+/// - `X64`: The native view of 64-bit Windows, the default.
+/// - `X86`: The view of 32-bit programs, where parts of `SOFTWARE` are redirected to `WOW6432Node`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum RegistryHiveType {
+    #[default]
+    X64,
+    X86,
+}
+
+/// A registry value together with its type.
+///
+/// # Variants
+/// - `None`: A value without data (`REG_NONE`).
+/// - `String`: A string value (`REG_SZ`).
+/// - `ExpandString`: A string that may contain unexpanded environment variables (`REG_EXPAND_SZ`).
+/// - `Binary`: Raw bytes (`REG_BINARY`).
+/// - `DWord`: A 32-bit number (`REG_DWORD`).
+/// - `QWord`: A 64-bit number (`REG_QWORD`).
+/// - `MultiString`: A list of strings (`REG_MULTI_SZ`).
+/// - `Other`: Any other type, with its raw type number and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryValue {
+    None,
+    String(String),
+    ExpandString(String),
+    Binary(Vec<u8>),
+    DWord(u32),
+    QWord(u64),
+    MultiString(Vec<String>),
+    Other(u32, Vec<u8>),
+}
+
+/// Trait implemented by every registry backend.
+///
+/// A backend only has to provide the three primitive operations. The typed
+/// accessors used by the commands are built on top of them.
+pub trait RegistryBackend {
+    /// Retrieves the names of the subkeys for a given registry hive and path.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` containing the names of the subkeys.
+    /// * `Err(e)` if there was an error querying the subkeys.
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>>;
+
+    /// Retrieves the names of the values of a given registry hive and path.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` containing the names of the values.
+    /// * `Err(e)` if there was an error querying the values.
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>>;
+
+    /// Retrieves a value together with its type.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve. An empty name is the default value.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RegistryValue)` containing the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue>;
+
+    /// Retrieves the last write time of a key.
+    ///
+    /// Not every backend can provide last write times, so the default
+    /// implementation reports them as unsupported.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path of the key within the hive.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Timestamp)` containing the time the key was last written.
+    /// * `Err(Error::Unsupported)` if the backend does not know last write times.
+    /// * `Err(e)` if there was an error querying the key.
+    fn get_key_last_write_time(&self, _hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        Err(Error::Unsupported(format!("last write time of {path}")))
+    }
+
+    /// Returns the file on the machine Rustbelt runs on that holds a key.
+    ///
+    /// Backends that read hive files return the file, so what a run read
+    /// can be accounted for. The default implementation returns `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive of the key.
+    /// * `path` - The path of the key within the hive.
+    fn source_file(&self, _hive: RegistryHive, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Retrieves the value from a given registry hive, path, and value name.
+    ///
+    /// This function can be used when you don't know the type of the registry key or don't care about its type.
+    /// It will always return the value in the registry as a string value. If you need the value in the actual type,
+    /// use the underlying functions below. Expand strings are not expanded, and the strings of multi strings are
+    /// separated by `, `.
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` containing the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<String> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::DWord(_) => {
+                let value = self.get_dword_value(hive, path, name)?;
+                Ok(value.to_string())
+            }
+            RegistryValue::QWord(_) => {
+                let value = self.get_qword_value(hive, path, name)?;
+                Ok(value.to_string())
+            }
+            RegistryValue::String(value) | RegistryValue::ExpandString(value) => Ok(value),
+            RegistryValue::MultiString(values) => Ok(values.join(", ")),
+            RegistryValue::Binary(value) => Ok(format!("{:?}", value)),
+            RegistryValue::None => Ok(String::new()),
+            _ => Err(Error::InvalidData(format!(
+                "unsupported value type for {path}\\{name}"
+            ))),
+        }
+    }
+
+    /// Retrieves a string value from a given registry hive, path, and value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` containing the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_string_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<String> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::String(value) | RegistryValue::ExpandString(value) => Ok(value),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not a string"))),
+        }
+    }
+
+    /// Retrieves a multi string value from a given registry hive, path, and value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` containing the strings of the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_multi_string_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<Vec<String>> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::MultiString(values) => Ok(values),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not a multi string"))),
+        }
+    }
+
+    /// Retrieves an expanded string value from a given registry hive, path, and value name.
+    ///
+    /// The environment variables of an expand string, such as `%SystemRoot%`,
+    /// are those of the target rather than of the machine Rustbelt runs on,
+    /// so they are only expanded against the environment that is given.
+    /// Plain strings are returned as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    /// * `environment` - The environment variables to expand, or `None` to return the value unexpanded.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` containing the value, see `expand_environment_strings`.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_expanded_string_value(
+        &self,
+        hive: RegistryHive,
+        path: &str,
+        name: &str,
+        environment: Option<&HashMap<String, String>>,
+    ) -> Result<String> {
+        match (self.get_raw_value(hive, path, name)?, environment) {
+            (RegistryValue::ExpandString(value), Some(environment)) => Ok(expand_environment_strings(&value, environment)),
+            (RegistryValue::String(value) | RegistryValue::ExpandString(value), _) => Ok(value),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not a string"))),
+        }
+    }
+
+    /// Retrieves a dword value (32-bit number) from a given registry hive, path, and value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u32)` containing the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_dword_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<u32> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::DWord(value) => Ok(value),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not a dword"))),
+        }
+    }
+
+    /// Retrieves a qword value (64-bit number) from a given registry hive, path, and value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` containing the value.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_qword_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<u64> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::QWord(value) => Ok(value),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not a qword"))),
+        }
+    }
+
+    /// Retrieves a binary value from a given registry hive, path, and value name.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    /// * `name` - The name of the value to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` containing the binary values.
+    /// * `Err(e)` if there was an error retrieving the value.
+    fn get_binary_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<Vec<u8>> {
+        match self.get_raw_value(hive, path, name)? {
+            RegistryValue::Binary(value) | RegistryValue::Other(_, value) => Ok(value),
+            _ => Err(Error::InvalidData(format!("{path}\\{name} is not binary"))),
+        }
+    }
+
+    /// Retrieves all values of a given registry hive and path, with their types.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to query.
+    /// * `path` - The path within the hive to query.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, RegistryValue)>)` containing the names and values, in the order of the key.
+    /// * `Err(e)` if there was an error retrieving the values.
+    fn get_values(&self, hive: RegistryHive, path: &str) -> Result<Vec<(String, RegistryValue)>> {
+        self.get_value_names(hive, path)?
+            .into_iter()
+            .map(|name| {
+                let value = self.get_raw_value(hive, path, &name)?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+}
+
+/// Retrieves the SIDs of the users that have a profile on the machine, see `users::user_profiles`.
+///
+/// # Arguments
+///
+/// * `registry` - The registry of the machine.
+///
+/// # Returns
+///
+/// * `Ok(Vec<String>)` containing the SIDs.
+/// * `Err(e)` if `ProfileList` could not be read.
+pub fn get_user_sids(registry: &dyn RegistryBackend) -> Result<Vec<String>> {
+    Ok(users::user_profiles(registry)?.into_iter().map(|profile| profile.sid).collect())
+}
+
+/// Retrieves a registry hive from a given name.
+///
+/// # Arguments
+///
+/// * `name` - The name of the root key of the hive, full or abbreviated and in any case, such as `HKEY_LOCAL_MACHINE` or `hklm`.
+///
+/// # Returns
+///
+/// * `Ok(RegistryHive)` containing the hive.
+/// * `Err(Error::InvalidData)` if the name is not the name of a hive.
+pub fn get_hive(name: &str) -> Result<RegistryHive> {
+    match name.to_uppercase().as_str() {
+        "HKEY_CLASSES_ROOT" | "HKCR" => Ok(RegistryHive::ClassesRoot),
+        "HKEY_CURRENT_CONFIG" | "HKCC" => Ok(RegistryHive::CurrentConfig),
+        "HKEY_CURRENT_USER" | "HKCU" => Ok(RegistryHive::CurrentUser),
+        "HKEY_DYN_DATA" | "HKDD" => Ok(RegistryHive::DynData),
+        "HKEY_LOCAL_MACHINE" | "HKLM" => Ok(RegistryHive::LocalMachine),
+        "HKEY_PERFORMANCE_DATA" | "HKPD" => Ok(RegistryHive::PerformanceData),
+        "HKEY_USERS" | "HKU" => Ok(RegistryHive::Users),
+        _ => Err(Error::InvalidData(format!("unknown registry hive {name}"))),
+    }
+}
+
+/// Expands the environment variables of a string, as Windows does for expand strings.
+///
+/// Variables are written as `%NAME%` and their names are compared case
+/// insensitively. Variables that are not in the environment are kept as they are.
+///
+/// # Arguments
+///
+/// * `text` - The string to expand, such as `%SystemRoot%\System32`.
+/// * `environment` - The environment variables by name.
+pub fn expand_environment_strings(text: &str, environment: &HashMap<String, String>) -> String {
+    let lookup = |name: &str| {
+        environment
+            .iter()
+            .find(|(variable, _)| variable.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('%') {
+        let Some(length) = rest[start + 1..].find('%') else {
+            break;
+        };
+        let end = start + length + 2;
+        expanded.push_str(&rest[..start]);
+        match lookup(&rest[start + 1..end - 1]).filter(|_| length > 0) {
+            Some(value) => expanded.push_str(value),
+            None => expanded.push_str(&rest[start..end]),
+        }
+        rest = &rest[end..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use offline::OfflineRegistry;
+
+    const REG: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Values]
+"Big"=hex(b):ff,00,00,00,00,00,00,00
+"Path"=hex(2):25,00,53,00,79,00,73,00,74,00,65,00,6d,00,52,00,6f,00,6f,00,74,00,25,00,5c,00,25,00,58,00,25,00,00,00
+"Multi"=hex(7):61,00,00,00,62,00,00,00,00,00
+"Plain"="%SystemRoot%"
+"#;
+
+    #[test]
+    fn test_typed_values() {
+        let registry = OfflineRegistry::from_reg(REG).unwrap();
+        let hive = RegistryHive::LocalMachine;
+        assert_eq!(registry.get_qword_value(hive, "SOFTWARE\\Values", "Big").unwrap(), 255);
+        assert_eq!(registry.get_multi_string_value(hive, "SOFTWARE\\Values", "Multi").unwrap(), ["a", "b"]);
+        assert_eq!(registry.get_value(hive, "SOFTWARE\\Values", "Multi").unwrap(), "a, b");
+        assert!(matches!(registry.get_qword_value(hive, "SOFTWARE\\Values", "Multi"), Err(Error::InvalidData(_))));
+
+        // Expand strings are only expanded against the environment that is given, plain strings never.
+        let environment = HashMap::from([("SYSTEMROOT".to_string(), "C:\\Windows".to_string())]);
+        assert_eq!(registry.get_expanded_string_value(hive, "SOFTWARE\\Values", "Path", None).unwrap(), "%SystemRoot%\\%X%");
+        assert_eq!(
+            registry.get_expanded_string_value(hive, "SOFTWARE\\Values", "Path", Some(&environment)).unwrap(),
+            "C:\\Windows\\%X%"
+        );
+        assert_eq!(
+            registry.get_expanded_string_value(hive, "SOFTWARE\\Values", "Plain", Some(&environment)).unwrap(),
+            "%SystemRoot%"
+        );
+        assert_eq!(expand_environment_strings("100%% %systemroot", &environment), "100%% %systemroot");
+
+        let values = registry.get_values(hive, "SOFTWARE\\Values").unwrap();
+        let names: Vec<&str> = values.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Big", "Path", "Multi", "Plain"]);
+        assert_eq!(values[0].1, RegistryValue::QWord(255));
+
+        assert_eq!(get_hive("hkey_local_machine").unwrap(), RegistryHive::LocalMachine);
+        assert_eq!(get_hive("HKPD").unwrap(), RegistryHive::PerformanceData);
+        assert!(matches!(get_hive("HKXX"), Err(Error::InvalidData(_))));
+    }
+}
@@ -0,0 +1,484 @@
+//! Registry backend reading offline hive files.
+//!
+//! Hive files are mounted at a path in one of the registry hives, the way
+//! Windows mounts `config\SOFTWARE` at `HKEY_LOCAL_MACHINE\SOFTWARE`. The
+//! backend can be built from the `Windows\System32\config` directory of a
+//! disk image, or from a `.reg` file, which is useful for test fixtures.
+//! Images also get the hives of their users, which are read on demand.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use super::{
+    hive::{names_equal, Hive, KeyNode},
+    hive_writer::HiveBuilder,
+    reg_file::{self, RegKey},
+    users::{user_profiles, CLASSES_HIVE_FILE, CLASSES_SUFFIX, USER_HIVE_FILE},
+    RegistryBackend, RegistryHive, RegistryValue,
+};
+use crate::{
+    error::{Error, Result},
+    utils::{fs::ImageFileSystem, time::Timestamp},
+};
+
+/// The hives under `Windows\System32\config` and where Windows mounts them.
+const IMAGE_HIVES: [(&str, &str); 4] = [
+    ("SOFTWARE", "SOFTWARE"),
+    ("SYSTEM", "SYSTEM"),
+    ("SAM", "SAM"),
+    ("SECURITY", "SECURITY"),
+];
+
+/// A hive file, read when it is mounted or the first time one of its keys is accessed.
+struct Source {
+    hive: OnceLock<std::result::Result<Hive, String>>,
+    // The hive file on disk, if it was read from one.
+    file: Option<PathBuf>,
+}
+
+impl Source {
+    fn loaded(hive: Hive, file: Option<PathBuf>) -> Self {
+        Source { hive: OnceLock::from(Ok(hive)), file }
+    }
+
+    fn lazy(file: PathBuf) -> Self {
+        Source { hive: OnceLock::new(), file: Some(file) }
+    }
+
+    /// Returns the hive, reading the file the first time. A file that could not be read stays unreadable.
+    fn hive(&self) -> Result<&Hive> {
+        let hive = self.hive.get_or_init(|| match &self.file {
+            Some(file) => Hive::from_file(file).map_err(|e| format!("{}: {e}", file.display())),
+            None => Err("hive without a file".to_string()),
+        });
+        hive.as_ref().map_err(|e| Error::InvalidData(e.clone()))
+    }
+}
+
+/// A hive file mounted in the registry.
+struct Mount {
+    hive: RegistryHive,
+    // Path of the mount point within `hive`, empty for the root of the hive.
+    path: String,
+    source: Arc<Source>,
+    // Path within the hive file that is visible at the mount point.
+    inner: String,
+}
+
+/// Registry backend reading offline hive files.
+#[derive(Default)]
+pub struct OfflineRegistry {
+    mounts: Vec<Mount>,
+}
+
+/// Splits a registry path into its components.
+fn components(path: &str) -> Vec<&str> {
+    path.split('\\').filter(|component| !component.is_empty()).collect()
+}
+
+/// Joins registry path components, skipping empty ones.
+fn join(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .filter(|part| !part.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+impl OfflineRegistry {
+    /// Creates a backend without any mounted hives.
+    pub fn new() -> Self {
+        OfflineRegistry::default()
+    }
+
+    /// Creates a backend from the hives of a Windows image.
+    ///
+    /// The `SOFTWARE`, `SYSTEM`, `SAM` and `SECURITY` hives are mounted under
+    /// `HKEY_LOCAL_MACHINE` when they exist, and `HKEY_CLASSES_ROOT` shows
+    /// `HKEY_LOCAL_MACHINE\SOFTWARE\Classes`. The `NTUSER.DAT` and
+    /// `UsrClass.dat` of every profile in `ProfileList` are mounted at
+    /// `HKEY_USERS\<SID>` and `HKEY_USERS\<SID>_Classes`, and only read when
+    /// one of their keys is accessed.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The root of the image, the directory that contains `Windows`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OfflineRegistry)` with the hives that were found.
+    /// * `Err(Error::NotFound)` if the image contains none of the hives.
+    /// * `Err(e)` if a hive exists but could not be read.
+    pub fn from_image(root: &Path) -> Result<Self> {
+        let config = root.join("Windows").join("System32").join("config");
+        let mut registry = OfflineRegistry::new();
+
+        for (file, mount_path) in IMAGE_HIVES {
+            let path = config.join(file);
+            if path.is_file() {
+                registry.mount_file(RegistryHive::LocalMachine, mount_path, &path)?;
+            }
+        }
+        if registry.mounts.is_empty() {
+            return Err(Error::NotFound(format!("registry hives in {}", config.display())));
+        }
+        registry.alias_classes_root();
+        registry.mount_user_hives(root);
+        Ok(registry)
+    }
+
+    /// Mounts the hive files of the profiles of an image that exist, to be read on demand.
+    fn mount_user_hives(&mut self, root: &Path) {
+        // Images without a readable ProfileList have no user hives.
+        let Ok(profiles) = user_profiles(self) else {
+            return;
+        };
+        let files = ImageFileSystem::new(root);
+        for profile in profiles {
+            let directory = expand_system_paths(&profile.profile_path);
+            for (suffix, file) in [("", USER_HIVE_FILE), (CLASSES_SUFFIX, CLASSES_HIVE_FILE)] {
+                if let Ok(file) = files.resolve(&format!("{directory}\\{file}")) {
+                    self.mounts.push(Mount {
+                        hive: RegistryHive::Users,
+                        path: format!("{}{suffix}", profile.sid),
+                        source: Arc::new(Source::lazy(file)),
+                        inner: String::new(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Creates a backend from the text of a `.reg` file.
+    ///
+    /// Every key below `HKEY_LOCAL_MACHINE` and `HKEY_USERS` is mounted as a
+    /// separate hive per top level key, like Windows does. If the file has no
+    /// `HKEY_CLASSES_ROOT` keys, `HKEY_LOCAL_MACHINE\SOFTWARE\Classes` is used.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The contents of the `.reg` file.
+    pub fn from_reg(text: &str) -> Result<Self> {
+        OfflineRegistry::from_reg_keys(reg_file::parse(text)?)
+    }
+
+    /// Creates a backend from a `.reg` file on disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the `.reg` file.
+    pub fn from_reg_file(path: &Path) -> Result<Self> {
+        OfflineRegistry::from_reg_keys(reg_file::parse_file(path)?)
+    }
+
+    fn from_reg_keys(keys: Vec<RegKey>) -> Result<Self> {
+        // Builders by hive and mount path, ordered so mounting is deterministic.
+        let mut builders: BTreeMap<(String, String), (RegistryHive, String, HiveBuilder)> =
+            BTreeMap::new();
+
+        for key in keys {
+            let parts = components(&key.path);
+            let (mount_path, inner) = match key.hive {
+                RegistryHive::LocalMachine | RegistryHive::Users if !parts.is_empty() => {
+                    (parts[0].to_string(), join(&parts[1..]))
+                }
+                _ => (String::new(), join(&parts)),
+            };
+
+            let id = (format!("{:?}", key.hive), mount_path.to_uppercase());
+            let (_, _, builder) = builders
+                .entry(id)
+                .or_insert_with(|| (key.hive, mount_path, HiveBuilder::new()));
+            builder.add_key(&inner);
+            if let Some(last_written) = key.last_written {
+                builder.set_last_written(&inner, last_written.to_filetime()?);
+            }
+            for (name, value) in key.values {
+                builder.set_value(&inner, &name, value);
+            }
+        }
+
+        let mut registry = OfflineRegistry::new();
+        for (_, (hive, mount_path, builder)) in builders {
+            registry.mount(hive, &mount_path, Hive::from_bytes(builder.build())?);
+        }
+        registry.alias_classes_root();
+        Ok(registry)
+    }
+
+    /// Mounts a hive file.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to mount the file in.
+    /// * `path` - The mount point within `hive`, for example `SOFTWARE`. An empty path mounts the file at the root.
+    /// * `source` - The hive file to mount.
+    pub fn mount(&mut self, hive: RegistryHive, path: &str, source: Hive) -> &mut Self {
+        self.mounts.push(Mount {
+            hive,
+            path: join(&components(path)),
+            source: Arc::new(Source::loaded(source, None)),
+            inner: String::new(),
+        });
+        self
+    }
+
+    /// Reads a hive file and mounts it, remembering the file as the source of its keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive to mount the file in.
+    /// * `path` - The mount point within `hive`, for example `SOFTWARE`.
+    /// * `file` - The path of the hive file.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(&mut OfflineRegistry)` if the file could be read.
+    /// * `Err(e)` if the file could not be read or is not a valid hive.
+    pub fn mount_file(&mut self, hive: RegistryHive, path: &str, file: &Path) -> Result<&mut Self> {
+        self.mounts.push(Mount {
+            hive,
+            path: join(&components(path)),
+            source: Arc::new(Source::loaded(Hive::from_file(file)?, Some(file.to_path_buf()))),
+            inner: String::new(),
+        });
+        Ok(self)
+    }
+
+    /// Shows `HKEY_LOCAL_MACHINE\SOFTWARE\Classes` as `HKEY_CLASSES_ROOT`
+    /// unless a hive is mounted there already.
+    fn alias_classes_root(&mut self) {
+        if self.mounts.iter().any(|mount| mount.hive == RegistryHive::ClassesRoot) {
+            return;
+        }
+
+        let software = self.mounts.iter().find(|mount| {
+            mount.hive == RegistryHive::LocalMachine && names_equal(&mount.path, "SOFTWARE")
+        });
+        if let Some(software) = software {
+            let source = software.source.clone();
+            self.mounts.push(Mount {
+                hive: RegistryHive::ClassesRoot,
+                path: String::new(),
+                source,
+                inner: "Classes".to_string(),
+            });
+        }
+    }
+
+    /// Finds the mount that contains `path` and returns it with the path within its hive file.
+    fn resolve(&self, hive: RegistryHive, path: &str) -> Option<(&Mount, String)> {
+        let parts = components(path);
+
+        self.mounts
+            .iter()
+            .filter(|mount| mount.hive == hive)
+            .filter_map(|mount| {
+                let mount_parts = components(&mount.path);
+                let matches = mount_parts.len() <= parts.len()
+                    && mount_parts
+                        .iter()
+                        .zip(&parts)
+                        .all(|(mount_part, part)| names_equal(mount_part, part));
+                matches.then_some((mount, mount_parts.len()))
+            })
+            .max_by_key(|(_, depth)| *depth)
+            .map(|(mount, depth)| {
+                let rest = join(&parts[depth..]);
+                // A hive that cannot be read fails when the key is opened.
+                let rest = match mount.source.hive() {
+                    Ok(hive) => resolve_control_set(hive, &rest),
+                    Err(_) => rest,
+                };
+                (mount, join(&[&mount.inner, &rest]))
+            })
+    }
+
+    /// Opens a key, or returns `None` if the path is not inside any mounted hive.
+    fn open_key(&self, hive: RegistryHive, path: &str) -> Result<Option<KeyNode<'_>>> {
+        match self.resolve(hive, path) {
+            Some((mount, inner)) => mount
+                .source
+                .hive()?
+                .open_key(&inner)
+                .map(Some)
+                .map_err(|e| match e {
+                    Error::NotFound(_) => not_found(hive, path),
+                    e => e,
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the names of the mount points directly below `path`.
+    fn mount_names(&self, hive: RegistryHive, path: &str) -> Vec<String> {
+        let parts = components(path);
+        let mut names: Vec<String> = vec![];
+
+        for mount in self.mounts.iter().filter(|mount| mount.hive == hive) {
+            let mount_parts = components(&mount.path);
+            let is_child = mount_parts.len() == parts.len() + 1
+                && parts
+                    .iter()
+                    .zip(&mount_parts)
+                    .all(|(part, mount_part)| names_equal(part, mount_part));
+            let name = mount_parts.last().map(|name| name.to_string());
+
+            if let Some(name) = name.filter(|_| is_child) {
+                if !names.iter().any(|existing| names_equal(existing, &name)) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+}
+
+/// Replaces a leading `CurrentControlSet` by the control set selected in the hive.
+///
+/// `CurrentControlSet` is a link Windows creates at boot time and therefore
+/// does not exist in a `SYSTEM` hive file. The active set is stored in
+/// `Select\Current`.
+fn resolve_control_set(hive: &Hive, path: &str) -> String {
+    let parts = components(path);
+    match parts.first() {
+        Some(first) if names_equal(first, "CurrentControlSet") => {
+            let current = hive
+                .open_key("Select")
+                .ok()
+                .and_then(|select| select.value("Current").ok().flatten())
+                .and_then(|value| value.value().ok());
+            let number = match current {
+                Some(RegistryValue::DWord(number)) => number,
+                _ => 1,
+            };
+            join(&[&format!("ControlSet{number:03}"), &join(&parts[1..])])
+        }
+        _ => path.to_string(),
+    }
+}
+
+/// Replaces the leading `%SystemDrive%` or `%SystemRoot%` of a path, as found in `ProfileList`, by their usual values.
+fn expand_system_paths(path: &str) -> String {
+    for (variable, value) in [("%SystemDrive%", "C:"), ("%SystemRoot%", "C:\\Windows")] {
+        if path.get(..variable.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(variable)) {
+            return format!("{value}{}", &path[variable.len()..]);
+        }
+    }
+    path.to_string()
+}
+
+fn not_found(hive: RegistryHive, path: &str) -> Error {
+    Error::NotFound(format!("{hive:?}\\{path}"))
+}
+
+impl RegistryBackend for OfflineRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let mounts = self.mount_names(hive, path);
+        let mut names = match self.open_key(hive, path)? {
+            Some(key) => key
+                .sub_keys()?
+                .iter()
+                .map(KeyNode::name)
+                .collect::<Result<Vec<_>>>()?,
+            None if !mounts.is_empty() => vec![],
+            None => return Err(not_found(hive, path)),
+        };
+
+        for name in mounts {
+            if !names.iter().any(|existing| names_equal(existing, &name)) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        match self.open_key(hive, path)? {
+            Some(key) => key.values()?.iter().map(|value| value.name()).collect(),
+            None if !self.mount_names(hive, path).is_empty() => Ok(vec![]),
+            None => Err(not_found(hive, path)),
+        }
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        let key = self
+            .open_key(hive, path)?
+            .ok_or_else(|| not_found(hive, path))?;
+        match key.value(name)? {
+            Some(value) => value.value(),
+            None => Err(Error::NotFound(format!("{hive:?}\\{path}\\{name}"))),
+        }
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        let key = self
+            .open_key(hive, path)?
+            .ok_or_else(|| not_found(hive, path))?;
+        Timestamp::from_filetime(key.last_written()?)
+    }
+
+    fn source_file(&self, hive: RegistryHive, path: &str) -> Option<PathBuf> {
+        self.resolve(hive, path).and_then(|(mount, _)| mount.source.file.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REG: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Classes\CLSID\{1}]
+@="Provider"
+
+[HKEY_LOCAL_MACHINE\SYSTEM\Select]
+; LastWriteTime: 2024-03-01T12:00:00Z
+"Current"=dword:00000002
+
+[HKEY_LOCAL_MACHINE\SYSTEM\ControlSet002\Control]
+"Name"="two"
+"#;
+
+    #[test]
+    fn test_mounts() {
+        let registry = OfflineRegistry::from_reg(REG).unwrap();
+
+        let mut roots = registry.get_sub_key_names(RegistryHive::LocalMachine, "").unwrap();
+        roots.sort();
+        assert_eq!(roots, vec!["SOFTWARE", "SYSTEM"]);
+        assert_eq!(
+            registry.get_sub_key_names(RegistryHive::ClassesRoot, "clsid").unwrap(),
+            vec!["{1}"]
+        );
+        assert_eq!(
+            registry.get_raw_value(RegistryHive::ClassesRoot, "CLSID\\{1}", "").unwrap(),
+            RegistryValue::String("Provider".to_string())
+        );
+        assert_eq!(
+            registry
+                .get_raw_value(RegistryHive::LocalMachine, "SYSTEM\\CurrentControlSet\\Control", "Name")
+                .unwrap(),
+            RegistryValue::String("two".to_string())
+        );
+        assert!(matches!(
+            registry.get_sub_key_names(RegistryHive::LocalMachine, "SOFTWARE\\Missing"),
+            Err(Error::NotFound(_))
+        ));
+        assert_eq!(
+            registry
+                .get_key_last_write_time(RegistryHive::LocalMachine, "SYSTEM\\Select")
+                .unwrap()
+                .to_string(),
+            "2024-03-01T12:00:00Z"
+        );
+        assert!(matches!(
+            registry.get_raw_value(RegistryHive::Users, "S-1-5-18", "x"),
+            Err(Error::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+//! Parser for `.reg` files, as exported by `regedit`.
+//!
+//! Supported are `Windows Registry Editor Version 5.00` and `REGEDIT4` files
+//! with string, `dword:` and `hex:`/`hex(n):` values. Deletions (`[-key]` and
+//! `"name"=-`) are rejected, since a `.reg` file is only used to describe the
+//! contents of a registry here.
+//!
+//! `.reg` files have no way to store the last write time of a key, so a
+//! comment of the form `; LastWriteTime: 2024-03-01T12:00:00Z` inside a key
+//! sets it. Tools reading the file with `regedit` simply ignore it.
+
+use std::{fs, path::Path};
+
+use super::{hive::decode_value, RegistryHive, RegistryValue};
+use crate::{
+    error::{Error, Result},
+    utils::time::Timestamp,
+};
+
+/// Comment that sets the last write time of the current key.
+const LAST_WRITE_TIME_DIRECTIVE: &str = "LastWriteTime:";
+
+/// A key of a `.reg` file together with its values.
+///
+/// # Fields
+/// - `hive`: The hive the key belongs to.
+/// - `path`: The path of the key within the hive, without leading or trailing backslashes.
+/// - `values`: The values of the key, in file order. The default value has an empty name.
+/// - `last_written`: The last write time of the key, if the file sets it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegKey {
+    pub hive: RegistryHive,
+    pub path: String,
+    pub values: Vec<(String, RegistryValue)>,
+    pub last_written: Option<Timestamp>,
+}
+
+/// Maps the root of a `.reg` key path to a hive.
+///
+/// # Arguments
+///
+/// * `name` - The root, for example `HKEY_LOCAL_MACHINE` or `HKLM`.
+pub fn root_hive(name: &str) -> Option<RegistryHive> {
+    super::get_hive(name).ok()
+}
+
+/// Reads and parses a `.reg` file. Both UTF-16 (as written by `regedit`) and UTF-8 files are accepted.
+///
+/// # Arguments
+///
+/// * `path` - The path of the `.reg` file.
+pub fn parse_file(path: &Path) -> Result<Vec<RegKey>> {
+    let bytes = fs::read(path)?;
+    let text = match bytes.strip_prefix(&[0xff, 0xfe]) {
+        Some(utf16) => super::hive::decode_utf16(utf16),
+        None => String::from_utf8(bytes)
+            .map_err(|e| Error::InvalidData(format!("{}: {e}", path.display())))?,
+    };
+    parse(&text)
+}
+
+/// Parses the text of a `.reg` file.
+///
+/// # Arguments
+///
+/// * `text` - The contents of the file.
+///
+/// # Returns
+///
+/// * `Ok(Vec<RegKey>)` containing the keys in file order.
+/// * `Err(Error::InvalidData)` if the file is malformed.
+pub fn parse(text: &str) -> Result<Vec<RegKey>> {
+    let text = text.trim_start_matches('\u{feff}');
+    let mut lines = logical_lines(text).into_iter();
+
+    match lines.next() {
+        Some((_, header))
+            if header == "Windows Registry Editor Version 5.00" || header == "REGEDIT4" => {}
+        _ => return Err(Error::InvalidData("not a .reg file: missing header".to_string())),
+    }
+
+    let mut keys: Vec<RegKey> = vec![];
+    for (number, line) in lines {
+        let invalid = |what: &str| Error::InvalidData(format!(".reg line {number}: {what}"));
+
+        if let Some(section) = line.strip_prefix('[') {
+            let section = section.strip_suffix(']').ok_or_else(|| invalid("unterminated key"))?;
+            if section.starts_with('-') {
+                return Err(invalid("key deletions are not supported"));
+            }
+
+            let (root, path) = section.split_once('\\').unwrap_or((section, ""));
+            let hive = root_hive(root).ok_or_else(|| invalid(&format!("unknown root key {root}")))?;
+            keys.push(RegKey {
+                hive,
+                path: path.trim_matches('\\').to_string(),
+                values: vec![],
+                last_written: None,
+            });
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix(';') {
+            if let Some(time) = comment.trim().strip_prefix(LAST_WRITE_TIME_DIRECTIVE) {
+                let key = keys.last_mut().ok_or_else(|| invalid("last write time outside of a key"))?;
+                key.last_written = Some(time.trim().parse().map_err(|e: Error| invalid(&e.to_string()))?);
+            }
+            continue;
+        }
+
+        let key = keys.last_mut().ok_or_else(|| invalid("value outside of a key"))?;
+        let (name, data) = if let Some(data) = line.strip_prefix("@=") {
+            (String::new(), data)
+        } else if line.starts_with('"') {
+            let (name, rest) = parse_string(&line).ok_or_else(|| invalid("unterminated value name"))?;
+            let data = rest.strip_prefix('=').ok_or_else(|| invalid("expected '='"))?;
+            (name, data)
+        } else {
+            return Err(invalid("expected a key or a value"));
+        };
+
+        let value = parse_data(data).map_err(|e| invalid(&e))?;
+        key.values.push((name, value));
+    }
+    Ok(keys)
+}
+
+/// Splits the text into logical lines, joining continuation lines and
+/// dropping empty lines. Every line is returned with its 1-based line number.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = vec![];
+    let mut current: Option<(usize, String)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let (number, mut joined) = match current.take() {
+            Some((number, joined)) => (number, joined),
+            None if line.is_empty() => continue,
+            None => (index + 1, String::new()),
+        };
+
+        // Strings and key names never end in a backslash, only hex data is continued.
+        match line.strip_suffix('\\') {
+            Some(line) => {
+                joined.push_str(line);
+                current = Some((number, joined));
+            }
+            _ => {
+                joined.push_str(line);
+                lines.push((number, joined));
+            }
+        }
+    }
+    if let Some(line) = current {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Parses a quoted string at the start of `text`.
+///
+/// # Returns
+///
+/// The unescaped string and the rest of the text after the closing quote.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut result = String::new();
+    let mut chars = text.strip_prefix('"')?.char_indices();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Some((result, &text[index + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => result.push('\n'),
+                'r' => result.push('\r'),
+                't' => result.push('\t'),
+                '0' => result.push('\0'),
+                escaped => result.push(escaped),
+            },
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+/// Parses the data of a value, everything after the `=`.
+fn parse_data(data: &str) -> std::result::Result<RegistryValue, String> {
+    if data == "-" {
+        return Err("value deletions are not supported".to_string());
+    }
+
+    if data.starts_with('"') {
+        return match parse_string(data) {
+            Some((string, "")) => Ok(RegistryValue::String(string)),
+            _ => Err("malformed string value".to_string()),
+        };
+    }
+
+    if let Some(number) = data.strip_prefix("dword:") {
+        return u32::from_str_radix(number, 16)
+            .map(RegistryValue::DWord)
+            .map_err(|e| format!("malformed dword {number}: {e}"));
+    }
+
+    let (data_type, bytes) = if let Some(bytes) = data.strip_prefix("hex:") {
+        (3, bytes)
+    } else if let Some(rest) = data.strip_prefix("hex(") {
+        let (data_type, bytes) = rest.split_once("):").ok_or("malformed hex type")?;
+        let data_type =
+            u32::from_str_radix(data_type, 16).map_err(|e| format!("malformed hex type: {e}"))?;
+        (data_type, bytes)
+    } else {
+        return Err(format!("unsupported value data {data}"));
+    };
+
+    let bytes = bytes
+        .split(',')
+        .map(str::trim)
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|e| format!("malformed byte {byte}: {e}")))
+        .collect::<std::result::Result<Vec<u8>, String>>()?;
+
+    decode_value(data_type, bytes).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"Windows Registry Editor Version 5.00
+
+; A comment
+[HKEY_LOCAL_MACHINE\SOFTWARE\Test]
+; LastWriteTime: 2024-03-01T12:00:00Z
+@="default"
+"Quoted \"name\""="C:\\Path\\file.dll"
+"Number"=dword:0000002a
+"Blob"=hex:01,02,\
+  03
+"Big"=hex(b):ff,00,00,00,00,00,00,00
+"Expand"=hex(2):25,00,41,00,25,00,00,00
+"Multi"=hex(7):61,00,00,00,62,00,00,00,00,00
+
+[HKCU\Empty]
+"#;
+
+        let keys = parse(text).expect("Failed to parse .reg file");
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].hive, RegistryHive::LocalMachine);
+        assert_eq!(keys[0].path, "SOFTWARE\\Test");
+        assert_eq!(
+            keys[0].values,
+            vec![
+                (String::new(), RegistryValue::String("default".to_string())),
+                (
+                    "Quoted \"name\"".to_string(),
+                    RegistryValue::String("C:\\Path\\file.dll".to_string())
+                ),
+                ("Number".to_string(), RegistryValue::DWord(42)),
+                ("Blob".to_string(), RegistryValue::Binary(vec![1, 2, 3])),
+                ("Big".to_string(), RegistryValue::QWord(255)),
+                ("Expand".to_string(), RegistryValue::ExpandString("%A%".to_string())),
+                (
+                    "Multi".to_string(),
+                    RegistryValue::MultiString(vec!["a".to_string(), "b".to_string()])
+                ),
+            ]
+        );
+        assert_eq!(keys[0].last_written, Some("2024-03-01T12:00:00Z".parse().unwrap()));
+        assert_eq!(keys[1].hive, RegistryHive::CurrentUser);
+        assert_eq!(keys[1].last_written, None);
+        assert!(keys[1].values.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("[HKEY_LOCAL_MACHINE\\A]").is_err());
+        assert!(parse("REGEDIT4\n[-HKEY_LOCAL_MACHINE\\A]").is_err());
+        assert!(parse("REGEDIT4\n[HKEY_LOCAL_MACHINE\\A]\n\"a\"=-").is_err());
+        assert!(parse("REGEDIT4\n[HKEY_NOWHERE\\A]").is_err());
+        assert!(parse("REGEDIT4\n\"a\"=\"b\"").is_err());
+    }
+}
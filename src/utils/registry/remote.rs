@@ -0,0 +1,382 @@
+//! Registry backend for the registry of a remote machine.
+//!
+//! The registry is read through the Windows Remote Registry Protocol
+//! (MS-RRP), which is served over the `winreg` named pipe. Every operation
+//! opens the key it needs relative to a cached hive handle and closes it
+//! again, so no state is left on the server between calls.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use super::{hive::decode_value, RegistryBackend, RegistryHive, RegistryValue};
+use crate::{
+    error::{Error, Result},
+    utils::{
+        auth::Authenticator,
+        rpc::{
+            ndr::{ContextHandle, NdrReader, NdrWriter},
+            smb2::{SmbClient, SMB_PORT},
+            RpcClient, RpcTransport, SyntaxId,
+        },
+        time::Timestamp,
+    },
+};
+
+/// The UUID of the winreg interface, version 1.0.
+const WINREG_UUID: &str = "338cd001-2244-31f1-aaaa-900038001003";
+
+const OPNUM_OPEN_CLASSES_ROOT: u16 = 0;
+const OPNUM_OPEN_CURRENT_USER: u16 = 1;
+const OPNUM_OPEN_LOCAL_MACHINE: u16 = 2;
+const OPNUM_OPEN_USERS: u16 = 4;
+const OPNUM_CLOSE_KEY: u16 = 5;
+const OPNUM_ENUM_KEY: u16 = 9;
+const OPNUM_ENUM_VALUE: u16 = 10;
+const OPNUM_OPEN_KEY: u16 = 15;
+const OPNUM_QUERY_INFO_KEY: u16 = 16;
+const OPNUM_QUERY_VALUE: u16 = 17;
+const OPNUM_OPEN_CURRENT_CONFIG: u16 = 27;
+
+const MAXIMUM_ALLOWED: u32 = 0x0200_0000;
+const KEY_READ: u32 = 0x0002_0019;
+
+const ERROR_SUCCESS: u32 = 0;
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+const ERROR_ACCESS_DENIED: u32 = 5;
+const ERROR_MORE_DATA: u32 = 234;
+const ERROR_NO_MORE_ITEMS: u32 = 259;
+
+/// The longest key name, plus the terminator.
+const MAX_KEY_NAME: usize = 256;
+/// The longest value name, plus the terminator.
+const MAX_VALUE_NAME: usize = 16384;
+
+/// A transport that can be moved to the thread using the registry.
+type Transport = Box<dyn RpcTransport + Send>;
+
+/// Registry backend that reads the registry of a remote machine over MS-RRP.
+pub struct RemoteRegistry {
+    session: Mutex<Session>,
+}
+
+struct Session {
+    rpc: RpcClient<Transport>,
+    hives: HashMap<RegistryHive, ContextHandle>,
+}
+
+/// Splits `host[:port]` into the host name and the port.
+///
+/// # Arguments
+///
+/// * `target` - The target, for example `dc01`, `10.0.0.1:4455` or `[::1]:445`.
+/// * `default_port` - The port to use if the target has none.
+pub(crate) fn split_host_port(target: &str, default_port: u16) -> (&str, u16) {
+    if let Some(rest) = target.strip_prefix('[') {
+        // A bracketed IPv6 address, optionally followed by a port.
+        if let Some((host, port)) = rest.split_once(']') {
+            let port = port.strip_prefix(':').and_then(|port| port.parse().ok());
+            return (host, port.unwrap_or(default_port));
+        }
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (target, default_port),
+        },
+        _ => (target, default_port),
+    }
+}
+
+/// Converts a Win32 error code returned by the server.
+fn check(code: u32, what: impl FnOnce() -> String) -> Result<()> {
+    match code {
+        ERROR_SUCCESS => Ok(()),
+        ERROR_FILE_NOT_FOUND => Err(Error::NotFound(what())),
+        ERROR_ACCESS_DENIED => Err(Error::Remote(format!("access denied to {}", what()))),
+        code => Err(Error::Remote(format!("error {code} reading {}", what()))),
+    }
+}
+
+/// Writes an `RPC_UNICODE_STRING` without a buffer, as used for class names the client does not want.
+fn empty_string(writer: &mut NdrWriter) {
+    writer.u16(0);
+    writer.u16(0);
+    writer.null_pointer();
+}
+
+impl RemoteRegistry {
+    /// Connects to the remote registry of a machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The name or address of the machine, optionally with a port (`host:445`).
+    /// * `auth` - The authenticator for the SMB session.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RemoteRegistry)` if the `winreg` pipe could be opened and bound.
+    /// * `Err(Error::Remote)` if the machine refused the session or the pipe.
+    /// * `Err(Error::Io)` if the machine could not be reached.
+    pub fn connect(target: &str, auth: &mut dyn Authenticator) -> Result<Self> {
+        let (host, port) = split_host_port(target, SMB_PORT);
+        let client = SmbClient::connect((host, port), host, auth)?;
+        RemoteRegistry::from_transport(Box::new(client.open_pipe("winreg")?))
+    }
+
+    /// Creates a backend on top of an open transport to the `winreg` interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport, usually an open `winreg` named pipe.
+    pub fn from_transport(transport: Box<dyn RpcTransport + Send>) -> Result<Self> {
+        let rpc = RpcClient::bind(transport, SyntaxId::new(WINREG_UUID, 1, 0)?)?;
+        Ok(RemoteRegistry {
+            session: Mutex::new(Session {
+                rpc,
+                hives: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Runs an operation on an open key and closes the key afterwards.
+    fn with_key<T>(
+        &self,
+        hive: RegistryHive,
+        path: &str,
+        operation: impl FnOnce(&mut Session, &ContextHandle) -> Result<T>,
+    ) -> Result<T> {
+        let mut session = self
+            .session
+            .lock()
+            .map_err(|_| Error::Remote("remote registry session poisoned".to_string()))?;
+
+        let root = session.open_hive(hive)?;
+        let path = path.trim_matches('\\');
+        if path.is_empty() {
+            return operation(&mut session, &root);
+        }
+
+        let key = session.open_key(&root, path, hive)?;
+        let result = operation(&mut session, &key);
+        let closed = session.close_key(&key);
+        let result = result?;
+        closed?;
+        Ok(result)
+    }
+}
+
+impl Session {
+    /// Opens the handle of a hive, or returns the cached one.
+    fn open_hive(&mut self, hive: RegistryHive) -> Result<ContextHandle> {
+        if let Some(handle) = self.hives.get(&hive) {
+            return Ok(*handle);
+        }
+
+        let opnum = match hive {
+            RegistryHive::ClassesRoot => OPNUM_OPEN_CLASSES_ROOT,
+            RegistryHive::CurrentUser => OPNUM_OPEN_CURRENT_USER,
+            RegistryHive::LocalMachine => OPNUM_OPEN_LOCAL_MACHINE,
+            RegistryHive::Users => OPNUM_OPEN_USERS,
+            RegistryHive::CurrentConfig => OPNUM_OPEN_CURRENT_CONFIG,
+            RegistryHive::DynData | RegistryHive::PerformanceData => {
+                return Err(Error::Unsupported(format!("remote {hive:?} hive")))
+            }
+        };
+
+        let mut request = NdrWriter::new();
+        request.null_pointer(); // server name
+        request.u32(MAXIMUM_ALLOWED);
+        let response = self.rpc.call(opnum, &request.into_bytes())?;
+
+        let mut reader = NdrReader::new(&response);
+        let handle = reader.context_handle()?;
+        check(reader.u32()?, || format!("{hive:?}"))?;
+        self.hives.insert(hive, handle);
+        Ok(handle)
+    }
+
+    fn open_key(&mut self, parent: &ContextHandle, path: &str, hive: RegistryHive) -> Result<ContextHandle> {
+        let mut request = NdrWriter::new();
+        request.context_handle(parent);
+        request.terminated_string(path);
+        request.u32(0); // options
+        request.u32(KEY_READ);
+        let response = self.rpc.call(OPNUM_OPEN_KEY, &request.into_bytes())?;
+
+        let mut reader = NdrReader::new(&response);
+        let handle = reader.context_handle()?;
+        check(reader.u32()?, || format!("{hive:?}\\{path}"))?;
+        Ok(handle)
+    }
+
+    fn close_key(&mut self, key: &ContextHandle) -> Result<()> {
+        let mut request = NdrWriter::new();
+        request.context_handle(key);
+        let response = self.rpc.call(OPNUM_CLOSE_KEY, &request.into_bytes())?;
+
+        let mut reader = NdrReader::new(&response);
+        reader.context_handle()?;
+        check(reader.u32()?, || "key handle".to_string())
+    }
+
+    fn enum_keys(&mut self, key: &ContextHandle, what: &str) -> Result<Vec<String>> {
+        let mut names = vec![];
+        loop {
+            let mut request = NdrWriter::new();
+            request.context_handle(key);
+            request.u32(names.len() as u32);
+            request.unicode_string(&[], MAX_KEY_NAME);
+            request.pointer(); // class name
+            empty_string(&mut request);
+            request.null_pointer(); // last write time
+            let response = self.rpc.call(OPNUM_ENUM_KEY, &request.into_bytes())?;
+
+            let mut reader = NdrReader::new(&response);
+            let name = reader.unicode_string()?;
+            if reader.pointer()? {
+                reader.unicode_string()?;
+            }
+            if reader.pointer()? {
+                reader.u64()?;
+            }
+            match reader.u32()? {
+                ERROR_NO_MORE_ITEMS => return Ok(names),
+                code => check(code, || what.to_string())?,
+            }
+            names.push(name.map(|(name, _)| name).unwrap_or_default());
+        }
+    }
+
+    fn enum_values(&mut self, key: &ContextHandle, what: &str) -> Result<Vec<String>> {
+        let mut names = vec![];
+        let mut max_name = MAX_KEY_NAME;
+        loop {
+            let mut request = NdrWriter::new();
+            request.context_handle(key);
+            request.u32(names.len() as u32);
+            request.unicode_string(&[], max_name);
+            request.pointer(); // type
+            request.u32(0);
+            request.null_pointer(); // data
+            request.pointer(); // data size
+            request.u32(0);
+            request.pointer(); // data length
+            request.u32(0);
+            let response = self.rpc.call(OPNUM_ENUM_VALUE, &request.into_bytes())?;
+
+            let mut reader = NdrReader::new(&response);
+            let name = reader.unicode_string()?;
+            if reader.pointer()? {
+                reader.u32()?;
+            }
+            if reader.pointer()? {
+                reader.conformant_varying_bytes()?;
+            }
+            for _ in 0..2 {
+                if reader.pointer()? {
+                    reader.u32()?;
+                }
+            }
+            match reader.u32()? {
+                ERROR_NO_MORE_ITEMS => return Ok(names),
+                ERROR_MORE_DATA if max_name < MAX_VALUE_NAME => {
+                    max_name = MAX_VALUE_NAME;
+                    continue;
+                }
+                code => check(code, || what.to_string())?,
+            }
+            names.push(name.map(|(name, _)| name).unwrap_or_default());
+        }
+    }
+
+    fn query_value(&mut self, key: &ContextHandle, name: &str, what: &str) -> Result<RegistryValue> {
+        let mut size = 512u32;
+        loop {
+            let mut request = NdrWriter::new();
+            request.context_handle(key);
+            request.terminated_string(name);
+            request.pointer(); // type
+            request.u32(0);
+            request.pointer(); // data
+            request.conformant_varying_bytes(&[], size);
+            request.pointer(); // data size
+            request.u32(size);
+            request.pointer(); // data length
+            request.u32(0);
+            let response = self.rpc.call(OPNUM_QUERY_VALUE, &request.into_bytes())?;
+
+            let mut reader = NdrReader::new(&response);
+            let data_type = if reader.pointer()? { reader.u32()? } else { 0 };
+            let data = if reader.pointer()? {
+                reader.conformant_varying_bytes()?.1.to_vec()
+            } else {
+                vec![]
+            };
+            let needed = if reader.pointer()? { reader.u32()? } else { 0 };
+            let length = if reader.pointer()? { reader.u32()? } else { 0 };
+            match reader.u32()? {
+                ERROR_MORE_DATA if needed > size => {
+                    size = needed;
+                    continue;
+                }
+                code => check(code, || what.to_string())?,
+            }
+
+            let length = (length as usize).min(data.len());
+            return decode_value(data_type, data[..length].to_vec());
+        }
+    }
+
+    fn query_last_write_time(&mut self, key: &ContextHandle, what: &str) -> Result<Timestamp> {
+        let mut request = NdrWriter::new();
+        request.context_handle(key);
+        empty_string(&mut request);
+        let response = self.rpc.call(OPNUM_QUERY_INFO_KEY, &request.into_bytes())?;
+
+        let mut reader = NdrReader::new(&response);
+        reader.unicode_string()?;
+        // Sub key and value counts and sizes, and the size of the security descriptor.
+        for _ in 0..7 {
+            reader.u32()?;
+        }
+        let low = reader.u32()? as u64;
+        let high = reader.u32()? as u64;
+        check(reader.u32()?, || what.to_string())?;
+        Timestamp::from_filetime(high << 32 | low)
+    }
+}
+
+impl RegistryBackend for RemoteRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let what = format!("{hive:?}\\{path}");
+        self.with_key(hive, path, |session, key| session.enum_keys(key, &what))
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let what = format!("{hive:?}\\{path}");
+        self.with_key(hive, path, |session, key| session.enum_values(key, &what))
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        let what = format!("{hive:?}\\{path}\\{name}");
+        self.with_key(hive, path, |session, key| session.query_value(key, name, &what))
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        let what = format!("{hive:?}\\{path}");
+        self.with_key(hive, path, |session, key| session.query_last_write_time(key, &what))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("dc01", 445), ("dc01", 445));
+        assert_eq!(split_host_port("127.0.0.1:4455", 445), ("127.0.0.1", 4455));
+        assert_eq!(split_host_port("[::1]:4455", 445), ("::1", 4455));
+        assert_eq!(split_host_port("[::1]", 445), ("::1", 445));
+        assert_eq!(split_host_port("fe80::1", 445), ("fe80::1", 445));
+    }
+}
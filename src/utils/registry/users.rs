@@ -0,0 +1,295 @@
+//! User profiles and the hives of their users.
+//!
+//! Windows lists every profile of a machine under `ProfileList`, with the SID
+//! of the user and the directory of the profile, which holds `NTUSER.DAT`.
+//! The hive of a user is visible at `HKEY_USERS\<SID>` and the classes of the
+//! user, from `UsrClass.dat`, at `HKEY_USERS\<SID>_Classes`. Backends show
+//! the hives they can, see `for_each_user`.
+//!
+//! `ProfileList` does not hold the names of the users. They are resolved from
+//! the SID where the backend shows where to, see `user_profiles`.
+
+use super::{RegistryBackend, RegistryHive, RegistryValue};
+use crate::error::{Error, Result};
+
+/// The key that lists the profiles of the machine, in `HKEY_LOCAL_MACHINE`.
+pub const PROFILE_LIST: &str = "SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion\\ProfileList";
+
+/// The suffix of the SID under which the classes of a user are visible in `HKEY_USERS`.
+pub const CLASSES_SUFFIX: &str = "_Classes";
+
+/// The hive file of a user, relative to the profile directory.
+pub const USER_HIVE_FILE: &str = "NTUSER.DAT";
+
+/// The classes hive file of a user, relative to the profile directory.
+pub const CLASSES_HIVE_FILE: &str = "AppData\\Local\\Microsoft\\Windows\\UsrClass.dat";
+
+/// The key of a logged on user, in its hive, whose `USERNAME` value holds the name of the user.
+pub const VOLATILE_ENVIRONMENT: &str = "Volatile Environment";
+
+/// The key of the local account domain in the `SAM` hive, in `HKEY_LOCAL_MACHINE`.
+pub const SAM_ACCOUNT: &str = "SAM\\SAM\\Domains\\Account";
+
+/// Names of the service accounts, whose profiles are not named after them.
+const WELL_KNOWN_USERS: [(&str, &str); 3] = [
+    ("S-1-5-18", "SYSTEM"),
+    ("S-1-5-19", "LOCAL SERVICE"),
+    ("S-1-5-20", "NETWORK SERVICE"),
+];
+
+/// A profile of a user, as listed in `ProfileList`.
+///
+/// # Fields
+/// - `sid`: The SID of the user, such as `S-1-5-21-...-1001`.
+/// - `profile_path`: The directory of the profile, unexpanded, such as `C:\Users\alice` or `%systemroot%\system32\config\systemprofile`.
+/// - `username`: The name of the user, see `user_profiles`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserProfile {
+    pub sid: String,
+    pub profile_path: String,
+    pub username: String,
+}
+
+impl UserProfile {
+    /// Returns the path of a key of the user in `HKEY_USERS`, such as `S-1-5-21-...-1001\Software`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key within the hive of the user, empty for its root.
+    pub fn key_path(&self, path: &str) -> String {
+        join(&self.sid, path)
+    }
+
+    /// Returns the path of a key of the classes of the user in `HKEY_USERS`, such as `S-1-5-21-...-1001_Classes\CLSID`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the key within the classes of the user, empty for their root.
+    pub fn classes_path(&self, path: &str) -> String {
+        join(&format!("{}{CLASSES_SUFFIX}", self.sid), path)
+    }
+}
+
+fn join(root: &str, path: &str) -> String {
+    match path.trim_matches('\\') {
+        "" => root.to_string(),
+        path => format!("{root}\\{path}"),
+    }
+}
+
+/// Lists the profiles of the machine from `ProfileList`.
+///
+/// Profiles without a profile directory, and the `.bak` copies Windows keeps
+/// of profiles it could not load, are skipped.
+///
+/// The name of a user is resolved from its SID, in order:
+/// - the names of the service accounts, which are fixed;
+/// - `USERNAME` in the `Volatile Environment` of the user, which Windows only
+///   writes while the user is logged on;
+/// - the names of the local accounts in the `SAM` hive, which needs a backend
+///   that can read it, such as an offline image or a live machine as SYSTEM.
+///
+/// Otherwise, for domain users that are not logged on or when neither is
+/// readable, the name of the profile directory is used. It is a guess: it is
+/// not updated when the user is renamed, and may carry a suffix such as
+/// `alice.CONTOSO` when profiles of the same name exist.
+///
+/// # Arguments
+///
+/// * `registry` - The registry of the machine.
+///
+/// # Returns
+///
+/// * `Ok(Vec<UserProfile>)` containing the profiles, in the order of the keys.
+/// * `Err(e)` if `ProfileList` could not be read.
+pub fn user_profiles(registry: &dyn RegistryBackend) -> Result<Vec<UserProfile>> {
+    let mut profiles = vec![];
+    let accounts = local_accounts(registry)?;
+    for sid in registry.get_sub_key_names(RegistryHive::LocalMachine, PROFILE_LIST)? {
+        if sid.to_ascii_lowercase().ends_with(".bak") {
+            continue;
+        }
+        let key = format!("{PROFILE_LIST}\\{sid}");
+        let profile_path = match registry.get_string_value(RegistryHive::LocalMachine, &key, "ProfileImagePath") {
+            Ok(path) if !path.is_empty() => path,
+            Ok(_) | Err(Error::NotFound(_) | Error::InvalidData(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let username = match WELL_KNOWN_USERS.iter().find(|(known, _)| *known == sid) {
+            Some((_, name)) => name.to_string(),
+            None => match logged_on_username(registry, &sid)? {
+                Some(name) => name,
+                None => match accounts.iter().find(|(account, _)| *account == sid) {
+                    Some((_, name)) => name.clone(),
+                    None => {
+                        profile_path.trim_end_matches('\\').rsplit('\\').next().unwrap_or_default().to_string()
+                    }
+                },
+            },
+        };
+        profiles.push(UserProfile { sid, profile_path, username });
+    }
+    Ok(profiles)
+}
+
+/// Reads the name of a logged on user from its `Volatile Environment`.
+///
+/// # Returns
+///
+/// * `Ok(Some(name))` if the name could be read.
+/// * `Ok(None)` if it could not, as for users that are not logged on.
+/// * `Err(e)` if the limits of the command were reached.
+fn logged_on_username(registry: &dyn RegistryBackend, sid: &str) -> Result<Option<String>> {
+    let key = join(sid, VOLATILE_ENVIRONMENT);
+    match registry.get_string_value(RegistryHive::Users, &key, "USERNAME") {
+        Ok(name) if !name.is_empty() => Ok(Some(name)),
+        Err(e @ Error::Limit(_)) => Err(e),
+        Ok(_) | Err(_) => Ok(None),
+    }
+}
+
+/// Lists the local accounts of the machine from the `SAM` hive.
+///
+/// The SID of an account is the SID of the machine, stored at the end of the
+/// `V` value of the account domain, followed by the RID of the account, which
+/// `Users\Names\<name>` stores as the type of its default value.
+///
+/// # Returns
+///
+/// * `Ok(Vec<(String, String)>)` containing the SID and name of the accounts, empty if the hive is not readable.
+/// * `Err(e)` if the limits of the command were reached.
+fn local_accounts(registry: &dyn RegistryBackend) -> Result<Vec<(String, String)>> {
+    let read = || -> Result<Vec<(String, String)>> {
+        let domain = registry.get_binary_value(RegistryHive::LocalMachine, SAM_ACCOUNT, "V")?;
+        let Some(machine_sid) = domain.len().checked_sub(24).and_then(|start| machine_sid(&domain[start..])) else {
+            return Ok(vec![]);
+        };
+        let names = format!("{SAM_ACCOUNT}\\Users\\Names");
+        let mut accounts = vec![];
+        for name in registry.get_sub_key_names(RegistryHive::LocalMachine, &names)? {
+            let key = format!("{names}\\{name}");
+            if let RegistryValue::Other(rid, _) = registry.get_raw_value(RegistryHive::LocalMachine, &key, "")? {
+                accounts.push((format!("{machine_sid}-{rid}"), name));
+            }
+        }
+        Ok(accounts)
+    };
+    match read() {
+        Ok(accounts) => Ok(accounts),
+        Err(e @ Error::Limit(_)) => Err(e),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Formats a binary machine SID, `S-1-5-21-` followed by three sub-authorities.
+fn machine_sid(data: &[u8]) -> Option<String> {
+    if data[..12] != [1, 4, 0, 0, 0, 0, 0, 5, 21, 0, 0, 0] {
+        return None;
+    }
+    let sub_authorities: Vec<String> = data[12..]
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).to_string())
+        .collect();
+    Some(format!("S-1-5-21-{}", sub_authorities.join("-")))
+}
+
+/// Reads something of every user whose hive the registry shows.
+///
+/// Users that `read` fails for with `Error::NotFound` are skipped, whether
+/// their hive lacks what is read or is not available at all: on a live
+/// machine the hive of a user that is not logged on may be unreadable, and
+/// remote registries only show the hives of logged on users.
+///
+/// # Arguments
+///
+/// * `registry` - The registry of the machine.
+/// * `read` - Reads what is wanted of a user, typically below `HKEY_USERS\<SID>`, see `UserProfile::key_path`.
+///
+/// # Returns
+///
+/// * `Ok(Vec<(UserProfile, T)>)` containing what was read, with the user it was read of.
+/// * `Err(e)` if the profiles could not be listed, or `read` failed other than with `Error::NotFound`.
+pub fn for_each_user<T>(
+    registry: &dyn RegistryBackend,
+    mut read: impl FnMut(&UserProfile) -> Result<T>,
+) -> Result<Vec<(UserProfile, T)>> {
+    let mut results = vec![];
+    for profile in user_profiles(registry)? {
+        match read(&profile) {
+            Ok(result) => results.push((profile, result)),
+            Err(Error::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::registry::offline::OfflineRegistry;
+
+    const REG: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-18]
+"ProfileImagePath"=hex(2):25,00,73,00,79,00,73,00,74,00,65,00,6d,00,72,00,6f,00,6f,00,74,00,25,00,00,00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-1-2-3-1001]
+"ProfileImagePath"="C:\\Users\\alice"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-1-2-3-1001.bak]
+"ProfileImagePath"="C:\\Users\\TEMP"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-1-2-3-1002]
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001\Environment]
+"TEMP"="C:\\Temp"
+"#;
+
+    #[test]
+    fn test_user_profiles() {
+        let registry = OfflineRegistry::from_reg(REG).unwrap();
+        let profiles = user_profiles(&registry).unwrap();
+        let names: Vec<(&str, &str)> =
+            profiles.iter().map(|profile| (profile.sid.as_str(), profile.username.as_str())).collect();
+        assert_eq!(names, [("S-1-5-18", "SYSTEM"), ("S-1-5-21-1-2-3-1001", "alice")]);
+        assert_eq!(profiles[0].profile_path, "%systemroot%");
+        assert_eq!(profiles[1].classes_path("CLSID\\"), "S-1-5-21-1-2-3-1001_Classes\\CLSID");
+
+        // Users without the key read are skipped.
+        let temp = for_each_user(&registry, |profile| {
+            registry.get_string_value(RegistryHive::Users, &profile.key_path("Environment"), "TEMP")
+        })
+        .unwrap();
+        assert_eq!(temp.len(), 1);
+        assert_eq!((temp[0].0.username.as_str(), temp[0].1.as_str()), ("alice", "C:\\Temp"));
+    }
+
+    #[test]
+    fn test_resolved_usernames() {
+        let reg = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-1-2-3-1001]
+"ProfileImagePath"="C:\\Users\\alice"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-9-9-9-1105]
+"ProfileImagePath"="C:\\Users\\bob.CONTOSO"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Microsoft\Windows NT\CurrentVersion\ProfileList\S-1-5-21-9-9-9-1106]
+"ProfileImagePath"="C:\\Users\\carol.CONTOSO"
+
+[HKEY_LOCAL_MACHINE\SAM\SAM\Domains\Account]
+"V"=hex:00,00,00,00,01,04,00,00,00,00,00,05,15,00,00,00,01,00,00,00,02,00,00,00,03,00,00,00
+
+[HKEY_LOCAL_MACHINE\SAM\SAM\Domains\Account\Users\Names\alicia]
+@=hex(3e9):
+
+[HKEY_USERS\S-1-5-21-9-9-9-1105\Volatile Environment]
+"USERNAME"="bob"
+"#;
+        let registry = OfflineRegistry::from_reg(reg).unwrap();
+        let names: Vec<String> = user_profiles(&registry).unwrap().into_iter().map(|profile| profile.username).collect();
+        // A renamed local account, a logged on domain user and the fallback to the profile directory.
+        assert_eq!(names, ["alicia", "bob", "carol.CONTOSO"]);
+    }
+}
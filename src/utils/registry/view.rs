@@ -0,0 +1,194 @@
+//! The 32-bit view of the registry, on any backend.
+//!
+//! On 64-bit Windows, 32-bit programs see parts of `SOFTWARE` redirected to
+//! `WOW6432Node` keys. The redirected keys are ordinary keys of the hives, so
+//! `RegistryView` shows the view of 32-bit programs by rewriting paths, which
+//! works the same on live, remote and offline registries. `Runtime::with_view`
+//! and `--view x86` show it to the commands.
+
+use std::path::PathBuf;
+
+use super::{users::CLASSES_SUFFIX, RegistryBackend, RegistryHive, RegistryHiveType, RegistryValue};
+use crate::{error::Result, utils::time::Timestamp};
+
+/// The key 32-bit programs see instead of its parent.
+pub const WOW64_NODE: &str = "WOW6432Node";
+
+/// The subkeys of `Classes` that are redirected, the others are shared by both views.
+const REDIRECTED_CLASSES: [&str; 5] = ["CLSID", "DirectShow", "Interface", "Media Type", "MediaFoundation"];
+
+/// A registry backend seen through a registry view.
+///
+/// # Fields
+/// - `registry`: The backend, which shows the native 64-bit view.
+/// - `view`: The view to show.
+pub struct RegistryView {
+    pub registry: Box<dyn RegistryBackend>,
+    pub view: RegistryHiveType,
+}
+
+impl RegistryView {
+    /// Shows a backend through a view.
+    ///
+    /// # Arguments
+    ///
+    /// * `registry` - The backend, which shows the native 64-bit view.
+    /// * `view` - The view to show.
+    pub fn new(registry: Box<dyn RegistryBackend>, view: RegistryHiveType) -> Self {
+        RegistryView { registry, view }
+    }
+
+    /// Returns the path of a key of the view in the native view.
+    ///
+    /// In the 32-bit view, `HKLM\SOFTWARE` is redirected to
+    /// `HKLM\SOFTWARE\WOW6432Node`, except for `Classes`, and the `CLSID`,
+    /// `Interface` and other redirected subkeys of `Classes` are redirected to
+    /// `Classes\WOW6432Node`, in `HKLM\SOFTWARE`, `HKEY_CLASSES_ROOT`, and the
+    /// classes of users, whether at `HKU\<SID>_Classes` or at `Software\Classes`
+    /// of `HKEY_CURRENT_USER` and `HKU\<SID>`. Paths that are already in
+    /// `WOW6432Node` are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `hive` - The registry hive of the key.
+    /// * `path` - The path of the key in the view.
+    pub fn native_path(&self, hive: RegistryHive, path: &str) -> String {
+        let mut parts: Vec<&str> = path.split('\\').filter(|part| !part.is_empty()).collect();
+        if self.view == RegistryHiveType::X64 {
+            return parts.join("\\");
+        }
+        let is = |index: usize, name: &str| parts.get(index).is_some_and(|part| part.eq_ignore_ascii_case(name));
+        let classes = |index: usize| {
+            let redirected = parts.get(index).is_some_and(|part| {
+                REDIRECTED_CLASSES.iter().any(|name| name.eq_ignore_ascii_case(part))
+            });
+            redirected.then_some(index)
+        };
+
+        // The index at which WOW6432Node is inserted, if the key is redirected.
+        let insert = match hive {
+            RegistryHive::LocalMachine if is(0, "SOFTWARE") && is(1, "Classes") => classes(2),
+            RegistryHive::LocalMachine if is(0, "SOFTWARE") && !is(1, WOW64_NODE) => Some(1),
+            RegistryHive::ClassesRoot => classes(0),
+            RegistryHive::CurrentUser if is(0, "Software") && is(1, "Classes") => classes(2),
+            RegistryHive::Users if is(1, "Software") && is(2, "Classes") => classes(3),
+            RegistryHive::Users
+                if parts
+                    .first()
+                    .is_some_and(|root| root.to_ascii_lowercase().ends_with(&CLASSES_SUFFIX.to_ascii_lowercase())) =>
+            {
+                classes(1)
+            }
+            _ => None,
+        };
+        if let Some(index) = insert {
+            parts.insert(index, WOW64_NODE);
+        }
+        parts.join("\\")
+    }
+}
+
+impl RegistryBackend for RegistryView {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.registry.get_sub_key_names(hive, &self.native_path(hive, path))
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.registry.get_value_names(hive, &self.native_path(hive, path))
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        self.registry.get_raw_value(hive, &self.native_path(hive, path), name)
+    }
+
+    fn get_key_last_write_time(&self, hive: RegistryHive, path: &str) -> Result<Timestamp> {
+        self.registry.get_key_last_write_time(hive, &self.native_path(hive, path))
+    }
+
+    fn source_file(&self, hive: RegistryHive, path: &str) -> Option<PathBuf> {
+        self.registry.source_file(hive, &self.native_path(hive, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::registry::offline::OfflineRegistry;
+
+    const REG: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Vendor]
+"Bits"=dword:00000040
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\WOW6432Node\Vendor]
+"Bits"=dword:00000020
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Classes\CLSID\{1}\InprocServer32]
+@="C:\\Windows\\System32\\provider.dll"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Classes\WOW6432Node\CLSID\{1}\InprocServer32]
+@="C:\\Windows\\SysWOW64\\provider.dll"
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Classes\.txt]
+@="txtfile"
+"#;
+
+    #[test]
+    fn test_views() {
+        let native = RegistryView::new(Box::new(OfflineRegistry::from_reg(REG).unwrap()), RegistryHiveType::X64);
+        let wow64 = RegistryView::new(Box::new(OfflineRegistry::from_reg(REG).unwrap()), RegistryHiveType::X86);
+
+        assert_eq!(native.get_dword_value(RegistryHive::LocalMachine, "SOFTWARE\\Vendor", "Bits").unwrap(), 64);
+        assert_eq!(wow64.get_dword_value(RegistryHive::LocalMachine, "software\\Vendor", "Bits").unwrap(), 32);
+        assert_eq!(
+            wow64.get_string_value(RegistryHive::ClassesRoot, "CLSID\\{1}\\InprocServer32", "").unwrap(),
+            "C:\\Windows\\SysWOW64\\provider.dll"
+        );
+        // Shared keys are the same in both views.
+        assert_eq!(wow64.get_string_value(RegistryHive::LocalMachine, "SOFTWARE\\Classes\\.txt", "").unwrap(), "txtfile");
+        assert_eq!(wow64.native_path(RegistryHive::LocalMachine, "SOFTWARE\\WOW6432Node\\Vendor"), "SOFTWARE\\WOW6432Node\\Vendor");
+        assert_eq!(wow64.native_path(RegistryHive::Users, "S-1-5-21-1_Classes\\CLSID"), "S-1-5-21-1_Classes\\WOW6432Node\\CLSID");
+        assert_eq!(wow64.native_path(RegistryHive::LocalMachine, "SYSTEM\\Select"), "SYSTEM\\Select");
+    }
+
+    #[test]
+    fn test_user_classes_views() {
+        let reg = r#"Windows Registry Editor Version 5.00
+
+[HKEY_CURRENT_USER\Software\Classes\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x64\\handler.dll"
+
+[HKEY_CURRENT_USER\Software\Classes\WOW6432Node\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x86\\handler.dll"
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001\Software\Classes\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x64\\handler.dll"
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001\Software\Classes\WOW6432Node\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x86\\handler.dll"
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001_Classes\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x64\\handler.dll"
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001_Classes\WOW6432Node\CLSID\{2}\InprocServer32]
+@="C:\\Users\\alice\\x86\\handler.dll"
+
+[HKEY_USERS\S-1-5-21-1-2-3-1001\Software\Vendor]
+"Bits"=dword:00000040
+"#;
+        let native = RegistryView::new(Box::new(OfflineRegistry::from_reg(reg).unwrap()), RegistryHiveType::X64);
+        let wow64 = RegistryView::new(Box::new(OfflineRegistry::from_reg(reg).unwrap()), RegistryHiveType::X86);
+
+        let keys = [
+            (RegistryHive::CurrentUser, "Software\\Classes\\CLSID\\{2}\\InprocServer32"),
+            (RegistryHive::Users, "S-1-5-21-1-2-3-1001\\Software\\Classes\\CLSID\\{2}\\InprocServer32"),
+            (RegistryHive::Users, "S-1-5-21-1-2-3-1001_Classes\\CLSID\\{2}\\InprocServer32"),
+        ];
+        for (hive, path) in keys {
+            assert_eq!(native.get_string_value(hive, path, "").unwrap(), "C:\\Users\\alice\\x64\\handler.dll");
+            assert_eq!(wow64.get_string_value(hive, path, "").unwrap(), "C:\\Users\\alice\\x86\\handler.dll");
+        }
+        // Only the classes of users are redirected.
+        assert_eq!(wow64.get_dword_value(RegistryHive::Users, "S-1-5-21-1-2-3-1001\\Software\\Vendor", "Bits").unwrap(), 64);
+    }
+}
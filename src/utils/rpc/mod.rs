@@ -0,0 +1,343 @@
+//! DCE/RPC over connection-oriented transports, such as SMB2 named pipes.
+//!
+//! This is the subset of the connection-oriented protocol (C706 and MS-RPCE)
+//! that Rustbelt needs to talk to Windows RPC interfaces: binding to one
+//! interface with the NDR transfer syntax and making calls, with fragmented
+//! requests and responses.
+pub mod ndr;
+pub mod smb2;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::error::{Error, Result};
+
+/// Packet types of the connection-oriented protocol.
+pub const PTYPE_REQUEST: u8 = 0;
+pub const PTYPE_RESPONSE: u8 = 2;
+pub const PTYPE_FAULT: u8 = 3;
+pub const PTYPE_BIND: u8 = 11;
+pub const PTYPE_BIND_ACK: u8 = 12;
+pub const PTYPE_BIND_NAK: u8 = 13;
+
+/// Packet flags marking the first and the last fragment of a call.
+pub const PFC_FIRST_FRAG: u8 = 0x01;
+pub const PFC_LAST_FRAG: u8 = 0x02;
+
+/// Size of the common header of every packet.
+pub const HEADER_SIZE: usize = 16;
+/// Size of the headers of request and response packets.
+const REQUEST_HEADER_SIZE: usize = 24;
+/// The fragment size Rustbelt proposes, the default of Windows.
+const MAX_FRAGMENT: u16 = 4280;
+
+/// An interface or transfer syntax: a UUID and a version.
+///
+/// # Fields
+/// - `uuid`: The UUID in its little-endian wire format.
+/// - `version`: The major version in the low and the minor version in the high 16 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntaxId {
+    pub uuid: [u8; 16],
+    pub version: u32,
+}
+
+impl SyntaxId {
+    /// Creates a syntax identifier from the text form of its UUID.
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid` - The UUID, for example `338cd001-2244-31f1-aaaa-900038001003`.
+    /// * `major` - The major version.
+    /// * `minor` - The minor version.
+    pub fn new(uuid: &str, major: u16, minor: u16) -> Result<Self> {
+        let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 {
+            return Err(Error::InvalidData(format!("invalid UUID {uuid}")));
+        }
+
+        let mut bytes = [0u8; 16];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)
+                .map_err(|_| Error::InvalidData(format!("invalid UUID {uuid}")))?;
+        }
+        // The first three fields are little-endian on the wire.
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+
+        Ok(SyntaxId {
+            uuid: bytes,
+            version: major as u32 | (minor as u32) << 16,
+        })
+    }
+
+    /// The NDR 2.0 transfer syntax.
+    pub fn ndr() -> Self {
+        SyntaxId::new("8a885d04-1ceb-11c9-9fe8-08002b104860", 2, 0).expect("valid UUID")
+    }
+
+    /// Encodes the syntax identifier.
+    pub fn to_bytes(&self) -> [u8; 20] {
+        let mut bytes = [0u8; 20];
+        bytes[..16].copy_from_slice(&self.uuid);
+        LittleEndian::write_u32(&mut bytes[16..], self.version);
+        bytes
+    }
+}
+
+/// The common header of a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PduHeader {
+    pub ptype: u8,
+    pub flags: u8,
+    pub frag_length: u16,
+    pub auth_length: u16,
+    pub call_id: u32,
+}
+
+impl PduHeader {
+    /// Parses the header at the start of a packet.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE || data[0] != 5 {
+            return Err(Error::Remote("invalid DCE/RPC packet".to_string()));
+        }
+        if data[4] & 0xf0 != 0x10 {
+            return Err(Error::Remote("big-endian DCE/RPC is not supported".to_string()));
+        }
+
+        Ok(PduHeader {
+            ptype: data[2],
+            flags: data[3],
+            frag_length: LittleEndian::read_u16(&data[8..10]),
+            auth_length: LittleEndian::read_u16(&data[10..12]),
+            call_id: LittleEndian::read_u32(&data[12..16]),
+        })
+    }
+
+    /// Encodes a packet with this header. The fragment length is taken from the body.
+    pub fn encode(&self, body: &[u8]) -> Vec<u8> {
+        let mut pdu = vec![5, 0, self.ptype, self.flags, 0x10, 0, 0, 0];
+        pdu.extend_from_slice(&((HEADER_SIZE + body.len()) as u16).to_le_bytes());
+        pdu.extend_from_slice(&self.auth_length.to_le_bytes());
+        pdu.extend_from_slice(&self.call_id.to_le_bytes());
+        pdu.extend_from_slice(body);
+        pdu
+    }
+}
+
+/// A byte stream that carries DCE/RPC packets, such as a named pipe.
+pub trait RpcTransport {
+    /// Writes data to the transport.
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Reads the next available data from the transport. Never returns an empty buffer.
+    fn read(&mut self) -> Result<Vec<u8>>;
+}
+
+impl<T: RpcTransport + ?Sized> RpcTransport for Box<T> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        (**self).write(data)
+    }
+
+    fn read(&mut self) -> Result<Vec<u8>> {
+        (**self).read()
+    }
+}
+
+/// Reads one packet from a transport.
+///
+/// # Arguments
+///
+/// * `transport` - The transport to read from.
+/// * `buffer` - Data that was read but not consumed yet. It keeps the data after the packet.
+pub fn read_pdu(transport: &mut dyn RpcTransport, buffer: &mut Vec<u8>) -> Result<Vec<u8>> {
+    loop {
+        if buffer.len() >= HEADER_SIZE {
+            let header = PduHeader::parse(buffer)?;
+            let length = header.frag_length as usize;
+            if length < HEADER_SIZE {
+                return Err(Error::Remote("invalid DCE/RPC fragment length".to_string()));
+            }
+            if buffer.len() >= length {
+                let rest = buffer.split_off(length);
+                return Ok(std::mem::replace(buffer, rest));
+            }
+        }
+        buffer.extend_from_slice(&transport.read()?);
+    }
+}
+
+/// A client bound to one RPC interface.
+pub struct RpcClient<T: RpcTransport> {
+    transport: T,
+    buffer: Vec<u8>,
+    call_id: u32,
+    max_xmit: usize,
+}
+
+impl<T: RpcTransport> RpcClient<T> {
+    /// Binds to an interface over a transport.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport to use, for example an open named pipe.
+    /// * `interface` - The interface to bind to.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RpcClient)` if the server accepted the interface.
+    /// * `Err(Error::Remote)` if the server rejected it.
+    pub fn bind(transport: T, interface: SyntaxId) -> Result<Self> {
+        let mut client = RpcClient {
+            transport,
+            buffer: vec![],
+            call_id: 1,
+            max_xmit: MAX_FRAGMENT as usize,
+        };
+
+        let mut body = vec![];
+        body.extend_from_slice(&MAX_FRAGMENT.to_le_bytes()); // max_xmit_frag
+        body.extend_from_slice(&MAX_FRAGMENT.to_le_bytes()); // max_recv_frag
+        body.extend_from_slice(&0u32.to_le_bytes()); // assoc_group_id
+        body.extend_from_slice(&[1, 0, 0, 0]); // one presentation context
+        body.extend_from_slice(&0u16.to_le_bytes()); // context id
+        body.extend_from_slice(&[1, 0]); // one transfer syntax
+        body.extend_from_slice(&interface.to_bytes());
+        body.extend_from_slice(&SyntaxId::ndr().to_bytes());
+
+        let header = PduHeader {
+            ptype: PTYPE_BIND,
+            flags: PFC_FIRST_FRAG | PFC_LAST_FRAG,
+            frag_length: 0,
+            auth_length: 0,
+            call_id: client.next_call_id(),
+        };
+        client.transport.write(&header.encode(&body))?;
+
+        let pdu = read_pdu(&mut client.transport, &mut client.buffer)?;
+        let header = PduHeader::parse(&pdu)?;
+        match header.ptype {
+            PTYPE_BIND_ACK => {}
+            PTYPE_BIND_NAK => return Err(Error::Remote("the server rejected the RPC bind".to_string())),
+            ptype => return Err(Error::Remote(format!("unexpected DCE/RPC packet type {ptype} in bind"))),
+        }
+
+        let body = &pdu[HEADER_SIZE..];
+        if body.len() < 10 {
+            return Err(Error::Remote("truncated bind acknowledgement".to_string()));
+        }
+        let max_recv = LittleEndian::read_u16(&body[2..4]) as usize;
+        client.max_xmit = max_recv.clamp(REQUEST_HEADER_SIZE + 8, MAX_FRAGMENT as usize);
+
+        // Skip the secondary address and its padding to get to the results.
+        let address_len = LittleEndian::read_u16(&body[8..10]) as usize;
+        let results = (10 + address_len).div_ceil(4) * 4;
+        let result = body
+            .get(results + 4..results + 6)
+            .map(LittleEndian::read_u16)
+            .ok_or_else(|| Error::Remote("truncated bind acknowledgement".to_string()))?;
+        if result != 0 {
+            return Err(Error::Remote(format!("the server rejected the interface ({result})")));
+        }
+        Ok(client)
+    }
+
+    fn next_call_id(&mut self) -> u32 {
+        let call_id = self.call_id;
+        self.call_id += 1;
+        call_id
+    }
+
+    /// Calls an operation of the bound interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `opnum` - The operation number.
+    /// * `stub` - The NDR encoded input parameters.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` containing the NDR encoded output parameters.
+    /// * `Err(Error::Remote)` if the server returned a fault.
+    pub fn call(&mut self, opnum: u16, stub: &[u8]) -> Result<Vec<u8>> {
+        let call_id = self.next_call_id();
+        let chunk_size = self.max_xmit - REQUEST_HEADER_SIZE;
+        let chunks: Vec<&[u8]> = if stub.is_empty() {
+            vec![stub]
+        } else {
+            stub.chunks(chunk_size).collect()
+        };
+
+        for (index, chunk) in chunks.iter().enumerate() {
+            let mut flags = 0;
+            if index == 0 {
+                flags |= PFC_FIRST_FRAG;
+            }
+            if index == chunks.len() - 1 {
+                flags |= PFC_LAST_FRAG;
+            }
+
+            let mut body = vec![];
+            body.extend_from_slice(&(stub.len() as u32).to_le_bytes()); // alloc_hint
+            body.extend_from_slice(&0u16.to_le_bytes()); // context id
+            body.extend_from_slice(&opnum.to_le_bytes());
+            body.extend_from_slice(chunk);
+
+            let header = PduHeader {
+                ptype: PTYPE_REQUEST,
+                flags,
+                frag_length: 0,
+                auth_length: 0,
+                call_id,
+            };
+            self.transport.write(&header.encode(&body))?;
+        }
+
+        let mut output = vec![];
+        loop {
+            let pdu = read_pdu(&mut self.transport, &mut self.buffer)?;
+            let header = PduHeader::parse(&pdu)?;
+            if header.call_id != call_id {
+                return Err(Error::Remote(format!(
+                    "DCE/RPC response for call {} while waiting for {call_id}",
+                    header.call_id
+                )));
+            }
+
+            let body_end = pdu.len() - header.auth_length as usize;
+            let body = pdu
+                .get(REQUEST_HEADER_SIZE..body_end)
+                .ok_or_else(|| Error::Remote("truncated DCE/RPC response".to_string()))?;
+            match header.ptype {
+                PTYPE_RESPONSE => output.extend_from_slice(body),
+                PTYPE_FAULT => {
+                    let status = body.get(..4).map(LittleEndian::read_u32).unwrap_or_default();
+                    return Err(Error::Remote(format!("RPC fault {status:#010x} in operation {opnum}")));
+                }
+                ptype => return Err(Error::Remote(format!("unexpected DCE/RPC packet type {ptype}"))),
+            }
+
+            if header.flags & PFC_LAST_FRAG != 0 {
+                return Ok(output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syntax_id() {
+        let ndr = SyntaxId::ndr().to_bytes();
+        assert_eq!(
+            ndr,
+            [
+                0x04, 0x5d, 0x88, 0x8a, 0xeb, 0x1c, 0xc9, 0x11, 0x9f, 0xe8, 0x08, 0x00, 0x2b, 0x10,
+                0x48, 0x60, 0x02, 0x00, 0x00, 0x00
+            ]
+        );
+        assert!(SyntaxId::new("not-a-uuid", 1, 0).is_err());
+    }
+}
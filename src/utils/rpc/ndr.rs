@@ -0,0 +1,275 @@
+//! Network Data Representation (NDR 2.0) encoding, as used by DCE/RPC.
+//!
+//! Only the little-endian representation is supported, which is what every
+//! Windows peer negotiates. Unique pointers are written with their pointee
+//! directly after them, which is correct for top-level parameters and for
+//! structures whose only pointer is their last member.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::error::{Error, Result};
+
+/// An RPC context handle, the server side reference to an open object such as a registry key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ContextHandle(pub [u8; 20]);
+
+impl ContextHandle {
+    /// Returns whether this is the null handle, which servers return for closed or failed handles.
+    pub fn is_null(&self) -> bool {
+        self.0 == [0; 20]
+    }
+}
+
+/// Writes NDR encoded data.
+pub struct NdrWriter {
+    buffer: Vec<u8>,
+    next_referent: u32,
+}
+
+impl Default for NdrWriter {
+    fn default() -> Self {
+        NdrWriter {
+            buffer: vec![],
+            next_referent: 0x0002_0000,
+        }
+    }
+}
+
+impl NdrWriter {
+    /// Creates an empty writer.
+    pub fn new() -> Self {
+        NdrWriter::default()
+    }
+
+    /// Pads the data with zeroes to a multiple of `alignment`.
+    pub fn align(&mut self, alignment: usize) {
+        while !self.buffer.len().is_multiple_of(alignment) {
+            self.buffer.push(0);
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.align(2);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.align(8);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes raw bytes without alignment.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Writes a non-null unique pointer. The pointee must be written next.
+    pub fn pointer(&mut self) {
+        let referent = self.next_referent;
+        self.next_referent += 4;
+        self.u32(referent);
+    }
+
+    /// Writes a null unique pointer.
+    pub fn null_pointer(&mut self) {
+        self.u32(0);
+    }
+
+    pub fn context_handle(&mut self, handle: &ContextHandle) {
+        self.align(4);
+        self.bytes(&handle.0);
+    }
+
+    /// Writes a conformant varying array of bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The transmitted bytes.
+    /// * `max_count` - The size of the array, at least `bytes.len()`.
+    pub fn conformant_varying_bytes(&mut self, bytes: &[u8], max_count: u32) {
+        self.u32(max_count);
+        self.u32(0);
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+
+    /// Writes an `RPC_UNICODE_STRING` with its buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `chars` - The UTF-16 characters of the string, including a terminator if the protocol wants one.
+    /// * `max_chars` - The size of the buffer in characters, at least `chars.len()`.
+    pub fn unicode_string(&mut self, chars: &[u16], max_chars: usize) {
+        self.u16((chars.len() * 2) as u16);
+        self.u16((max_chars * 2) as u16);
+        self.pointer();
+        self.u32(max_chars as u32);
+        self.u32(0);
+        self.u32(chars.len() as u32);
+        for c in chars {
+            self.buffer.extend_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    /// Writes a null-terminated `RPC_UNICODE_STRING`.
+    pub fn terminated_string(&mut self, string: &str) {
+        let mut chars: Vec<u16> = string.encode_utf16().collect();
+        chars.push(0);
+        let len = chars.len();
+        self.unicode_string(&chars, len);
+    }
+
+    /// Returns the encoded data.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// Reads NDR encoded data.
+pub struct NdrReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> NdrReader<'a> {
+    /// Creates a reader over NDR encoded data.
+    pub fn new(data: &'a [u8]) -> Self {
+        NdrReader { data, position: 0 }
+    }
+
+    /// Skips padding up to a multiple of `alignment`.
+    pub fn align(&mut self, alignment: usize) {
+        self.position = self.position.div_ceil(alignment) * alignment;
+    }
+
+    /// Reads `len` raw bytes without alignment.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| Error::InvalidData("truncated NDR data".to_string()))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        self.align(2);
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        self.align(4);
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        self.align(8);
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+
+    /// Reads a unique pointer and returns whether it is non-null.
+    pub fn pointer(&mut self) -> Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+
+    pub fn context_handle(&mut self) -> Result<ContextHandle> {
+        self.align(4);
+        let mut handle = ContextHandle::default();
+        handle.0.copy_from_slice(self.bytes(20)?);
+        Ok(handle)
+    }
+
+    /// Reads a conformant varying array of bytes.
+    ///
+    /// # Returns
+    ///
+    /// The size of the array and the transmitted bytes.
+    pub fn conformant_varying_bytes(&mut self) -> Result<(u32, &'a [u8])> {
+        let max_count = self.u32()?;
+        let offset = self.u32()?;
+        let count = self.u32()?;
+        if offset.checked_add(count).is_none_or(|end| end > max_count) {
+            return Err(Error::InvalidData("invalid NDR array bounds".to_string()));
+        }
+        Ok((max_count, self.bytes(count as usize)?))
+    }
+
+    /// Reads an `RPC_UNICODE_STRING`.
+    ///
+    /// # Returns
+    ///
+    /// The string without terminating null characters and the size of its
+    /// buffer in characters, or `None` if the buffer pointer is null.
+    pub fn unicode_string(&mut self) -> Result<Option<(String, usize)>> {
+        let _length = self.u16()?;
+        let _max_length = self.u16()?;
+        if !self.pointer()? {
+            return Ok(None);
+        }
+
+        let max_count = self.u32()?;
+        let offset = self.u32()?;
+        let count = self.u32()?;
+        if offset.checked_add(count).is_none_or(|end| end > max_count) {
+            return Err(Error::InvalidData("invalid NDR string bounds".to_string()));
+        }
+
+        let chars: Vec<u16> = self
+            .bytes(count as usize * 2)?
+            .chunks_exact(2)
+            .map(LittleEndian::read_u16)
+            .collect();
+        let end = chars.iter().position(|c| *c == 0).unwrap_or(chars.len());
+        Ok(Some((String::from_utf16_lossy(&chars[..end]), max_count as usize)))
+    }
+
+    /// Returns the number of bytes that have not been read yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let handle = ContextHandle([7; 20]);
+        let mut writer = NdrWriter::new();
+        writer.u8(1);
+        writer.context_handle(&handle);
+        writer.terminated_string("Software");
+        writer.null_pointer();
+        writer.u64(42);
+        writer.pointer();
+        writer.conformant_varying_bytes(&[1, 2, 3], 16);
+        let data = writer.into_bytes();
+
+        let mut reader = NdrReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 1);
+        assert_eq!(reader.context_handle().unwrap(), handle);
+        assert_eq!(reader.unicode_string().unwrap(), Some(("Software".to_string(), 9)));
+        assert!(!reader.pointer().unwrap());
+        assert_eq!(reader.u64().unwrap(), 42);
+        assert!(reader.pointer().unwrap());
+        assert_eq!(reader.conformant_varying_bytes().unwrap(), (16, &[1u8, 2, 3][..]));
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.u32().is_err());
+    }
+}
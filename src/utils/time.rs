@@ -0,0 +1,443 @@
+//! Decoding and rendering of the timestamp formats found on Windows systems.
+//!
+//! Every decoder checks its input and returns `Error::InvalidData` for data
+//! that does not describe a valid point in time, instead of panicking or
+//! silently wrapping around. Decoded times are `Timestamp`s, which commands
+//! return as `Value::Timestamp` so they are rendered the same way everywhere.
+
+use std::{fmt, str::FromStr};
+
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat,
+    Utc,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+
+/// Seconds between the `FILETIME` epoch (1601-01-01) and the Unix epoch (1970-01-01).
+const FILETIME_UNIX_OFFSET: i64 = 11_644_473_600;
+/// Number of `FILETIME` intervals (100 nanoseconds) per second.
+const FILETIME_TICKS_PER_SECOND: u64 = 10_000_000;
+/// The smallest and largest valid OLE automation dates (100-01-01 and the end of 9999-12-31).
+const OLE_MIN: f64 = -657_435.0;
+const OLE_MAX: f64 = 2_958_466.0;
+
+/// A point in time, stored in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(DateTime<Utc>);
+
+impl Timestamp {
+    /// Returns the current time.
+    pub fn now() -> Self {
+        Timestamp(Utc::now())
+    }
+
+    /// Decodes a `FILETIME`: the number of 100 nanosecond intervals since 1601-01-01 UTC.
+    ///
+    /// # Arguments
+    ///
+    /// * `filetime` - The `FILETIME` as a 64-bit number.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Timestamp)` if the value is a valid `FILETIME`.
+    /// * `Err(Error::InvalidData)` if it is larger than the largest `FILETIME` Windows accepts.
+    pub fn from_filetime(filetime: u64) -> Result<Self> {
+        if filetime > i64::MAX as u64 {
+            return Err(invalid("FILETIME", filetime));
+        }
+
+        let seconds = (filetime / FILETIME_TICKS_PER_SECOND) as i64 - FILETIME_UNIX_OFFSET;
+        let nanos = (filetime % FILETIME_TICKS_PER_SECOND) as u32 * 100;
+        DateTime::from_timestamp(seconds, nanos)
+            .map(Timestamp)
+            .ok_or_else(|| invalid("FILETIME", filetime))
+    }
+
+    /// Decodes a `FILETIME` stored as 8 little-endian bytes, as found in the registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The raw bytes, which must be exactly 8 bytes long.
+    pub fn from_filetime_bytes(bytes: &[u8]) -> Result<Self> {
+        match bytes.len() {
+            8 => Timestamp::from_filetime(LittleEndian::read_u64(bytes)),
+            len => Err(Error::InvalidData(format!("FILETIME must be 8 bytes, got {len}"))),
+        }
+    }
+
+    /// Decodes a `SYSTEMTIME` structure stored as 16 little-endian bytes.
+    ///
+    /// The day of the week is ignored, since it follows from the date.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The raw structure: year, month, day of week, day, hour, minute, second and milliseconds.
+    pub fn from_systemtime_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 16 {
+            return Err(Error::InvalidData(format!(
+                "SYSTEMTIME must be 16 bytes, got {}",
+                bytes.len()
+            )));
+        }
+
+        let field = |index: usize| LittleEndian::read_u16(&bytes[index * 2..index * 2 + 2]) as u32;
+        let date = NaiveDate::from_ymd_opt(field(0) as i32, field(1), field(3));
+        let time = NaiveTime::from_hms_milli_opt(field(4), field(5), field(6), field(7));
+
+        match (date, time) {
+            (Some(date), Some(time)) => Ok(Timestamp(NaiveDateTime::new(date, time).and_utc())),
+            _ => Err(Error::InvalidData(format!("invalid SYSTEMTIME {bytes:02x?}"))),
+        }
+    }
+
+    /// Decodes an MS-DOS date and time, as used by FAT file systems and ZIP archives.
+    ///
+    /// DOS times have no time zone and a resolution of two seconds. They are
+    /// taken to be in UTC.
+    ///
+    /// # Arguments
+    ///
+    /// * `date` - The date: bits 9-15 are the year since 1980, bits 5-8 the month and bits 0-4 the day.
+    /// * `time` - The time: bits 11-15 are the hour, bits 5-10 the minute and bits 0-4 the seconds divided by two.
+    pub fn from_dos(date: u16, time: u16) -> Result<Self> {
+        let (date, time) = (date as u32, time as u32);
+        let naive_date = NaiveDate::from_ymd_opt(1980 + (date >> 9) as i32, (date >> 5) & 0xf, date & 0x1f);
+        let naive_time = NaiveTime::from_hms_opt(time >> 11, (time >> 5) & 0x3f, (time & 0x1f) * 2);
+
+        match (naive_date, naive_time) {
+            (Some(date), Some(time)) => Ok(Timestamp(NaiveDateTime::new(date, time).and_utc())),
+            _ => Err(Error::InvalidData(format!("invalid DOS date {date:#06x} and time {time:#06x}"))),
+        }
+    }
+
+    /// Decodes a Unix timestamp in seconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `seconds` - The number of seconds since 1970-01-01 UTC.
+    pub fn from_unix(seconds: i64) -> Result<Self> {
+        DateTime::from_timestamp(seconds, 0)
+            .map(Timestamp)
+            .ok_or_else(|| invalid("Unix timestamp", seconds))
+    }
+
+    /// Decodes a Unix timestamp in milliseconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `millis` - The number of milliseconds since 1970-01-01 UTC.
+    pub fn from_unix_millis(millis: i64) -> Result<Self> {
+        DateTime::from_timestamp_millis(millis)
+            .map(Timestamp)
+            .ok_or_else(|| invalid("Unix timestamp", millis))
+    }
+
+    /// Decodes an OLE automation date (`DATE`), as used by COM and WMI.
+    ///
+    /// An OLE date is the number of days since 1899-12-30. The fraction is
+    /// the time of day, also for negative dates, so `-1.25` is 1899-12-29 06:00.
+    ///
+    /// # Arguments
+    ///
+    /// * `days` - The OLE date.
+    pub fn from_ole(days: f64) -> Result<Self> {
+        if !days.is_finite() || !(OLE_MIN..OLE_MAX).contains(&days) {
+            return Err(invalid("OLE date", days));
+        }
+
+        let whole_days = days.trunc();
+        let millis = ((days - whole_days).abs() * 86_400_000.0).round() as i64;
+        let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .ok_or_else(|| invalid("OLE date", days))?;
+
+        epoch
+            .checked_add_signed(Duration::days(whole_days as i64))
+            .and_then(|time| time.checked_add_signed(Duration::milliseconds(millis)))
+            .map(|time| Timestamp(time.and_utc()))
+            .ok_or_else(|| invalid("OLE date", days))
+    }
+
+    /// Encodes the timestamp as a `FILETIME`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` containing the `FILETIME`.
+    /// * `Err(Error::InvalidData)` if the timestamp is before 1601 or after the largest `FILETIME`.
+    pub fn to_filetime(&self) -> Result<u64> {
+        let seconds = self.0.timestamp().checked_add(FILETIME_UNIX_OFFSET);
+        let ticks = seconds
+            .and_then(|seconds| u64::try_from(seconds).ok())
+            .and_then(|seconds| seconds.checked_mul(FILETIME_TICKS_PER_SECOND))
+            .and_then(|ticks| ticks.checked_add(self.0.timestamp_subsec_nanos() as u64 / 100));
+
+        ticks
+            .filter(|ticks| *ticks <= i64::MAX as u64)
+            .ok_or_else(|| invalid("FILETIME for", self))
+    }
+
+    /// Returns the timestamp as a UTC date and time.
+    pub fn to_utc(&self) -> DateTime<Utc> {
+        self.0
+    }
+
+    /// Renders the timestamp.
+    ///
+    /// # Arguments
+    ///
+    /// * `display` - The time zone and format to render the timestamp in.
+    pub fn render(&self, display: &TimeDisplay) -> String {
+        match display.format {
+            TimeFormat::Epoch => self.0.timestamp().to_string(),
+            TimeFormat::Iso8601 => match display.zone {
+                DisplayZone::Utc => self.0.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                DisplayZone::Local => self
+                    .0
+                    .with_timezone(&Local)
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, false),
+                DisplayZone::Fixed(offset) => self
+                    .0
+                    .with_timezone(&offset)
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, false),
+            },
+        }
+    }
+}
+
+impl From<DateTime<Utc>> for Timestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        Timestamp(time)
+    }
+}
+
+impl Serialize for Timestamp {
+    /// Serializes the timestamp as an RFC 3339 date and time in UTC.
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render(&TimeDisplay::default()))
+    }
+}
+
+fn invalid(what: &str, value: impl fmt::Display) -> Error {
+    Error::InvalidData(format!("{what} {value} is out of range"))
+}
+
+/// The time zone timestamps are rendered in.
+///
+/// # Variants
+/// - `Utc`: Coordinated Universal Time.
+/// - `Local`: The time zone of the machine Rustbelt runs on.
+/// - `Fixed`: A fixed offset from UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisplayZone {
+    #[default]
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for Timestamp {
+    type Err = Error;
+
+    /// Parses an RFC 3339 date and time, such as `2024-03-01T12:00:00Z`.
+    fn from_str(time: &str) -> Result<Self> {
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| Timestamp(time.with_timezone(&Utc)))
+            .map_err(|e| Error::InvalidData(format!("invalid timestamp {time}: {e}")))
+    }
+}
+
+impl FromStr for DisplayZone {
+    type Err = Error;
+
+    /// Parses `utc`, `local` or an offset such as `+02:00` or `-0530`.
+    fn from_str(zone: &str) -> Result<Self> {
+        match zone.to_lowercase().as_str() {
+            "utc" | "z" => Ok(DisplayZone::Utc),
+            "local" => Ok(DisplayZone::Local),
+            _ => {
+                let (sign, offset) = match zone.as_bytes().first() {
+                    Some(b'+') => (1, &zone[1..]),
+                    Some(b'-') => (-1, &zone[1..]),
+                    _ => return Err(Error::InvalidData(format!("unknown time zone {zone}"))),
+                };
+                let digits: String = offset.chars().filter(|c| *c != ':').collect();
+                // Only ASCII digits, so the split below falls between characters and signs are not parsed.
+                if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Err(Error::InvalidData(format!("invalid offset {zone}")));
+                }
+                let (hours, minutes) = match digits.len() {
+                    2 => (digits.as_str(), "00"),
+                    4 => digits.split_at(2),
+                    _ => return Err(Error::InvalidData(format!("invalid offset {zone}"))),
+                };
+
+                let hours: i32 = hours.parse().map_err(|_| Error::InvalidData(format!("invalid offset {zone}")))?;
+                let minutes: i32 = minutes.parse().map_err(|_| Error::InvalidData(format!("invalid offset {zone}")))?;
+                FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+                    .filter(|_| minutes < 60)
+                    .map(DisplayZone::Fixed)
+                    .ok_or_else(|| Error::InvalidData(format!("invalid offset {zone}")))
+            }
+        }
+    }
+}
+
+/// The format timestamps are rendered in.
+///
+/// # Variants
+/// - `Iso8601`: An ISO 8601 date and time with its UTC offset, for example `2024-03-01T12:00:00Z`.
+/// - `Epoch`: The number of seconds since 1970-01-01 UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeFormat {
+    #[default]
+    Iso8601,
+    Epoch,
+}
+
+impl FromStr for TimeFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.to_lowercase().as_str() {
+            "iso8601" | "iso" => Ok(TimeFormat::Iso8601),
+            "epoch" | "unix" => Ok(TimeFormat::Epoch),
+            _ => Err(Error::InvalidData(format!("unknown time format {format}"))),
+        }
+    }
+}
+
+/// How timestamps are rendered in command output.
+///
+/// # Fields
+/// - `zone`: The time zone to render timestamps in. Ignored for epoch output.
+/// - `format`: The format to render timestamps in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimeDisplay {
+    pub zone: DisplayZone,
+    pub format: TimeFormat,
+}
+
+/// Parses a duration such as `500ms`, `2s`, `5m` or `1h`. A number without a unit is in seconds.
+///
+/// # Arguments
+///
+/// * `text` - The duration.
+///
+/// # Returns
+///
+/// * `Ok(Duration)` if the text is a non-negative number with a known unit.
+/// * `Err(Error::InvalidData)` otherwise.
+pub fn parse_duration(text: &str) -> Result<std::time::Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let seconds_per_unit = match unit.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(Error::InvalidData(format!("unknown unit in duration {text}, use ms, s, m or h"))),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|number| std::time::Duration::try_from_secs_f64(number * seconds_per_unit).ok())
+        .ok_or_else(|| Error::InvalidData(format!("invalid duration {text}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iso(timestamp: Result<Timestamp>) -> String {
+        timestamp.expect("Failed to decode").to_string()
+    }
+
+    #[test]
+    fn test_decoders() {
+        assert_eq!(iso(Timestamp::from_filetime(116_444_736_000_000_000)), "1970-01-01T00:00:00Z");
+        assert_eq!(iso(Timestamp::from_filetime(0)), "1601-01-01T00:00:00Z");
+        assert_eq!(
+            iso(Timestamp::from_filetime_bytes(&[0x01, 0xa0, 0x87, 0xfc, 0xcf, 0x6b, 0xda, 0x01])),
+            "2024-03-01T12:00:00.000000100Z"
+        );
+        assert_eq!(
+            iso(Timestamp::from_systemtime_bytes(&[
+                0xe8, 0x07, 3, 0, 5, 0, 1, 0, 12, 0, 30, 0, 15, 0, 0xf4, 0x01
+            ])),
+            "2024-03-01T12:30:15.500Z"
+        );
+        assert_eq!(iso(Timestamp::from_dos(0x5861, 0x6000)), "2024-03-01T12:00:00Z");
+        assert_eq!(iso(Timestamp::from_unix(1_709_294_400)), "2024-03-01T12:00:00Z");
+        assert_eq!(iso(Timestamp::from_unix_millis(-1)), "1969-12-31T23:59:59.999Z");
+        assert_eq!(iso(Timestamp::from_ole(45_352.5)), "2024-03-01T12:00:00Z");
+        assert_eq!(iso(Timestamp::from_ole(-1.25)), "1899-12-29T06:00:00Z");
+
+        let timestamp = Timestamp::from_filetime(133_537_680_000_000_001).unwrap();
+        assert_eq!(timestamp.to_filetime().unwrap(), 133_537_680_000_000_001);
+        assert!(Timestamp::from_unix(-20_000_000_000).unwrap().to_filetime().is_err());
+    }
+
+    #[test]
+    fn test_decoders_reject_invalid_input() {
+        assert!(Timestamp::from_filetime(u64::MAX).is_err());
+        assert!(Timestamp::from_filetime_bytes(&[0; 4]).is_err());
+        assert!(Timestamp::from_systemtime_bytes(&[0; 16]).is_err());
+        assert!(Timestamp::from_systemtime_bytes(&[0; 8]).is_err());
+        assert!(Timestamp::from_dos(0x5840 | 30, 0).is_err());
+        assert!(Timestamp::from_dos(0x5861, 24 << 11).is_err());
+        assert!(Timestamp::from_unix(i64::MAX).is_err());
+        assert!(Timestamp::from_ole(f64::NAN).is_err());
+        assert!(Timestamp::from_ole(3_000_000.0).is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let timestamp = Timestamp::from_unix(1_709_294_400).unwrap();
+        let render = |zone: &str, format: &str| {
+            timestamp.render(&TimeDisplay {
+                zone: zone.parse().unwrap(),
+                format: format.parse().unwrap(),
+            })
+        };
+
+        assert_eq!(render("utc", "iso8601"), "2024-03-01T12:00:00Z");
+        assert_eq!(render("+02:00", "iso8601"), "2024-03-01T14:00:00+02:00");
+        assert_eq!(render("-0530", "iso8601"), "2024-03-01T06:30:00-05:30");
+        assert_eq!(render("+01:00", "epoch"), "1709294400");
+        assert!("+25:00".parse::<DisplayZone>().is_err());
+        assert!("mars".parse::<DisplayZone>().is_err());
+        assert!("+1é1".parse::<DisplayZone>().is_err());
+        assert!("-+1".parse::<DisplayZone>().is_err());
+        assert!("rfc2822".parse::<TimeFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        for invalid in ["", "s", "-1s", "2d", "1.2.3s"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+}
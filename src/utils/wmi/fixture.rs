@@ -0,0 +1,145 @@
+//! WMI backend serving instances from a JSON fixture.
+//!
+//! A fixture maps namespaces to classes, and classes to their instances:
+//!
+//! ```json
+//! {
+//!     "root\\SecurityCenter2": {
+//!         "AntiVirusProduct": [
+//!             { "displayName": "Windows Defender" }
+//!         ]
+//!     }
+//! }
+//! ```
+//!
+//! Only `SELECT <fields> FROM <class>` queries are supported.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use serde_json::Value as Json;
+
+use super::WmiBackend;
+use crate::{
+    commands::base::{Row, Value},
+    error::{Error, Result},
+};
+
+/// WMI backend serving instances from a JSON fixture.
+#[derive(Default)]
+pub struct FixtureWmi {
+    // Instances by lowercase namespace and lowercase class name.
+    classes: HashMap<(String, String), Vec<serde_json::Map<String, Json>>>,
+}
+
+impl FixtureWmi {
+    /// Creates a backend from the JSON text of a fixture.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - The fixture, in the format described in the module documentation.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(FixtureWmi)` if the fixture is valid.
+    /// * `Err(e)` if the fixture could not be parsed.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let document: Json = serde_json::from_str(json)
+            .map_err(|e| Error::InvalidData(format!("WMI fixture: {e}")))?;
+        let invalid = |what: &str| Error::InvalidData(format!("WMI fixture: {what}"));
+
+        let mut classes = HashMap::new();
+        for (namespace, namespace_classes) in document.as_object().ok_or_else(|| invalid("expected an object"))? {
+            let namespace_classes = namespace_classes
+                .as_object()
+                .ok_or_else(|| invalid(&format!("namespace {namespace} is not an object")))?;
+
+            for (class, instances) in namespace_classes {
+                let instances = instances
+                    .as_array()
+                    .ok_or_else(|| invalid(&format!("class {class} is not an array")))?
+                    .iter()
+                    .map(|instance| {
+                        instance
+                            .as_object()
+                            .cloned()
+                            .ok_or_else(|| invalid(&format!("instance of {class} is not an object")))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                classes.insert((namespace.to_lowercase(), class.to_lowercase()), instances);
+            }
+        }
+        Ok(FixtureWmi { classes })
+    }
+
+    /// Reads a fixture from a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the JSON fixture.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        FixtureWmi::from_json(&fs::read_to_string(path)?)
+    }
+}
+
+/// Extracts the class name from a `SELECT ... FROM <class>` query.
+fn query_class(query: &str) -> Result<String> {
+    let words: Vec<&str> = query.split_whitespace().collect();
+    let from = words
+        .iter()
+        .position(|word| word.eq_ignore_ascii_case("from"))
+        .filter(|_| words.first().is_some_and(|word| word.eq_ignore_ascii_case("select")));
+
+    match from.map(|from| &words[from + 1..]) {
+        Some([class]) => Ok(class.to_lowercase()),
+        _ => Err(Error::Unsupported(format!(
+            "WMI fixtures only support SELECT ... FROM <class>: {query}"
+        ))),
+    }
+}
+
+impl WmiBackend for FixtureWmi {
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        let key = (namespace.to_lowercase(), query_class(query)?);
+        let instances = self.classes.get(&key).map(Vec::as_slice).unwrap_or_default();
+
+        let rows = instances
+            .iter()
+            .map(|instance| {
+                fields
+                    .iter()
+                    .map(|field| {
+                        let value = instance.get(field).map(Value::from_json).unwrap_or(Value::Null);
+                        (field.clone(), value)
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_fixture() {
+        let wmi = FixtureWmi::from_json(
+            r#"{"root\\Test": {"Thing": [{"Name": "a", "Size": 1}, {"Name": "b"}]}}"#,
+        )
+        .unwrap();
+        let fields = vec!["Name".to_string(), "Size".to_string()];
+
+        let rows = wmi.query("ROOT\\test", "SELECT * FROM thing", &fields).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get("Size"), Some(&Value::Integer(1)));
+        assert_eq!(rows[1].get("Size"), Some(&Value::Null));
+
+        assert!(wmi.query("root\\Test", "SELECT * FROM Other", &fields).unwrap().is_empty());
+        assert!(matches!(
+            wmi.query("root\\Test", "SELECT * FROM Thing WHERE Name = 'a'", &fields),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+//! WMI backend for the local machine, using COM.
+
+use windows::{
+    core::*,
+    Win32::System::{Com::*, Variant::*, Wmi::*},
+};
+
+use super::WmiBackend;
+use crate::{
+    commands::base::{Row, Value},
+    error::{Error, Result},
+    runtime::limits::checkpoint,
+};
+
+/// How long to wait for the next instance before checking the limits of the running command, in milliseconds.
+const WAIT_SLICE_MS: i32 = 500;
+
+/// WMI backend that queries the local machine through COM.
+///
+/// # Fields
+/// - `username`: Optional username used to connect to the WMI server.
+/// - `password`: Optional password used to connect to the WMI server.
+#[derive(Default)]
+pub struct LiveWmi {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl LiveWmi {
+    /// Executes a WQL query and returns the raw enumerator.
+    pub fn exec_query(&self, namespace: &str, query: &str) -> Result<IEnumWbemClassObject> {
+        let username_bstr = self
+            .username
+            .as_ref()
+            .map(BSTR::from)
+            .unwrap_or_default();
+        let password_bstr = self
+            .password
+            .as_ref()
+            .map(BSTR::from)
+            .unwrap_or_default();
+
+        unsafe {
+            let locator: IWbemLocator = CoCreateInstance(&WbemLocator, None, CLSCTX_INPROC_SERVER)?;
+            let server = locator.ConnectServer(
+                &BSTR::from(namespace),
+                &username_bstr,
+                &password_bstr,
+                &BSTR::new(),
+                0,
+                &BSTR::new(),
+                None,
+            )?;
+
+            let enumerator = server.ExecQuery(
+                &BSTR::from("WQL"),
+                &BSTR::from(query),
+                WBEM_FLAG_FORWARD_ONLY | WBEM_FLAG_RETURN_IMMEDIATELY,
+                None,
+            )?;
+
+            Ok(enumerator)
+        }
+    }
+}
+
+impl WmiBackend for LiveWmi {
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        let results = self.exec_query(namespace, query)?;
+
+        // Instances that cannot be read are skipped rather than failing the whole query. A query
+        // that reaches a limit returns what it has, and the runtime reports the result as partial.
+        let mut rows = vec![];
+        for row in WbemIterator::from(&results, fields.to_vec()) {
+            match row {
+                Ok(row) => rows.push(row),
+                Err(Error::Limit(_)) => break,
+                Err(_) => {}
+            }
+        }
+        Ok(rows)
+    }
+}
+
+pub struct WbemIterator<'a> {
+    results: &'a IEnumWbemClassObject,
+    fields: Vec<String>
+}
+
+impl<'a> WbemIterator<'a> {
+    pub fn from(
+        enumerator: &'a IEnumWbemClassObject,
+        fields: Vec<String>
+    )-> WbemIterator<'a> {
+        WbemIterator { 
+            results: enumerator,
+            fields
+        }
+    }
+}
+
+/// Converts a `VARIANT` returned by WMI into a `Value`.
+///
+/// Types without a direct counterpart (arrays, objects, ...) are converted to their string form.
+pub fn variant_to_value(variant: &VARIANT) -> Value {
+    let converted = match variant.vt() {
+        VT_EMPTY | VT_NULL => Some(Value::Null),
+        VT_BOOL => bool::try_from(variant).ok().map(Value::Bool),
+        VT_BSTR => BSTR::try_from(variant).ok().map(|s| Value::String(s.to_string())),
+        VT_I1 | VT_I2 | VT_I4 | VT_I8 | VT_INT => i64::try_from(variant).ok().map(Value::Integer),
+        VT_UI1 | VT_UI2 | VT_UI4 | VT_UI8 | VT_UINT => {
+            u64::try_from(variant).ok().map(Value::Unsigned)
+        }
+        VT_R4 | VT_R8 => f64::try_from(variant).ok().map(Value::Float),
+        _ => None,
+    };
+    converted.unwrap_or_else(|| Value::String(variant.to_string()))
+}
+
+impl<'a> Iterator for WbemIterator<'a> {
+    // Each item is a Result containing the requested fields of one instance.
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut row = [None; 1];
+        let mut returned = 0;
+        
+        // Wait in slices rather than forever, so a query that hangs stops when its command reaches a limit.
+        loop {
+            let hr = unsafe { self.results.Next(WAIT_SLICE_MS, &mut row, &mut returned) };
+            if hr.0 != WBEM_S_TIMEDOUT.0 {
+                if let Err(e) = hr.ok() {
+                    return Some(Err(e.into()));
+                }
+                break;
+            }
+            if let Err(e) = checkpoint() {
+                return Some(Err(e));
+            }
+        }
+
+        let mut columns: Row = Row::new();
+
+        if let Some(instance) = row[0].as_ref() {
+            for field in &self.fields {
+                let mut value = VARIANT::default();
+
+                unsafe { 
+                    let res =  instance.Get(&HSTRING::from(field), 0, &mut value, None, None);
+                    // all columns must be Ok
+                    if let Some(e) = res.err() {
+                        return Some(Err(e.into()));
+                    } 
+                };
+                columns.insert(field.to_string(), variant_to_value(&value));
+            }
+            Some(Ok(columns))
+        } else {
+            None
+        }
+    }
+}
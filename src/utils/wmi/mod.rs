@@ -0,0 +1,41 @@
+//! Access to WMI through interchangeable backends.
+pub mod fixture;
+#[cfg(windows)]
+pub mod live;
+pub mod winrm;
+
+use crate::{
+    commands::base::Row,
+    error::{Error, Result},
+};
+
+/// Trait implemented by every WMI backend.
+pub trait WmiBackend {
+    /// Runs a WQL query and returns the requested fields of every instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `namespace` - The WMI namespace to query, for example `root\SecurityCenter2`.
+    /// * `query` - The WQL query to run.
+    /// * `fields` - The properties to retrieve from each returned instance.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Row>)` containing one row per instance.
+    /// * `Err(e)` if the query could not be executed.
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>>;
+}
+
+/// WMI backend for targets without WMI, such as offline images.
+///
+/// Every query fails with `Error::Unsupported`.
+#[derive(Default)]
+pub struct UnavailableWmi {}
+
+impl WmiBackend for UnavailableWmi {
+    fn query(&self, namespace: &str, _query: &str, _fields: &[String]) -> Result<Vec<Row>> {
+        Err(Error::Unsupported(format!(
+            "WMI namespace {namespace} is not available for this target"
+        )))
+    }
+}
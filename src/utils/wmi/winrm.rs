@@ -0,0 +1,533 @@
+//! WMI backend for remote machines over WS-Management (WinRM).
+//!
+//! Queries are sent as WS-Enumeration requests with a WQL filter to the
+//! WMI resource of the namespace, and the returned CIM instances are mapped
+//! to rows. WS-Management carries no type information for plain properties,
+//! so their values are strings. Missing and nil properties are null, repeated
+//! properties are lists and `cim:Datetime` properties are timestamps.
+//!
+//! With NTLM, every connection is authenticated once with empty requests,
+//! and over HTTP the messages are then sealed with the NTLM session, the
+//! way WinRM expects when unencrypted traffic is not allowed.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Mutex,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use byteorder::{ByteOrder, LittleEndian};
+use zeroize::Zeroizing;
+
+use super::WmiBackend;
+use crate::{
+    commands::base::{Row, Value},
+    error::{Error, Result},
+    runtime::limits::{checkpoint, timed_out, wait_timeout},
+    utils::{
+        auth::{
+            ntlm::{Credentials, NtlmAuthenticator, NtlmSession},
+            Authenticator,
+        },
+        http::{HttpConnection, Request, Response, Url},
+        registry::remote::split_host_port,
+        time::Timestamp,
+        xml::{self, escape, Element},
+    },
+};
+
+/// The default WinRM port for HTTP.
+pub const WINRM_HTTP_PORT: u16 = 5985;
+
+const NS_SOAP: &str = "http://www.w3.org/2003/05/soap-envelope";
+const NS_ADDRESSING: &str = "http://schemas.xmlsoap.org/ws/2004/08/addressing";
+const NS_WSMAN: &str = "http://schemas.dmtf.org/wbem/wsman/1/wsman.xsd";
+const NS_ENUMERATION: &str = "http://schemas.xmlsoap.org/ws/2004/09/enumeration";
+const ACTION_ENUMERATE: &str = "http://schemas.xmlsoap.org/ws/2004/09/enumeration/Enumerate";
+const ACTION_PULL: &str = "http://schemas.xmlsoap.org/ws/2004/09/enumeration/Pull";
+const ANONYMOUS: &str = "http://schemas.xmlsoap.org/ws/2004/08/addressing/role/anonymous";
+const DIALECT_WQL: &str = "http://schemas.microsoft.com/wbem/wsman/1/WQL";
+/// The prefix of the resource URIs of WMI classes.
+const WMI_RESOURCE: &str = "http://schemas.microsoft.com/wbem/wsman/1/wmi";
+
+/// The content type of SOAP messages.
+pub const SOAP_CONTENT_TYPE: &str = "application/soap+xml;charset=UTF-8";
+/// The protocol of WinRM messages sealed with an NTLM session.
+pub const ENCRYPTED_PROTOCOL: &str = "application/HTTP-SPNEGO-session-encrypted";
+/// The boundary between the parts of encrypted messages.
+const ENCRYPTED_BOUNDARY: &str = "Encrypted Boundary";
+const USER_AGENT: &str = "Rustbelt WinRM client";
+
+/// The most instances requested per round trip.
+const MAX_ELEMENTS: u32 = 1000;
+/// The timeout for connecting and for every read and write, unless the running command has less time left.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the backend authenticates to the WinRM service.
+///
+/// # Variants
+/// - `None`: No authentication.
+/// - `Basic`: HTTP basic authentication, which WinRM only accepts for local accounts.
+/// - `Ntlm`: NTLM through the `Negotiate` scheme. Over HTTP, messages are sealed with the NTLM session.
+#[derive(Clone)]
+pub enum WinRmAuth {
+    None,
+    Basic { username: String, password: Zeroizing<String> },
+    Ntlm(Credentials),
+}
+
+/// A connection to the endpoint, with the NTLM session that seals its messages, if any.
+struct Channel {
+    connection: HttpConnection,
+    session: Option<NtlmSession>,
+}
+
+/// WMI backend that runs queries on a remote machine through WinRM.
+pub struct WinRmWmi {
+    endpoint: Url,
+    auth: WinRmAuth,
+    accept_invalid_certs: bool,
+    channel: Mutex<Option<Channel>>,
+}
+
+/// Returns a random `uuid:` message identifier.
+fn message_id() -> String {
+    let random = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.finish()
+    };
+    let (high, low) = (random(1), random(2));
+    format!(
+        "uuid:{:08X}-{:04X}-4{:03X}-{:04X}-{:012X}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0x0fff,
+        (low >> 48) & 0x3fff | 0x8000,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// Seals a SOAP message into the multipart body of an encrypted WinRM message.
+///
+/// # Arguments
+///
+/// * `session` - The NTLM session of the sender.
+/// * `message` - The SOAP message.
+pub fn encrypt_message(session: &mut NtlmSession, message: &[u8]) -> Vec<u8> {
+    let mut sealed = message.to_vec();
+    let signature = session.seal(&mut sealed);
+
+    let mut body = format!(
+        concat!(
+            "--{boundary}\r\n",
+            "\tContent-Type: {protocol}\r\n",
+            "\tOriginalContent: type={soap};Length={length}\r\n",
+            "--{boundary}\r\n",
+            "\tContent-Type: application/octet-stream\r\n"
+        ),
+        boundary = ENCRYPTED_BOUNDARY,
+        protocol = ENCRYPTED_PROTOCOL,
+        soap = SOAP_CONTENT_TYPE,
+        length = message.len(),
+    )
+    .into_bytes();
+    body.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+    body.extend_from_slice(&signature);
+    body.extend_from_slice(&sealed);
+    body.extend_from_slice(format!("--{ENCRYPTED_BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+/// Unseals the SOAP message in the multipart body of an encrypted WinRM message.
+///
+/// # Arguments
+///
+/// * `session` - The NTLM session of the receiver.
+/// * `body` - The body of the encrypted message.
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` containing the SOAP message.
+/// * `Err(Error::Remote)` if the body is malformed or its signature is invalid.
+pub fn decrypt_message(session: &mut NtlmSession, body: &[u8]) -> Result<Vec<u8>> {
+    let invalid = || Error::Remote("malformed encrypted WinRM message".to_string());
+    let find = |needle: &[u8]| body.windows(needle.len()).position(|window| window == needle);
+
+    let length_start = find(b"Length=").ok_or_else(invalid)? + "Length=".len();
+    let length: usize = body[length_start..]
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .map(|byte| *byte as char)
+        .collect::<String>()
+        .parse()
+        .map_err(|_| invalid())?;
+
+    let marker = b"\tContent-Type: application/octet-stream\r\n";
+    let data = &body[find(marker).ok_or_else(invalid)? + marker.len()..];
+    let signature_length = data.get(..4).map(LittleEndian::read_u32).ok_or_else(invalid)? as usize;
+    let signature = data.get(4..4 + signature_length).ok_or_else(invalid)?;
+    let mut message = data
+        .get(4 + signature_length..4 + signature_length + length)
+        .ok_or_else(invalid)?
+        .to_vec();
+    session.unseal(&mut message, signature)?;
+    Ok(message)
+}
+
+impl WinRmWmi {
+    /// Creates a backend for a WinRM endpoint. No connection is made until the first query.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - The URL of the endpoint, for example `http://host:5985/wsman`.
+    /// * `auth` - How to authenticate to the endpoint.
+    pub fn new(endpoint: Url, auth: WinRmAuth) -> Self {
+        WinRmWmi {
+            endpoint,
+            auth,
+            accept_invalid_certs: false,
+            channel: Mutex::new(None),
+        }
+    }
+
+    /// Creates a backend for the default HTTP endpoint of a host, `http://<host>:5985/wsman`.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The name or address of the host. A port, as in `host:445`, is ignored.
+    /// * `auth` - How to authenticate to the endpoint.
+    pub fn for_host(host: &str, auth: WinRmAuth) -> Self {
+        let (host, _) = split_host_port(host, WINRM_HTTP_PORT);
+        let endpoint = Url {
+            https: false,
+            host: host.to_string(),
+            port: WINRM_HTTP_PORT,
+            path: "/wsman".to_string(),
+        };
+        WinRmWmi::new(endpoint, auth)
+    }
+
+    /// Sets whether to accept invalid server certificates on HTTPS endpoints, such as self-signed ones.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Returns the endpoint of this backend.
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
+    fn access_denied(&self) -> Error {
+        Error::Remote(format!("access denied by {}", self.endpoint))
+    }
+
+    /// Sends a SOAP envelope and returns the parsed response envelope.
+    fn post(&self, envelope: &str) -> Result<Element> {
+        let mut channel = self
+            .channel
+            .lock()
+            .map_err(|_| Error::Remote("WinRM connection poisoned".to_string()))?;
+
+        // The server may have closed a connection that was idle, so retry once on a new one.
+        let reused = channel.is_some();
+        let response = match self.send(&mut channel, envelope) {
+            Err(Error::Io(_) | Error::Remote(_)) if reused => {
+                *channel = None;
+                self.send(&mut channel, envelope)
+            }
+            result => result,
+        };
+        let (response, body) = response.inspect_err(|_| *channel = None)?;
+        drop(channel);
+
+        match response.status {
+            200 => xml::parse(&String::from_utf8_lossy(&body)),
+            401 => Err(self.access_denied()),
+            _ => Err(fault(&response, &body)),
+        }
+    }
+
+    /// Sends an envelope over the channel, opening it first if needed.
+    ///
+    /// # Returns
+    ///
+    /// The response and its body, unsealed if the channel is sealed.
+    fn send(&self, channel: &mut Option<Channel>, envelope: &str) -> Result<(Response, Vec<u8>)> {
+        if channel.is_none() {
+            *channel = Some(self.open()?);
+        }
+        let channel = channel.as_mut().expect("channel was just opened");
+        // A server that stops responding must not outlast the deadline of the running command.
+        let timeout = wait_timeout(TIMEOUT);
+        channel.connection.set_timeout(timeout)?;
+
+        let mut request = Request::new("POST", &self.endpoint.path).header("User-Agent", USER_AGENT);
+        request = match &mut channel.session {
+            Some(session) => request
+                .header(
+                    "Content-Type",
+                    &format!(r#"multipart/encrypted;protocol="{ENCRYPTED_PROTOCOL}";boundary="{ENCRYPTED_BOUNDARY}""#),
+                )
+                .body(encrypt_message(session, envelope.as_bytes())),
+            None => request
+                .header("Content-Type", SOAP_CONTENT_TYPE)
+                .body(envelope.as_bytes().to_vec()),
+        };
+        if let WinRmAuth::Basic { username, password } = &self.auth {
+            let token = Zeroizing::new(STANDARD.encode(format!("{username}:{}", password.as_str())));
+            request = request.header("Authorization", &format!("Basic {}", token.as_str()));
+        }
+
+        let response = channel.connection.send(&request).map_err(|e| timed_out(e, timeout))?;
+        let encrypted = response
+            .get_header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("multipart/encrypted"));
+        let body = match (&mut channel.session, encrypted) {
+            (Some(session), true) => decrypt_message(session, &response.body)?,
+            _ => response.body.clone(),
+        };
+        Ok((response, body))
+    }
+
+    /// Opens a connection to the endpoint and authenticates it if the backend uses NTLM.
+    fn open(&self) -> Result<Channel> {
+        let timeout = wait_timeout(TIMEOUT);
+        let mut connection = HttpConnection::connect(&self.endpoint, timeout, self.accept_invalid_certs)
+            .map_err(|e| timed_out(e, timeout))?;
+        let WinRmAuth::Ntlm(credentials) = &self.auth else {
+            return Ok(Channel { connection, session: None });
+        };
+
+        // NTLM authenticates the connection with empty requests. TLS already protects HTTPS.
+        let mut auth = NtlmAuthenticator::new(credentials.clone()).with_sealing(!self.endpoint.https);
+        let mut challenge: Option<Vec<u8>> = None;
+        loop {
+            let token = auth.next_token(challenge.as_deref())?;
+            let request = Request::new("POST", &self.endpoint.path)
+                .header("User-Agent", USER_AGENT)
+                .header("Authorization", &format!("Negotiate {}", STANDARD.encode(token)));
+            let response = connection.send(&request).map_err(|e| timed_out(e, timeout))?;
+
+            match response.status {
+                200 if challenge.is_some() => break,
+                401 if challenge.is_none() => {
+                    let token = response
+                        .get_headers("WWW-Authenticate")
+                        .find_map(|value| value.strip_prefix("Negotiate "))
+                        .ok_or_else(|| self.access_denied())?;
+                    let token = STANDARD
+                        .decode(token.trim())
+                        .map_err(|e| Error::Remote(format!("invalid WinRM challenge: {e}")))?;
+                    challenge = Some(token);
+                }
+                401 => return Err(self.access_denied()),
+                _ => return Err(fault(&response, &response.body)),
+            }
+        }
+
+        let session = if self.endpoint.https { None } else { auth.take_session() };
+        Ok(Channel { connection, session })
+    }
+
+    /// Builds a SOAP envelope for an action on a resource.
+    fn envelope(&self, action: &str, resource: &str, body: &str) -> String {
+        format!(
+            concat!(
+                r#"<s:Envelope xmlns:s="{soap}" xmlns:a="{addressing}" xmlns:w="{wsman}" xmlns:n="{enumeration}">"#,
+                "<s:Header>",
+                "<a:To>{to}</a:To>",
+                r#"<w:ResourceURI s:mustUnderstand="true">{resource}</w:ResourceURI>"#,
+                r#"<a:ReplyTo><a:Address s:mustUnderstand="true">{anonymous}</a:Address></a:ReplyTo>"#,
+                r#"<a:Action s:mustUnderstand="true">{action}</a:Action>"#,
+                r#"<w:MaxEnvelopeSize s:mustUnderstand="true">512000</w:MaxEnvelopeSize>"#,
+                "<a:MessageID>{message_id}</a:MessageID>",
+                "<w:OperationTimeout>PT60S</w:OperationTimeout>",
+                "</s:Header>",
+                "<s:Body>{body}</s:Body>",
+                "</s:Envelope>"
+            ),
+            soap = NS_SOAP,
+            addressing = NS_ADDRESSING,
+            wsman = NS_WSMAN,
+            enumeration = NS_ENUMERATION,
+            to = escape(&self.endpoint.to_string()),
+            resource = escape(resource),
+            anonymous = ANONYMOUS,
+            action = action,
+            message_id = message_id(),
+            body = body,
+        )
+    }
+}
+
+/// Converts an error response into an error, using the message of the SOAP fault if there is one.
+fn fault(response: &Response, body: &[u8]) -> Error {
+    let message = xml::parse(&String::from_utf8_lossy(body))
+        .ok()
+        .and_then(|envelope| {
+            let fault = envelope.find("Fault")?;
+            fault
+                .find("Message")
+                .or_else(|| fault.find("Text"))
+                .map(|message| message.text().trim().to_string())
+        });
+    match message {
+        Some(message) => Error::Remote(format!("WinRM fault: {message}")),
+        None => Error::Remote(format!("WinRM returned HTTP {} {}", response.status, response.reason)),
+    }
+}
+
+/// Returns the resource URI of every class in a WMI namespace.
+fn resource_uri(namespace: &str) -> String {
+    let path = namespace.replace('\\', "/").trim_matches('/').to_lowercase();
+    format!("{WMI_RESOURCE}/{path}/*")
+}
+
+/// Converts a property element into a value.
+fn property_value(property: &Element) -> Value {
+    if property.attribute("nil") == Some("true") {
+        return Value::Null;
+    }
+    match property.elements().next() {
+        Some(child) if child.local_name() == "Datetime" => {
+            let text = child.text();
+            match text.trim().parse::<Timestamp>() {
+                Ok(timestamp) => Value::from(timestamp),
+                Err(_) => Value::from(text),
+            }
+        }
+        _ => Value::from(property.text()),
+    }
+}
+
+/// Maps a CIM instance to a row with the requested fields, or all properties if none are requested.
+fn instance_row(instance: &Element, fields: &[String]) -> Row {
+    let names: Vec<String> = if fields.is_empty() {
+        let mut names: Vec<String> = vec![];
+        for property in instance.elements() {
+            if !names.iter().any(|name| name == property.local_name()) {
+                names.push(property.local_name().to_string());
+            }
+        }
+        names
+    } else {
+        fields.to_vec()
+    };
+
+    names
+        .into_iter()
+        .map(|name| {
+            let mut values: Vec<Value> = instance
+                .elements()
+                .filter(|property| property.local_name().eq_ignore_ascii_case(&name))
+                .map(property_value)
+                .collect();
+            let value = match values.len() {
+                0 => Value::Null,
+                1 => values.remove(0),
+                _ => Value::List(values),
+            };
+            (name, value)
+        })
+        .collect()
+}
+
+/// Returns the items, the enumeration context and whether the sequence has ended from an enumeration response.
+fn enumeration_page(envelope: &Element, fields: &[String]) -> Result<(Vec<Row>, Option<String>, bool)> {
+    let body = envelope
+        .child("Body")
+        .ok_or_else(|| Error::Remote("WinRM response without a body".to_string()))?;
+    let response = body
+        .elements()
+        .next()
+        .ok_or_else(|| Error::Remote("empty WinRM response".to_string()))?;
+
+    let rows = match response.child("Items") {
+        Some(items) => items.elements().map(|instance| instance_row(instance, fields)).collect(),
+        None => vec![],
+    };
+    let context = response
+        .child("EnumerationContext")
+        .map(|context| context.text().trim().to_string())
+        .filter(|context| !context.is_empty());
+    let ended = response.child("EndOfSequence").is_some();
+    Ok((rows, context, ended))
+}
+
+impl WmiBackend for WinRmWmi {
+    fn query(&self, namespace: &str, query: &str, fields: &[String]) -> Result<Vec<Row>> {
+        let resource = resource_uri(namespace);
+        let enumerate = format!(
+            concat!(
+                "<n:Enumerate>",
+                "<w:OptimizeEnumeration/>",
+                "<w:MaxElements>{max}</w:MaxElements>",
+                r#"<w:Filter Dialect="{dialect}">{query}</w:Filter>"#,
+                "</n:Enumerate>"
+            ),
+            max = MAX_ELEMENTS,
+            dialect = DIALECT_WQL,
+            query = escape(query),
+        );
+
+        let envelope = self.post(&self.envelope(ACTION_ENUMERATE, &resource, &enumerate))?;
+        let (mut rows, mut context, mut ended) = enumeration_page(&envelope, fields)?;
+
+        // Large enumerations take many pulls, so the deadline of the running command is checked after every one.
+        while !ended {
+            checkpoint()?;
+            let Some(current) = context.take() else {
+                return Err(Error::Remote("WinRM enumeration without a context".to_string()));
+            };
+            let pull = format!(
+                "<n:Pull><n:EnumerationContext>{}</n:EnumerationContext><n:MaxElements>{MAX_ELEMENTS}</n:MaxElements></n:Pull>",
+                escape(&current)
+            );
+            let envelope = self.post(&self.envelope(ACTION_PULL, &resource, &pull))?;
+            let (page, next, page_ended) = enumeration_page(&envelope, fields)?;
+            rows.extend(page);
+            context = next;
+            ended = page_ended;
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_row() {
+        let instance = xml::parse(concat!(
+            r#"<p:Thing xmlns:p="urn:p" xmlns:xsi="urn:xsi" xmlns:cim="urn:cim">"#,
+            "<p:Name>a &amp; b</p:Name>",
+            r#"<p:Empty xsi:nil="true"/>"#,
+            "<p:Tag>x</p:Tag><p:Tag>y</p:Tag>",
+            "<p:Installed><cim:Datetime>2024-03-01T12:00:00Z</cim:Datetime></p:Installed>",
+            "</p:Thing>"
+        ))
+        .unwrap();
+        let fields: Vec<String> = ["Name", "Empty", "Tag", "Installed", "Missing"]
+            .iter()
+            .map(|field| field.to_string())
+            .collect();
+
+        let row = instance_row(&instance, &fields);
+        assert_eq!(row["Name"], Value::from("a & b"));
+        assert_eq!(row["Empty"], Value::Null);
+        assert_eq!(row["Tag"], Value::List(vec![Value::from("x"), Value::from("y")]));
+        assert_eq!(row["Installed"].to_string(), "2024-03-01T12:00:00Z");
+        assert_eq!(row["Missing"], Value::Null);
+        assert_eq!(instance_row(&instance, &[]).len(), 4);
+
+        assert_eq!(
+            resource_uri("root\\SecurityCenter2"),
+            "http://schemas.microsoft.com/wbem/wsman/1/wmi/root/securitycenter2/*"
+        );
+    }
+}
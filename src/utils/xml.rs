@@ -0,0 +1,297 @@
+//! A small XML reader for the documents of network protocols, such as SOAP envelopes.
+//!
+//! The reader builds a tree of elements and text. It does not validate the
+//! document, and it ignores declarations, comments, processing instructions
+//! and document types. Element and attribute names keep their prefix, which
+//! `Element::local_name` strips, because the protocols Rustbelt speaks use
+//! fixed, well-known namespaces.
+
+use crate::error::{Error, Result};
+
+/// A node of an XML document.
+///
+/// # Variants
+/// - `Element`: A child element.
+/// - `Text`: Character data, with references resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+/// An XML element.
+///
+/// # Fields
+/// - `name`: The qualified name of the element, for example `s:Envelope`.
+/// - `attributes`: The attributes by qualified name, in document order.
+/// - `children`: The child nodes, in document order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+/// Strips the namespace prefix of a qualified name.
+fn local(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+impl Element {
+    /// Returns the name of the element without its namespace prefix.
+    pub fn local_name(&self) -> &str {
+        local(&self.name)
+    }
+
+    /// Returns the value of an attribute, matched by its local name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| local(key) == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the child elements.
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// Returns the first child element with the given local name.
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.local_name() == name)
+    }
+
+    /// Returns the first element with the given local name in the tree below this element.
+    pub fn find(&self, name: &str) -> Option<&Element> {
+        self.elements()
+            .find_map(|element| (element.local_name() == name).then_some(element).or_else(|| element.find(name)))
+    }
+
+    /// Returns the concatenated text of the element and its descendants.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            match node {
+                Node::Text(value) => text.push_str(value),
+                Node::Element(element) => text.push_str(&element.text()),
+            }
+        }
+        text
+    }
+}
+
+/// Escapes text for use in element content or attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn invalid(what: &str) -> Error {
+    Error::InvalidData(format!("XML: {what}"))
+}
+
+/// Resolves the entity and character references in text.
+fn unescape(text: &str) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or_else(|| invalid("unterminated reference"))? + start;
+        let reference = &rest[start + 1..end];
+        let c = match reference {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = if let Some(hex) = reference.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else if let Some(decimal) = reference.strip_prefix('#') {
+                    decimal.parse().ok()
+                } else {
+                    None
+                };
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| invalid(&format!("unknown reference &{reference};")))?
+            }
+        };
+        result.push(c);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parses an XML document and returns its root element.
+///
+/// # Arguments
+///
+/// * `document` - The text of the document.
+///
+/// # Returns
+///
+/// * `Ok(Element)` containing the root element.
+/// * `Err(Error::InvalidData)` if the document is not well-formed.
+pub fn parse(document: &str) -> Result<Element> {
+    let mut stack: Vec<Element> = vec![];
+    let mut root: Option<Element> = None;
+    let mut rest = document;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            if !rest.trim().is_empty() {
+                return Err(invalid("text outside of the root element"));
+            }
+            break;
+        };
+
+        let text = &rest[..start];
+        if let Some(parent) = stack.last_mut() {
+            if !text.is_empty() {
+                parent.children.push(Node::Text(unescape(text)?));
+            }
+        } else if !text.trim().is_empty() {
+            return Err(invalid("text outside of the root element"));
+        }
+        rest = &rest[start..];
+
+        // Markup that does not contribute to the tree.
+        let skipped = [("<?", "?>"), ("<!--", "-->"), ("<!DOCTYPE", ">")]
+            .iter()
+            .find(|(open, _)| rest.starts_with(open));
+        if let Some((open, close)) = skipped {
+            let end = rest[open.len()..].find(close).ok_or_else(|| invalid("unterminated markup"))?;
+            rest = &rest[open.len() + end + close.len()..];
+            continue;
+        }
+
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").ok_or_else(|| invalid("unterminated CDATA section"))?;
+            let parent = stack.last_mut().ok_or_else(|| invalid("CDATA outside of the root element"))?;
+            parent.children.push(Node::Text(cdata[..end].to_string()));
+            rest = &cdata[end + 3..];
+            continue;
+        }
+
+        let end = tag_end(rest).ok_or_else(|| invalid("unterminated tag"))?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().ok_or_else(|| invalid("unexpected end tag"))?;
+            if element.name != name.trim() {
+                return Err(invalid(&format!("</{}> does not close <{}>", name.trim(), element.name)));
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(Node::Element(element)),
+                None if root.is_none() => root = Some(element),
+                None => return Err(invalid("more than one root element")),
+            }
+            continue;
+        }
+
+        let (tag, empty) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let element = parse_tag(tag)?;
+        if empty {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(Node::Element(element)),
+                None if root.is_none() => root = Some(element),
+                None => return Err(invalid("more than one root element")),
+            }
+        } else {
+            stack.push(element);
+        }
+    }
+
+    if let Some(element) = stack.last() {
+        return Err(invalid(&format!("<{}> is not closed", element.name)));
+    }
+    root.ok_or_else(|| invalid("no root element"))
+}
+
+/// Returns the position of the `>` that ends the tag at the start of `text`, skipping quoted values.
+fn tag_end(text: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            ('>', None) => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses the name and attributes of a start tag.
+fn parse_tag(tag: &str) -> Result<Element> {
+    let tag = tag.trim();
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let name = &tag[..name_end];
+    if name.is_empty() {
+        return Err(invalid("empty tag name"));
+    }
+
+    let mut attributes = vec![];
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let equals = rest.find('=').ok_or_else(|| invalid(&format!("attribute without value in <{name}>")))?;
+        let key = rest[..equals].trim().to_string();
+        let value = rest[equals + 1..].trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| invalid(&format!("unquoted attribute in <{name}>")))?;
+        let end = value[1..].find(quote).ok_or_else(|| invalid("unterminated attribute value"))? + 1;
+        attributes.push((key, unescape(&value[1..end])?));
+        rest = value[end + 1..].trim_start();
+    }
+
+    Ok(Element {
+        name: name.to_string(),
+        attributes,
+        children: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let document = r#"<?xml version="1.0"?>
+<!-- comment -->
+<s:Envelope xmlns:s="urn:s" a='1 &amp; 2'>
+  <s:Body><p:Name>A &lt;b&gt; &#x43;<![CDATA[<d>]]></p:Name><p:Empty xsi:nil="true"/></s:Body>
+</s:Envelope>"#;
+        let root = parse(document).unwrap();
+        assert_eq!(root.local_name(), "Envelope");
+        assert_eq!(root.attribute("a"), Some("1 & 2"));
+        assert_eq!(root.find("Name").unwrap().text(), "A <b> C<d>");
+        assert_eq!(root.find("Empty").unwrap().attribute("nil"), Some("true"));
+        assert_eq!(escape("<a & 'b'>"), "&lt;a &amp; &apos;b&apos;&gt;");
+
+        assert!(parse("<a><b></a>").is_err());
+        assert!(parse("<a/><b/>").is_err());
+        assert!(parse("<a>&unknown;</a>").is_err());
+    }
+}
@@ -0,0 +1,593 @@
+//! Integration tests for the embedding API of the `rustbelt` library.
+
+mod common;
+
+use std::{path::Path, sync::Arc};
+
+use common::{runtime_with, MemoryRegistry, SharedRegistry};
+use rustbelt::{
+    command_names,
+    commands::base::{schema::catalog_json_schema, user_rows},
+    get_command,
+    runtime::{
+        audit::{plan, AccessEvent, AuditLog},
+        formatter::{
+            seatbelt_formatter::{SeatbeltFormatter, SeatbeltJsonFormatter},
+            siem_formatter::{EcsFormatter, OcsfFormatter},
+            simple_formatter::SimpleFormatter,
+            Formatter,
+        },
+        limits::{Limit, Limits},
+        manifest::{Discrepancy, ManifestRecorder},
+        query::{Filter, Query},
+        redact::Redactor,
+        seatbelt,
+        stats::{RunStats, Status, STATS_SOURCE},
+        watch::{Watcher, CHANGE_COLUMN},
+        writer::{bundle_writer::BundleWriter, Writer},
+    },
+    utils::{
+        bundle::{self, IdentityKey},
+        registry::{get_user_sids, hive_writer::HiveBuilder, offline::OfflineRegistry, RegistryHive, RegistryValue},
+        time::{DisplayZone, TimeDisplay, TimeFormat},
+    },
+    schemas, CommandResult, Error, Row, Runtime, Value,
+};
+
+#[test]
+fn test_command_lookup_by_name() {
+    let names = command_names();
+    assert!(names.iter().any(|name| name == "amsiproviders"));
+    assert!(names.iter().any(|name| name == "group:misc"));
+    assert!(get_command("amsiproviders").is_some());
+    assert!(get_command("does-not-exist").is_none());
+}
+
+#[test]
+fn test_execute_unknown_command() {
+    let runtime = runtime_with(MemoryRegistry::default());
+    let result = runtime.execute("does-not-exist", &[]);
+    assert!(matches!(result, Err(Error::NotFound(_))));
+}
+
+#[test]
+fn test_execute_with_custom_registry_backend() {
+    let provider = "{2781761E-28E0-4109-99FE-B9D127C57AFE}";
+    let registry = MemoryRegistry::default()
+        .with_value(
+            &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+            "",
+            RegistryValue::String("Windows Defender".to_string()),
+        )
+        .with_value(
+            &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+            "",
+            RegistryValue::String("C:\\mpoav.dll".to_string()),
+        );
+    let runtime = runtime_with(registry);
+
+    let result = runtime.execute("amsiproviders", &[]).expect("Command failed");
+    let CommandResult::Simple(dto) = result else {
+        panic!("amsiproviders should return a simple result");
+    };
+
+    assert_eq!(dto.source, "Amsi Providers");
+    assert_eq!(dto.data.len(), 1);
+    assert_eq!(
+        dto.data[0].get("AMSI Provider"),
+        Some(&Value::from("C:\\mpoav.dll"))
+    );
+}
+
+#[test]
+fn test_format_result() {
+    let registry = MemoryRegistry::default().with_value(
+        "SYSTEM\\ControlSet001\\Control\\Windows",
+        "ShutdownTime",
+        RegistryValue::Binary(vec![0x00, 0x80, 0x3e, 0xd5, 0xde, 0xb1, 0x9d, 0x01]),
+    );
+    let runtime = runtime_with(registry);
+
+    let result = runtime.execute("lastshutdown", &[]).expect("Command failed");
+    let output = SimpleFormatter::default().parse_result(&result);
+
+    assert!(output.starts_with("==[Last Shutdown]=="));
+    assert!(output.contains("Last Shutdown : 1970-01-01T00:00:00Z"));
+
+    let time = TimeDisplay {
+        zone: "+02:00".parse().unwrap(),
+        format: TimeFormat::Iso8601,
+    };
+    let output = SimpleFormatter::new(time).parse_result(&result);
+    assert!(output.contains("Last Shutdown : 1970-01-01T02:00:00+02:00"));
+
+    let time = TimeDisplay {
+        zone: DisplayZone::Utc,
+        format: TimeFormat::Epoch,
+    };
+    let output = SimpleFormatter::new(time).parse_result(&result);
+    assert!(output.contains("Last Shutdown : 0"));
+}
+
+#[test]
+fn test_offline_image() {
+    let root = std::env::temp_dir().join(format!("rustbelt-image-{}", std::process::id()));
+    let config = root.join("Windows").join("System32").join("config");
+    std::fs::create_dir_all(&config).unwrap();
+
+    let mut software = HiveBuilder::new();
+    software.add_key("Microsoft\\AMSI\\Providers\\{1}");
+    software.set_value(
+        "Classes\\CLSID\\{1}\\InprocServer32",
+        "",
+        RegistryValue::String("C:\\offline.dll".to_string()),
+    );
+    std::fs::write(config.join("SOFTWARE"), software.build()).unwrap();
+    std::fs::write(root.join("Windows").join("win.ini"), b"[fonts]").unwrap();
+
+    let runtime = Runtime::offline(&root).expect("Failed to open image");
+    let result = runtime.execute("amsiproviders", &[]);
+    let contents = runtime.files().read_file("C:\\WINDOWS\\WIN.INI");
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let CommandResult::Simple(dto) = result.expect("Command failed") else {
+        panic!("Expected a simple result");
+    };
+    assert_eq!(
+        dto.data[0].get("AMSI Provider"),
+        Some(&Value::from("C:\\offline.dll"))
+    );
+    assert_eq!(contents.expect("Failed to read file"), b"[fonts]");
+}
+
+#[test]
+fn test_offline_manifest() {
+    let root = std::env::temp_dir().join(format!("rustbelt-manifest-image-{}", std::process::id()));
+    let config = root.join("Windows").join("System32").join("config");
+    std::fs::create_dir_all(&config).unwrap();
+
+    let mut software = HiveBuilder::new();
+    software.add_key("Microsoft\\AMSI\\Providers");
+    std::fs::write(config.join("SOFTWARE"), software.build()).unwrap();
+    std::fs::write(config.join("SYSTEM"), HiveBuilder::new().build()).unwrap();
+    std::fs::write(root.join("Windows").join("win.ini"), b"[fonts]").unwrap();
+
+    let recorder = Arc::new(ManifestRecorder::new(&root));
+    let runtime = Runtime::offline(&root).unwrap().with_manifest(recorder.clone());
+    let args = ["rustbelt", "amsiproviders"].map(String::from);
+    runtime.execute("amsiproviders", &args).expect("Command failed");
+    runtime.files().read_file("C:\\Windows\\win.ini").unwrap();
+    let manifest = recorder.manifest();
+    let unchanged = manifest.verify(&root);
+    std::fs::write(root.join("Windows").join("win.ini"), b"[fonts]\n").unwrap();
+    let changed = manifest.verify(&root);
+    std::fs::remove_dir_all(&root).unwrap();
+
+    // The command only read the SOFTWARE hive, and the file was read outside of any command.
+    let software = Path::new("Windows").join("System32").join("config").join("SOFTWARE");
+    let win_ini = Path::new("Windows").join("win.ini");
+    assert_eq!(manifest.commands.len(), 1);
+    assert_eq!(manifest.commands[0].name, "amsiproviders");
+    assert_eq!(manifest.commands[0].artifacts, [software.as_path()]);
+    let paths: Vec<&Path> = manifest.artifacts.iter().map(|artifact| artifact.path.as_path()).collect();
+    assert_eq!(paths, [software.as_path(), win_ini.as_path()]);
+    assert!(unchanged.is_empty());
+    assert!(matches!(&changed[..], [Discrepancy::Changed { path, .. }] if *path == win_ini));
+}
+
+#[test]
+fn test_audit_log() {
+    let provider = "{2781761E-28E0-4109-99FE-B9D127C57AFE}";
+    let registry = MemoryRegistry::default()
+        .with_value(
+            &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+            "",
+            RegistryValue::String("Windows Defender".to_string()),
+        )
+        .with_value(
+            &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+            "",
+            RegistryValue::String("C:\\mpoav.dll".to_string()),
+        );
+    let log = Arc::new(AuditLog::new());
+    let runtime = runtime_with(registry).with_audit(log.clone());
+    runtime.execute("amsiproviders", &[]).expect("Command failed");
+    assert!(runtime.execute("antivirus", &[]).is_err());
+    let _ = runtime.registry().get_value_names(RegistryHive::LocalMachine, "SOFTWARE");
+
+    let events = log.events();
+    let (amsi, rest): (Vec<&AccessEvent>, Vec<&AccessEvent>) =
+        events.iter().partition(|event| event.command.as_deref() == Some("amsiproviders"));
+    assert_eq!(amsi.len(), 3);
+    assert_eq!(rest[0].command.as_deref(), Some("antivirus"));
+    assert_eq!((rest[0].resource, rest[0].path.as_str()), ("wmi", "root\\SecurityCenter2"));
+    assert!(rest[0].error.is_some());
+    assert_eq!((rest[1].command.as_deref(), rest[1].operation), (None, "values"));
+
+    // What the command accessed is covered by what it declares for --dry-run.
+    let CommandResult::Simple(planned) = plan(&["amsiproviders".to_string()]).unwrap() else {
+        panic!("plans are simple results");
+    };
+    let covers = |pattern: &str, path: &str| {
+        let (pattern, path): (Vec<&str>, Vec<&str>) = (pattern.split('\\').collect(), path.split('\\').collect());
+        pattern.len() == path.len()
+            && pattern.iter().zip(&path).all(|(pattern, part)| *pattern == "*" || pattern.eq_ignore_ascii_case(part))
+    };
+    for event in amsi {
+        assert!(
+            planned.data.iter().any(|row| covers(&row["Path"].to_string(), &event.path)),
+            "{} is not declared",
+            event.path
+        );
+    }
+}
+
+#[test]
+fn test_encrypted_output() {
+    let registry = MemoryRegistry::default().with_value(
+        "SYSTEM\\ControlSet001\\Control\\Windows",
+        "ShutdownTime",
+        RegistryValue::Binary(vec![0x00, 0x80, 0x3e, 0xd5, 0xde, 0xb1, 0x9d, 0x01]),
+    );
+    let runtime = runtime_with(registry);
+    let result = runtime.execute("lastshutdown", &[]).expect("Command failed");
+    let output = SimpleFormatter::default().parse_result(&result);
+
+    let identity = IdentityKey::generate().unwrap();
+    let mut writer = BundleWriter::new(vec![], &identity.recipient()).unwrap();
+    writer.write_line(output.clone()).unwrap();
+    writer.write_line(output.clone()).unwrap();
+    writer.finish().unwrap();
+    assert!(writer.write_line(output.clone()).is_err());
+
+    let encrypted = writer.into_inner().expect("the bundle is finished");
+    assert!(!String::from_utf8_lossy(&encrypted).contains("Last Shutdown"));
+    let decrypted = bundle::open(encrypted.as_slice(), &identity).unwrap();
+    assert_eq!(String::from_utf8(decrypted).unwrap(), format!("{output}\n{output}\n"));
+}
+
+#[test]
+fn test_output_schemas() {
+    let schemas = schemas();
+    for name in ["amsiproviders", "antivirus", "lastshutdown", "osinfo", "runmru"] {
+        assert!(schemas.iter().any(|(command, _)| command == name), "{name} has no schema");
+    }
+
+    // Columns come out in the declared order, whatever order the command inserted them in.
+    let registry = MemoryRegistry::default()
+        .with_value(
+            "SOFTWARE\\Microsoft\\Cryptography",
+            "MachineGuid",
+            RegistryValue::String("6b1a9c0e".to_string()),
+        )
+        .with_value(
+            "Software\\Microsoft\\Windows NT\\CurrentVersion",
+            "UBR",
+            RegistryValue::String("3342".to_string()),
+        )
+        .with_value(
+            "Software\\Microsoft\\Windows NT\\CurrentVersion",
+            "ProductName",
+            RegistryValue::String("Windows 10 Pro".to_string()),
+        );
+    let result = runtime_with(registry).execute("osinfo", &[]).expect("Command failed");
+    let columns: Vec<&String> = result.tables()[0].data[0].keys().collect();
+    assert_eq!(columns, ["ProductName", "UBR", "MachineGuid"]);
+
+    let document = catalog_json_schema(schemas.iter().map(|(name, schema)| (name.as_str(), *schema)));
+    let rows = &document["$defs"]["osinfo"]["properties"]["data"]["items"];
+    assert_eq!(rows["required"], serde_json::json!(["MachineGuid"]));
+    assert_eq!(rows["x-identity"], serde_json::json!(["MachineGuid"]));
+}
+
+#[test]
+fn test_redacted_output() {
+    let machine = |name: &str, guid: &str| {
+        MemoryRegistry::default()
+            .with_value("SOFTWARE\\Microsoft\\Cryptography", "MachineGuid", RegistryValue::String(guid.to_string()))
+            .with_value(
+                "SYSTEM\\CurrentControlSet\\Control\\ComputerName\\ComputerName",
+                "ComputerName",
+                RegistryValue::String(name.to_string()),
+            )
+    };
+
+    // Runtimes that share a redactor share its pseudonyms.
+    let redactor = Arc::new(Redactor::new());
+    let mut rows = vec![];
+    for (name, guid) in [("WS01", "6b1a9c0e"), ("DC01", "0f3e2d1c"), ("ws01", "6B1A9C0E")] {
+        let runtime = runtime_with(machine(name, guid)).with_redactor(redactor.clone());
+        let result = runtime.execute("osinfo", &[]).expect("Command failed");
+        rows.push(result.tables()[0].data[0].clone());
+    }
+    let column = |name: &str| -> Vec<&Value> { rows.iter().map(|row| &row[name]).collect() };
+    assert_eq!(column("COMPUTERNAME"), [&Value::from("host-1"), &Value::from("host-2"), &Value::from("host-1")]);
+    assert_eq!(column("MachineGuid"), [&Value::from("id-1"), &Value::from("id-2"), &Value::from("id-1")]);
+
+    let path = std::env::temp_dir().join(format!("rustbelt-redact-{}.json", std::process::id()));
+    redactor.write_mapping(&path).unwrap();
+    let mapping: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(mapping["hostname"], serde_json::json!({ "host-1": "WS01", "host-2": "DC01" }));
+    assert_eq!(mapping["identifier"]["id-2"], "0f3e2d1c");
+    std::fs::remove_file(path).unwrap();
+
+    // Without a redactor, nothing changes.
+    let result = runtime_with(machine("WS01", "6b1a9c0e")).execute("osinfo", &[]).unwrap();
+    assert_eq!(result.tables()[0].data[0]["COMPUTERNAME"], Value::from("WS01"));
+}
+
+#[test]
+fn test_redacted_user_rows() {
+    let registry = OfflineRegistry::from_reg_file(Path::new("tests/fixtures/runmru/registry.reg")).unwrap();
+    let runtime = Runtime::from_backends(
+        Box::new(registry),
+        Box::new(rustbelt::utils::wmi::UnavailableWmi::default()),
+        Box::new(rustbelt::utils::fs::LocalFileSystem::default()),
+    )
+    .with_redactor(Arc::new(Redactor::new()));
+    let result = runtime.execute("runmru", &[]).expect("Command failed");
+    let rows = &result.tables()[0].data;
+    let users: Vec<(&Value, &Value)> = rows.iter().map(|row| (&row["SID"], &row["Username"])).collect();
+    assert_eq!(users[0], (&Value::from("S-1-5-21-0-0-1-1001"), &Value::from("user-1")));
+    assert_eq!(users[3], (&Value::from("S-1-5-21-0-0-2-1105"), &Value::from("user-2")));
+}
+
+#[test]
+fn test_query_results() {
+    let mut registry = MemoryRegistry::default();
+    for (index, dll) in ["C:\\mpoav.dll", "C:\\Temp\\evil.dll", "D:\\other.dll"].iter().enumerate() {
+        let provider = format!("{{00000000-0000-0000-0000-{index:012}}}");
+        registry = registry
+            .with_value(
+                &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+                "",
+                RegistryValue::String(format!("Provider {index}")),
+            )
+            .with_value(
+                &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+                "",
+                RegistryValue::String(dll.to_string()),
+            );
+    }
+    let filter: Filter = "`AMSI Provider` matches '^c:\\\\' and not `amsi provider` contains 'mpoav'".parse().unwrap();
+    let runtime = runtime_with(registry).with_query(Query::new().filter(filter).select(["AMSI Provider"]));
+
+    let result = runtime.execute("amsiproviders", &[]).expect("Command failed");
+    let rows = &result.tables()[0].data;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].len(), 1);
+    assert_eq!(rows[0]["AMSI Provider"], Value::from("C:\\Temp\\evil.dll"));
+
+    assert!(matches!("Name ==".parse::<Filter>(), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn test_seatbelt_output() {
+    let registry = MemoryRegistry::default()
+        .with_value(
+            "SOFTWARE\\Microsoft\\AMSI\\Providers\\{2781761E-28E0-4109-99FE-B9D127C57AFE}",
+            "",
+            RegistryValue::String("Windows Defender IOfficeAntivirus".to_string()),
+        )
+        .with_value(
+            "SOFTWARE\\Classes\\CLSID\\{2781761E-28E0-4109-99FE-B9D127C57AFE}\\InprocServer32",
+            "",
+            RegistryValue::String("C:\\mpoav.dll".to_string()),
+        );
+    let result = runtime_with(registry).execute("amsiproviders", &[]).expect("Command failed");
+
+    let text = SeatbeltFormatter::default().parse_result(&result);
+    assert_eq!(text, "====== AMSIProviders ======\n\n  ProviderPath                   : C:\\mpoav.dll");
+
+    let json = SeatbeltJsonFormatter::default().parse_result(&result);
+    assert_eq!(json, r#"{"Type":"Seatbelt.Commands.Windows.AMSIProviderDTO","Data":{"ProviderPath":"C:\\mpoav.dll"}}"#);
+
+    // Seatbelt JSON output reads back into the Rustbelt table.
+    let imported = seatbelt::import(&json).unwrap();
+    assert_eq!(imported.tables()[0].source, "Amsi Providers");
+    assert_eq!(imported.tables()[0].data, [Row::from([("AMSI Provider".to_string(), Value::from("C:\\mpoav.dll"))])]);
+}
+
+#[test]
+fn test_siem_export() {
+    let registry = MemoryRegistry::default()
+        .with_value("SOFTWARE\\Microsoft\\Cryptography", "MachineGuid", RegistryValue::String("6b1a9c0e".to_string()))
+        .with_value(
+            "SYSTEM\\CurrentControlSet\\Control\\ComputerName\\ComputerName",
+            "ComputerName",
+            RegistryValue::String("WS01".to_string()),
+        )
+        .with_value(
+            "Software\\Microsoft\\Windows NT\\CurrentVersion",
+            "ProductName",
+            RegistryValue::String("Windows 10 Pro".to_string()),
+        )
+        .with_value("Software\\Microsoft\\Windows NT\\CurrentVersion", "UBR", RegistryValue::String("2965".to_string()));
+    let result = runtime_with(registry).execute("osinfo", &[]).expect("Command failed");
+
+    let ecs: serde_json::Value = serde_json::from_str(&EcsFormatter::default().parse_result(&result)).unwrap();
+    assert_eq!(ecs["event"]["dataset"], "rustbelt.osinfo");
+    assert_eq!(ecs["host"], serde_json::json!({ "name": "WS01", "id": "6b1a9c0e", "os": { "name": "Windows 10 Pro" } }));
+    assert_eq!(ecs["rustbelt"]["osinfo"], serde_json::json!({ "UBR": "2965" }));
+
+    let ocsf: serde_json::Value = serde_json::from_str(&OcsfFormatter::default().parse_result(&result)).unwrap();
+    assert_eq!(ocsf["class_uid"], 5001);
+    assert_eq!(ocsf["device"], serde_json::json!({ "hostname": "WS01", "uid": "6b1a9c0e", "os": { "name": "Windows 10 Pro" } }));
+    assert_eq!(ocsf["unmapped"]["rustbelt"]["osinfo"], serde_json::json!({ "UBR": "2965" }));
+}
+
+#[test]
+fn test_watch_changes() {
+    let provider = |registry: MemoryRegistry, id: &str, dll: &str| {
+        registry
+            .with_value(
+                &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{id}"),
+                "",
+                RegistryValue::String(id.to_string()),
+            )
+            .with_value(
+                &format!("SOFTWARE\\Classes\\CLSID\\{id}\\InprocServer32"),
+                "",
+                RegistryValue::String(dll.to_string()),
+            )
+    };
+    let registry = SharedRegistry::default();
+    registry.change(|registry| provider(provider(registry, "{1}", "C:\\mpoav.dll"), "{2}", "C:\\vendor.dll"));
+    let runtime = Runtime::from_backends(
+        Box::new(registry.clone()),
+        Box::new(rustbelt::utils::wmi::UnavailableWmi::default()),
+        Box::new(rustbelt::utils::fs::LocalFileSystem::default()),
+    );
+    let mut watcher = Watcher::new();
+    let mut pass = || {
+        let changes = watcher.diff(&runtime.execute("amsiproviders", &[]).expect("Command failed"));
+        let rows: Vec<(String, String)> = changes
+            .tables()
+            .iter()
+            .flat_map(|table| &table.data)
+            .map(|row| (row[CHANGE_COLUMN].to_string(), row["AMSI Provider"].to_string()))
+            .collect();
+        rows
+    };
+
+    assert_eq!(pass().len(), 2);
+    assert!(pass().is_empty());
+
+    // A provider is removed and another one registered while the runtime is kept.
+    registry.change(|mut registry| {
+        registry.remove_key("SOFTWARE\\Microsoft\\AMSI\\Providers\\{2}");
+        provider(registry, "{3}", "C:\\Users\\Public\\evil.dll")
+    });
+    assert_eq!(
+        pass(),
+        [
+            ("added".to_string(), "C:\\Users\\Public\\evil.dll".to_string()),
+            ("removed".to_string(), "C:\\vendor.dll".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_limits() {
+    /// Registry that takes its time for every value.
+    struct SlowRegistry(MemoryRegistry);
+
+    impl rustbelt::utils::registry::RegistryBackend for SlowRegistry {
+        fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> rustbelt::Result<Vec<String>> {
+            self.0.get_sub_key_names(hive, path)
+        }
+
+        fn get_value_names(&self, hive: RegistryHive, path: &str) -> rustbelt::Result<Vec<String>> {
+            self.0.get_value_names(hive, path)
+        }
+
+        fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> rustbelt::Result<RegistryValue> {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            self.0.get_raw_value(hive, path, name)
+        }
+    }
+
+    let providers = (0..10).fold(MemoryRegistry::default(), |registry, index| {
+        let provider = format!("{{00000000-0000-0000-0000-{index:012}}}");
+        registry
+            .with_value(&format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"), "", RegistryValue::String(provider.clone()))
+            .with_value(
+                &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+                "",
+                RegistryValue::String(format!("C:\\provider{index}.dll")),
+            )
+    });
+
+    // A command that runs too long stops at the next access, and keeps the rows it has.
+    let runtime = runtime_with(MemoryRegistry::default())
+        .with_registry(Box::new(SlowRegistry(providers)))
+        .with_limits(Limits::new().timeout(std::time::Duration::from_millis(70)));
+    let Err(Error::Partial(partial)) = runtime.execute("amsiproviders", &[]) else {
+        panic!("the command timed out");
+    };
+    let rows = partial.result.tables()[0].data.len();
+    assert!(rows > 0 && rows < 10, "{rows} rows");
+    assert_eq!(partial.stopped, [("amsiproviders".to_string(), Limit::Timeout(std::time::Duration::from_millis(70)))]);
+
+    // Caps drop the rows past them.
+    let runtime = runtime.with_limits(Limits::new().max_rows(2));
+    let Err(Error::Partial(partial)) = runtime.execute("amsiproviders", &[]) else {
+        panic!("the cap was reached");
+    };
+    assert_eq!(partial.result.tables()[0].data.len(), 2);
+    assert_eq!(partial.stopped[0].1, Limit::Rows(2));
+
+    runtime.cancel_handle().cancel();
+    assert!(matches!(runtime.execute("amsiproviders", &[]), Err(Error::Limit(Limit::Cancelled))));
+}
+
+#[test]
+fn test_run_stats() {
+    let stats = Arc::new(RunStats::new());
+    let registry = MemoryRegistry::default()
+        .with_value("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}", "", RegistryValue::String("{provider}".into()))
+        .with_value(
+            "SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32",
+            "",
+            RegistryValue::String("C:\\provider.dll".into()),
+        );
+    let runtime = runtime_with(registry).with_stats(stats.clone());
+
+    // Members of groups are recorded one by one, and failures with their error.
+    assert!(runtime.execute("group:misc", &[]).is_err());
+    runtime.execute("amsiproviders", &[]).unwrap();
+    let commands = stats.commands();
+    let summary: Vec<(&str, Status, usize)> =
+        commands.iter().map(|stats| (stats.command.as_str(), stats.status, stats.rows)).collect();
+    assert_eq!(summary, [("antivirus", Status::Failed, 0), ("amsiproviders", Status::Ok, 1)]);
+    assert!(commands[0].error.is_some());
+    assert!(commands[1].bytes > 0 && commands[1].error.is_none());
+
+    let result = stats.to_result();
+    assert_eq!(result.tables()[0].source, STATS_SOURCE);
+    assert_eq!(result.tables()[0].data[1]["Rows"], Value::from(1u64));
+}
+
+#[test]
+fn test_user_hives() {
+    let root = std::env::temp_dir().join(format!("rustbelt-users-{}", std::process::id()));
+    let config = root.join("Windows").join("System32").join("config");
+    let classes = root.join("Users").join("alice").join("AppData").join("Local").join("Microsoft").join("Windows");
+    std::fs::create_dir_all(&config).unwrap();
+    std::fs::create_dir_all(&classes).unwrap();
+
+    let profiles = "Microsoft\\Windows NT\\CurrentVersion\\ProfileList";
+    let mut software = HiveBuilder::new();
+    for (sid, path) in [("S-1-5-21-1-2-3-1001", "%SystemDrive%\\Users\\alice"), ("S-1-5-21-1-2-3-1002", "C:\\Users\\bob")] {
+        software.set_value(&format!("{profiles}\\{sid}"), "ProfileImagePath", RegistryValue::ExpandString(path.to_string()));
+    }
+    std::fs::write(config.join("SOFTWARE"), software.build()).unwrap();
+    let mut user = HiveBuilder::new();
+    user.set_value("Environment", "TEMP", RegistryValue::ExpandString("%USERPROFILE%\\Temp".to_string()));
+    std::fs::write(root.join("Users").join("alice").join("ntuser.dat"), user.build()).unwrap();
+    let mut user_classes = HiveBuilder::new();
+    user_classes.add_key("CLSID\\{1}");
+    std::fs::write(classes.join("UsrClass.dat"), user_classes.build()).unwrap();
+
+    let runtime = Runtime::offline(&root).expect("Failed to open image");
+    let sids = get_user_sids(runtime.registry());
+    let mut hives = runtime.registry().get_sub_key_names(RegistryHive::Users, "").unwrap();
+    let clsids = runtime.registry().get_sub_key_names(RegistryHive::Users, "S-1-5-21-1-2-3-1001_Classes\\CLSID");
+    // Bob has no hive in the image, so he has no rows.
+    let rows = user_rows(&runtime, |profile| {
+        let temp = runtime.registry().get_string_value(RegistryHive::Users, &profile.key_path("Environment"), "TEMP")?;
+        Ok(vec![Row::from([("TEMP".to_string(), Value::from(temp))])])
+    });
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(sids.unwrap(), ["S-1-5-21-1-2-3-1001", "S-1-5-21-1-2-3-1002"]);
+    hives.sort();
+    assert_eq!(hives, ["S-1-5-21-1-2-3-1001", "S-1-5-21-1-2-3-1001_Classes"]);
+    assert_eq!(clsids.unwrap(), ["{1}"]);
+    let rows = rows.unwrap();
+    assert_eq!(rows.len(), 1);
+    let columns: Vec<&String> = rows[0].keys().collect();
+    assert_eq!(columns, ["SID", "Username", "TEMP"]);
+    assert_eq!(rows[0]["Username"], Value::from("alice"));
+    assert_eq!(rows[0]["TEMP"], Value::from("%USERPROFILE%\\Temp"));
+}
@@ -0,0 +1,173 @@
+//! Integration tests for the command line: configuration files, profiles, `config show`, `--watch`, `--view` and the
+//! summary of `--targets`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use rustbelt::utils::registry::{hive_writer::HiveBuilder, RegistryValue};
+
+const CONFIG: &str = r#"
+[defaults]
+timezone = "local"
+delay = "1s"
+
+[profiles.triage]
+format = "ndjson"
+output = "triage.ndjson"
+offline = "image"
+watch = "30s"
+redact = true
+commands = ["osinfo"]
+"#;
+
+/// Writes the configuration to a directory of its own, which also stands in for the configuration directory of the user.
+fn config_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustbelt-cli-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("config.toml"), CONFIG).unwrap();
+    dir
+}
+
+/// Runs `rustbelt config show` with the configuration and profile, and returns what it printed.
+fn show(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustbelt"))
+        .env("XDG_CONFIG_HOME", dir)
+        .env("APPDATA", dir)
+        .args(["--config".as_ref(), dir.join("config.toml").as_os_str()])
+        .args(args)
+        .args(["config", "show"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_config_show() {
+    let dir = config_dir("show");
+    let output = show(&dir, &["--profile", "triage"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(lines[0].starts_with("# Configuration: ") && lines[0].ends_with("config.toml"));
+    assert_eq!(lines[1], "# Profile: triage");
+    for line in [
+        "offline = \"image\"",
+        "delay = \"1s\"",
+        "watch = \"30s\"",
+        "format = \"ndjson\"",
+        "output = \"triage.ndjson\"",
+        "redact = true",
+        "timezone = \"local\"",
+        "commands = [\"osinfo\"]",
+    ] {
+        assert!(lines.contains(&line), "{line} missing from\n{output}");
+    }
+}
+
+#[test]
+fn test_command_line_over_profile() {
+    let dir = config_dir("precedence");
+    let output = show(
+        &dir,
+        &[
+            "--profile", "triage", "--format", "json", "--collector", "https://collector/upload", "--targets",
+            "targets.txt", "--redact=false", "--timezone", "+02:00",
+        ],
+    );
+    fs::remove_dir_all(&dir).unwrap();
+
+    // The command line wins, and the options of the profile that conflict with it are left out.
+    for line in ["format = \"json\"", "collector = \"https://collector/upload\"", "targets = \"targets.txt\"", "timezone = \"+02:00\""] {
+        assert!(output.lines().any(|output| output == line), "{line} missing from\n{output}");
+    }
+    for option in ["output", "offline", "watch", "redact"] {
+        assert!(!output.lines().any(|line| line.starts_with(&format!("{option} ="))), "{option} in\n{output}");
+    }
+    assert!(output.contains("delay = \"1s\""));
+}
+
+#[test]
+fn test_watch_partial_pass() {
+    let root = std::env::temp_dir().join(format!("rustbelt-cli-watch-{}", std::process::id()));
+    let config = root.join("Windows").join("System32").join("config");
+    fs::create_dir_all(&config).unwrap();
+    let mut software = HiveBuilder::new();
+    for provider in ["{1}", "{2}"] {
+        software.add_key(&format!("Microsoft\\AMSI\\Providers\\{provider}"));
+        let dll = RegistryValue::String(format!("C:\\{provider}.dll"));
+        software.set_value(&format!("Classes\\CLSID\\{provider}\\InprocServer32"), "", dll);
+    }
+    fs::write(config.join("SOFTWARE"), software.build()).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rustbelt"))
+        .args(["--offline".as_ref(), root.as_os_str()])
+        .args(["--watch", "10ms", "--watch-count", "2", "--max-rows", "1", "--format", "ndjson", "amsiproviders"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    // Both passes are partial. The first adds the row it kept, and the second has the same row, so nothing changed.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert_eq!(stderr.matches("is partial").count(), 2, "{stderr}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1, "{stdout}");
+    assert!(stdout.contains(r#""Change":"added""#), "{stdout}");
+    assert!(stdout.contains(r#""Partial":true"#), "{stdout}");
+}
+
+#[test]
+fn test_registry_view() {
+    let root = std::env::temp_dir().join(format!("rustbelt-cli-view-{}", std::process::id()));
+    let config = root.join("Windows").join("System32").join("config");
+    fs::create_dir_all(&config).unwrap();
+    // A provider of 64-bit programs, and one of 32-bit programs in the keys they are redirected to.
+    let mut software = HiveBuilder::new();
+    for (provider, wow64) in [("{1}", ""), ("{2}", "WOW6432Node\\")] {
+        software.add_key(&format!("{wow64}Microsoft\\AMSI\\Providers\\{provider}"));
+        let dll = RegistryValue::String(format!("C:\\{provider}.dll"));
+        software.set_value(&format!("Classes\\{wow64}CLSID\\{provider}\\InprocServer32"), "", dll);
+    }
+    fs::write(config.join("SOFTWARE"), software.build()).unwrap();
+
+    let providers = |view: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rustbelt"))
+            .args(["--offline".as_ref(), root.as_os_str()])
+            .args(view)
+            .args(["--format", "ndjson", "amsiproviders"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let native = providers(&[]);
+    let wow64 = providers(&["--view", "x86"]);
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(native.contains("{1}.dll") && !native.contains("{2}.dll"), "{native}");
+    assert!(wow64.contains("{2}.dll") && !wow64.contains("{1}.dll"), "{wow64}");
+}
+
+#[test]
+fn test_redacted_summary() {
+    let dir = std::env::temp_dir().join(format!("rustbelt-cli-summary-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Bind and drop a listener to get a port nothing listens on, so the target is unreachable.
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    fs::write(dir.join("targets.txt"), format!("{address}\n")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rustbelt"))
+        .args(["--targets".as_ref(), dir.join("targets.txt").as_os_str()])
+        .args(["--redact", "osinfo"])
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unreachable targets:\n\thost-1 : "), "{stderr}");
+    assert!(!stderr.contains(&address.to_string()), "{stderr}");
+}
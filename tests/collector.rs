@@ -0,0 +1,179 @@
+//! Integration tests for uploading results to a collector.
+
+mod common;
+
+use std::{
+    fs,
+    io::BufReader,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use common::{runtime_with, MemoryRegistry};
+use rustbelt::{
+    runtime::{
+        formatter::{ndjson_formatter::NdjsonFormatter, Formatter},
+        writer::{http_writer::HttpWriter, Writer},
+    },
+    utils::{
+        bundle::{self, IdentityKey},
+        collector::Collector,
+        http::{read_request, write_response, Response},
+        registry::RegistryValue,
+    },
+};
+
+const TOKEN: (&str, &str) = ("Authorization", "Bearer s3cret");
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustbelt-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Returns the NDJSON output of a command with many rows.
+fn output() -> String {
+    let mut registry = MemoryRegistry::default();
+    for index in 0..20 {
+        let provider = format!("{{00000000-0000-0000-0000-{index:012}}}");
+        registry = registry
+            .with_value(
+                &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+                "",
+                RegistryValue::String(format!("Provider {index}")),
+            )
+            .with_value(
+                &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+                "",
+                RegistryValue::String(format!("C:\\provider{index}.dll")),
+            );
+    }
+    let result = runtime_with(registry).execute("amsiproviders", &[]).unwrap();
+    NdjsonFormatter::default().parse_result(&result)
+}
+
+/// Starts a collector that answers the first `failures` requests with `503 Service Unavailable`.
+///
+/// # Returns
+///
+/// The URL of the collector and the number of requests it received.
+fn spawn_collector(dir: &Path, failures: usize) -> (String, Arc<AtomicUsize>) {
+    let collector = Collector::new(dir).unwrap().require_header(TOKEN.0, TOKEN.1);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/upload", listener.local_addr().unwrap());
+    let requests = Arc::new(AtomicUsize::new(0));
+
+    let counter = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            while let Ok(Some(request)) = read_request(&mut reader) {
+                let response = match counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    true => Response::new(503, "Service Unavailable"),
+                    false => collector.handle(&request),
+                };
+                write_response(&mut writer, &response).unwrap();
+            }
+        }
+    });
+    (url, requests)
+}
+
+#[test]
+fn test_upload_in_chunks() {
+    let dir = temp_dir("collector-plain");
+    let (url, requests) = spawn_collector(&dir, 2);
+    let output = output();
+
+    let mut writer = HttpWriter::new(url.parse().unwrap())
+        .unwrap()
+        .header(TOKEN.0, TOKEN.1)
+        .chunk_size(500)
+        .retries(2, Duration::from_millis(10));
+    writer.write_line(output.clone()).unwrap();
+    writer.finish().unwrap();
+
+    // Two failures are retried, and the output arrives in order.
+    let chunks = output.len().div_ceil(500);
+    assert!(requests.load(Ordering::SeqCst) >= chunks + 2);
+    let stored = fs::read_to_string(dir.join(format!("{}.ndjson", writer.run()))).unwrap();
+    assert_eq!(stored, format!("{output}\n"));
+    assert_eq!(stored.lines().count(), 20);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_encrypted_upload() {
+    let dir = temp_dir("collector-encrypted");
+    let (url, _) = spawn_collector(&dir, 0);
+    let identity = IdentityKey::generate().unwrap();
+    let output = output();
+
+    let mut writer = HttpWriter::new(url.parse().unwrap())
+        .unwrap()
+        .header(TOKEN.0, TOKEN.1)
+        .chunk_size(1000)
+        .encrypt_to(identity.recipient());
+    writer.write_line(output.clone()).unwrap();
+    writer.finish().unwrap();
+
+    let mut bundles: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    bundles.sort();
+    assert!(bundles.len() > 1);
+    let mut decrypted = vec![];
+    for path in &bundles {
+        assert!(path.file_name().unwrap().to_string_lossy().starts_with(writer.run()));
+        decrypted.extend(bundle::open(fs::File::open(path).unwrap(), &identity).unwrap());
+    }
+    assert_eq!(String::from_utf8(decrypted).unwrap(), format!("{output}\n"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_spool_when_unreachable() {
+    let dir = temp_dir("collector-spool");
+    let spool = dir.join("spool");
+    fs::create_dir_all(&spool).unwrap();
+    let output = output();
+
+    // The collector rejects the upload without the token, which is not retried.
+    let (url, requests) = spawn_collector(&dir, 0);
+    let mut writer = HttpWriter::new(url.parse().unwrap()).unwrap().chunk_size(500).spool(&spool);
+    writer.write_line(output.clone()).unwrap();
+    writer.finish().unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    let spooled = writer.spool_path().unwrap();
+    assert_eq!(fs::read_to_string(&spooled).unwrap(), format!("{output}\n"));
+
+    // A collector that is down is retried, and the spool is encrypted like the uploads.
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", closed.local_addr().unwrap());
+    drop(closed);
+    let identity = IdentityKey::generate().unwrap();
+    let mut writer = HttpWriter::new(url.parse().unwrap())
+        .unwrap()
+        .retries(1, Duration::from_millis(10))
+        .encrypt_to(identity.recipient())
+        .spool(&spool);
+    writer.write_line(output.clone()).unwrap();
+    writer.finish().unwrap();
+    let spooled = writer.spool_path().unwrap();
+    assert!(spooled.to_string_lossy().ends_with(".bundle"));
+    let decrypted = bundle::open(fs::File::open(&spooled).unwrap(), &identity).unwrap();
+    assert_eq!(String::from_utf8(decrypted).unwrap(), format!("{output}\n"));
+
+    // Without a spool, the failure is reported.
+    let mut writer = HttpWriter::new(url.parse().unwrap()).unwrap().retries(0, Duration::ZERO);
+    writer.write_line(output).unwrap();
+    assert!(writer.finish().is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
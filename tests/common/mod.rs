@@ -0,0 +1,108 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+pub mod ntlm_server;
+pub mod rrp_server;
+pub mod winrm_server;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use rustbelt::{
+    utils::{
+        fs::LocalFileSystem,
+        registry::{RegistryBackend, RegistryHive, RegistryValue},
+        wmi::UnavailableWmi,
+    },
+    Error, Result, Runtime,
+};
+
+/// Registry backend serving a fixed set of keys from memory.
+#[derive(Default)]
+pub struct MemoryRegistry {
+    keys: HashMap<String, Vec<(String, RegistryValue)>>,
+}
+
+impl MemoryRegistry {
+    pub fn with_value(mut self, path: &str, name: &str, value: RegistryValue) -> Self {
+        self.keys
+            .entry(path.to_lowercase())
+            .or_default()
+            .push((name.to_string(), value));
+        self
+    }
+
+    /// Removes a key and its subkeys, to simulate changes between runs.
+    pub fn remove_key(&mut self, path: &str) {
+        let path = path.to_lowercase();
+        let prefix = format!("{path}\\");
+        self.keys.retain(|key, _| *key != path && !key.starts_with(&prefix));
+    }
+}
+
+/// Registry backend serving a `MemoryRegistry` that tests change while a runtime uses it.
+#[derive(Clone, Default)]
+pub struct SharedRegistry(pub Arc<RwLock<MemoryRegistry>>);
+
+impl SharedRegistry {
+    /// Changes the registry in place.
+    pub fn change(&self, change: impl FnOnce(MemoryRegistry) -> MemoryRegistry) {
+        let mut registry = self.0.write().unwrap();
+        *registry = change(std::mem::take(&mut *registry));
+    }
+}
+
+impl RegistryBackend for SharedRegistry {
+    fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.0.read().unwrap().get_sub_key_names(hive, path)
+    }
+
+    fn get_value_names(&self, hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        self.0.read().unwrap().get_value_names(hive, path)
+    }
+
+    fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        self.0.read().unwrap().get_raw_value(hive, path, name)
+    }
+}
+
+impl RegistryBackend for MemoryRegistry {
+    fn get_sub_key_names(&self, _hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}\\", path.to_lowercase());
+        let mut names: Vec<String> = self
+            .keys
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .map(|rest| rest.split('\\').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn get_value_names(&self, _hive: RegistryHive, path: &str) -> Result<Vec<String>> {
+        match self.keys.get(&path.to_lowercase()) {
+            Some(values) => Ok(values.iter().map(|(name, _)| name.clone()).collect()),
+            None => Err(Error::NotFound(path.to_string())),
+        }
+    }
+
+    fn get_raw_value(&self, _hive: RegistryHive, path: &str, name: &str) -> Result<RegistryValue> {
+        self.keys
+            .get(&path.to_lowercase())
+            .and_then(|values| values.iter().find(|(value_name, _)| value_name == name))
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| Error::NotFound(format!("{path}\\{name}")))
+    }
+}
+
+pub fn runtime_with(registry: MemoryRegistry) -> Runtime {
+    Runtime::from_backends(
+        Box::new(registry),
+        Box::new(UnavailableWmi::default()),
+        Box::new(LocalFileSystem::default()),
+    )
+}
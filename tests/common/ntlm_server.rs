@@ -0,0 +1,177 @@
+//! The server side of NTLMv2, for the stand-in servers.
+//!
+//! The acceptor checks the NTLMv2 response and the MIC of the client against
+//! a known password, the way a domain controller would, and derives the
+//! session key the client exported.
+
+use byteorder::{ByteOrder, LittleEndian};
+use hmac::{Hmac, KeyInit, Mac};
+use md5::Md5;
+use rc4::{Rc4, StreamCipher};
+
+use rustbelt::{
+    utils::auth::ntlm::{
+        self, av_pairs, encode_av_pairs, payload, AV_FLAGS, AV_NB_COMPUTER_NAME, AV_NB_DOMAIN_NAME,
+        AV_TIMESTAMP, NEGOTIATE_128, NEGOTIATE_56, NEGOTIATE_ALWAYS_SIGN, NEGOTIATE_EXTENDED_SESSIONSECURITY,
+        NEGOTIATE_KEY_EXCH, NEGOTIATE_NTLM, NEGOTIATE_SEAL, NEGOTIATE_SIGN, NEGOTIATE_TARGET_INFO,
+        NEGOTIATE_UNICODE, NEGOTIATE_VERSION, SIGNATURE,
+    },
+    Error, Result,
+};
+
+const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+/// The flags the server supports.
+const SERVER_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_SIGN
+    | NEGOTIATE_SEAL
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_VERSION
+    | NEGOTIATE_128
+    | NEGOTIATE_KEY_EXCH
+    | NEGOTIATE_56;
+
+/// The user the stand-in servers accept.
+#[derive(Clone)]
+pub struct User {
+    pub domain: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl User {
+    pub fn new(domain: &str, username: &str, password: &str) -> Self {
+        User {
+            domain: domain.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn from_utf16(data: &[u8]) -> String {
+    let units: Vec<u16> = data.chunks_exact(2).map(LittleEndian::read_u16).collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5>>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn denied(what: &str) -> Error {
+    Error::Remote(format!("logon failure: {what}"))
+}
+
+/// One NTLM exchange on the server side.
+pub struct Acceptor {
+    user: User,
+    negotiate: Vec<u8>,
+    challenge: Vec<u8>,
+}
+
+/// The result of a successful exchange.
+pub struct Accepted {
+    pub session_key: [u8; 16],
+    pub flags: u32,
+}
+
+impl Acceptor {
+    pub fn new(user: User) -> Self {
+        Acceptor {
+            user,
+            negotiate: vec![],
+            challenge: vec![],
+        }
+    }
+
+    /// Answers a NEGOTIATE message with a CHALLENGE message that carries a timestamp, so clients have to send a MIC.
+    pub fn challenge(&mut self, negotiate: &[u8]) -> Result<Vec<u8>> {
+        if negotiate.len() < 16 || &negotiate[..8] != SIGNATURE || LittleEndian::read_u32(&negotiate[8..12]) != 1 {
+            return Err(denied("invalid NEGOTIATE message"));
+        }
+        let flags = LittleEndian::read_u32(&negotiate[12..16]) & SERVER_FLAGS | NEGOTIATE_TARGET_INFO;
+
+        let target_info = encode_av_pairs(&[
+            (AV_NB_DOMAIN_NAME, &utf16("DOMAIN")),
+            (AV_NB_COMPUTER_NAME, &utf16("SERVER")),
+            (AV_TIMESTAMP, &0x01da_6bd8_5b3a_4000u64.to_le_bytes()),
+        ]);
+        let target_name = utf16("DOMAIN");
+
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&2u32.to_le_bytes());
+        message.extend_from_slice(&(target_name.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(target_name.len() as u16).to_le_bytes());
+        message.extend_from_slice(&56u32.to_le_bytes());
+        message.extend_from_slice(&flags.to_le_bytes());
+        message.extend_from_slice(&SERVER_CHALLENGE);
+        message.extend_from_slice(&[0; 8]);
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&((56 + target_name.len()) as u32).to_le_bytes());
+        message.extend_from_slice(&[10, 0, 0x7c, 0x4f, 0, 0, 0, 15]);
+        message.extend_from_slice(&target_name);
+        message.extend_from_slice(&target_info);
+
+        self.negotiate = negotiate.to_vec();
+        self.challenge = message.clone();
+        Ok(message)
+    }
+
+    /// Checks an AUTHENTICATE message.
+    pub fn accept(&self, message: &[u8]) -> Result<Accepted> {
+        if message.len() < 88 || &message[..8] != SIGNATURE || LittleEndian::read_u32(&message[8..12]) != 3 {
+            return Err(denied("invalid AUTHENTICATE message"));
+        }
+        let nt_response = payload(message, 20)?;
+        let domain = from_utf16(payload(message, 28)?);
+        let username = from_utf16(payload(message, 36)?);
+        let encrypted_key = payload(message, 52)?;
+        let flags = LittleEndian::read_u32(&message[60..64]);
+
+        let expected = self.user.username.to_lowercase();
+        let qualified = format!("{}@{}", expected, self.user.domain.to_lowercase());
+        if username.to_lowercase() != expected && username.to_lowercase() != qualified {
+            return Err(denied("unknown user"));
+        }
+        if nt_response.len() < 48 {
+            return Err(denied("not an NTLMv2 response"));
+        }
+
+        let response_key = ntlm::ntowf_v2(&ntlm::nt_hash(&self.user.password), &username, &domain);
+        let (proof, blob) = nt_response.split_at(16);
+        if hmac_md5(response_key.as_slice(), &[&SERVER_CHALLENGE, blob]) != proof {
+            return Err(denied("wrong password"));
+        }
+
+        let session_base_key = hmac_md5(response_key.as_slice(), &[proof]);
+        let mut session_key = session_base_key;
+        if flags & NEGOTIATE_KEY_EXCH != 0 {
+            session_key.copy_from_slice(encrypted_key);
+            Rc4::new_from_slice(&session_base_key).unwrap().apply_keystream(&mut session_key);
+        }
+
+        // The challenge carries a timestamp, so the client has to announce and send a MIC.
+        let pairs = av_pairs(&blob[28..])?;
+        let mic_flag = pairs
+            .iter()
+            .any(|(id, value)| *id == AV_FLAGS && LittleEndian::read_u32(value) & 2 != 0);
+        let mut zeroed = message.to_vec();
+        zeroed[72..88].fill(0);
+        if !mic_flag || hmac_md5(&session_key, &[&self.negotiate, &self.challenge, &zeroed]) != message[72..88] {
+            return Err(denied("missing or invalid MIC"));
+        }
+
+        Ok(Accepted { session_key, flags })
+    }
+}
@@ -0,0 +1,593 @@
+//! A stand-in for the remote registry of a Windows machine.
+//!
+//! The server speaks the subset of SMB2, DCE/RPC and MS-RRP that the remote
+//! registry backend uses, and answers from any registry backend. It splits
+//! its RPC responses into small fragments and its pipe reads into small
+//! chunks, so the reassembly paths of the client are exercised as well.
+//! With a user, the server requires NTLM and signs and checks every message
+//! after the session setup.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::ntlm_server::{Acceptor, User};
+use rustbelt::{
+    utils::{
+        registry::{hive_writer::encode_value, RegistryBackend, RegistryHive},
+        rpc::{
+            ndr::{ContextHandle, NdrReader, NdrWriter},
+            read_pdu,
+            smb2::{self, Header},
+            PduHeader, RpcTransport, HEADER_SIZE, PFC_FIRST_FRAG, PFC_LAST_FRAG, PTYPE_BIND,
+            PTYPE_BIND_ACK, PTYPE_FAULT, PTYPE_REQUEST, PTYPE_RESPONSE,
+        },
+    },
+    Error, Result,
+};
+
+/// The fragment size the server negotiates, small enough to split most calls.
+const FRAGMENT_SIZE: usize = 160;
+/// The most data the server returns for one pipe read.
+const READ_CHUNK: usize = 100;
+
+const STATUS_OBJECT_NAME_NOT_FOUND: u32 = 0xC000_0034;
+const STATUS_ACCESS_DENIED: u32 = 0xC000_0022;
+const STATUS_LOGON_FAILURE: u32 = 0xC000_006D;
+const ERROR_FILE_NOT_FOUND: u32 = 2;
+const ERROR_MORE_DATA: u32 = 234;
+const ERROR_NO_MORE_ITEMS: u32 = 259;
+/// The fault status for operations the server does not implement.
+const NCA_S_OP_RNG_ERROR: u32 = 0x1c01_0002;
+
+type SharedRegistry = Arc<dyn RegistryBackend + Send + Sync>;
+
+/// Starts a server for `registry` on a free local port.
+///
+/// The server runs until the test process exits.
+pub fn spawn(registry: impl RegistryBackend + Send + Sync + 'static) -> SocketAddr {
+    spawn_for(registry, None, None)
+}
+
+/// Starts a server for `registry` that only accepts `user`, see `spawn`.
+pub fn spawn_with_user(registry: impl RegistryBackend + Send + Sync + 'static, user: User) -> SocketAddr {
+    spawn_for(registry, Some(user), None)
+}
+
+/// How a man in the middle tampers with the final session setup response, to keep the session unsigned.
+///
+/// # Variants
+/// - `StripSignature`: Removes the signature.
+/// - `Guest`: Removes the signature and makes the session a guest session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Downgrade {
+    StripSignature,
+    Guest,
+}
+
+/// Starts a server for `registry` that only accepts `user`, behind a man in the middle, see `spawn`.
+pub fn spawn_downgraded(
+    registry: impl RegistryBackend + Send + Sync + 'static,
+    user: User,
+    downgrade: Downgrade,
+) -> SocketAddr {
+    spawn_for(registry, Some(user), Some(downgrade))
+}
+
+fn spawn_for(
+    registry: impl RegistryBackend + Send + Sync + 'static,
+    user: Option<User>,
+    downgrade: Option<Downgrade>,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stand-in server");
+    let address = listener.local_addr().unwrap();
+    let registry: SharedRegistry = Arc::new(registry);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let registry = registry.clone();
+            let acceptor = user.clone().map(Acceptor::new);
+            stream.set_nodelay(true).unwrap();
+            thread::spawn(move || {
+                // The client closing the connection ends the session.
+                let _ = Connection::new(stream, registry, acceptor, downgrade).run();
+            });
+        }
+    });
+    address
+}
+
+/// The SMB2 side of a connection: it moves pipe data to and from the RPC server.
+struct Connection {
+    stream: TcpStream,
+    rpc: RpcServer,
+    pending: Vec<u8>,
+    setup_rounds: u32,
+    acceptor: Option<Acceptor>,
+    signing_key: Option<[u8; 16]>,
+    downgrade: Option<Downgrade>,
+}
+
+impl Connection {
+    fn new(
+        stream: TcpStream,
+        registry: SharedRegistry,
+        acceptor: Option<Acceptor>,
+        downgrade: Option<Downgrade>,
+    ) -> Self {
+        Connection {
+            stream,
+            rpc: RpcServer::new(registry),
+            pending: vec![],
+            setup_rounds: 0,
+            acceptor,
+            signing_key: None,
+            downgrade,
+        }
+    }
+
+    /// Answers a session setup request with NTLM, or with two rounds of placeholders without a user.
+    fn session_setup(&mut self, message: &[u8], response: &mut Header) -> Vec<u8> {
+        response.session_id = 0x0000_4000_0000_0011;
+        self.setup_rounds += 1;
+        let mut reply = vec![9, 0, 0, 0];
+        let more = |response: &mut Header, reply: &mut Vec<u8>, token: &[u8]| {
+            response.status = smb2::STATUS_MORE_PROCESSING_REQUIRED;
+            reply.extend_from_slice(&((smb2::HEADER_SIZE + 8) as u16).to_le_bytes());
+            reply.extend_from_slice(&(token.len() as u16).to_le_bytes());
+            reply.extend_from_slice(token);
+        };
+
+        let Some(acceptor) = &mut self.acceptor else {
+            // Ask for a second round, like a challenge-response protocol does.
+            if self.setup_rounds == 1 {
+                more(response, &mut reply, b"CHAL");
+            } else {
+                reply.extend_from_slice(&[0; 4]);
+            }
+            return reply;
+        };
+
+        let offset = LittleEndian::read_u16(&message[smb2::HEADER_SIZE + 12..]) as usize;
+        let length = LittleEndian::read_u16(&message[smb2::HEADER_SIZE + 14..]) as usize;
+        let token = &message[offset..offset + length];
+        let result = if self.setup_rounds == 1 {
+            acceptor.challenge(token).map(|challenge| more(response, &mut reply, &challenge))
+        } else {
+            acceptor.accept(token).map(|accepted| {
+                self.signing_key = Some(accepted.session_key);
+                reply.extend_from_slice(&[0; 4]);
+            })
+        };
+        if result.is_err() {
+            response.status = STATUS_LOGON_FAILURE;
+            reply.truncate(4);
+            reply.extend_from_slice(&[0; 4]);
+        }
+        reply
+    }
+
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let message = smb2::read_frame(&mut self.stream)?;
+            let request = Header::parse(&message)?;
+            let body = &message[smb2::HEADER_SIZE..];
+            let signed = self.signing_key.is_some_and(|key| smb2::verify_signature(&key, &message));
+
+            let mut response = Header {
+                command: request.command,
+                flags: smb2::FLAGS_SERVER_TO_REDIR,
+                message_id: request.message_id,
+                tree_id: request.tree_id,
+                session_id: request.session_id,
+                credits: 64,
+                ..Default::default()
+            };
+
+            let reply = match request.command {
+                _ if self.signing_key.is_some() && !signed => {
+                    response.status = STATUS_ACCESS_DENIED;
+                    vec![9, 0, 0, 0, 0, 0, 0, 0]
+                }
+                smb2::NEGOTIATE => {
+                    let mut reply = vec![0u8; 64];
+                    LittleEndian::write_u16(&mut reply[0..2], 65);
+                    if self.acceptor.is_some() {
+                        reply[2] = 0x03; // signing enabled and required
+                    }
+                    LittleEndian::write_u16(&mut reply[4..6], 0x0210);
+                    LittleEndian::write_u32(&mut reply[28..32], 0x10000);
+                    LittleEndian::write_u32(&mut reply[32..36], 0x10000);
+                    LittleEndian::write_u32(&mut reply[36..40], 0x10000);
+                    reply
+                }
+                smb2::SESSION_SETUP => self.session_setup(&message, &mut response),
+                smb2::TREE_CONNECT => {
+                    response.tree_id = 1;
+                    let mut reply = vec![0u8; 16];
+                    reply[0] = 16;
+                    reply[2] = 2; // pipe share
+                    reply
+                }
+                smb2::CREATE => {
+                    let offset = LittleEndian::read_u16(&body[44..46]) as usize;
+                    let length = LittleEndian::read_u16(&body[46..48]) as usize;
+                    let name: Vec<u16> = message[offset..offset + length]
+                        .chunks_exact(2)
+                        .map(LittleEndian::read_u16)
+                        .collect();
+                    if String::from_utf16_lossy(&name).eq_ignore_ascii_case("winreg") {
+                        let mut reply = vec![0u8; 88];
+                        reply[0] = 89;
+                        reply[64..80].copy_from_slice(&[0xAB; 16]);
+                        reply
+                    } else {
+                        response.status = STATUS_OBJECT_NAME_NOT_FOUND;
+                        vec![9, 0, 0, 0, 0, 0, 0, 0]
+                    }
+                }
+                smb2::WRITE => {
+                    let offset = LittleEndian::read_u16(&body[2..4]) as usize;
+                    let length = LittleEndian::read_u32(&body[4..8]) as usize;
+                    self.rpc.receive(&message[offset..offset + length])?;
+                    self.pending.extend(self.rpc.take_output());
+
+                    let mut reply = vec![0u8; 16];
+                    reply[0] = 17;
+                    LittleEndian::write_u32(&mut reply[4..8], length as u32);
+                    reply
+                }
+                smb2::READ => {
+                    // Answer asynchronously, with an interim response first.
+                    let interim = Header {
+                        status: smb2::STATUS_PENDING,
+                        flags: smb2::FLAGS_SERVER_TO_REDIR | smb2::FLAGS_ASYNC_COMMAND,
+                        ..response
+                    };
+                    smb2::write_frame(&mut self.stream, &interim.encode(&[9, 0, 0, 0, 0, 0, 0, 0, 0]))?;
+
+                    let length = self.pending.len().min(READ_CHUNK);
+                    let data: Vec<u8> = self.pending.drain(..length).collect();
+                    if !self.pending.is_empty() {
+                        response.status = smb2::STATUS_BUFFER_OVERFLOW;
+                    }
+
+                    let mut reply = vec![0u8; 16];
+                    reply[0] = 17;
+                    reply[2] = (smb2::HEADER_SIZE + 16) as u8;
+                    LittleEndian::write_u32(&mut reply[4..8], data.len() as u32);
+                    reply.extend_from_slice(&data);
+                    reply
+                }
+                smb2::CLOSE => {
+                    let mut reply = vec![0u8; 60];
+                    reply[0] = 60;
+                    reply
+                }
+                _ => vec![4, 0, 0, 0],
+            };
+
+            let mut message = response.encode(&reply);
+            if let Some(key) = self.signing_key {
+                LittleEndian::write_u32(&mut message[16..20], response.flags | smb2::FLAGS_SIGNED);
+                let signature = smb2::signature(&key, &message);
+                message[48..64].copy_from_slice(&signature);
+            }
+            if request.command == smb2::SESSION_SETUP && response.status == smb2::STATUS_SUCCESS {
+                if let Some(downgrade) = self.downgrade {
+                    LittleEndian::write_u32(&mut message[16..20], response.flags);
+                    message[48..64].fill(0);
+                    if downgrade == Downgrade::Guest {
+                        LittleEndian::write_u16(&mut message[smb2::HEADER_SIZE + 2..], smb2::SESSION_FLAG_IS_GUEST);
+                    }
+                }
+            }
+            smb2::write_frame(&mut self.stream, &message)?;
+        }
+    }
+}
+
+/// The DCE/RPC side of a connection, serving the winreg interface.
+struct RpcServer {
+    registry: SharedRegistry,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    request: Vec<u8>,
+    handles: HashMap<ContextHandle, (RegistryHive, String)>,
+    next_handle: u32,
+}
+
+/// Gives the server access to data that was written, for `read_pdu`.
+struct Input<'a>(&'a mut Vec<u8>);
+
+impl RpcTransport for Input<'_> {
+    fn write(&mut self, _data: &[u8]) -> Result<()> {
+        unreachable!()
+    }
+
+    fn read(&mut self) -> Result<Vec<u8>> {
+        if self.0.is_empty() {
+            return Err(Error::Remote("no data".to_string()));
+        }
+        Ok(std::mem::take(self.0))
+    }
+}
+
+impl RpcServer {
+    fn new(registry: SharedRegistry) -> Self {
+        RpcServer {
+            registry,
+            input: vec![],
+            output: vec![],
+            request: vec![],
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Handles every complete packet in the data written so far.
+    fn receive(&mut self, data: &[u8]) -> Result<()> {
+        let mut incoming = std::mem::take(&mut self.input);
+        incoming.extend_from_slice(data);
+        let mut buffer = vec![];
+
+        while let Ok(pdu) = read_pdu(&mut Input(&mut incoming), &mut buffer) {
+            self.handle_pdu(&pdu)?;
+        }
+        buffer.extend_from_slice(&incoming);
+        self.input = buffer;
+        Ok(())
+    }
+
+    fn handle_pdu(&mut self, pdu: &[u8]) -> Result<()> {
+        let header = PduHeader::parse(pdu)?;
+        match header.ptype {
+            PTYPE_BIND => {
+                let mut body = vec![];
+                body.extend_from_slice(&(FRAGMENT_SIZE as u16).to_le_bytes());
+                body.extend_from_slice(&(FRAGMENT_SIZE as u16).to_le_bytes());
+                body.extend_from_slice(&0x1234u32.to_le_bytes());
+                let address = b"\\PIPE\\winreg\0";
+                body.extend_from_slice(&(address.len() as u16).to_le_bytes());
+                body.extend_from_slice(address);
+                while !body.len().is_multiple_of(4) {
+                    body.push(0);
+                }
+                body.extend_from_slice(&[1, 0, 0, 0]); // one result
+                body.extend_from_slice(&[0; 4]); // accepted
+                body.extend_from_slice(&rustbelt::utils::rpc::SyntaxId::ndr().to_bytes());
+                self.send(PTYPE_BIND_ACK, PFC_FIRST_FRAG | PFC_LAST_FRAG, header.call_id, &body);
+            }
+            PTYPE_REQUEST => {
+                self.request.extend_from_slice(&pdu[24..]);
+                if header.flags & PFC_LAST_FRAG != 0 {
+                    let opnum = LittleEndian::read_u16(&pdu[22..24]);
+                    let stub = std::mem::take(&mut self.request);
+                    match self.dispatch(opnum, &stub) {
+                        Some(output) => self.respond(header.call_id, &output),
+                        None => {
+                            let mut body = vec![0u8; 8];
+                            body.extend_from_slice(&NCA_S_OP_RNG_ERROR.to_le_bytes());
+                            body.extend_from_slice(&[0; 4]);
+                            self.send(PTYPE_FAULT, PFC_FIRST_FRAG | PFC_LAST_FRAG, header.call_id, &body);
+                        }
+                    }
+                }
+            }
+            ptype => panic!("unexpected packet type {ptype}"),
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, ptype: u8, flags: u8, call_id: u32, body: &[u8]) {
+        let header = PduHeader {
+            ptype,
+            flags,
+            frag_length: 0,
+            auth_length: 0,
+            call_id,
+        };
+        self.output.extend(header.encode(body));
+    }
+
+    /// Sends a response, split into fragments of the negotiated size.
+    fn respond(&mut self, call_id: u32, stub: &[u8]) {
+        let chunks: Vec<&[u8]> = stub.chunks(FRAGMENT_SIZE - HEADER_SIZE - 8).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let mut flags = 0;
+            if index == 0 {
+                flags |= PFC_FIRST_FRAG;
+            }
+            if index == chunks.len() - 1 {
+                flags |= PFC_LAST_FRAG;
+            }
+            let mut body = vec![];
+            body.extend_from_slice(&(stub.len() as u32).to_le_bytes());
+            body.extend_from_slice(&[0; 4]); // context id, cancel count and reserved
+            body.extend_from_slice(chunk);
+            self.send(PTYPE_RESPONSE, flags, call_id, &body);
+        }
+    }
+
+    fn new_handle(&mut self, hive: RegistryHive, path: String) -> ContextHandle {
+        let mut handle = ContextHandle::default();
+        LittleEndian::write_u32(&mut handle.0[16..], self.next_handle);
+        self.next_handle += 1;
+        self.handles.insert(handle, (hive, path));
+        handle
+    }
+
+    /// Runs an operation and returns its output, or `None` for unknown operations.
+    fn dispatch(&mut self, opnum: u16, stub: &[u8]) -> Option<Vec<u8>> {
+        let mut reader = NdrReader::new(stub);
+        let mut writer = NdrWriter::new();
+
+        let hive = match opnum {
+            0 => Some(RegistryHive::ClassesRoot),
+            1 => Some(RegistryHive::CurrentUser),
+            2 => Some(RegistryHive::LocalMachine),
+            4 => Some(RegistryHive::Users),
+            27 => Some(RegistryHive::CurrentConfig),
+            _ => None,
+        };
+        if let Some(hive) = hive {
+            let handle = self.new_handle(hive, String::new());
+            writer.context_handle(&handle);
+            writer.u32(0);
+            return Some(writer.into_bytes());
+        }
+
+        let handle = reader.context_handle().ok()?;
+        let (hive, path) = self.handles.get(&handle).cloned()?;
+        let registry = self.registry.clone();
+
+        match opnum {
+            // BaseRegCloseKey
+            5 => {
+                self.handles.remove(&handle);
+                writer.context_handle(&ContextHandle::default());
+                writer.u32(0);
+            }
+            // BaseRegEnumKey
+            9 => {
+                let index = reader.u32().ok()? as usize;
+                let (_, max_chars) = reader.unicode_string().ok()??;
+                match registry.get_sub_key_names(hive, &path) {
+                    Ok(names) if index < names.len() => {
+                        let name = &names[index];
+                        let mut chars: Vec<u16> = name.encode_utf16().collect();
+                        chars.push(0);
+                        let code = if chars.len() > max_chars { ERROR_MORE_DATA } else { 0 };
+                        write_enum_name(&mut writer, &chars, max_chars, code);
+                        writer.null_pointer(); // class
+                        writer.null_pointer(); // last write time
+                        writer.u32(code);
+                    }
+                    result => {
+                        write_enum_name(&mut writer, &[], max_chars, 0);
+                        writer.null_pointer();
+                        writer.null_pointer();
+                        writer.u32(if result.is_ok() { ERROR_NO_MORE_ITEMS } else { ERROR_FILE_NOT_FOUND });
+                    }
+                }
+            }
+            // BaseRegEnumValue
+            10 => {
+                let index = reader.u32().ok()? as usize;
+                let (_, max_chars) = reader.unicode_string().ok()??;
+                let names = registry.get_value_names(hive, &path).unwrap_or_default();
+                match names.get(index) {
+                    Some(name) => {
+                        let mut chars: Vec<u16> = name.encode_utf16().collect();
+                        chars.push(0);
+                        let code = if chars.len() > max_chars { ERROR_MORE_DATA } else { 0 };
+                        let value = registry.get_raw_value(hive, &path, name).ok()?;
+                        let (data_type, data) = encode_value(&value);
+                        write_enum_name(&mut writer, &chars, max_chars, code);
+                        writer.pointer();
+                        writer.u32(data_type);
+                        writer.null_pointer();
+                        writer.pointer();
+                        writer.u32(data.len() as u32);
+                        writer.pointer();
+                        writer.u32(data.len() as u32);
+                        writer.u32(code);
+                    }
+                    None => {
+                        write_enum_name(&mut writer, &[], max_chars, 0);
+                        for _ in 0..4 {
+                            writer.null_pointer();
+                        }
+                        writer.u32(ERROR_NO_MORE_ITEMS);
+                    }
+                }
+            }
+            // BaseRegOpenKey
+            15 => {
+                let (sub_key, _) = reader.unicode_string().ok()??;
+                let full = [path.as_str(), sub_key.as_str()]
+                    .iter()
+                    .filter(|part| !part.is_empty())
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join("\\");
+                if registry.get_value_names(hive, &full).is_ok()
+                    || registry.get_sub_key_names(hive, &full).is_ok_and(|names| !names.is_empty())
+                {
+                    let handle = self.new_handle(hive, full);
+                    writer.context_handle(&handle);
+                    writer.u32(0);
+                } else {
+                    writer.context_handle(&ContextHandle::default());
+                    writer.u32(ERROR_FILE_NOT_FOUND);
+                }
+            }
+            // BaseRegQueryInfoKey
+            16 => {
+                let filetime = registry
+                    .get_key_last_write_time(hive, &path)
+                    .and_then(|time| time.to_filetime())
+                    .unwrap_or_default();
+                writer.u16(0);
+                writer.u16(0);
+                writer.null_pointer();
+                for _ in 0..7 {
+                    writer.u32(0);
+                }
+                writer.u32(filetime as u32);
+                writer.u32((filetime >> 32) as u32);
+                writer.u32(0);
+            }
+            // BaseRegQueryValue
+            17 => {
+                let (name, _) = reader.unicode_string().ok()??;
+                reader.pointer().ok()?;
+                reader.u32().ok()?;
+                reader.pointer().ok()?;
+                let (size, _) = reader.conformant_varying_bytes().ok()?;
+
+                match registry.get_raw_value(hive, &path, &name) {
+                    Ok(value) => {
+                        let (data_type, data) = encode_value(&value);
+                        let fits = data.len() <= size as usize;
+                        writer.pointer();
+                        writer.u32(data_type);
+                        writer.pointer();
+                        writer.conformant_varying_bytes(if fits { &data } else { &[] }, size);
+                        writer.pointer();
+                        writer.u32(data.len() as u32);
+                        writer.pointer();
+                        writer.u32(if fits { data.len() as u32 } else { 0 });
+                        writer.u32(if fits { 0 } else { ERROR_MORE_DATA });
+                    }
+                    Err(_) => {
+                        for _ in 0..4 {
+                            writer.null_pointer();
+                        }
+                        writer.u32(ERROR_FILE_NOT_FOUND);
+                    }
+                }
+            }
+            _ => return None,
+        }
+        Some(writer.into_bytes())
+    }
+}
+
+/// Writes the name returned by an enumeration, or an empty buffer when it does not fit.
+fn write_enum_name(writer: &mut NdrWriter, chars: &[u16], max_chars: usize, code: u32) {
+    if code == 0 {
+        writer.unicode_string(chars, max_chars);
+    } else {
+        writer.unicode_string(&[], max_chars);
+    }
+}
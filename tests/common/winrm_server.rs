@@ -0,0 +1,288 @@
+//! A stand-in for the WinRM service of a Windows machine.
+//!
+//! The server answers WS-Enumeration requests for WMI classes from a JSON
+//! fixture in the format of `utils::wmi::fixture`. It returns one instance
+//! per page, so the client has to pull the rest of an enumeration. With an
+//! NTLM user, connections have to authenticate first, and messages are
+//! sealed with the NTLM session.
+
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{Map, Value as Json};
+
+use super::ntlm_server::{Acceptor, User};
+use rustbelt::{
+    utils::{
+        auth::ntlm::{NtlmSession, NEGOTIATE_SEAL},
+        http::{read_request, write_response, Request, Response},
+        wmi::winrm::{decrypt_message, encrypt_message, ENCRYPTED_PROTOCOL},
+        xml::{self, escape},
+    },
+    Result,
+};
+
+const WMI_RESOURCE: &str = "http://schemas.microsoft.com/wbem/wsman/1/wmi/";
+const ENVELOPE_START: &str = concat!(
+    r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope""#,
+    r#" xmlns:w="http://schemas.dmtf.org/wbem/wsman/1/wsman.xsd""#,
+    r#" xmlns:n="http://schemas.xmlsoap.org/ws/2004/09/enumeration""#,
+    r#" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"><s:Body>"#
+);
+const ENVELOPE_END: &str = "</s:Body></s:Envelope>";
+
+struct Server {
+    fixture: Map<String, Json>,
+    authorization: Option<String>,
+    ntlm: Option<User>,
+}
+
+/// The NTLM state of a connection.
+#[derive(Default)]
+struct NtlmState {
+    acceptor: Option<Acceptor>,
+    authenticated: bool,
+    session: Option<NtlmSession>,
+}
+
+/// Starts a server for the JSON `fixture` on a free local port.
+///
+/// With `credentials`, the server requires basic authentication with that user name and password.
+/// The server runs until the test process exits.
+pub fn spawn(fixture: &str, credentials: Option<(&str, &str)>) -> SocketAddr {
+    start(Server {
+        fixture: serde_json::from_str(fixture).expect("Invalid WMI fixture"),
+        authorization: credentials
+            .map(|(username, password)| format!("Basic {}", STANDARD.encode(format!("{username}:{password}")))),
+        ntlm: None,
+    })
+}
+
+/// Starts a server for the JSON `fixture` that only accepts `user` through NTLM, see `spawn`.
+pub fn spawn_with_user(fixture: &str, user: User) -> SocketAddr {
+    start(Server {
+        fixture: serde_json::from_str(fixture).expect("Invalid WMI fixture"),
+        authorization: None,
+        ntlm: Some(user),
+    })
+}
+
+fn start(server: Server) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind stand-in server");
+    let address = listener.local_addr().unwrap();
+    let server = Arc::new(server);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = server.clone();
+            stream.set_nodelay(true).unwrap();
+            thread::spawn(move || {
+                // The client closing the connection ends it.
+                let _ = server.serve(stream);
+            });
+        }
+    });
+    address
+}
+
+impl Server {
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut ntlm = NtlmState::default();
+        while let Some(request) = read_request(&mut reader)? {
+            let response = match &self.ntlm {
+                Some(user) => self.handle_ntlm(user, &mut ntlm, request),
+                None => self.handle(&request),
+            };
+            write_response(&mut writer, &response)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        if self.authorization.is_some() && request.get_header("Authorization") != self.authorization.as_deref() {
+            return Response::new(401, "Unauthorized").header("WWW-Authenticate", "Basic realm=\"WSMAN\"");
+        }
+        let Ok(envelope) = xml::parse(&String::from_utf8_lossy(&request.body)) else {
+            return fault("The request is not well-formed XML.");
+        };
+
+        let resource = envelope.find("ResourceURI").map(|uri| uri.text()).unwrap_or_default();
+        let Some(namespace) = resource
+            .strip_prefix(WMI_RESOURCE)
+            .and_then(|path| path.strip_suffix("/*"))
+        else {
+            return fault("The WS-Management service cannot process the request. The resource URI is not supported.");
+        };
+        let Some(classes) = self.namespace(namespace) else {
+            return fault("The WS-Management service cannot process the request. The WMI namespace is invalid.");
+        };
+
+        match envelope.find("Body").and_then(|body| body.elements().next()) {
+            Some(body) if body.local_name() == "Enumerate" => {
+                let query = body.find("Filter").map(|filter| filter.text()).unwrap_or_default();
+                let Some(class) = query.split_whitespace().last() else {
+                    return fault("The WQL query is invalid.");
+                };
+                let instances = instances(classes, class);
+                let context = format!("{class}:1");
+                page("EnumerateResponse", "w", class, &instances, 0, &context)
+            }
+            Some(body) if body.local_name() == "Pull" => {
+                let context = body.find("EnumerationContext").map(|context| context.text()).unwrap_or_default();
+                let Some((class, offset)) = context.split_once(':') else {
+                    return fault("The enumeration context is invalid.");
+                };
+                let offset: usize = offset.parse().unwrap_or(usize::MAX);
+                let instances = instances(classes, class);
+                let context = format!("{class}:{}", offset + 1);
+                page("PullResponse", "n", class, &instances, offset, &context)
+            }
+            _ => fault("The action is not supported."),
+        }
+    }
+
+    /// Authenticates the connection with NTLM, then unseals requests and seals responses.
+    fn handle_ntlm(&self, user: &User, state: &mut NtlmState, mut request: Request) -> Response {
+        let unauthorized = || Response::new(401, "Unauthorized").header("WWW-Authenticate", "Negotiate");
+        let token = request
+            .get_header("Authorization")
+            .and_then(|value| value.strip_prefix("Negotiate "))
+            .and_then(|token| STANDARD.decode(token).ok());
+
+        if let Some(token) = token {
+            return match state.acceptor.take() {
+                None => {
+                    let mut acceptor = Acceptor::new(user.clone());
+                    match acceptor.challenge(&token) {
+                        Ok(challenge) => {
+                            state.acceptor = Some(acceptor);
+                            Response::new(401, "Unauthorized")
+                                .header("WWW-Authenticate", &format!("Negotiate {}", STANDARD.encode(challenge)))
+                        }
+                        Err(_) => unauthorized(),
+                    }
+                }
+                Some(acceptor) => match acceptor.accept(&token) {
+                    Ok(accepted) => {
+                        state.authenticated = true;
+                        state.session = (accepted.flags & NEGOTIATE_SEAL != 0)
+                            .then(|| NtlmSession::server(&accepted.session_key, accepted.flags));
+                        Response::new(200, "OK")
+                    }
+                    Err(_) => unauthorized(),
+                },
+            };
+        }
+        if !state.authenticated {
+            return unauthorized();
+        }
+
+        let Some(session) = &mut state.session else {
+            return self.handle(&request);
+        };
+        match decrypt_message(session, &request.body) {
+            Ok(body) => request.body = body,
+            Err(_) => return Response::new(400, "Bad Request"),
+        }
+        let mut response = self.handle(&request);
+        response.body = encrypt_message(session, &response.body);
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+        response.header(
+            "Content-Type",
+            &format!(r#"multipart/encrypted;protocol="{ENCRYPTED_PROTOCOL}";boundary="Encrypted Boundary""#),
+        )
+    }
+
+    /// Returns the classes of a namespace, given as the path of a resource URI.
+    fn namespace(&self, path: &str) -> Option<&Map<String, Json>> {
+        self.fixture
+            .iter()
+            .find(|(namespace, _)| namespace.replace('\\', "/").eq_ignore_ascii_case(path))
+            .and_then(|(_, classes)| classes.as_object())
+    }
+}
+
+fn instances(classes: &Map<String, Json>, class: &str) -> Vec<Map<String, Json>> {
+    classes
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(class))
+        .and_then(|(_, instances)| instances.as_array())
+        .map(|instances| instances.iter().filter_map(|instance| instance.as_object().cloned()).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the instance at `offset`, and ends the enumeration on the last one.
+///
+/// Enumerate responses use the WS-Management elements of optimized enumerations, pull responses
+/// those of WS-Enumeration, like WinRM does.
+fn page(
+    response: &str,
+    items_prefix: &str,
+    class: &str,
+    instances: &[Map<String, Json>],
+    offset: usize,
+    context: &str,
+) -> Response {
+    let mut body = format!("{ENVELOPE_START}<n:{response}>");
+    if offset + 1 < instances.len() {
+        body.push_str(&format!("<n:EnumerationContext>{}</n:EnumerationContext>", escape(context)));
+    }
+    body.push_str(&format!("<{items_prefix}:Items>"));
+    if let Some(instance) = instances.get(offset) {
+        body.push_str(&instance_xml(class, instance));
+    }
+    body.push_str(&format!("</{items_prefix}:Items>"));
+    if offset + 1 >= instances.len() {
+        body.push_str(&format!("<{items_prefix}:EndOfSequence/>"));
+    }
+    body.push_str(&format!("</n:{response}>{ENVELOPE_END}"));
+    soap(200, "OK", body)
+}
+
+fn instance_xml(class: &str, instance: &Map<String, Json>) -> String {
+    let mut xml = format!(r#"<p:{class} xmlns:p="{WMI_RESOURCE}{class}">"#);
+    for (name, value) in instance {
+        let values = match value {
+            Json::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match value {
+                Json::Null => xml.push_str(&format!(r#"<p:{name} xsi:nil="true"/>"#)),
+                Json::String(text) => xml.push_str(&format!("<p:{name}>{}</p:{name}>", escape(&text))),
+                value => xml.push_str(&format!("<p:{name}>{value}</p:{name}>")),
+            }
+        }
+    }
+    xml.push_str(&format!("</p:{class}>"));
+    xml
+}
+
+fn fault(message: &str) -> Response {
+    let body = format!(
+        concat!(
+            "{start}<s:Fault><s:Code><s:Value>s:Sender</s:Value></s:Code>",
+            "<s:Reason><s:Text>{message}</s:Text></s:Reason>",
+            "<s:Detail><f:WSManFault xmlns:f=\"http://schemas.microsoft.com/wbem/wsman/1/wsmanfault\">",
+            "<f:Message>{message}</f:Message></f:WSManFault></s:Detail></s:Fault>{end}"
+        ),
+        start = ENVELOPE_START,
+        message = escape(message),
+        end = ENVELOPE_END,
+    );
+    soap(500, "Internal Server Error", body)
+}
+
+fn soap(status: u16, reason: &str, body: String) -> Response {
+    Response::new(status, reason)
+        .header("Content-Type", "application/soap+xml;charset=UTF-8")
+        .body(body.into_bytes())
+}
@@ -0,0 +1,108 @@
+//! Golden-output tests: every registered command runs against its fixtures
+//! and the result is compared with a checked-in snapshot.
+//!
+//! The fixtures of a command live in `tests/fixtures/<command>/`, where a `:`
+//! in the command name is replaced by `_`:
+//!
+//! * `registry.reg` - The registry, in `.reg` format. It is turned into
+//!   synthetic hive files and read through the offline registry backend. A
+//!   `; LastWriteTime: 2024-03-01T12:00:00Z` comment in a key sets its last
+//!   write time, which is 1601-01-01 otherwise.
+//! * `wmi.json` - The WMI instances, see `utils::wmi::fixture`.
+//! * `files/` - The file system, with `files/Windows` as `C:\Windows`.
+//! * `expected.json` - The expected result, or `{"error": "..."}` when the
+//!   command is expected to fail.
+//!
+//! All inputs are optional, but every command must have a snapshot. Run the
+//! tests with `UPDATE_SNAPSHOTS=1` to write the snapshots after a deliberate
+//! change, and review the diff before committing them.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::json;
+
+use rustbelt::{
+    command_names,
+    utils::{
+        fs::ImageFileSystem,
+        registry::offline::OfflineRegistry,
+        wmi::{fixture::FixtureWmi, UnavailableWmi, WmiBackend},
+    },
+    Result, Runtime,
+};
+
+fn fixture_dir(command: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(command.replace(':', "_"))
+}
+
+fn fixture_runtime(dir: &Path) -> Result<Runtime> {
+    let registry_file = dir.join("registry.reg");
+    let registry = if registry_file.is_file() {
+        OfflineRegistry::from_reg_file(&registry_file)?
+    } else {
+        OfflineRegistry::new()
+    };
+
+    let wmi_file = dir.join("wmi.json");
+    let wmi: Box<dyn WmiBackend> = if wmi_file.is_file() {
+        Box::new(FixtureWmi::from_file(&wmi_file)?)
+    } else {
+        Box::new(UnavailableWmi::default())
+    };
+
+    Ok(Runtime::from_backends(
+        Box::new(registry),
+        wmi,
+        Box::new(ImageFileSystem::new(dir.join("files"))),
+    ))
+}
+
+/// Runs a command against its fixtures and returns the result as pretty JSON.
+///
+/// The result is serialized directly, so columns keep the order of the command.
+fn run(command: &str, dir: &Path) -> String {
+    let result = fixture_runtime(dir).and_then(|runtime| runtime.execute(command, &[]));
+    let json = match result {
+        Ok(result) => serde_json::to_string_pretty(&result),
+        Err(e) => serde_json::to_string_pretty(&json!({ "error": e.to_string() })),
+    };
+    json.expect("Failed to serialize result") + "\n"
+}
+
+#[test]
+fn test_golden_outputs() {
+    let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some_and(|value| value != "0");
+    let mut failures = vec![];
+
+    for command in command_names() {
+        let dir = fixture_dir(&command);
+        let expected_file = dir.join("expected.json");
+        let actual = run(&command, &dir);
+
+        if update {
+            fs::create_dir_all(&dir).expect("Failed to create fixture directory");
+            fs::write(&expected_file, &actual).expect("Failed to write snapshot");
+            continue;
+        }
+
+        match fs::read_to_string(&expected_file) {
+            Ok(expected) if expected.replace("\r\n", "\n") == actual => {}
+            Ok(expected) => failures.push(format!(
+                "{command}: output differs from {}\n--- expected\n{expected}\n+++ actual\n{actual}",
+                expected_file.display()
+            )),
+            Err(_) => failures.push(format!(
+                "{command}: no snapshot at {}, run with UPDATE_SNAPSHOTS=1 to create it",
+                expected_file.display()
+            )),
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
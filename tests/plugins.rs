@@ -0,0 +1,304 @@
+//! Integration tests for WebAssembly plugin commands.
+#![cfg(feature = "plugins")]
+
+mod common;
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use common::{runtime_with, MemoryRegistry};
+use rustbelt::{
+    get_command,
+    plugins::{load_plugins, register_plugin, Plugin, PluginLimits, PLUGIN_DIR_ENV},
+    utils::registry::{hive_writer::HiveBuilder, RegistryValue},
+    CommandResult, Error, Value,
+};
+
+/// Escapes a string for use in a WAT string literal.
+fn wat_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Builds a plugin that describes itself as `name` and runs `run_body`.
+///
+/// The plugin has a bump allocator starting at offset 4096, and `extra` is
+/// added to the module verbatim.
+fn plugin(name: &str, memory_pages: u32, extra: &str, run_body: &str) -> Vec<u8> {
+    let description = format!(r#"{{"name":"{name}","about":"A test plugin"}}"#);
+    let wat = format!(
+        r#"(module
+            (import "rustbelt" "registry_get_value" (func $get_value (param i32 i32 i32 i32 i32) (result i64)))
+            (memory (export "memory") {memory_pages})
+            (global $heap (mut i32) (i32.const 4096))
+            (func $alloc (export "rustbelt_alloc") (param $len i32) (result i32)
+                (local $ptr i32)
+                (local.set $ptr (global.get $heap))
+                (global.set $heap (i32.add (global.get $heap) (local.get $len)))
+                (local.get $ptr))
+            (data (i32.const 0) "{description}")
+            (func (export "rustbelt_describe") (result i64)
+                (i64.const {description_len}))
+            {extra}
+            (func (export "rustbelt_run") (param $args i32) (param $args_len i32) (result i64)
+                {run_body}))"#,
+        description = wat_string(&description),
+        description_len = description.len(),
+    );
+    wat::parse_str(wat).expect("invalid test plugin")
+}
+
+/// Builds a plugin whose `rustbelt_run` returns `output` verbatim.
+fn static_plugin(name: &str, output: &str) -> Vec<u8> {
+    plugin(
+        name,
+        1,
+        &format!(r#"(data (i32.const 1024) "{}")"#, wat_string(output)),
+        &format!("(i64.const {})", (1024i64 << 32) | output.len() as i64),
+    )
+}
+
+/// Builds a plugin that returns the arguments it was run with, as JSON in the `Args` column of a single row.
+fn args_plugin(name: &str) -> Vec<u8> {
+    let prefix = r#"{"source":"Args","rows":[{"Args":"#;
+    let suffix = "}]}";
+    let extra = format!(
+        r#"(data (i32.const 1024) "{}") (data (i32.const 1536) "{}")"#,
+        wat_string(prefix),
+        wat_string(suffix),
+    );
+    let run_body = format!(
+        r#"(local $out i32)
+           (local.set $out (call $alloc (i32.add (local.get $args_len) (i32.const {wrap_len}))))
+           (memory.copy (local.get $out) (i32.const 1024) (i32.const {prefix_len}))
+           (memory.copy (i32.add (local.get $out) (i32.const {prefix_len})) (local.get $args) (local.get $args_len))
+           (memory.copy (i32.add (i32.add (local.get $out) (i32.const {prefix_len})) (local.get $args_len)) (i32.const 1536) (i32.const {suffix_len}))
+           (i64.or
+               (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+               (i64.extend_i32_u (i32.add (local.get $args_len) (i32.const {wrap_len}))))"#,
+        prefix_len = prefix.len(),
+        suffix_len = suffix.len(),
+        wrap_len = prefix.len() + suffix.len(),
+    );
+    plugin(name, 1, &extra, &run_body)
+}
+
+/// Runs the binary with the plugins of a directory, and returns its standard output.
+fn run_binary(plugins: &Path, config: Option<&Path>, args: &[&OsStr]) -> String {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rustbelt"));
+    command.env(PLUGIN_DIR_ENV, plugins).env("XDG_CONFIG_HOME", plugins).env("APPDATA", plugins);
+    if let Some(config) = config {
+        command.arg("--config").arg(config);
+    }
+    let output = command.args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Creates a directory with the args plugin and an offline image to run it on.
+fn plugin_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustbelt-plugins-{name}-{}", std::process::id()));
+    let config = dir.join("image").join("Windows").join("System32").join("config");
+    std::fs::create_dir_all(&config).unwrap();
+    std::fs::write(config.join("SOFTWARE"), HiveBuilder::new().build()).unwrap();
+    std::fs::write(dir.join("args.wasm"), args_plugin("args")).unwrap();
+    dir
+}
+
+#[test]
+fn test_plugin_description() {
+    let plugin = Plugin::from_bytes(&static_plugin("described", "{}"), PluginLimits::default())
+        .expect("Failed to load plugin");
+
+    assert_eq!(plugin.name(), "described");
+    assert_eq!(plugin.about(), "A test plugin");
+}
+
+#[test]
+fn test_plugin_rows_are_registered_as_command() {
+    let output = r#"{"source":"Static","rows":[{"Name":"x","Count":3,"Enabled":true}]}"#;
+    let plugin = Plugin::from_bytes(&static_plugin("static", output), PluginLimits::default())
+        .expect("Failed to load plugin");
+    register_plugin(plugin).expect("Failed to register plugin");
+
+    assert!(get_command("static").is_some());
+
+    let runtime = runtime_with(MemoryRegistry::default());
+    let CommandResult::Simple(dto) = runtime.execute("static", &[]).expect("Plugin failed") else {
+        panic!("plugins return simple results");
+    };
+
+    assert_eq!(dto.source, "Static");
+    assert_eq!(dto.data.len(), 1);
+    assert_eq!(dto.data[0].get("Name"), Some(&Value::from("x")));
+    assert_eq!(dto.data[0].get("Count"), Some(&Value::Integer(3)));
+    assert_eq!(dto.data[0].get("Enabled"), Some(&Value::Bool(true)));
+}
+
+#[test]
+fn test_plugin_reads_registry_through_runtime() {
+    let path = "SOFTWARE\\Test";
+    let name = "Answer";
+    let prefix = r#"{"source":"Echo","rows":[{"Answer":"#;
+    let suffix = "}]}";
+    let extra = format!(
+        r#"(data (i32.const 1024) "{}") (data (i32.const 1536) "{}")
+           (data (i32.const 2048) "{}") (data (i32.const 2560) "{}")"#,
+        wat_string(path),
+        wat_string(name),
+        wat_string(prefix),
+        wat_string(suffix),
+    );
+    // Call the host, then wrap its answer in a single row.
+    let run_body = format!(
+        r#"(local $answer i64) (local $ptr i32) (local $len i32) (local $out i32)
+           (local.set $answer (call $get_value (i32.const 3) (i32.const 1024) (i32.const {path_len}) (i32.const 1536) (i32.const {name_len})))
+           (local.set $ptr (i32.wrap_i64 (i64.shr_u (local.get $answer) (i64.const 32))))
+           (local.set $len (i32.wrap_i64 (local.get $answer)))
+           (local.set $out (call $alloc (i32.add (local.get $len) (i32.const {wrap_len}))))
+           (memory.copy (local.get $out) (i32.const 2048) (i32.const {prefix_len}))
+           (memory.copy (i32.add (local.get $out) (i32.const {prefix_len})) (local.get $ptr) (local.get $len))
+           (memory.copy (i32.add (i32.add (local.get $out) (i32.const {prefix_len})) (local.get $len)) (i32.const 2560) (i32.const {suffix_len}))
+           (i64.or
+               (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+               (i64.extend_i32_u (i32.add (local.get $len) (i32.const {wrap_len}))))"#,
+        path_len = path.len(),
+        name_len = name.len(),
+        prefix_len = prefix.len(),
+        suffix_len = suffix.len(),
+        wrap_len = prefix.len() + suffix.len(),
+    );
+    let plugin = Plugin::from_bytes(&plugin("echo", 1, &extra, &run_body), PluginLimits::default())
+        .expect("Failed to load plugin");
+    register_plugin(plugin).expect("Failed to register plugin");
+
+    let runtime = runtime_with(MemoryRegistry::default().with_value(
+        path,
+        name,
+        RegistryValue::DWord(42),
+    ));
+    let CommandResult::Simple(dto) = runtime.execute("echo", &[]).expect("Plugin failed") else {
+        panic!("plugins return simple results");
+    };
+
+    assert_eq!(dto.data[0].get("Answer"), Some(&Value::from(r#"{"ok":42}"#)));
+}
+
+#[test]
+fn test_plugin_error_is_reported() {
+    let output = r#"{"error":"nothing to see"}"#;
+    let plugin = Plugin::from_bytes(&static_plugin("failing", output), PluginLimits::default())
+        .expect("Failed to load plugin");
+    register_plugin(plugin).expect("Failed to register plugin");
+
+    let runtime = runtime_with(MemoryRegistry::default());
+    let result = runtime.execute("failing", &[]);
+
+    assert!(matches!(result, Err(Error::Plugin(message)) if message.contains("nothing to see")));
+}
+
+#[test]
+fn test_plugin_runs_out_of_fuel() {
+    let limits = PluginLimits {
+        fuel: 10_000,
+        ..PluginLimits::default()
+    };
+    let wasm = plugin("spinner", 1, "", "(loop $forever (br $forever)) (i64.const 0)");
+    let plugin = Plugin::from_bytes(&wasm, limits).expect("Failed to load plugin");
+    register_plugin(plugin).expect("Failed to register plugin");
+
+    let runtime = runtime_with(MemoryRegistry::default());
+    let result = runtime.execute("spinner", &[]);
+
+    assert!(matches!(result, Err(Error::Plugin(_))));
+}
+
+#[test]
+fn test_plugin_memory_limit() {
+    let limits = PluginLimits {
+        memory_bytes: 1024 * 1024,
+        ..PluginLimits::default()
+    };
+    // 32 pages of 64 KiB exceed the limit of 1 MiB.
+    let wasm = plugin("hungry", 32, "", "(i64.const 0)");
+
+    assert!(matches!(
+        Plugin::from_bytes(&wasm, limits),
+        Err(Error::Plugin(_))
+    ));
+}
+
+#[test]
+fn test_plugin_memory_out_of_bounds() {
+    let runtime = runtime_with(MemoryRegistry::default());
+
+    // An output that ends past the memory, with a huge length.
+    let wasm = plugin("overflowing", 1, "", &format!("(i64.const {})", (65000i64 << 32) | 0xffff_ffff));
+    register_plugin(Plugin::from_bytes(&wasm, PluginLimits::default()).expect("Failed to load plugin"))
+        .expect("Failed to register plugin");
+    let result = runtime.execute("overflowing", &[]);
+    assert!(matches!(result, Err(Error::Plugin(message)) if message.contains("out of bounds")));
+
+    // A path passed to the host that ends past the memory, which the host reports.
+    let run_body = "(call $get_value (i32.const 3) (i32.const 1024) (i32.const -1) (i32.const 0) (i32.const 0))";
+    let wasm = plugin("overreaching", 1, "", run_body);
+    register_plugin(Plugin::from_bytes(&wasm, PluginLimits::default()).expect("Failed to load plugin"))
+        .expect("Failed to register plugin");
+    let result = runtime.execute("overreaching", &[]);
+    assert!(matches!(result, Err(Error::Plugin(message)) if message.contains("out of bounds")));
+}
+
+#[test]
+fn test_load_plugins_from_directory() {
+    let dir = std::env::temp_dir().join(format!("rustbelt-plugins-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("loaded.wasm"), static_plugin("loaded", "{}")).unwrap();
+    std::fs::write(dir.join("broken.wasm"), b"not a module").unwrap();
+    std::fs::write(dir.join("ignored.txt"), b"not a plugin").unwrap();
+
+    let loaded = load_plugins(&dir, PluginLimits::default()).expect("Failed to read directory");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(loaded.len(), 2);
+    assert!(loaded.iter().any(|(_, result)| matches!(result, Ok(name) if name == "loaded")));
+    assert!(loaded.iter().any(|(_, result)| result.is_err()));
+    assert!(get_command("loaded").is_some());
+}
+
+#[test]
+fn test_plugin_args_exclude_credentials() {
+    let dir = plugin_dir("credentials");
+    let image = dir.join("image");
+    let args: Vec<&OsStr> = ["--offline".as_ref(), image.as_os_str()]
+        .into_iter()
+        .chain(["-u", "alice", "--password", "Correct Horse", "--format", "ndjson", "args"].map(OsStr::new))
+        .collect();
+    let output = run_binary(&dir, None, &args);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The plugin gets the program and its own name, but none of the options in front of it.
+    assert!(output.contains(r#""Args":["rustbelt","args"]"#), "{output}");
+    assert!(!output.contains("Correct Horse") && !output.contains("alice"), "{output}");
+}
+
+#[test]
+fn test_plugin_args_from_config_and_command_line() {
+    let dir = plugin_dir("config-args");
+    let image = dir.join("image");
+    let config = dir.join("extra.toml");
+    std::fs::write(&config, "[defaults]\nformat = \"ndjson\"\n\n[defaults.args]\nargs = [\"--from-config\"]\n").unwrap();
+    let args: Vec<&OsStr> = ["--offline".as_ref(), image.as_os_str()]
+        .into_iter()
+        .chain(["args", "--hive", "HKLM", "extra"].map(OsStr::new))
+        .collect();
+    let output = run_binary(&dir, Some(&config), &args);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The format of the configuration applies, and the plugin gets its arguments from both.
+    assert!(
+        output.contains(r#""Args":["rustbelt","args","--hive","HKLM","extra","--from-config"]"#),
+        "{output}"
+    );
+}
@@ -0,0 +1,316 @@
+//! Integration tests for remote targets, against a local stand-in server.
+
+mod common;
+
+use common::{ntlm_server::User, rrp_server::Downgrade};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value as Json;
+
+use rustbelt::{
+    commands::base::Value,
+    runtime::limits::{Limit, Limits},
+    utils::{
+        auth::{
+            ntlm::{Credentials, NtlmAuthenticator},
+            Anonymous,
+        },
+        registry::{
+            offline::OfflineRegistry, remote::RemoteRegistry, RegistryBackend, RegistryHive,
+            RegistryValue,
+        },
+        wmi::{
+            winrm::{WinRmAuth, WinRmWmi},
+            WmiBackend,
+        },
+    },
+    Error, Runtime,
+};
+
+const REG: &str = r#"Windows Registry Editor Version 5.00
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Rustbelt]
+; LastWriteTime: 2024-03-01T12:00:00Z
+"Name"="remote"
+"Count"=dword:0000002a
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Rustbelt\First]
+
+[HKEY_LOCAL_MACHINE\SOFTWARE\Rustbelt\Second]
+"#;
+
+#[test]
+fn test_remote_registry_operations() {
+    // A value larger than the first read buffer and than an RPC fragment.
+    let long = "x".repeat(700);
+    let text = format!("{REG}\"Long\"=\"{long}\"\n");
+    let address = common::rrp_server::spawn(OfflineRegistry::from_reg(&text).unwrap());
+    let registry = RemoteRegistry::connect(&address.to_string(), &mut Anonymous).unwrap();
+
+    let hklm = RegistryHive::LocalMachine;
+    assert_eq!(
+        registry.get_sub_key_names(hklm, "SOFTWARE\\Rustbelt").unwrap(),
+        vec!["First", "Second"]
+    );
+    assert_eq!(
+        registry.get_value_names(hklm, "SOFTWARE\\Rustbelt").unwrap(),
+        vec!["Name", "Count"]
+    );
+    assert_eq!(
+        registry.get_raw_value(hklm, "SOFTWARE\\Rustbelt", "Count").unwrap(),
+        RegistryValue::DWord(42)
+    );
+    assert_eq!(
+        registry.get_string_value(hklm, "SOFTWARE\\Rustbelt\\Second", "Long").unwrap(),
+        long
+    );
+    assert_eq!(
+        registry
+            .get_key_last_write_time(hklm, "SOFTWARE\\Rustbelt")
+            .unwrap()
+            .to_string(),
+        "2024-03-01T12:00:00Z"
+    );
+
+    assert!(matches!(
+        registry.get_value_names(hklm, "SOFTWARE\\Missing"),
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(
+        registry.get_raw_value(hklm, "SOFTWARE\\Rustbelt", "Missing"),
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(
+        registry.get_sub_key_names(RegistryHive::PerformanceData, ""),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn test_remote_commands_match_fixtures() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures");
+
+    for command in ["amsiproviders", "lastshutdown", "osinfo"] {
+        let dir = fixtures.join(command);
+        let registry = OfflineRegistry::from_reg_file(&dir.join("registry.reg")).unwrap();
+        let address = common::rrp_server::spawn(registry);
+
+        let runtime = Runtime::remote(&address.to_string(), &mut Anonymous).unwrap();
+        assert!(runtime.is_remote());
+        let result = runtime.execute(command, &[]).unwrap();
+
+        let expected: Json =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("expected.json")).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&result).unwrap(), expected, "{command}");
+    }
+}
+
+#[test]
+fn test_remote_connection_refused() {
+    // Bind and drop a listener to get a port nothing listens on.
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert!(matches!(
+        Runtime::remote(&address.to_string(), &mut Anonymous),
+        Err(Error::Io(_))
+    ));
+}
+
+#[test]
+fn test_remote_registry_timeout() {
+    // A server that takes seconds to answer every call, as if it stopped responding.
+    struct Unresponsive(OfflineRegistry);
+
+    impl RegistryBackend for Unresponsive {
+        fn get_sub_key_names(&self, hive: RegistryHive, path: &str) -> rustbelt::Result<Vec<String>> {
+            std::thread::sleep(Duration::from_secs(3));
+            self.0.get_sub_key_names(hive, path)
+        }
+
+        fn get_value_names(&self, hive: RegistryHive, path: &str) -> rustbelt::Result<Vec<String>> {
+            std::thread::sleep(Duration::from_secs(3));
+            self.0.get_value_names(hive, path)
+        }
+
+        fn get_raw_value(&self, hive: RegistryHive, path: &str, name: &str) -> rustbelt::Result<RegistryValue> {
+            std::thread::sleep(Duration::from_secs(3));
+            self.0.get_raw_value(hive, path, name)
+        }
+    }
+
+    let address = common::rrp_server::spawn(Unresponsive(OfflineRegistry::from_reg(REG).unwrap()));
+    let timeout = Duration::from_millis(200);
+    let runtime = Runtime::remote(&address.to_string(), &mut Anonymous).unwrap().with_limits(Limits::new().timeout(timeout));
+
+    // The command stops at its deadline while it waits for the server, not at the next call after the answer.
+    let started = Instant::now();
+    let Err(Error::Partial(partial)) = runtime.execute("amsiproviders", &[]) else {
+        panic!("the command timed out");
+    };
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    assert_eq!(partial.stopped, [("amsiproviders".to_string(), Limit::Timeout(timeout))]);
+}
+
+fn winrm(address: std::net::SocketAddr, auth: WinRmAuth) -> WinRmWmi {
+    WinRmWmi::new(format!("http://{address}/wsman").parse().unwrap(), auth)
+}
+
+fn basic(username: &str, password: &str) -> WinRmAuth {
+    WinRmAuth::Basic {
+        username: username.to_string(),
+        password: password.to_string().into(),
+    }
+}
+
+#[test]
+fn test_winrm_query() {
+    let fixture = r#"{"root\\Test": {"Thing": [
+        {"Name": "a & b", "Size": 1, "Tags": ["x", "y"]},
+        {"Name": "c", "Size": null},
+        {"Name": "d"}
+    ]}}"#;
+    let address = common::winrm_server::spawn(fixture, Some(("admin", "secret")));
+    let wmi = winrm(address, basic("admin", "secret"));
+    let fields: Vec<String> = ["Name", "Size", "Tags"].iter().map(|field| field.to_string()).collect();
+
+    // Every instance is on its own page, so this pulls twice over the same connection.
+    let rows = wmi.query("ROOT\\test", "SELECT * FROM Thing", &fields).unwrap();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0]["Name"], Value::from("a & b"));
+    assert_eq!(rows[0]["Size"], Value::from("1"));
+    assert_eq!(rows[0]["Tags"], Value::List(vec![Value::from("x"), Value::from("y")]));
+    assert_eq!(rows[1]["Size"], Value::Null);
+    assert_eq!(rows[2]["Tags"], Value::Null);
+
+    assert!(wmi.query("root\\Test", "SELECT * FROM Other", &fields).unwrap().is_empty());
+    match wmi.query("root\\Missing", "SELECT * FROM Thing", &fields) {
+        Err(Error::Remote(message)) => assert!(message.contains("namespace is invalid"), "{message}"),
+        other => panic!("expected a fault, got {other:?}"),
+    }
+
+    for auth in [WinRmAuth::None, basic("admin", "wrong")] {
+        match winrm(address, auth).query("root\\Test", "SELECT * FROM Thing", &fields) {
+            Err(Error::Remote(message)) => assert!(message.contains("access denied"), "{message}"),
+            other => panic!("expected access denied, got {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn test_winrm_command_matches_fixture() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("antivirus");
+    let fixture = std::fs::read_to_string(dir.join("wmi.json")).unwrap();
+    let address = common::winrm_server::spawn(&fixture, None);
+
+    let runtime = common::runtime_with(common::MemoryRegistry::default())
+        .with_wmi(Box::new(winrm(address, WinRmAuth::None)));
+    let result = runtime.execute("antivirus", &[]).unwrap();
+
+    let expected: Json =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("expected.json")).unwrap()).unwrap();
+    assert_eq!(serde_json::to_value(&result).unwrap(), expected);
+}
+
+fn user() -> User {
+    User::new("DOMAIN", "alice", "Correct Horse")
+}
+
+#[test]
+fn test_winrm_timeout() {
+    // A service that accepts connections and never answers them.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let connections: Vec<_> = listener.incoming().collect();
+        drop(connections);
+    });
+
+    let timeout = Duration::from_millis(200);
+    let runtime = common::runtime_with(common::MemoryRegistry::default())
+        .with_wmi(Box::new(winrm(address, WinRmAuth::None)))
+        .with_limits(Limits::new().timeout(timeout));
+    let started = Instant::now();
+    let Err(Error::Partial(partial)) = runtime.execute("antivirus", &[]) else {
+        panic!("the command timed out");
+    };
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+    assert_eq!(partial.stopped, [("antivirus".to_string(), Limit::Timeout(timeout))]);
+}
+
+#[test]
+fn test_ntlm_remote_registry() {
+    let address = common::rrp_server::spawn_with_user(OfflineRegistry::from_reg(REG).unwrap(), user());
+    let hklm = RegistryHive::LocalMachine;
+
+    // The server requires signing, so every message after the session setup is signed and checked.
+    let credentials = Credentials::with_password("DOMAIN\\alice", "Correct Horse");
+    let runtime = Runtime::remote_with_credentials(&address.to_string(), &credentials).unwrap();
+    assert_eq!(
+        runtime.registry().get_string_value(hklm, "SOFTWARE\\Rustbelt", "Name").unwrap(),
+        "remote"
+    );
+
+    // A user principal name and the NT hash of "Correct Horse".
+    let credentials = Credentials::with_nt_hash("alice@domain", "5c242fec5e4cbf0d1e211e4c4844d924").unwrap();
+    let mut auth = NtlmAuthenticator::new(credentials).with_signing(true);
+    let registry = RemoteRegistry::connect(&address.to_string(), &mut auth).unwrap();
+    assert_eq!(
+        registry.get_sub_key_names(hklm, "SOFTWARE\\Rustbelt").unwrap(),
+        vec!["First", "Second"]
+    );
+
+    let wrong = Credentials::with_password("DOMAIN\\alice", "wrong");
+    assert!(matches!(
+        Runtime::remote_with_credentials(&address.to_string(), &wrong),
+        Err(Error::Remote(_))
+    ));
+    assert!(matches!(
+        RemoteRegistry::connect(&address.to_string(), &mut Anonymous),
+        Err(Error::Remote(_))
+    ));
+}
+
+#[test]
+fn test_signing_downgrade() {
+    let credentials = Credentials::with_password("DOMAIN\\alice", "Correct Horse");
+    // A man in the middle cannot keep the session unsigned, whether it strips the signature or claims a guest session.
+    for (downgrade, expected) in [(Downgrade::StripSignature, "signature"), (Downgrade::Guest, "guest")] {
+        let address =
+            common::rrp_server::spawn_downgraded(OfflineRegistry::from_reg(REG).unwrap(), user(), downgrade);
+        match Runtime::remote_with_credentials(&address.to_string(), &credentials) {
+            Err(Error::Remote(message)) => assert!(message.contains(expected), "{message}"),
+            Err(e) => panic!("expected a remote error, got {e:?}"),
+            Ok(_) => panic!("the {downgrade:?} downgrade was accepted"),
+        }
+    }
+}
+
+#[test]
+fn test_ntlm_winrm_query() {
+    let fixture = std::fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/antivirus/wmi.json"),
+    )
+    .unwrap();
+    let address = common::winrm_server::spawn_with_user(&fixture, user());
+    let fields = vec!["displayName".to_string()];
+
+    // Over HTTP, requests and responses are sealed with the NTLM session.
+    let credentials = Credentials::with_password("DOMAIN\\alice", "Correct Horse");
+    let wmi = winrm(address, WinRmAuth::Ntlm(credentials));
+    for _ in 0..2 {
+        let rows = wmi
+            .query("root\\SecurityCenter2", "SELECT * FROM AntiVirusProduct", &fields)
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["displayName"], Value::from("Example AV"));
+    }
+
+    let wrong = Credentials::with_password("DOMAIN\\alice", "wrong");
+    let wmi = winrm(address, WinRmAuth::Ntlm(wrong));
+    match wmi.query("root\\SecurityCenter2", "SELECT * FROM AntiVirusProduct", &fields) {
+        Err(Error::Remote(message)) => assert!(message.contains("access denied"), "{message}"),
+        other => panic!("expected access denied, got {other:?}"),
+    }
+}
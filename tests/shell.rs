@@ -0,0 +1,111 @@
+//! Integration tests for the interactive shell.
+#![cfg(feature = "shell")]
+
+mod common;
+
+use common::{runtime_with, MemoryRegistry};
+use rustbelt::{
+    shell::{Flow, Shell},
+    utils::registry::RegistryValue,
+    Error,
+};
+
+fn shell() -> Shell {
+    let mut registry = MemoryRegistry::default()
+        .with_value("SOFTWARE\\Vendor\\App", "Version", RegistryValue::DWord(42))
+        .with_value("SOFTWARE\\Vendor\\App", "", RegistryValue::String("default".to_string()));
+    for (index, dll) in ["C:\\mpoav.dll", "C:\\Temp\\evil.dll"].iter().enumerate() {
+        let provider = format!("{{00000000-0000-0000-0000-{index:012}}}");
+        registry = registry
+            .with_value(
+                &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+                "",
+                RegistryValue::String(format!("Provider {index}")),
+            )
+            .with_value(
+                &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+                "",
+                RegistryValue::String(dll.to_string()),
+            );
+    }
+    Shell::new(runtime_with(registry))
+}
+
+/// Handles a line and returns its output.
+fn run(shell: &mut Shell, line: &str) -> String {
+    let mut out = vec![];
+    assert_eq!(shell.handle(line, &mut out).unwrap(), Flow::Continue, "{line}");
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_commands_and_filters() {
+    let mut shell = shell();
+    assert!(run(&mut shell, "commands").lines().any(|line| line == "amsiproviders"));
+
+    let output = run(&mut shell, "amsiproviders");
+    assert!(output.contains("mpoav.dll") && output.contains("evil.dll"));
+
+    // Filters, projections and the format stay in effect for later commands.
+    run(&mut shell, "where `AMSI Provider` contains 'temp'");
+    run(&mut shell, "select AMSI Provider");
+    run(&mut shell, "format ndjson");
+    let output = run(&mut shell, "amsiproviders");
+    let row: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+    assert_eq!(row["data"], serde_json::json!({ "AMSI Provider": "C:\\Temp\\evil.dll" }));
+    assert!(run(&mut shell, "show").contains("where:  `AMSI Provider` contains 'temp'"));
+
+    run(&mut shell, "where");
+    assert_eq!(run(&mut shell, "amsiproviders").lines().count(), 2);
+
+    // The help lists every format that can be switched to.
+    let help = run(&mut shell, "help");
+    assert!(rustbelt::runtime::formatter::FORMATS.iter().all(|format| help.contains(format)));
+
+    let mut out = vec![];
+    assert!(matches!(shell.handle("nosuchcommand", &mut out), Err(Error::NotFound(_))));
+    assert!(matches!(shell.handle("where Name >", &mut out), Err(Error::InvalidData(_))));
+    assert!(matches!(shell.handle("format xml", &mut out), Err(Error::InvalidData(_))));
+    assert_eq!(shell.handle("exit", &mut out).unwrap(), Flow::Exit);
+}
+
+#[test]
+fn test_registry_browsing() {
+    let mut shell = shell();
+    assert_eq!(shell.prompt(), "rustbelt> ");
+
+    run(&mut shell, "cd HKLM\\SOFTWARE\\Vendor");
+    assert_eq!(shell.prompt(), "rustbelt HKLM\\SOFTWARE\\Vendor> ");
+    assert_eq!(run(&mut shell, "ls"), "app\\\n");
+
+    run(&mut shell, "cd app");
+    assert_eq!(run(&mut shell, "ls"), "Version  REG_DWORD  0x0000002a (42)\n(Default)  REG_SZ  default\n");
+    assert_eq!(run(&mut shell, "get Version"), "0x0000002a (42)\n");
+    assert_eq!(run(&mut shell, "get ..\\app \"\""), "default\n");
+
+    run(&mut shell, "cd ..\\..");
+    assert_eq!(run(&mut shell, "pwd"), "HKLM\\SOFTWARE\n");
+    run(&mut shell, "cd");
+    assert_eq!(shell.prompt(), "rustbelt> ");
+
+    let mut out = vec![];
+    assert!(matches!(shell.handle("ls SOFTWARE", &mut out), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn test_completion() {
+    let mut shell = shell();
+    let (start, candidates) = shell.complete("amsi", 4);
+    assert_eq!(start, 0);
+    assert_eq!(candidates, ["amsiproviders"]);
+
+    let complete = |shell: &Shell, line: &str| shell.complete(line, line.len());
+    assert_eq!(complete(&shell, "format nd"), (7, vec!["ndjson".to_string()]));
+    assert_eq!(complete(&shell, "cd HKL").1, ["HKLM\\"]);
+    assert_eq!(complete(&shell, "cd HKLM\\software\\v").1, ["HKLM\\software\\vendor\\"]);
+
+    run(&mut shell, "cd HKLM\\SOFTWARE");
+    let (start, candidates) = complete(&shell, "ls mi");
+    assert_eq!(start, 3);
+    assert_eq!(candidates, ["microsoft\\"]);
+}
@@ -0,0 +1,94 @@
+//! Integration tests for runs against many targets.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::{runtime_with, MemoryRegistry};
+use rustbelt::{
+    runtime::targets::{merge, run_targets, RunSummary, Target, TARGET_COLUMN},
+    utils::registry::RegistryValue,
+    CommandResult, Error, Value,
+};
+
+fn provider_registry(dll: &str) -> MemoryRegistry {
+    let provider = "{2781761E-28E0-4109-99FE-B9D127C57AFE}";
+    MemoryRegistry::default()
+        .with_value(
+            &format!("SOFTWARE\\Microsoft\\AMSI\\Providers\\{provider}"),
+            "",
+            RegistryValue::String("Windows Defender".to_string()),
+        )
+        .with_value(
+            &format!("SOFTWARE\\Classes\\CLSID\\{provider}\\InprocServer32"),
+            "",
+            RegistryValue::String(dll.to_string()),
+        )
+}
+
+#[test]
+fn test_run_targets() {
+    let targets: Vec<Target> = ["alpha", "down", "beta", "gamma"]
+        .iter()
+        .map(|host| Target::Host(host.to_string()))
+        .collect();
+    let commands = vec![
+        ("amsiproviders".to_string(), vec![]),
+        ("does-not-exist".to_string(), vec![]),
+    ];
+
+    let connected = AtomicUsize::new(0);
+    let reports = run_targets(&targets, &commands, 2, |target| {
+        connected.fetch_add(1, Ordering::SeqCst);
+        match target.to_string().as_str() {
+            "down" => Err(Error::Remote("connection refused".to_string())),
+            host => Ok(runtime_with(provider_registry(&format!("C:\\{host}.dll")))),
+        }
+    });
+    assert_eq!(connected.load(Ordering::SeqCst), 4);
+
+    // Reports keep the order of the targets, whatever order the workers finished in.
+    let names: Vec<String> = reports.iter().map(|report| report.target.to_string()).collect();
+    assert_eq!(names, ["alpha", "down", "beta", "gamma"]);
+    assert!(reports[1].outcome.is_err());
+
+    let CommandResult::Group(tables) = reports[2].tagged_result() else {
+        panic!("tagged results are groups");
+    };
+    assert_eq!(tables.len(), 1);
+    assert_eq!(tables[0].data[0][TARGET_COLUMN], Value::from("beta"));
+    assert_eq!(tables[0].data[0]["AMSI Provider"], Value::from("C:\\beta.dll"));
+
+    let CommandResult::Group(merged) = merge(&reports) else {
+        panic!("merged results are groups");
+    };
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].source, "Amsi Providers");
+    let tagged: Vec<(Value, Value)> = merged[0]
+        .data
+        .iter()
+        .map(|row| (row[TARGET_COLUMN].clone(), row["AMSI Provider"].clone()))
+        .collect();
+    assert_eq!(
+        tagged,
+        vec![
+            (Value::from("alpha"), Value::from("C:\\alpha.dll")),
+            (Value::from("beta"), Value::from("C:\\beta.dll")),
+            (Value::from("gamma"), Value::from("C:\\gamma.dll")),
+        ]
+    );
+
+    let summary = RunSummary::new(&reports);
+    assert_eq!(summary.targets, 4);
+    assert_eq!(summary.unreachable.len(), 1);
+    assert_eq!(summary.unreachable[0].0, "down");
+    assert_eq!(summary.failed.len(), 3);
+    assert!(summary
+        .failed
+        .iter()
+        .all(|(_, command, _)| command == "does-not-exist"));
+
+    let text = summary.to_string();
+    assert!(text.contains("4 targets, 3 reachable, 3 failed commands"), "{text}");
+    assert!(text.contains("down : remote error: connection refused"), "{text}");
+}